@@ -26,17 +26,27 @@ pub async fn clacks<B>(req: Request<B>, next: Next<B>) -> Result<Response, Statu
 pub async fn http_logging<B>(req: Request<B>, next: Next<B>) -> Result<Response, StatusCode> {
     let method = req.method().to_owned();
     let uri = req.uri().to_owned();
+    let chain = v1::proxy::proxy_chain(req.headers());
     let response = next.run(req).await;
+
+    let mut notes: Vec<String> = Vec::new();
+    if !chain.is_empty() {
+        notes.push(format!("relayed by {}", chain.join(" -> ")));
+    }
     if let Some(proxied_from) = response.headers().get("Serval-Proxied-From") {
+        notes.push(format!("via {}", proxied_from.to_str().unwrap()));
+    }
+
+    if notes.is_empty() {
+        log::info!("{} {} {}", response.status().as_u16(), method, uri);
+    } else {
         log::info!(
-            "{} {} {} (via {})",
+            "{} {} {} ({})",
             response.status().as_u16(),
             method,
             uri,
-            proxied_from.to_str().unwrap(),
+            notes.join("; "),
         );
-    } else {
-        log::info!("{} {} {}", response.status().as_u16(), method, uri);
     }
     Ok(response)
 }
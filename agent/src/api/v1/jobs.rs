@@ -5,7 +5,9 @@ use axum::response::IntoResponse;
 use axum::routing::{any, get, post};
 use engine::errors::ServalEngineError;
 use engine::ServalEngine;
+use utils::errors::ServalError;
 use utils::mesh::ServalRole;
+use utils::structs::versions::VersionRequirement;
 use utils::structs::Job;
 
 use crate::storage::STORAGE;
@@ -24,23 +26,23 @@ pub fn mount_proxy(router: ServalRouter) -> ServalRouter {
 }
 
 /// Relay all storage requests to a node that can handle them.
-async fn proxy(State(state): State<AppState>, mut request: Request<Body>) -> impl IntoResponse {
+async fn proxy(State(state): State<AppState>, request: Request<Body>) -> impl IntoResponse {
     let path = request.uri().path();
     log::info!("relaying a job runner request; path={path}");
     metrics::increment_counter!("proxy:{path}");
 
-    if let Ok(resp) =
-        super::proxy::relay_request(&mut request, &ServalRole::Runner, &state.instance_id).await
-    {
-        resp
-    } else {
-        // Welp, not much we can do
-        metrics::increment_counter!("proxy:error");
-        (
-            StatusCode::SERVICE_UNAVAILABLE,
-            "Peer with the job runner role not available",
-        )
-            .into_response()
+    match super::proxy::relay_request(request, &ServalRole::Runner, &state).await {
+        Ok(resp) => resp,
+        Err(e @ ServalError::ProxyLoopDetected(_)) => e.into_response(),
+        Err(_) => {
+            // Welp, not much we can do
+            metrics::increment_counter!("proxy:error");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Peer with the job runner role not available",
+            )
+                .into_response()
+        }
     }
 }
 
@@ -49,9 +51,11 @@ async fn running(_state: State<AppState>) -> impl IntoResponse {
     StatusCode::NOT_IMPLEMENTED
 }
 
-/// This is the main worker endpoint. It accepts incoming jobs and runs them.
+/// This is the main worker endpoint. It accepts incoming jobs and runs them. The job is named by
+/// its fully-qualified name, optionally followed by `@` and a version requirement (`@1.2`, `@^2`,
+/// `@latest`); the highest stored version that satisfies it runs.
 async fn run_job(
-    Path(name): Path<String>,
+    Path(spec): Path<String>,
     state: State<AppState>,
     input: Bytes,
 ) -> impl IntoResponse {
@@ -59,11 +63,16 @@ async fn run_job(
         return (StatusCode::SERVICE_UNAVAILABLE, "unable to locate a storage node on the mesh".to_string()).into_response();
     };
 
-    let Ok(manifest) = storage.manifest(&name).await else {
-        return (StatusCode::NOT_FOUND, "no manifest of that name found").into_response();
+    let (name, requirement) = match VersionRequirement::split(&spec) {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
+    };
+
+    let Ok(manifest) = storage.resolve_manifest(name, &requirement).await else {
+        return (StatusCode::NOT_FOUND, format!("no manifest found matching {name}@{requirement}")).into_response();
     };
 
-    let Ok(executable) = storage.executable_as_bytes(&name, manifest.version()).await else {
+    let Ok(executable) = storage.executable_as_bytes(name, manifest.version()).await else {
         return (StatusCode::NOT_FOUND,
             format!("no executable found for manifest;  name={name}; version={}", manifest.version())).into_response();
     };
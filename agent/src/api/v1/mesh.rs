@@ -1,43 +1,94 @@
-use axum::extract::{Path, State};
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use axum::extract::{Path, Query, State};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
 use axum::routing::get;
 use axum::Json;
-use utils::mesh::{KaboodleMesh, ServalRole};
-use utils::structs::api::MeshMember;
+use futures::{stream, Stream, StreamExt};
+use utils::mesh::{KaboodleMesh, ServalMesh, ServalRole};
+use utils::structs::api::{MeshEvent, MeshMember, PeerQuery};
 
 use crate::structures::*;
 
 /// Mount all mesh-related introspection endpoints.
 pub fn mount(router: ServalRouter) -> ServalRouter {
     router
-        .route("/v1/mesh/peers/:role", get(filter_peers)) // TODO
-        .route("/v1/mesh/peers", get(list_peers)) // TODO
+        .route("/v1/mesh/peers/:role", get(filter_peers))
+        .route("/v1/mesh/peers", get(list_peers))
+        .route("/v1/mesh/events", get(stream_events))
 }
 
-/// List all known peers.
-async fn list_peers(_state: State<AppState>) -> Json<Vec<MeshMember>> {
+/// List all known peers, narrowed down and ordered by the `count`, `roles` and `sort` query
+/// parameters.
+async fn list_peers(Query(query): Query<PeerQuery>, _state: State<AppState>) -> Response {
     let mesh = MESH.get().expect("Peer network not initialized!"); // yes, we crash in this case
-    let peers = mesh
-        .peers()
-        .await
-        .into_iter()
-        .map(|peer| peer.into())
-        .collect();
-    Json(peers)
+    match query.apply(known_members(mesh).await) {
+        Ok(peers) => Json(peers).into_response(),
+        Err(e) => e.into_response(),
+    }
 }
 
-/// Filter known peers to only those that advertise the specific role.
+/// Filter known peers to only those that advertise the specific role and can be reached over http.
+/// Takes the same query parameters as the full listing.
 async fn filter_peers(
     Path(role): Path<ServalRole>,
+    Query(query): Query<PeerQuery>,
     _state: State<AppState>,
-) -> Json<Vec<MeshMember>> {
-    // TODO: add a "count" paramter
+) -> Response {
     let mesh = MESH.get().expect("Peer network not initialized!"); // yes, we crash in this case
-    let peers = mesh
-        .peers_with_role(&role)
+    let mut peers = known_members(mesh).await;
+    peers.retain(|peer| peer.roles.contains(&role) && peer.http_address.is_some());
+    match query.apply(peers) {
+        Ok(peers) => Json(peers).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+// Everyone we know about in the mesh, along with our latest latency measurement for each of them.
+async fn known_members(mesh: &ServalMesh) -> Vec<MeshMember> {
+    let latencies: HashMap<String, f64> = mesh
+        .peer_latencies()
         .await
         .into_iter()
-        .map(|peer| peer.into())
+        .map(|(peer, latency)| {
+            let millis = latency.as_micros() as f64 / 1000.0;
+            (peer.instance_id().to_string(), millis)
+        })
         .collect();
 
-    Json(peers)
+    mesh.peers()
+        .await
+        .into_iter()
+        .map(|peer| {
+            let mut member = MeshMember::from(peer);
+            member.latency_ms = latencies.get(&member.instance_id).copied();
+            member
+        })
+        .collect()
+}
+
+/// Stream changes to the mesh's membership as server-sent events, one per `MeshEvent`. The stream
+/// opens with a `joined` event for every current member, so subscribers can build their whole view
+/// of the mesh from it. If a subscriber falls too far behind, we end the stream; reconnecting gets
+/// it a fresh view.
+async fn stream_events(
+    _state: State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    metrics::increment_counter!("mesh:events:subscribe");
+    let (members, receiver) = MESH_EVENTS.subscribe();
+    let current = stream::iter(members).map(|peer| MeshEvent::Joined { peer });
+    let changes = stream::unfold(receiver, |mut receiver| async move {
+        let event = receiver.recv().await.ok()?;
+        Some((event, receiver))
+    });
+    let events = current.chain(changes).map(|event| {
+        Ok(Event::default()
+            .event(event.name())
+            .json_data(&event)
+            .expect("mesh events always serialize"))
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
 }
@@ -1,4 +1,5 @@
 pub mod jobs;
 pub mod mesh;
 pub mod proxy;
+pub mod ranges;
 pub mod storage;
@@ -1,27 +1,47 @@
-use axum::body::{Body, HttpBody};
-use axum::http::{Request, StatusCode};
-use axum::response::{IntoResponse, Response};
-use http::header::{CONTENT_LENGTH, EXPECT, HOST};
+use axum::body::{self, Body};
+use axum::http::{Request, Uri};
+use axum::response::Response;
+use http::header::{
+    HeaderMap, CONNECTION, EXPECT, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER,
+    TRANSFER_ENCODING, UPGRADE,
+};
 use http::HeaderValue;
 use utils::errors::ServalError;
 use utils::mesh::{PeerMetadata, ServalRole};
 use uuid::Uuid;
 
-use crate::structures::MESH;
+use crate::structures::{RunnerState, MESH};
+
+/// The instance ids of every agent that has relayed a request so far, oldest first.
+pub const PROXY_CHAIN_HEADER: &str = "Serval-Proxy-Chain";
+/// How many times a request has been relayed so far.
+pub const HOP_COUNT_HEADER: &str = "Serval-Hop-Count";
 
 // Relay the given request to to the first node that we discover that is advertising the given
 // service. in the future, we may keep a list of known nodes for a given service so we can avoid
 // running the discovery process for every proxy request.
 pub async fn relay_request(
-    req: &mut Request<Body>,
+    req: Request<Body>,
     role: &ServalRole,
-    source_instance_id: &Uuid,
+    state: &RunnerState,
 ) -> Result<Response, ServalError> {
+    let source_instance_id = &state.instance_id;
+    if let Err(err) = check_for_loops(req.headers(), source_instance_id, state.max_proxy_hops) {
+        log::warn!(
+            "Declining to relay request; path={}; err={err}",
+            req.uri().path()
+        );
+        metrics::increment_counter!("proxy:loop_detected");
+        return Err(err);
+    }
+
     let mesh = MESH.get().expect("Peer network not initialized!");
 
     let candidates = mesh.peers_with_role(role).await;
     let Some(peer) = candidates.first() else {
-        log::warn!("proxy_unavailable_services failed to find a node offering the service; service={role}");
+        log::warn!(
+            "proxy_unavailable_services failed to find a node offering the service; service={role}"
+        );
         metrics::increment_counter!("proxy:no_service");
         return Err(ServalError::ServiceNotFound);
     };
@@ -34,140 +54,356 @@ pub async fn relay_request(
     })
 }
 
+/// Send the request on to the given peer and hand back its response. Neither body is ever
+/// buffered: the incoming body is handed to the outgoing request as-is and the peer's response
+/// body is handed back the same way, so chunks (and any trailers the transport carries) flow
+/// through this node as they arrive.
 async fn proxy_request_to_other_node(
-    req: &mut Request<Body>,
+    req: Request<Body>,
     peer: &PeerMetadata,
     source_instance_id: &Uuid,
 ) -> Result<Response, ServalError> {
     let target_instance_id = peer.instance_id();
     let http_address = peer.http_address();
 
-    let path = req.uri().path();
-    let query = req
-        .uri()
-        .query()
-        .map(|qs| format!("?{qs}"))
-        .unwrap_or_else(|| "".to_string());
+    let (mut parts, body) = req.into_parts();
+    let mut chain = proxy_chain(&parts.headers);
+    chain.push(source_instance_id.to_string());
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
     // We know that we are only ever handed a candidate with a http_address.
-    let url = format!("http://{}{path}{query}", http_address.unwrap());
-    let mut inner_req = reqwest::Client::new().request(req.method().clone(), url);
+    let url = format!("http://{}{path_and_query}", http_address.unwrap());
+    parts.uri = url.parse::<Uri>().map_err(anyhow::Error::from)?;
 
-    // Copy over the headers, modulo a few that are only relevant to the original request
-    for (k, v) in req.headers().iter() {
-        if k == CONTENT_LENGTH || k == EXPECT || k == HOST {
-            continue;
-        }
-        inner_req = inner_req.header(k, v);
-    }
-
-    inner_req = inner_req.header(
+    // Drop the headers that only describe the connection to us, not the request itself.
+    strip_hop_by_hop_headers(&mut parts.headers);
+    parts.headers.remove(EXPECT);
+    parts.headers.remove(HOST);
+    parts.headers.insert(
         "Serval-Proxied-For",
         HeaderValue::from_str(&source_instance_id.to_string()).map_err(anyhow::Error::from)?,
     );
-
-    // Copy the body over
-    if let Some(req_body_bytes_res) = req.body_mut().data().await {
-        if let Ok(req_body_bytes) = req_body_bytes_res {
-            inner_req = inner_req.body(req_body_bytes);
-        } else {
-            log::warn!("Failed to copy body bytes over; aborting this request");
-            return Ok((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to copy body bytes",
-            )
-                .into_response());
-        }
-    }
+    parts.headers.insert(
+        PROXY_CHAIN_HEADER,
+        HeaderValue::from_str(&chain.join(", ")).map_err(anyhow::Error::from)?,
+    );
+    parts
+        .headers
+        .insert(HOP_COUNT_HEADER, HeaderValue::from(chain.len()));
 
     // Actually send the request
-    let inner_req_res = inner_req.send().await?;
-    let mut resp = reqwest_response_to_axum_response(inner_req_res).await?;
+    let inner_req = Request::from_parts(parts, body);
+    let inner_resp = hyper::Client::new()
+        .request(inner_req)
+        .await
+        .map_err(|err| {
+            log::warn!(
+                "Failed to read response from proxy node; addr={http_address:?}; err={err:?}"
+            );
+            anyhow::Error::from(err)
+        })?;
 
-    resp.headers_mut().append(
+    let (mut parts, body) = inner_resp.into_parts();
+    strip_hop_by_hop_headers(&mut parts.headers);
+    parts.headers.append(
         "Serval-Proxied-From",
         HeaderValue::from_str(target_instance_id).map_err(anyhow::Error::from)?,
     );
 
-    Ok(resp)
+    Ok(Response::from_parts(parts, body::boxed(body)))
 }
 
-async fn reqwest_response_to_axum_response(
-    reqwest_resp: reqwest::Response,
-) -> Result<Response, ServalError> {
-    let inner_status = reqwest_resp.status();
-    let inner_headers = reqwest_resp.headers().to_owned();
-    let addr = reqwest_resp.remote_addr();
-    let inner_body = reqwest_resp.bytes().await.map_err(|err| {
-        log::warn!("Failed to read response from proxy node; addr={addr:?}; err={err:?}");
-        err
-    })?;
-    let mut axum_resp = (inner_status, inner_body).into_response();
-
-    // Remove any headers that axum hallucinated into the response if the reqwest response has them;
-    // in particular, it will set a content-type of application/octet-stream, which we don't need if
-    // the reqwest_resp has a content-type header of its own.
-    let headers = axum_resp.headers_mut();
-    for k in inner_headers.keys() {
-        if inner_headers.contains_key(k) {
-            headers.remove(k);
-        }
+/// Read the chain of agents that have already relayed this request.
+pub fn proxy_chain(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(PROXY_CHAIN_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+        .collect()
+}
+
+/// Refuse to relay a request that has already passed through this agent, or that has already been
+/// relayed as many times as we allow. Either means role advertisements on the mesh disagree about
+/// who can serve the request, and relaying it again would only feed a request storm.
+fn check_for_loops(
+    headers: &HeaderMap,
+    instance_id: &Uuid,
+    max_hops: usize,
+) -> Result<(), ServalError> {
+    let chain = proxy_chain(headers);
+    let hops = headers
+        .get(HOP_COUNT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or_default()
+        .max(chain.len());
+
+    if chain.contains(&instance_id.to_string()) {
+        return Err(ServalError::ProxyLoopDetected(format!(
+            "request was already relayed by this agent; chain={}",
+            chain.join(", ")
+        )));
+    }
+    if hops >= max_hops {
+        return Err(ServalError::ProxyLoopDetected(format!(
+            "request exceeded the hop limit of {max_hops}; chain={}",
+            chain.join(", ")
+        )));
     }
 
-    for (k, v) in inner_headers.iter() {
-        headers.append(k, v.clone());
+    Ok(())
+}
+
+/// Remove the headers that are meaningful only for a single connection. Hyper works out framing
+/// for each leg of the relay on its own, so passing these along would only confuse it.
+fn strip_hop_by_hop_headers(headers: &mut HeaderMap) {
+    // The Connection header may name further headers that are scoped to this connection.
+    let named: Vec<String> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in named {
+        headers.remove(name.as_str());
     }
 
-    Ok(axum_resp)
+    for name in [
+        CONNECTION,
+        PROXY_AUTHENTICATE,
+        PROXY_AUTHORIZATION,
+        TE,
+        TRAILER,
+        TRANSFER_ENCODING,
+        UPGRADE,
+    ] {
+        headers.remove(name);
+    }
+    headers.remove("keep-alive");
 }
 
 #[cfg(test)]
 mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
-    use anyhow::{anyhow, Result};
-    use axum::body::Bytes;
-    use http::response::Builder;
-    use reqwest::Response;
-    use utils::futures::get_future_sync;
+    use axum::body::{Bytes, HttpBody, StreamBody};
+    use axum::extract::State;
+    use axum::response::IntoResponse;
+    use axum::routing::post;
+    use axum::Router;
+    use futures::{stream, StreamExt};
+    use http::StatusCode;
 
     use super::*;
 
-    fn get_axum_body_as_bytes(resp: axum::response::Response) -> Result<Bytes> {
-        let body = resp.into_body();
-        let Some(body_bytes) = get_future_sync(hyper::body::to_bytes(body)).ok() else {
-            return Err(anyhow!("Could not get body bytes"));
-        };
+    const CHUNK_SIZE: usize = 64 * 1024;
 
-        Ok(body_bytes)
+    // How far one end of a relayed body has got ahead of the other. If the relay buffered a body,
+    // the sender would be able to finish before the receiver saw the first byte of it.
+    #[derive(Default)]
+    struct Progress {
+        sent: AtomicUsize,
+        furthest_ahead: AtomicUsize,
+    }
+
+    impl Progress {
+        fn received(&self, received: usize) {
+            let ahead = self.sent.load(Ordering::SeqCst).saturating_sub(received);
+            self.furthest_ahead.fetch_max(ahead, Ordering::SeqCst);
+        }
+
+        fn furthest_ahead(&self) -> usize {
+            self.furthest_ahead.load(Ordering::SeqCst)
+        }
+    }
+
+    // Bodies relayed in each direction by a counting peer.
+    #[derive(Default)]
+    struct Traffic {
+        request: Arc<Progress>,
+        response: Arc<Progress>,
+    }
+
+    // Start an upstream node on loopback that counts the bytes it is sent and then streams back
+    // that many bytes of its own, and return a peer pointing at it. The proxy chain it was sent is
+    // echoed back so tests can see it.
+    async fn counting_peer(traffic: Arc<Traffic>) -> PeerMetadata {
+        async fn count_and_reply(
+            State(traffic): State<Arc<Traffic>>,
+            req: Request<Body>,
+        ) -> impl IntoResponse {
+            let chain = proxy_chain(req.headers()).join(", ");
+            let mut body = req.into_body();
+            let mut received: usize = 0;
+            while let Some(chunk) = body.data().await {
+                received += chunk.unwrap().len();
+                traffic.request.received(received);
+            }
+            let headers = [("foo", "bar".to_string()), ("chain", chain)];
+            (
+                StatusCode::IM_A_TEAPOT,
+                headers,
+                StreamBody::new(chunks(received, traffic.response.clone())),
+            )
+        }
+
+        let app = Router::new()
+            .route("/count", post(count_and_reply))
+            .with_state(traffic);
+        let server = axum::Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .serve(app.into_make_service());
+        let port = server.local_addr().port();
+        tokio::spawn(server);
+
+        PeerMetadata::new(
+            "upstream".to_string(),
+            Some(port),
+            vec![ServalRole::Storage],
+            Vec::new(),
+            Ipv4Addr::LOCALHOST.into(),
+        )
+    }
+
+    // A stream of `len` bytes that is produced lazily, one chunk at a time. Each chunk is counted
+    // as sent as it is produced.
+    fn chunks(
+        len: usize,
+        progress: Arc<Progress>,
+    ) -> impl futures::Stream<Item = Result<Bytes, std::io::Error>> {
+        let full = len / CHUNK_SIZE;
+        let rest = len % CHUNK_SIZE;
+        stream::iter(
+            (0..full)
+                .map(|_| CHUNK_SIZE)
+                .chain(Some(rest).filter(|n| *n > 0)),
+        )
+        .map(move |size| {
+            progress.sent.fetch_add(size, Ordering::SeqCst);
+            Ok(Bytes::from(vec![b's'; size]))
+        })
     }
 
     #[test]
-    fn test_reqwest_response_to_axum_response() {
-        let mut reqwest_resp = Response::from(
-            Builder::new()
-                .status(418)
-                .body("<whistling noises intensify>")
-                .unwrap(),
+    fn loops_and_long_chains_are_refused() {
+        let me = Uuid::new_v4();
+        let other = Uuid::new_v4();
+
+        let mut headers = HeaderMap::new();
+        assert!(check_for_loops(&headers, &me, 4).is_ok());
+
+        headers.insert(
+            PROXY_CHAIN_HEADER,
+            HeaderValue::from_str(&format!("{other}, {me}")).unwrap(),
         );
-        reqwest_resp
-            .headers_mut()
-            .append("foo", HeaderValue::from_str("bar").unwrap());
-
-        // Make sure the Reqwest response matches expectations
-        assert_eq!(reqwest_resp.status(), 418);
-
-        // Make sure the conversion works
-        let result = get_future_sync(reqwest_response_to_axum_response(reqwest_resp));
-        assert!(result.is_ok());
-        let axum_resp = result.unwrap();
-        assert_eq!(418, axum_resp.status());
+        assert!(matches!(
+            check_for_loops(&headers, &me, 4),
+            Err(ServalError::ProxyLoopDetected(_))
+        ));
+        assert!(check_for_loops(&headers, &Uuid::new_v4(), 4).is_ok());
+        assert!(matches!(
+            check_for_loops(&headers, &Uuid::new_v4(), 2),
+            Err(ServalError::ProxyLoopDetected(_))
+        ));
+
+        // A hop count with no chain still counts against the limit.
+        let mut headers = HeaderMap::new();
+        headers.insert(HOP_COUNT_HEADER, HeaderValue::from(3));
+        assert!(check_for_loops(&headers, &me, 4).is_ok());
+        assert!(check_for_loops(&headers, &me, 3).is_err());
+    }
+
+    #[test]
+    fn hop_by_hop_headers_are_stripped() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, HeaderValue::from_static("close, x-scoped"));
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("x-scoped", HeaderValue::from_static("yes"));
+        headers.insert("x-kept", HeaderValue::from_static("yes"));
+
+        strip_hop_by_hop_headers(&mut headers);
+        assert_eq!(1, headers.len());
+        assert_eq!("yes", headers.get("x-kept").unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn relays_status_headers_and_body() {
+        let peer = counting_peer(Arc::default()).await;
+        let source = Uuid::new_v4();
+        let earlier = Uuid::new_v4();
+        let req = Request::post("/count?yes=no")
+            .header(CONNECTION, "keep-alive")
+            .header(PROXY_CHAIN_HEADER, earlier.to_string())
+            .body(Body::from("<whistling noises intensify>"))
+            .unwrap();
+
+        let resp = proxy_request_to_other_node(req, &peer, &source)
+            .await
+            .expect("relaying should succeed");
+        assert_eq!(StatusCode::IM_A_TEAPOT, resp.status());
+        assert_eq!("bar", resp.headers().get("foo").unwrap());
         assert_eq!(
-            HeaderValue::from_str("bar").unwrap(),
-            axum_resp.headers().get("foo").unwrap()
+            format!("{earlier}, {source}"),
+            resp.headers().get("chain").unwrap().to_str().unwrap()
         );
-        let body_bytes: Vec<u8> = get_axum_body_as_bytes(axum_resp).unwrap().into();
         assert_eq!(
-            "<whistling noises intensify>",
-            String::from_utf8_lossy(&body_bytes)
+            "upstream",
+            resp.headers().get("Serval-Proxied-From").unwrap()
+        );
+
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!("<whistling noises intensify>".len(), body.len());
+    }
+
+    // Both bodies must reach the other end while they are still being sent. Whatever is buffered
+    // along the way (socket buffers, hyper's own) is bounded, and far smaller than the body.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn relays_large_bodies_with_bounded_memory() {
+        const BODY_SIZE: usize = 256 * 1024 * 1024;
+        const MAX_AHEAD: usize = 32 * 1024 * 1024;
+
+        let traffic = Arc::new(Traffic::default());
+        let peer = counting_peer(traffic.clone()).await;
+        let source = Uuid::new_v4();
+        let req = Request::post("/count")
+            .body(Body::wrap_stream(chunks(
+                BODY_SIZE,
+                traffic.request.clone(),
+            )))
+            .unwrap();
+
+        let resp = proxy_request_to_other_node(req, &peer, &source)
+            .await
+            .expect("relaying should succeed");
+        assert_eq!(StatusCode::IM_A_TEAPOT, resp.status());
+
+        let mut body = resp.into_body();
+        let mut received: usize = 0;
+        while let Some(chunk) = body.data().await {
+            received += chunk.unwrap().len();
+            traffic.response.received(received);
+        }
+
+        assert_eq!(BODY_SIZE, received);
+        assert!(
+            traffic.request.furthest_ahead() < MAX_AHEAD,
+            "the request body got {} bytes ahead of the peer",
+            traffic.request.furthest_ahead()
+        );
+        assert!(
+            traffic.response.furthest_ahead() < MAX_AHEAD,
+            "the response body got {} bytes ahead of the client",
+            traffic.response.furthest_ahead()
         );
     }
 }
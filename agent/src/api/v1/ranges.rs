@@ -0,0 +1,308 @@
+//! Conditional and partial downloads of stored data. Stored blobs never change, so their integrity
+//! makes a perfect entity tag: clients that already have a blob can revalidate it with
+//! `If-None-Match`, and clients that were interrupted can fetch just the rest of it with `Range`,
+//! using `If-Range` to make sure they're still fetching the same thing.
+//!
+//! Blobs that are stored compressed can also be sent as they're stored, to clients whose
+//! `Accept-Encoding` allows it. That's a different representation of the blob, so it gets an
+//! entity tag of its own.
+
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use ssri::Integrity;
+
+use crate::storage::compression::ZSTD;
+use crate::storage::{BlobMetadata, ByteRange};
+
+/// What a download request turns out to want, given what's stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Requested {
+    /// The client already has this data.
+    NotModified,
+    /// All of the data.
+    Full,
+    /// Just this part of the data.
+    Partial(ByteRange),
+    /// A part of the data that doesn't exist.
+    Unsatisfiable,
+}
+
+impl Requested {
+    /// Decide what a request wants from its `If-None-Match`, `Range` and `If-Range` headers. Range
+    /// headers we can't make sense of, or that ask for more than one range, are ignored and the
+    /// whole of the data is sent, as HTTP allows.
+    pub fn from_headers(headers: &HeaderMap, metadata: &BlobMetadata) -> Self {
+        let etag = etag(&metadata.integrity);
+        if let Some(candidates) = header_str(headers, header::IF_NONE_MATCH) {
+            if matches_any(candidates, &etag)
+                || matches_any(candidates, &encoded_etag(&metadata.integrity, ZSTD))
+            {
+                return Requested::NotModified;
+            }
+        }
+
+        let Some(range) = header_str(headers, header::RANGE) else {
+            return Requested::Full;
+        };
+        // If-Range may also hold a date, which can never match one of our entity tags; either way,
+        // a client whose copy is out of date gets the whole thing.
+        if let Some(validator) = header_str(headers, header::IF_RANGE) {
+            if validator.trim() != etag {
+                return Requested::Full;
+            }
+        }
+        match parse_range(range, metadata.size) {
+            Some(Ok(range)) => Requested::Partial(range),
+            Some(Err(())) => Requested::Unsatisfiable,
+            None => Requested::Full,
+        }
+    }
+
+    /// The response for requests that don't need a body from storage, if this is one of them.
+    pub fn bodiless_response(&self, metadata: &BlobMetadata) -> Option<Response> {
+        match self {
+            Requested::NotModified => Some(
+                (
+                    StatusCode::NOT_MODIFIED,
+                    [(header::ETAG, etag(&metadata.integrity))],
+                )
+                    .into_response(),
+            ),
+            Requested::Unsatisfiable => Some(
+                (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{}", metadata.size))],
+                )
+                    .into_response(),
+            ),
+            Requested::Full | Requested::Partial(_) => None,
+        }
+    }
+
+    /// The status and headers to send along with the data.
+    pub fn response_parts(&self, metadata: &BlobMetadata) -> (StatusCode, HeaderMap) {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        if let Ok(etag) = HeaderValue::from_str(&etag(&metadata.integrity)) {
+            headers.insert(header::ETAG, etag);
+        }
+
+        match self {
+            Requested::Partial(range) => {
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(range.len));
+                let content_range =
+                    format!("bytes {}-{}/{}", range.start, range.last(), metadata.size);
+                if let Ok(content_range) = HeaderValue::from_str(&content_range) {
+                    headers.insert(header::CONTENT_RANGE, content_range);
+                }
+                (StatusCode::PARTIAL_CONTENT, headers)
+            }
+            _ => {
+                headers.insert(header::CONTENT_LENGTH, HeaderValue::from(metadata.size));
+                (StatusCode::OK, headers)
+            }
+        }
+    }
+}
+
+/// The entity tag for stored data: its integrity string, quoted.
+pub fn etag(integrity: &Integrity) -> String {
+    format!("\"{integrity}\"")
+}
+
+/// The entity tag for stored data sent compressed with the given content coding.
+pub fn encoded_etag(integrity: &Integrity, encoding: &str) -> String {
+    format!("\"{integrity}-{encoding}\"")
+}
+
+/// The headers to send along with stored data sent just as it's stored, compressed with the given
+/// content coding. There's no range to worry about: those are always served uncompressed.
+pub fn encoded_response_headers(integrity: &Integrity, encoding: &str, size: u64) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+    headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    if let Ok(encoding) = HeaderValue::from_str(encoding) {
+        headers.insert(header::CONTENT_ENCODING, encoding);
+    }
+    if let Ok(etag) = HeaderValue::from_str(&encoded_etag(integrity, encoding)) {
+        headers.insert(header::ETAG, etag);
+    }
+    headers
+}
+
+/// Whether a request's `Accept-Encoding` header allows the given content coding, by name or by `*`,
+/// with a quality above zero.
+pub fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    let Some(accepted) = header_str(headers, header::ACCEPT_ENCODING) else {
+        return false;
+    };
+    accepted.split(',').any(|item| {
+        let mut parts = item.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let quality = parts
+            .filter_map(|param| param.strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        (name.eq_ignore_ascii_case(encoding) || name == "*") && quality > 0.0
+    })
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+// Whether an If-None-Match header matches the given entity tag. It uses weak comparison, so a
+// weak tag with the same value counts.
+fn matches_any(candidates: &str, etag: &str) -> bool {
+    candidates.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+// Parse a Range header against data of the given size. None means the header should be ignored;
+// an error means it asks for nothing that exists.
+fn parse_range(value: &str, size: u64) -> Option<Result<ByteRange, ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // A suffix: the last so many bytes.
+        let suffix: u64 = last.parse().ok()?;
+        if suffix == 0 || size == 0 {
+            return Some(Err(()));
+        }
+        let len = suffix.min(size);
+        return Some(Ok(ByteRange {
+            start: size - len,
+            len,
+        }));
+    }
+
+    let start: u64 = first.parse().ok()?;
+    let end = match last {
+        "" => u64::MAX,
+        last => last.parse::<u64>().ok()?,
+    };
+    if end < start {
+        return None;
+    }
+    if start >= size {
+        return Some(Err(()));
+    }
+    Some(Ok(ByteRange {
+        start,
+        len: end.min(size - 1) - start + 1,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> BlobMetadata {
+        BlobMetadata {
+            integrity: Integrity::from(b"hello, world"),
+            size: 100,
+        }
+    }
+
+    fn requested(headers: &[(header::HeaderName, &str)]) -> Requested {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(name, HeaderValue::from_str(value).unwrap());
+        }
+        Requested::from_headers(&map, &metadata())
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        let range = |start, len| Some(Ok(ByteRange { start, len }));
+        assert_eq!(parse_range("bytes=0-9", 100), range(0, 10));
+        assert_eq!(parse_range("bytes=90-", 100), range(90, 10));
+        assert_eq!(parse_range("bytes=90-500", 100), range(90, 10));
+        assert_eq!(parse_range("bytes=-10", 100), range(90, 10));
+        assert_eq!(parse_range("bytes=-500", 100), range(0, 100));
+        assert_eq!(parse_range("bytes=100-", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=-0", 100), Some(Err(())));
+        assert_eq!(parse_range("bytes=9-0", 100), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("items=0-9", 100), None);
+        assert_eq!(parse_range("bytes=lots", 100), None);
+    }
+
+    #[test]
+    fn parses_accept_encoding() {
+        let accepts = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::ACCEPT_ENCODING,
+                HeaderValue::from_str(value).unwrap(),
+            );
+            accepts_encoding(&headers, ZSTD)
+        };
+        assert!(!accepts_encoding(&HeaderMap::new(), ZSTD));
+        assert!(accepts("zstd"));
+        assert!(accepts("gzip, deflate, br, zstd"));
+        assert!(accepts("gzip;q=1.0, ZSTD;q=0.5"));
+        assert!(accepts("*"));
+        assert!(!accepts("gzip, br"));
+        assert!(!accepts("zstd;q=0"));
+        assert!(!accepts("zstdx"));
+    }
+
+    #[test]
+    fn honors_conditional_headers() {
+        let etag = etag(&metadata().integrity);
+        let partial = Requested::Partial(ByteRange { start: 10, len: 10 });
+
+        assert_eq!(requested(&[]), Requested::Full);
+        assert_eq!(
+            requested(&[(header::IF_NONE_MATCH, &etag)]),
+            Requested::NotModified
+        );
+        assert_eq!(
+            requested(&[(header::IF_NONE_MATCH, &format!("\"other\", W/{etag}"))]),
+            Requested::NotModified
+        );
+        assert_eq!(
+            requested(&[(
+                header::IF_NONE_MATCH,
+                &encoded_etag(&metadata().integrity, ZSTD)
+            )]),
+            Requested::NotModified
+        );
+        assert_eq!(
+            requested(&[(header::IF_NONE_MATCH, "\"other\"")]),
+            Requested::Full
+        );
+        assert_eq!(requested(&[(header::RANGE, "bytes=10-19")]), partial);
+        assert_eq!(
+            requested(&[(header::RANGE, "bytes=10-19"), (header::IF_RANGE, &etag)]),
+            partial
+        );
+        assert_eq!(
+            requested(&[
+                (header::RANGE, "bytes=10-19"),
+                (header::IF_RANGE, "\"other\"")
+            ]),
+            Requested::Full
+        );
+        assert_eq!(
+            requested(&[(header::RANGE, "bytes=200-")]),
+            Requested::Unsatisfiable
+        );
+    }
+}
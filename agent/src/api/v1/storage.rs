@@ -1,23 +1,46 @@
-use axum::body::{Body, Bytes};
-use axum::extract::{Path, State};
-use axum::http::{header, Request, StatusCode};
-use axum::response::IntoResponse;
-use axum::routing::{any, get, head, patch, post, put};
+use std::net::SocketAddr;
+
+use axum::body::{Body, StreamBody};
+use axum::extract::{BodyStream, ConnectInfo, Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{any, delete, get, head, patch, post, put};
+use axum::{Json, Router};
+use futures::TryStreamExt;
+use once_cell::sync::OnceCell;
 use ssri::Integrity;
-use utils::diffs::apply_patch;
+use tokio_util::io::{ReaderStream, StreamReader};
 use utils::errors::ServalError;
-use utils::mesh::ServalRole;
+use utils::mesh::{verify_peer_request, ServalRole, PEER_SIGNATURE_HEADER};
+use utils::structs::api::{
+    BlobInfo, BlobQuery, BundleRequest, DiffQuery, ExecutableQuery, GarbageCollectionQuery,
+    ManifestQuery, ScrubQuery, UploadQuery, INTEGRITY_HEADER,
+};
 use utils::structs::Manifest;
 
-use crate::storage::STORAGE;
+use super::ranges::{accepts_encoding, encoded_response_headers, Requested};
+use crate::storage::compression::ZSTD;
+use crate::storage::{Storage, Upload, STORAGE};
 use crate::structures::*;
 
 /// Mount all storage endpoint handlers onto the passed-in router.
 pub fn mount(router: ServalRouter) -> ServalRouter {
     router
         .route("/v1/storage/manifests", post(store_manifest))
+        .route("/v1/storage/manifests", get(list_manifests))
         .route("/v1/storage/manifests/:name", get(get_manifest))
         .route("/v1/storage/manifests/:name", head(has_manifest))
+        .route("/v1/storage/manifests/:name", delete(delete_manifest))
+        .route("/v1/storage/manifests/:name/versions", get(list_versions))
+        .route(
+            "/v1/storage/manifests/:name/versions/:version",
+            get(get_manifest_version),
+        )
+        .route(
+            "/v1/storage/manifests/:name/versions/:version",
+            delete(delete_manifest_version),
+        )
         .route(
             "/v1/storage/manifests/:name/executable/:version",
             put(store_executable),
@@ -30,55 +53,117 @@ pub fn mount(router: ServalRouter) -> ServalRouter {
         .route("/v1/storage/data/*address", get(get_by_content_address))
         .route("/v1/storage/data/*address", head(has_content_address))
         .route("/v1/storage/data/*address", patch(patch_content_at_address))
+        .route("/v1/storage/data/*address", delete(delete_content_address))
+        .route("/v1/storage/diffs", get(diff_between))
+        .route("/v1/storage/blobs", get(list_described_blobs))
+        .route("/v1/storage/blobs/*address", get(get_blob_info))
+        .route("/v1/storage/blobs/*address", put(describe_blob))
+        .route("/v1/storage/pins", get(list_pins))
+        .route("/v1/storage/pins/*address", put(pin_content_address))
+        .route("/v1/storage/pins/*address", delete(unpin_content_address))
+        .route("/v1/storage/gc", post(collect_garbage))
+        .route("/v1/storage/scrub", post(scrub))
+        .route("/v1/storage/rotate-keys", post(rotate_keys))
+        .route("/v1/storage/export", post(export_bundle))
+        .route("/v1/storage/import", post(import_bundle))
+        .route("/v1/storage/usage", get(usage))
+        .merge(replica_routes(&STORAGE))
+}
+
+/// The routes storage peers replicate through, serving from the given storage once it's
+/// initialized. Only the mesh's storage peers may use them.
+pub fn replica_routes<S>(storage: ReplicaState) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/v1/storage/replicas/data", post(store_replica))
+        .route("/v1/storage/replicas/data/*address", get(get_replica))
+        .route("/v1/storage/replicas/data/*address", head(has_replica))
+        .route("/v1/storage/replicas/data/*address", delete(delete_replica))
+        .route("/v1/storage/replicas/keys/*key", put(store_replica_by_key))
+        .route("/v1/storage/replicas/keys/*key", get(get_replica_by_key))
+        .route("/v1/storage/replicas/keys/*key", head(has_replica_by_key))
+        .route(
+            "/v1/storage/replicas/keys/*key",
+            delete(delete_replica_by_key),
+        )
+        .route_layer(middleware::from_fn_with_state(storage, from_storage_peer))
+        .with_state(storage)
 }
 
+/// The storage the replica routes serve from.
+pub type ReplicaState = &'static OnceCell<Storage>;
+
 /// Mount a handler for all storage routes that relays requests to a node that can handle them.
 pub fn mount_proxy(router: ServalRouter) -> ServalRouter {
     router.route("/v1/storage/*rest", any(proxy))
 }
 
 /// Relay all storage requests to a node that can handle them.
-async fn proxy(State(state): State<AppState>, mut request: Request<Body>) -> impl IntoResponse {
+async fn proxy(State(state): State<AppState>, request: Request<Body>) -> impl IntoResponse {
     let path = request.uri().path();
     metrics::increment_counter!("storage:proxy");
     log::info!("relaying a storage request; path={path}");
 
-    if let Ok(resp) =
-        super::proxy::relay_request(&mut request, &ServalRole::Storage, &state.instance_id).await
-    {
-        resp
-    } else {
-        // Welp, not much we can do
-        (
-            StatusCode::SERVICE_UNAVAILABLE,
-            "Peer with the storage role not available",
-        )
-            .into_response()
+    match super::proxy::relay_request(request, &ServalRole::Storage, &state).await {
+        Ok(resp) => resp,
+        Err(e @ ServalError::ProxyLoopDetected(_)) => e.into_response(),
+        Err(_) => {
+            // Welp, not much we can do
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Peer with the storage role not available",
+            )
+                .into_response()
+        }
     }
 }
 
-async fn store_by_content_address(body: Bytes) -> impl IntoResponse {
+/// Store a blob in the content-addressable store. The request's Content-Type and `x-serval-*`
+/// headers, if it has any, describe the blob.
+async fn store_by_content_address(
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> impl IntoResponse {
     metrics::increment_counter!("storage:cas:get");
     let Some(storage) = STORAGE.get() else {
         return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
     };
+    if let Err(e) = validate_namespace(&query) {
+        return e.into_response();
+    }
+    let info = match BlobInfo::from_headers(&headers) {
+        Ok(info) => info,
+        Err(e) => return e.into_response(),
+    };
 
-    let bytes = body.to_vec();
+    let upload = match spool(storage, body).await {
+        Ok(upload) => upload,
+        Err(e) => return e.into_response(),
+    };
 
-    match storage.store_by_integrity(&bytes).await {
+    match storage
+        .store_upload_by_integrity(query.namespace.as_deref(), &upload)
+        .await
+    {
         Ok(integrity) => {
             log::info!(
                 "Stored new blob in CAS storage; integrity={}; size={}",
                 integrity,
-                bytes.len()
+                upload.size()
             );
+            if let Err(e) = describe_stored_blob(storage, &integrity, &info).await {
+                return e.into_response();
+            }
             (StatusCode::CREATED, integrity.to_string()).into_response()
         }
         Err(e) => e.into_response(),
     }
 }
 
-async fn get_by_content_address(Path(address): Path<String>) -> impl IntoResponse {
+async fn get_by_content_address(Path(address): Path<String>, headers: HeaderMap) -> impl IntoResponse {
     metrics::increment_counter!("storage:cas:get");
     let Some(storage) = STORAGE.get() else {
         return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
@@ -89,15 +174,61 @@ async fn get_by_content_address(Path(address): Path<String>) -> impl IntoRespons
         return e.into_response()
     };
 
-    match storage.stream_by_integrity(integrity).await {
-        Ok(stream) => {
-            let headers = [(
-                header::CONTENT_TYPE,
-                String::from("application/octet-stream"),
-            )];
+    let metadata = match storage.metadata_by_integrity(&integrity).await {
+        Ok(metadata) => metadata,
+        Err(ServalError::DataNotFound(s)) => return (StatusCode::NOT_FOUND, s).into_response(),
+        Err(e) => {
+            log::info!("Error serving CAS data; address={}; error={}", &address, e);
+            return e.into_response();
+        }
+    };
+    let described = match storage
+        .blob_info(&integrity)
+        .await
+        .and_then(|info| info.to_headers())
+    {
+        Ok(described) => described,
+        Err(e) => {
+            log::info!("Error describing CAS data; address={address}; error={e}");
+            return e.into_response();
+        }
+    };
+    let requested = Requested::from_headers(&headers, &metadata);
+    if let Some(response) = requested.bodiless_response(&metadata) {
+        return response;
+    }
+
+    // Clients that can decompress a blob themselves may as well have it just as it's stored.
+    if requested == Requested::Full && accepts_encoding(&headers, ZSTD) {
+        if let Some(encoded) = storage.encoded_stream_by_integrity(&integrity).await {
+            metrics::increment_counter!("storage:cas:get:encoded");
+            log::info!(
+                "Serving CAS data; address={}; encoding={}",
+                &address,
+                encoded.encoding
+            );
+            let mut headers = encoded_response_headers(&integrity, encoded.encoding, encoded.size);
+            headers.extend(described);
+            let body = StreamBody::new(ReaderStream::new(encoded.stream));
+            return (StatusCode::OK, headers, body).into_response();
+        }
+    }
 
-            log::info!("Serving CAS data; address={}", &address);
-            (headers, stream).into_response()
+    let result = match requested {
+        Requested::Partial(range) => storage.stream_range_by_integrity(&integrity, range).await,
+        _ => storage.stream_by_integrity(integrity).await,
+    };
+    match result {
+        Ok(stream) => {
+            log::info!(
+                "Serving CAS data; address={}; requested={:?}",
+                &address,
+                requested
+            );
+            let (status, mut headers) = requested.response_parts(&metadata);
+            headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+            headers.extend(described);
+            (status, headers, stream).into_response()
         }
         Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
         Err(e) => {
@@ -120,10 +251,16 @@ async fn has_content_address(Path(address): Path<String>) -> impl IntoResponse {
 
     match storage.data_exists_by_integrity(&integrity).await {
         Ok(exists) => {
-            if exists {
-                StatusCode::OK.into_response()
-            } else {
-                StatusCode::NOT_FOUND.into_response()
+            if !exists {
+                return StatusCode::NOT_FOUND.into_response();
+            }
+            match storage
+                .blob_info(&integrity)
+                .await
+                .and_then(|info| info.to_headers())
+            {
+                Ok(described) => (StatusCode::OK, described).into_response(),
+                Err(e) => e.into_response(),
             }
         }
         Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
@@ -138,39 +275,162 @@ async fn has_content_address(Path(address): Path<String>) -> impl IntoResponse {
     }
 }
 
-async fn patch_content_at_address(Path(address): Path<String>, body: Bytes) -> impl IntoResponse {
+async fn patch_content_at_address(
+    Path(address): Path<String>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> impl IntoResponse {
     metrics::increment_counter!("storage:cas:patch");
     let Some(storage) = STORAGE.get() else {
         return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
     };
+    if let Err(e) = validate_namespace(&query) {
+        return e.into_response();
+    }
+    let info = match BlobInfo::from_headers(&headers) {
+        Ok(info) => info,
+        Err(e) => return e.into_response(),
+    };
 
     let Ok(integrity) = address.parse::<Integrity>() else {
         let e = ServalError::BlobAddressInvalid(format!("{} is not a valid sub-resource integrity string", address));
         return e.into_response()
     };
+    let patch = match spool(storage, body).await {
+        Ok(patch) => patch,
+        Err(e) => return e.into_response(),
+    };
 
-    match storage.data_by_integrity(integrity).await {
-        Ok(original) => {
-            log::info!("Patching CAS data; address={}", &address);
-            let Ok(updated)= apply_patch(&original, &body) else {
-                return ServalError::StorageError("patch could not be applied".to_string()).into_response();
-            };
-            match storage.store_by_integrity(&updated).await {
-                Ok(integrity) => {
-                    log::info!(
-                        "Stored updated patch in CAS storage; old_integrity={}; new_integrity={}; size={}",
-                        address,
-                        integrity,
-                        updated.len()
-                    );
-                    (StatusCode::CREATED, integrity.to_string()).into_response()
-                }
-                Err(e) => e.into_response(),
+    log::info!("Patching CAS data; address={}", &address);
+    match storage
+        .patch_by_integrity(query.namespace.as_deref(), &integrity, &patch)
+        .await
+    {
+        Ok(updated) => {
+            log::info!(
+                "Stored updated patch in CAS storage; old_integrity={}; new_integrity={}; size={}",
+                address,
+                updated.integrity,
+                updated.size
+            );
+            if let Err(e) = describe_stored_blob(storage, &updated.integrity, &info).await {
+                return e.into_response();
             }
+            (StatusCode::CREATED, updated.integrity.to_string()).into_response()
         }
         Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
         Err(e) => {
-            log::info!("Error serving CAS data; address={}; error={}", &address, e);
+            log::info!("Error patching CAS data; address={}; error={}", &address, e);
+            e.into_response()
+        }
+    }
+}
+
+/// Respond with a bsdiff patch that turns one stored blob into another, which the PATCH endpoint
+/// above can apply. Blobs too big to make patches between are refused with a 413.
+async fn diff_between(Query(query): Query<DiffQuery>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:cas:diff");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    let (Ok(from), Ok(to)) = (query.from.parse::<Integrity>(), query.to.parse::<Integrity>())
+    else {
+        let e = ServalError::BlobAddressInvalid(format!(
+            "{} and {} must both be valid sub-resource integrity strings",
+            query.from, query.to
+        ));
+        return e.into_response();
+    };
+
+    match storage.diff_between(&from, &to).await {
+        Ok(patch) => {
+            log::info!(
+                "Serving patch between CAS blobs; from={}; to={}; size={}",
+                from,
+                to,
+                patch.len()
+            );
+            let headers = [(
+                header::CONTENT_TYPE,
+                String::from("application/octet-stream"),
+            )];
+            (headers, patch).into_response()
+        }
+        Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
+        Err(e) => {
+            log::info!("Error making patch; from={}; to={}; error={}", from, to, e);
+            e.into_response()
+        }
+    }
+}
+
+/// List the blobs that have descriptions, optionally only those with the given label.
+async fn list_described_blobs(Query(query): Query<BlobQuery>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:blobs:list");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.described_blobs(query.label.as_deref()).await {
+        Ok(blobs) => Json(blobs).into_response(),
+        Err(e) => {
+            log::warn!("error listing described blobs; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Respond with the description of a blob, which is empty if nothing is recorded about it.
+async fn get_blob_info(Path(address): Path<String>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:blobs:get");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    let Ok(integrity) = address.parse::<Integrity>() else {
+        let e = ServalError::BlobAddressInvalid(format!("{} is not a valid sub-resource integrity string", address));
+        return e.into_response()
+    };
+
+    match storage.data_exists_by_integrity(&integrity).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => return e.into_response(),
+    }
+    match storage.blob_info(&integrity).await {
+        Ok(info) => Json(info).into_response(),
+        Err(e) => {
+            log::info!("Error describing CAS data; address={address}; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Replace the description of a blob that's already stored. An empty description forgets it.
+async fn describe_blob(
+    Path(address): Path<String>,
+    Json(info): Json<BlobInfo>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:blobs:put");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    let Ok(integrity) = address.parse::<Integrity>() else {
+        let e = ServalError::BlobAddressInvalid(format!("{} is not a valid sub-resource integrity string", address));
+        return e.into_response()
+    };
+
+    match storage.describe_blob(&integrity, &info).await {
+        Ok(()) => {
+            log::info!("Described CAS data; address={address}");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
+        Err(e) => {
+            log::info!("Error describing CAS data; address={address}; error={e}");
             e.into_response()
         }
     }
@@ -180,21 +440,42 @@ async fn patch_content_at_address(Path(address): Path<String>, body: Bytes) -> i
 async fn get_executable(
     Path((name, version)): Path<(String, String)>,
     State(_state): State<AppState>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
     metrics::increment_counter!("storage:executable:get");
     let Some(storage) = STORAGE.get() else {
         return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
     };
 
-    match storage.executable_as_stream(&name, &version).await {
-        Ok(stream) => {
-            let headers = [(
-                header::CONTENT_TYPE,
-                String::from("application/octet-stream"),
-            )];
+    let metadata = match storage.executable_metadata(&name, &version).await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            log::warn!("error reading job binary; name={}; error={}", name, e);
+            return e.into_response();
+        }
+    };
+    let requested = Requested::from_headers(&headers, &metadata);
+    if let Some(response) = requested.bodiless_response(&metadata) {
+        return response;
+    }
 
-            log::info!("Serving job binary; name={}", &name);
-            (headers, stream).into_response()
+    let result = match requested {
+        Requested::Partial(range) => {
+            storage
+                .executable_range_as_stream(&name, &version, range)
+                .await
+        }
+        _ => storage.executable_as_stream(&name, &version).await,
+    };
+    match result {
+        Ok(stream) => {
+            log::info!(
+                "Serving job binary; name={}; requested={:?}",
+                &name,
+                requested
+            );
+            let (status, headers) = requested.response_parts(&metadata);
+            (status, headers, stream).into_response()
         }
         Err(e) => {
             log::warn!("error reading job binary; name={}; error={}", name, e);
@@ -203,6 +484,40 @@ async fn get_executable(
     }
 }
 
+/// List the latest versions of stored manifests, optionally narrowed down by namespace or name
+/// prefix, a page at a time.
+async fn list_manifests(Query(query): Query<ManifestQuery>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:manifest:list");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.list_manifests(&query).await {
+        Ok(listing) => Json(listing).into_response(),
+        Err(e) => {
+            log::warn!("error listing manifests; query={query:?}; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// List every stored version of the named manifest, with the integrity and size of its executable.
+async fn list_versions(Path(name): Path<String>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:manifest:versions");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.manifest_versions(&name).await {
+        Ok(versions) => Json(versions).into_response(),
+        Err(ServalError::ManifestNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
+        Err(e) => {
+            log::warn!("error listing manifest versions; name={name}; error={e}");
+            e.into_response()
+        }
+    }
+}
+
 /// Fetch task manifest by name. The manifest is returned as toml.
 async fn get_manifest(
     Path(name): Path<String>,
@@ -226,11 +541,32 @@ async fn get_manifest(
     }
 }
 
+/// Fetch a specific version of a task manifest. The manifest is returned as toml.
+async fn get_manifest_version(Path((name, version)): Path<(String, String)>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:manifest:get");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.manifest_version(&name, &version).await {
+        Ok(manifest) => {
+            let headers = [(header::CONTENT_TYPE, String::from("application/toml"))];
+            (headers, manifest.to_string()).into_response()
+        }
+        Err(ServalError::ManifestNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
+        Err(e) => {
+            log::warn!("error reading manifest; name={name}@{version}; error={e}");
+            e.into_response()
+        }
+    }
+}
+
 /// Store a job with its metadata.
 async fn store_executable(
     State(_state): State<AppState>,
     Path((name, version)): Path<(String, String)>,
-    body: Bytes,
+    Query(query): Query<ExecutableQuery>,
+    body: BodyStream,
 ) -> impl IntoResponse {
     metrics::increment_counter!("storage:executable:put");
     let Some(storage) = STORAGE.get() else {
@@ -241,16 +577,46 @@ async fn store_executable(
         return (StatusCode::NOT_FOUND, format!("no manifest of that name found; name={name}")).into_response();
     };
 
-    let bytes = body.to_vec();
+    // The executable may already be in the content-addressable store, put there by a patch.
+    if let Some(address) = query.integrity {
+        let Ok(blob) = address.parse::<Integrity>() else {
+            let e = ServalError::BlobAddressInvalid(format!(
+                "{} is not a valid sub-resource integrity string",
+                address
+            ));
+            return e.into_response();
+        };
+        return match storage
+            .store_executable_from_blob(&name, &version, &blob)
+            .await
+        {
+            Ok(integrity) => {
+                log::info!(
+                    "Stored new executable from CAS blob; name={}@{}; executable_hash={}",
+                    manifest.fq_name(),
+                    version,
+                    integrity
+                );
+                (StatusCode::CREATED, integrity.to_string()).into_response()
+            }
+            Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
+            Err(e) => e.into_response(),
+        };
+    }
+
+    let upload = match spool(storage, body).await {
+        Ok(upload) => upload,
+        Err(e) => return e.into_response(),
+    };
 
-    match storage.store_executable(&name, &version, &bytes).await {
+    match storage.store_executable(&name, &version, &upload).await {
         Ok(integrity) => {
             log::info!(
                 "Stored new executable; name={}@{}; executable_hash={}; size={}",
                 manifest.fq_name(),
                 version,
                 integrity,
-                bytes.len()
+                upload.size()
             );
             (StatusCode::CREATED, integrity.to_string()).into_response()
         }
@@ -303,3 +669,544 @@ async fn store_manifest(State(_state): State<AppState>, body: String) -> impl In
         Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
     }
 }
+
+/// Delete every version of the named manifest, along with their executables.
+async fn delete_manifest(Path(name): Path<String>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:manifest:delete");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.delete_manifest(&name).await {
+        Ok(()) => {
+            log::info!("Deleted manifest; name={name}");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(ServalError::ManifestNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
+        Err(e) => {
+            log::warn!("error deleting manifest; name={name}; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Delete one version of the named manifest, along with its executable.
+async fn delete_manifest_version(
+    Path((name, version)): Path<(String, String)>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:manifest:delete");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.delete_manifest_version(&name, &version).await {
+        Ok(()) => {
+            log::info!("Deleted manifest version; name={name}@{version}");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(ServalError::ManifestNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
+        Err(e) => {
+            log::warn!("error deleting manifest version; name={name}@{version}; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Delete a blob from the content-addressable store, unless something still refers to it.
+async fn delete_content_address(Path(address): Path<String>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:cas:delete");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    let Ok(integrity) = address.parse::<Integrity>() else {
+        let e = ServalError::BlobAddressInvalid(format!("{} is not a valid sub-resource integrity string", address));
+        return e.into_response()
+    };
+
+    match storage.delete_by_integrity(&integrity).await {
+        Ok(()) => {
+            log::info!("Deleted CAS data; address={address}");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
+        Err(e) => {
+            log::info!("Error deleting CAS data; address={address}; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// List the integrities of every pinned blob.
+async fn list_pins() -> impl IntoResponse {
+    metrics::increment_counter!("storage:pin:list");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.list_pins().await {
+        Ok(pins) => Json(pins).into_response(),
+        Err(e) => {
+            log::warn!("error listing pins; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Pin a blob so that garbage collection keeps it even if nothing else refers to it.
+async fn pin_content_address(Path(address): Path<String>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:pin:put");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    let Ok(integrity) = address.parse::<Integrity>() else {
+        let e = ServalError::BlobAddressInvalid(format!("{} is not a valid sub-resource integrity string", address));
+        return e.into_response()
+    };
+
+    match storage.pin(&integrity).await {
+        Ok(()) => {
+            log::info!("Pinned CAS data; address={address}");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
+        Err(e) => {
+            log::info!("Error pinning CAS data; address={address}; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Remove the pin from a blob.
+async fn unpin_content_address(Path(address): Path<String>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:pin:delete");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    let Ok(integrity) = address.parse::<Integrity>() else {
+        let e = ServalError::BlobAddressInvalid(format!("{} is not a valid sub-resource integrity string", address));
+        return e.into_response()
+    };
+
+    match storage.unpin(&integrity).await {
+        Ok(()) => {
+            log::info!("Unpinned CAS data; address={address}");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
+        Err(e) => {
+            log::info!("Error unpinning CAS data; address={address}; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Remove every blob that no manifest, executable or pin refers to. Pass `dry_run=true` to find out
+/// what would be removed without removing it.
+async fn collect_garbage(Query(query): Query<GarbageCollectionQuery>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:gc:post");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.collect_garbage(query.dry_run).await {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => {
+            log::warn!("error collecting garbage; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Check every stored blob against its integrity and report the corrupt ones. Pass `repair=true` to
+/// replace them with good copies from other backends or from peers.
+async fn scrub(Query(query): Query<ScrubQuery>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:scrub:post");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.scrub(query.repair).await {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => {
+            log::warn!("error scrubbing storage; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Re-encrypt everything stored encrypted under the current master key.
+async fn rotate_keys() -> impl IntoResponse {
+    metrics::increment_counter!("storage:rotate_keys:post");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.rotate_keys().await {
+        Ok(rotations) => Json(rotations).into_response(),
+        Err(e) => {
+            log::warn!("error rotating storage keys; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Export manifests, their executables, and blobs as a bundle that another mesh can import.
+async fn export_bundle(Json(request): Json<BundleRequest>) -> impl IntoResponse {
+    metrics::increment_counter!("storage:export:post");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    let bundle = match storage.export_bundle(&request).await {
+        Ok(bundle) => bundle,
+        Err(ServalError::DataNotFound(s) | ServalError::ManifestNotFound(s)) => {
+            return (StatusCode::NOT_FOUND, s).into_response()
+        }
+        Err(e) => {
+            log::warn!("error exporting a bundle; error={e}");
+            return e.into_response();
+        }
+    };
+    let size = bundle.size();
+    match bundle.into_stream().await {
+        Ok(stream) => {
+            let headers = [
+                (
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/x-tar"),
+                ),
+                (header::CONTENT_LENGTH, HeaderValue::from(size)),
+            ];
+            let body = StreamBody::new(ReaderStream::new(stream));
+            (StatusCode::OK, headers, body).into_response()
+        }
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Import a bundle exported from this or another mesh, charging its blobs to the given namespace.
+async fn import_bundle(Query(query): Query<UploadQuery>, body: BodyStream) -> impl IntoResponse {
+    metrics::increment_counter!("storage:import:post");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+    if let Err(e) = validate_namespace(&query) {
+        return e.into_response();
+    }
+
+    let reader = StreamReader::new(body.map_err(std::io::Error::other));
+    match storage
+        .import_bundle(query.namespace.as_deref(), reader)
+        .await
+    {
+        Ok(imported) => (StatusCode::CREATED, Json(imported)).into_response(),
+        Err(e) => {
+            log::warn!("error importing a bundle; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Report how much each namespace is storing, along with its quota.
+async fn usage() -> impl IntoResponse {
+    metrics::increment_counter!("storage:usage:get");
+    let Some(storage) = STORAGE.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.usage().await {
+        Ok(usage) => Json(usage).into_response(),
+        Err(e) => {
+            log::warn!("error reporting storage usage; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+// Refuse requests to the replica routes from anyone but our storage peers. If the mesh has a
+// secret, requests have to be signed with it; if it doesn't, they have to come from the address of
+// a storage peer.
+async fn from_storage_peer<B>(
+    State(storage): State<ReplicaState>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let headers = request.headers();
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let signed = verify_peer_request(
+        request.method().as_str(),
+        request.uri().path(),
+        header(INTEGRITY_HEADER),
+        header(PEER_SIGNATURE_HEADER),
+    );
+    let allowed = match (signed, storage.get()) {
+        (Some(signed), _) => signed,
+        (None, Some(storage)) => storage.is_storage_peer(remote.ip()).await,
+        (None, None) => false,
+    };
+    if !allowed {
+        metrics::increment_counter!("storage:replica:refused");
+        log::warn!(
+            "refusing a replica request from outside the mesh; remote={remote}; path={}",
+            request.uri().path()
+        );
+        return (
+            StatusCode::FORBIDDEN,
+            "replica requests are only accepted from storage peers",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+// Spool a replica a peer is sending us, refusing it unless it's what the peer said it was.
+async fn spool_replica(
+    storage: &Storage,
+    headers: &HeaderMap,
+    body: BodyStream,
+) -> Result<Upload, (StatusCode, String)> {
+    let Some(expected) = headers
+        .get(INTEGRITY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<Integrity>().ok())
+    else {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("replicas must send their integrity in {INTEGRITY_HEADER}"),
+        ));
+    };
+    let upload = spool(storage, body)
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if expected.matches(upload.integrity()).is_none() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("replica does not match its integrity; integrity={expected}"),
+        ));
+    }
+    Ok(upload)
+}
+
+/// Store a replica of a blob that a peer is copying to us. Replicas are stored only in our own
+/// backends, aren't replicated any further, and aren't charged to any namespace.
+async fn store_replica(
+    State(storage): State<ReplicaState>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:replica:post");
+    let Some(storage) = storage.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    let upload = match spool_replica(storage, &headers, body).await {
+        Ok(upload) => upload,
+        Err(e) => return e.into_response(),
+    };
+
+    match storage.store_replica(&upload).await {
+        Ok(integrity) => (StatusCode::CREATED, integrity.to_string()).into_response(),
+        Err(e) => {
+            log::warn!("error storing replica; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Stream a blob from our own backends, without asking any peers for it.
+async fn get_replica(
+    State(storage): State<ReplicaState>,
+    Path(address): Path<String>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:replica:get");
+    let Some(storage) = storage.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    let Ok(integrity) = address.parse::<Integrity>() else {
+        let e = ServalError::BlobAddressInvalid(format!("{} is not a valid sub-resource integrity string", address));
+        return e.into_response()
+    };
+
+    match storage.replica(&integrity).await {
+        Ok(stream) => StreamBody::new(ReaderStream::new(stream)).into_response(),
+        Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Check whether our own backends hold a blob, responding with its size if they do.
+async fn has_replica(
+    State(storage): State<ReplicaState>,
+    Path(address): Path<String>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:replica:head");
+    let Some(storage) = storage.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    let Ok(integrity) = address.parse::<Integrity>() else {
+        let e = ServalError::BlobAddressInvalid(format!("{} is not a valid sub-resource integrity string", address));
+        return e.into_response()
+    };
+
+    match storage.replica_metadata(&integrity).await {
+        Some(metadata) => {
+            let headers = [(header::CONTENT_LENGTH, HeaderValue::from(metadata.size))];
+            (StatusCode::OK, headers).into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Delete a blob from our own backends, because a peer deleted it. Succeeds whether or not we had
+/// it, but refuses if one of our own keys still refers to it.
+async fn delete_replica(
+    State(storage): State<ReplicaState>,
+    Path(address): Path<String>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:replica:delete");
+    let Some(storage) = storage.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    let Ok(integrity) = address.parse::<Integrity>() else {
+        let e = ServalError::BlobAddressInvalid(format!("{} is not a valid sub-resource integrity string", address));
+        return e.into_response()
+    };
+
+    match storage.delete_replica(&integrity).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            log::warn!("error deleting replica; address={address}; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Store a replica of keyed data that a peer is copying to us.
+async fn store_replica_by_key(
+    State(storage): State<ReplicaState>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:replica:put");
+    let Some(storage) = storage.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    let upload = match spool_replica(storage, &headers, body).await {
+        Ok(upload) => upload,
+        Err(e) => return e.into_response(),
+    };
+
+    match storage.store_replica_by_key(&key, &upload).await {
+        Ok(integrity) => (StatusCode::CREATED, integrity.to_string()).into_response(),
+        Err(e) => {
+            log::warn!("error storing replica; key={key}; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+/// Stream keyed data from our own backends, without asking any peers for it.
+async fn get_replica_by_key(
+    State(storage): State<ReplicaState>,
+    Path(key): Path<String>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:replica:get");
+    let Some(storage) = storage.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.replica_by_key(&key).await {
+        Ok(stream) => StreamBody::new(ReaderStream::new(stream)).into_response(),
+        Err(ServalError::DataNotFound(s)) => (StatusCode::NOT_FOUND, s).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Check whether our own backends hold keyed data, responding with its integrity and size if they
+/// do.
+async fn has_replica_by_key(
+    State(storage): State<ReplicaState>,
+    Path(key): Path<String>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:replica:head");
+    let Some(storage) = storage.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.replica_metadata_by_key(&key).await {
+        Some(metadata) => {
+            let Ok(integrity) = HeaderValue::from_str(&metadata.integrity.to_string()) else {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            };
+            let headers = [
+                (header::CONTENT_LENGTH, HeaderValue::from(metadata.size)),
+                (header::HeaderName::from_static(INTEGRITY_HEADER), integrity),
+            ];
+            (StatusCode::OK, headers).into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Delete keyed data from our own backends, because a peer deleted it.
+async fn delete_replica_by_key(
+    State(storage): State<ReplicaState>,
+    Path(key): Path<String>,
+) -> impl IntoResponse {
+    metrics::increment_counter!("storage:replica:delete");
+    let Some(storage) = storage.get() else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "storage uninitialized; programmer error".to_string()).into_response();
+    };
+
+    match storage.delete_replica_by_key(&key).await {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            log::warn!("error deleting replica; key={key}; error={e}");
+            e.into_response()
+        }
+    }
+}
+
+// Stream a request body to a temporary file as it arrives, rather than holding it all in memory,
+// refusing it if it's bigger than our upload limit. Storage routes that take big uploads read their
+// bodies this way, so the default body limit doesn't apply to them.
+async fn spool(storage: &Storage, body: BodyStream) -> Result<Upload, ServalError> {
+    let reader = StreamReader::new(body.map_err(std::io::Error::other));
+    Upload::spool(reader, storage.upload_limit()).await
+}
+
+// Record the description an upload came with, if it came with one. Uploads without one leave
+// whatever was recorded about the blob before alone.
+async fn describe_stored_blob(
+    storage: &Storage,
+    integrity: &Integrity,
+    info: &BlobInfo,
+) -> Result<(), ServalError> {
+    if info.is_empty() {
+        return Ok(());
+    }
+    storage.describe_blob(integrity, info).await.map_err(|e| {
+        log::warn!("error describing stored blob; integrity={integrity}; error={e}");
+        e
+    })
+}
+
+// Uploads may name the namespace they're charged to, which must be one a manifest could have.
+fn validate_namespace(query: &UploadQuery) -> Result<(), (StatusCode, String)> {
+    match &query.namespace {
+        Some(namespace) if !Manifest::is_valid_namespace(namespace) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("`{namespace}` is not a valid namespace"),
+        )),
+        _ => Ok(()),
+    }
+}
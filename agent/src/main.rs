@@ -22,7 +22,9 @@ use dotenvy::dotenv_override as dotenv;
 use engine::ServalEngine;
 // TODO: should switch on feature.
 use metrics_exporter_tcp::TcpBuilder;
-use utils::mesh::{mesh_interface_and_port, KaboodleMesh, PeerMetadata, ServalMesh, ServalRole};
+use utils::mesh::{
+    mesh_interface_and_port, mesh_seed_config, KaboodleMesh, PeerMetadata, ServalMesh, ServalRole,
+};
 use utils::networking::find_nearest_port;
 use uuid::Uuid;
 
@@ -53,6 +55,7 @@ async fn main() -> Result<()> {
             config.extensions_path.clone(),
             config.should_run_jobs,
             config.should_run_scheduler,
+            config.max_proxy_hops,
         )
         .await?,
     );
@@ -75,7 +78,7 @@ async fn main() -> Result<()> {
             .and_then(|port_str| port_str.parse::<u16>().ok());
         let port = predefined_port.unwrap_or_else(|| find_nearest_port(8100).unwrap());
         http_addr = format!("{host}:{port}").parse().unwrap();
-        let Ok(builder) = axum::Server::try_bind(&http_addr) else {
+        let Ok(builder) = Server::try_bind(&http_addr) else {
             // Port number in use already, presumably
             if predefined_port.is_some() {
                 log::error!("Specified port number ({port}) is already in use; aborting");
@@ -83,7 +86,7 @@ async fn main() -> Result<()> {
             }
             continue;
         };
-        break builder.serve(app.into_make_service());
+        break builder.serve(app.into_make_service_with_connect_info::<SocketAddr>());
     };
 
     log::info!("serval agent http will listen on {http_addr}");
@@ -119,15 +122,33 @@ async fn main() -> Result<()> {
     }
 
     let (mesh_interface, mesh_port) = mesh_interface_and_port();
+    let seed_config = mesh_seed_config(&mesh_interface);
+    // For now, the only capabilities we advertise are the extensions we can offer to jobs.
+    let mut capabilities: Vec<String> = state.extensions.keys().cloned().collect();
+    capabilities.sort();
     let metadata = PeerMetadata::new(
-        Uuid::new_v4().to_string(),
+        state.instance_id.to_string(),
         Some(http_addr.port()),
         roles,
+        capabilities,
         mesh_interface.ip(),
     );
     let mut mesh = ServalMesh::new(metadata, mesh_port, Some(mesh_interface)).await?;
+    if let Some(seed_config) = seed_config {
+        mesh.set_seeds(seed_config)?;
+    }
     mesh.start().await?;
+    let arrivals = mesh.discover_peers()?;
+    let departures = mesh.discover_departures()?;
     MESH.set(mesh).unwrap();
+    tokio::spawn(async move {
+        let mesh = MESH.get().expect("Peer network not initialized!");
+        MESH_EVENTS.follow(mesh, arrivals, departures).await;
+    });
+    if state.has_storage {
+        tokio::spawn(storage::replication::repair_replicas_forever());
+        tokio::spawn(storage::scrub::scrub_forever());
+    }
 
     // And finally, listen on HTTP.
     server.await.unwrap();
@@ -140,6 +161,7 @@ struct Config {
     should_run_jobs: bool,
     should_run_scheduler: bool,
     blob_path: Option<PathBuf>,
+    max_proxy_hops: usize,
 }
 fn init_config() -> Config {
     let storage_role = match &std::env::var("STORAGE_ROLE").unwrap_or_else(|_| "auto".to_string())[..]
@@ -147,8 +169,8 @@ fn init_config() -> Config {
         "always" => true,
         "auto" => {
             // todo: add some sort of heuristic to determine whether we should be a storage node
-            // for now, don't be a storage node unless explicitly asked to be; this should change
-            // once we have distributed storage rather than a single-node temporary hack.
+            // for now, don't be a storage node unless explicitly asked to be. Storage nodes can
+            // replicate to each other now, but nothing yet decides how many of them a mesh needs.
             false
         }
         "never" => false,
@@ -201,6 +223,17 @@ fn init_config() -> Config {
 
     let extensions_path = std::env::var("EXTENSIONS_PATH").ok().map(PathBuf::from);
 
+    // How many times a request may be relayed between agents before we assume it is going in
+    // circles and give up on it.
+    let max_proxy_hops: usize = std::env::var("MAX_PROXY_HOPS")
+        .ok()
+        .map(|hops_str| {
+            hops_str
+                .parse()
+                .expect("Invalid value given for MAX_PROXY_HOPS")
+        })
+        .unwrap_or(4);
+
     let instance_id: Uuid = std::env::var("INSTANCE_ID")
         .ok()
         .map(|uuid_str| {
@@ -214,6 +247,7 @@ fn init_config() -> Config {
         should_run_jobs,
         should_run_scheduler,
         blob_path,
+        max_proxy_hops,
     }
 }
 
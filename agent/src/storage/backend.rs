@@ -0,0 +1,500 @@
+use std::fs;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use async_trait::async_trait;
+use base64::Engine as _;
+use ssri::{Hash, Integrity, IntegrityChecker};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, ReadBuf};
+use utils::errors::{ServalError, ServalResult};
+use utils::structs::api::{BlobInfo, KeyRotation};
+
+use super::SendableStream;
+
+/// The operations every place we can persist data must support. `Storage` holds an ordered list of
+/// these and decides which of them to consult for each read and write; a backend only has to worry
+/// about itself.
+///
+/// Data is addressed in two ways: by the integrity hash of its contents, for the content-addressable
+/// store, and by a human-readable key, for manifests and executables. Storing by key also makes the
+/// data available by its integrity hash.
+///
+/// Backends whose data can change after it's written check what they read against the integrity
+/// it was stored under, and fail with `ServalError::IntegrityMismatch` if it doesn't match, so that
+/// `Storage` can go to another copy. Streams are checked as they're read; for them, the mismatch
+/// arrives as an `InvalidData` I/O error from the read that reaches the end of the data.
+#[async_trait]
+pub trait StorageBackend: std::fmt::Debug + Send + Sync {
+    /// A short name for this backend, for logging.
+    fn name(&self) -> &'static str;
+
+    /// Whether this backend is across the network, and slow enough that reads from it are worth
+    /// caching locally.
+    fn is_remote(&self) -> bool {
+        false
+    }
+
+    /// Store a blob of data in the content-addressable store, responding with its integrity hash.
+    async fn store_by_integrity(&self, bytes: &[u8]) -> ServalResult<Integrity>;
+
+    /// Store a blob whose integrity and size we already know, reading it from a stream rather than
+    /// from memory. Backends that can write as they read should; the default implementation reads
+    /// the whole stream into memory first. Fails if the data doesn't match the integrity.
+    async fn store_stream_by_integrity(
+        &self,
+        integrity: &Integrity,
+        _size: u64,
+        mut stream: SendableStream,
+    ) -> ServalResult<Integrity> {
+        let mut bytes = Vec::new();
+        stream.read_to_end(&mut bytes).await?;
+        integrity.check(&bytes)?;
+        self.store_by_integrity(&bytes).await
+    }
+
+    /// Given a content address, return a read stream for the object stored there.
+    async fn stream_by_integrity(&self, integrity: &Integrity) -> ServalResult<SendableStream>;
+
+    /// Load the object at the given content address into memory. Prefer stream_by_integrity() if
+    /// you do not need the bytes in memory.
+    async fn data_by_integrity(&self, integrity: &Integrity) -> ServalResult<Vec<u8>> {
+        let mut reader = self.stream_by_integrity(integrity).await?;
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Ok(bytes)
+    }
+
+    /// Describe the blob at the given content address without fetching it, if the backend can. The
+    /// default implementation has to read the data to find out.
+    async fn metadata_by_integrity(&self, integrity: &Integrity) -> ServalResult<BlobMetadata> {
+        let bytes = self.data_by_integrity(integrity).await?;
+        Ok(BlobMetadata {
+            integrity: integrity.clone(),
+            size: bytes.len() as u64,
+        })
+    }
+
+    /// Return a read stream for part of the object at the given content address. The range must
+    /// lie within the object. Backends that can start reading partway through should; the default
+    /// implementation reads and discards everything before the range.
+    async fn stream_range_by_integrity(
+        &self,
+        integrity: &Integrity,
+        range: ByteRange,
+    ) -> ServalResult<SendableStream> {
+        let stream = self.stream_by_integrity(integrity).await?;
+        range.of_stream(stream).await
+    }
+
+    /// Return a read stream for the blob at the given content address in the compressed form this
+    /// backend keeps it in, checked against the integrity of what it decompresses to. Responds with
+    /// None if the backend has the blob but keeps it uncompressed, which is all the default
+    /// implementation knows how to do.
+    async fn encoded_stream_by_integrity(
+        &self,
+        integrity: &Integrity,
+    ) -> ServalResult<Option<EncodedStream>> {
+        if self.data_exists_by_integrity(integrity).await? {
+            Ok(None)
+        } else {
+            Err(ServalError::DataNotFound(integrity.to_string()))
+        }
+    }
+
+    /// Check whether the given content address is present in this backend.
+    async fn data_exists_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool>;
+
+    /// Store data by key. Returns the integrity checksum.
+    async fn store_by_key(&self, key: &str, bytes: &[u8]) -> ServalResult<Integrity>;
+
+    /// Store data by key, reading it from a stream, when its integrity and size are already known.
+    /// The default implementation reads the whole stream into memory first.
+    async fn store_stream_by_key(
+        &self,
+        key: &str,
+        integrity: &Integrity,
+        _size: u64,
+        mut stream: SendableStream,
+    ) -> ServalResult<Integrity> {
+        let mut bytes = Vec::new();
+        stream.read_to_end(&mut bytes).await?;
+        integrity.check(&bytes)?;
+        self.store_by_key(key, &bytes).await
+    }
+
+    /// Point a key at a blob this backend already holds, as if the blob had been stored by key.
+    /// Backends that can do that without copying the blob should; the default implementation
+    /// streams the blob back into the backend by key. Fails if the backend doesn't have the blob.
+    async fn link_key(&self, key: &str, integrity: &Integrity) -> ServalResult<Integrity> {
+        let size = self.metadata_by_integrity(integrity).await?.size;
+        let stream = self.stream_by_integrity(integrity).await?;
+        self.store_stream_by_key(key, integrity, size, stream).await
+    }
+
+    /// Fetch data by key as a read stream.
+    async fn stream_by_key(&self, key: &str) -> ServalResult<SendableStream>;
+
+    /// Load the data stored under the given key into memory. Prefer stream_by_key() if you do not
+    /// need the bytes in memory.
+    async fn data_by_key(&self, key: &str) -> ServalResult<Vec<u8>> {
+        let mut reader = self.stream_by_key(key).await?;
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Ok(bytes)
+    }
+
+    /// Check whether anything is stored under the given key in this backend.
+    async fn data_exists_by_key(&self, key: &str) -> ServalResult<bool>;
+
+    /// Describe the data stored under the given key without fetching it, if the backend can. The
+    /// default implementation has to read the data to find out.
+    async fn metadata_by_key(&self, key: &str) -> ServalResult<BlobMetadata> {
+        let bytes = self.data_by_key(key).await?;
+        Ok(BlobMetadata {
+            integrity: Integrity::from(&bytes),
+            size: bytes.len() as u64,
+        })
+    }
+
+    /// List every key in this backend that starts with the given prefix, in no particular order.
+    async fn list_keys(&self, prefix: &str) -> ServalResult<Vec<String>>;
+
+    /// Remove the given key from this backend. The data it pointed to stays in the
+    /// content-addressable store until it is collected. Responds with whether the key was present.
+    async fn delete_by_key(&self, key: &str) -> ServalResult<bool>;
+
+    /// Remove the blob at the given content address from this backend. Responds with whether it was
+    /// present.
+    async fn delete_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool>;
+
+    /// List every blob in this backend's content-addressable store, whether or not any key points
+    /// to it. The sizes listed are what each blob takes up in the backend, which for backends that
+    /// compress what they store is less than the size of the data.
+    async fn list_blobs(&self) -> ServalResult<Vec<BlobMetadata>>;
+
+    /// Record what the stored blob at the given content address is, replacing whatever was
+    /// recorded about it before; an empty description forgets it. Backends that can't keep
+    /// descriptions of blobs ignore them, which is all the default implementation knows how to do.
+    async fn store_blob_info(&self, _integrity: &Integrity, _info: &BlobInfo) -> ServalResult<()> {
+        Ok(())
+    }
+
+    /// What's recorded about the blob at the given content address, if anything.
+    async fn blob_info(&self, _integrity: &Integrity) -> ServalResult<Option<BlobInfo>> {
+        Ok(None)
+    }
+
+    /// List every blob this backend has a description of, along with the description. Given a label,
+    /// as `key` or `key=value`, backends that can find blobs by label without reading every
+    /// description may list only the blobs that have it; callers check the labels either way.
+    async fn list_blob_info(
+        &self,
+        _label: Option<&str>,
+    ) -> ServalResult<Vec<(Integrity, BlobInfo)>> {
+        Ok(Vec::new())
+    }
+
+    /// Re-encrypt everything this backend stores under anything but its current master key, for
+    /// backends that encrypt what they store. Responds with None for backends that don't, which
+    /// is all the default implementation knows how to do.
+    async fn rotate_keys(&self) -> ServalResult<Option<KeyRotation>> {
+        Ok(None)
+    }
+
+    /// Replace a corrupt copy of a blob with a good one read from a stream, so that everything
+    /// that refers to the blob sees the good copy. The default implementation deletes the corrupt
+    /// copy and stores the new one, keeping its description; backends whose keys hold their own
+    /// copies of a blob's data must do more.
+    async fn restore_stream_by_integrity(
+        &self,
+        integrity: &Integrity,
+        size: u64,
+        stream: SendableStream,
+    ) -> ServalResult<Integrity> {
+        let info = self.blob_info(integrity).await?;
+        self.delete_by_integrity(integrity).await?;
+        let restored = self
+            .store_stream_by_integrity(integrity, size, stream)
+            .await?;
+        if let Some(info) = info {
+            self.store_blob_info(integrity, &info).await?;
+        }
+        Ok(restored)
+    }
+}
+
+/// What a backend knows about a stored blob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobMetadata {
+    /// The integrity hash of the data, which is also its content address.
+    pub integrity: Integrity,
+    /// The size of the data in bytes.
+    pub size: u64,
+}
+
+/// A stored blob in the compressed form a backend keeps it in.
+pub struct EncodedStream {
+    /// The HTTP content coding the blob is compressed with.
+    pub encoding: &'static str,
+    /// The size of the compressed blob in bytes.
+    pub size: u64,
+    /// The compressed blob.
+    pub stream: SendableStream,
+}
+
+/// A contiguous run of bytes within a stored blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    /// The offset of the first byte.
+    pub start: u64,
+    /// How many bytes the range covers.
+    pub len: u64,
+}
+
+impl ByteRange {
+    /// The offset of the last byte in the range. Meaningless for an empty range.
+    pub fn last(&self) -> u64 {
+        (self.start + self.len).saturating_sub(1)
+    }
+
+    /// Narrow a stream of a whole blob down to this range of it, by reading and discarding
+    /// everything before it.
+    pub async fn of_stream(&self, mut stream: SendableStream) -> ServalResult<SendableStream> {
+        let skipped =
+            tokio::io::copy(&mut (&mut stream).take(self.start), &mut tokio::io::sink()).await?;
+        if skipped < self.start {
+            return Err(ServalError::StorageError(format!(
+                "byte range {}-{} starts past the end of the data",
+                self.start,
+                self.last()
+            )));
+        }
+        Ok(Box::pin(stream.take(self.len)))
+    }
+
+    /// Narrow a whole blob in memory down to this range of it.
+    pub fn of_bytes(&self, bytes: Vec<u8>) -> ServalResult<Vec<u8>> {
+        let start = self.start as usize;
+        let end = start.saturating_add(self.len as usize);
+        match bytes.get(start..end.min(bytes.len())) {
+            Some(slice) => Ok(slice.to_vec()),
+            None => Err(ServalError::StorageError(format!(
+                "byte range {}-{} starts past the end of the data",
+                self.start,
+                self.last()
+            ))),
+        }
+    }
+}
+
+/// Check data read from a backend against the integrity it was stored under.
+pub(crate) fn verify(integrity: &Integrity, bytes: &[u8]) -> ServalResult<()> {
+    match integrity.check(bytes) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(mismatch(integrity)),
+    }
+}
+
+/// Check a stream of data read from a backend against the integrity it was stored under as it's
+/// read. The read that reaches the end of the stream fails if the data didn't match, before the
+/// last of the data is handed out.
+pub(crate) fn verify_stream(integrity: &Integrity, stream: SendableStream) -> SendableStream {
+    let checker = IntegrityChecker::new(integrity.clone());
+    checked_stream(integrity, stream, Box::new(checker), mismatch)
+}
+
+/// Like verify_stream(), for a stream read from a backend that checks it some other way than by
+/// hashing what goes by; compressed data, say, is checked by what it decompresses to.
+pub(crate) fn verify_stream_with(
+    integrity: &Integrity,
+    stream: SendableStream,
+    check: Box<dyn Check>,
+) -> SendableStream {
+    checked_stream(integrity, stream, check, mismatch)
+}
+
+/// Check a stream of data on its way into a backend against the integrity it's to be stored under,
+/// for backends that can't leave that to whatever they write with. A mismatch here is the writer's
+/// fault rather than the backend's, so it isn't counted as corruption.
+pub(crate) fn check_stream(integrity: &Integrity, stream: SendableStream) -> SendableStream {
+    let checker = IntegrityChecker::new(integrity.clone());
+    checked_stream(integrity, stream, Box::new(checker), |integrity| {
+        ServalError::StorageError(format!(
+            "data does not match the integrity it was to be stored under; integrity={integrity}"
+        ))
+    })
+}
+
+/// Something that checks data as it goes by, and says at the end whether it was what it should
+/// have been.
+pub(crate) trait Check: Send {
+    fn input(&mut self, data: &[u8]);
+    fn matches(self: Box<Self>) -> bool;
+}
+
+impl Check for IntegrityChecker {
+    fn input(&mut self, data: &[u8]) {
+        IntegrityChecker::input(self, data);
+    }
+
+    fn matches(self: Box<Self>) -> bool {
+        self.result().is_ok()
+    }
+}
+
+fn checked_stream(
+    integrity: &Integrity,
+    stream: SendableStream,
+    checker: Box<dyn Check>,
+    on_mismatch: fn(&Integrity) -> ServalError,
+) -> SendableStream {
+    Box::pin(VerifiedStream {
+        inner: stream,
+        integrity: integrity.clone(),
+        checker: Some(checker),
+        on_mismatch,
+        buffer: vec![0; VERIFIED_CHUNK_SIZE].into_boxed_slice(),
+        start: 0,
+        end: 0,
+    })
+}
+
+/// Whether an error means that stored data didn't match its integrity, whether it came from a read
+/// into memory or from the end of a checked stream.
+pub(crate) fn is_integrity_mismatch(e: &ServalError) -> bool {
+    match e {
+        ServalError::IntegrityMismatch(_) => true,
+        ServalError::IoError(e) => e.kind() == ErrorKind::InvalidData,
+        _ => false,
+    }
+}
+
+/// The error for data that doesn't match its integrity, noted as it's made so that every kind of
+/// read counts it.
+pub(crate) fn mismatch(integrity: &Integrity) -> ServalError {
+    metrics::increment_counter!("storage:integrity:mismatch");
+    log::warn!("stored data does not match its integrity; integrity={integrity}");
+    ServalError::IntegrityMismatch(integrity.to_string())
+}
+
+// How much a checked stream reads from the stream it checks at a time.
+const VERIFIED_CHUNK_SIZE: usize = 64 * 1024;
+
+struct VerifiedStream {
+    inner: SendableStream,
+    integrity: Integrity,
+    // Taken once the end of the stream has been checked.
+    checker: Option<Box<dyn Check>>,
+    on_mismatch: fn(&Integrity) -> ServalError,
+    // Data read from the inner stream, of which the part from `start` to `end` hasn't been handed
+    // out yet.
+    buffer: Box<[u8]>,
+    start: usize,
+    end: usize,
+}
+
+impl AsyncRead for VerifiedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            // Until the whole stream has been checked, the last byte read is held back, so that
+            // nobody reading corrupt data ever gets what looks like all of it.
+            let held = usize::from(this.checker.is_some());
+            let available = this.end - this.start;
+            if available > held {
+                let count = (available - held).min(buf.remaining());
+                buf.put_slice(&this.buffer[this.start..this.start + count]);
+                this.start += count;
+                return Poll::Ready(Ok(()));
+            }
+            let Some(checker) = this.checker.as_mut() else {
+                return Poll::Ready(Ok(()));
+            };
+
+            // Whatever is held back moves to the front, to make room for more.
+            this.buffer.copy_within(this.start..this.end, 0);
+            this.end -= this.start;
+            this.start = 0;
+            let mut chunk = ReadBuf::new(&mut this.buffer[this.end..]);
+            ready!(this.inner.as_mut().poll_read(cx, &mut chunk))?;
+            let read = chunk.filled().len();
+
+            if read > 0 {
+                checker.input(&this.buffer[this.end..this.end + read]);
+                this.end += read;
+            } else if let Some(checker) = this.checker.take() {
+                if !checker.matches() {
+                    let e = (this.on_mismatch)(&this.integrity);
+                    return Poll::Ready(Err(std::io::Error::new(ErrorKind::InvalidData, e)));
+                }
+            }
+        }
+    }
+}
+
+/// Open a range of the file at the given path for reading, seeking straight to its start.
+pub(crate) async fn open_file_range(path: &Path, range: ByteRange) -> ServalResult<SendableStream> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(range.start)).await?;
+    Ok(Box::pin(file.take(range.len)))
+}
+
+/// Find every blob in a content-addressed directory tree laid out the way both cacache and our
+/// directory backend do it: a directory per hash algorithm, under which the hex digest of each
+/// blob is split across nested directory and file names. Anything that doesn't fit is skipped.
+pub(crate) fn scan_content_directory(root: &Path) -> ServalResult<Vec<BlobMetadata>> {
+    let mut blobs = Vec::new();
+    if !root.is_dir() {
+        return Ok(blobs);
+    }
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let Ok(algorithm) = entry.file_name().to_string_lossy().parse() else {
+            continue;
+        };
+        if entry.file_type()?.is_dir() {
+            scan_digests(&entry.path(), algorithm, String::new(), &mut blobs)?;
+        }
+    }
+    Ok(blobs)
+}
+
+fn scan_digests(
+    directory: &Path,
+    algorithm: ssri::Algorithm,
+    partial: String,
+    blobs: &mut Vec<BlobMetadata>,
+) -> ServalResult<()> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        let digest = format!("{partial}{name}");
+        if entry.file_type()?.is_dir() {
+            scan_digests(&entry.path(), algorithm, digest, blobs)?;
+        } else if let Ok(bytes) = hex::decode(&digest) {
+            let integrity = Integrity {
+                hashes: vec![Hash {
+                    algorithm,
+                    digest: base64::engine::general_purpose::STANDARD.encode(bytes),
+                }],
+            };
+            blobs.push(BlobMetadata {
+                integrity,
+                size: entry.metadata()?.len(),
+            });
+        }
+    }
+    Ok(())
+}
@@ -1,15 +1,34 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io::ErrorKind;
 use std::path::PathBuf;
-use std::pin::Pin;
 
+use async_trait::async_trait;
 use serde::Serialize;
 use ssri::Integrity;
-use tokio::io::AsyncRead;
-use tokio_util::io::ReaderStream;
 use utils::errors::{ServalError, ServalResult};
+use utils::structs::api::BlobInfo;
 
-use super::SendableStream;
+use super::backend::{
+    check_stream, mismatch, open_file_range, scan_content_directory, verify, verify_stream,
+    EncodedStream,
+};
+use super::compression::{
+    decode, decode_bytes, known_encoding, verify_encoded_stream, Compression,
+};
+use super::{BlobMetadata, ByteRange, SendableStream, StorageBackend};
+
+// Where cacache keeps blobs, relative to the root of the cache. This is its layout as of cacache 11.
+const CACACHE_CONTENT_DIRECTORY: &str = "content-v2";
+
+// cacache only knows blobs by the integrity of what it stores, so a compressed blob is found through
+// an index entry under this prefix and the integrity of its uncompressed data. The entry's metadata
+// says how the blob is compressed and how big it is uncompressed.
+const COMPRESSED_PREFIX: &str = "_compressed/";
+
+// What a blob is, when we've been told, is kept in the metadata of an index entry under this prefix
+// and the blob's integrity.
+const INFO_PREFIX: &str = "_info/";
 
 /// This struct manages an agent's local cache of wasm jobs (manifests and executables).
 /// This cache uses the cacache crate behind the scenes, but this is an implementation detail
@@ -19,6 +38,15 @@ use super::SendableStream;
 #[derive(Clone, Debug, Serialize)]
 pub struct BlobStore {
     location: PathBuf,
+    compression: Compression,
+}
+
+// Where a compressed blob is kept, and what it decompresses to.
+#[derive(Debug)]
+struct Compressed {
+    stored: Integrity,
+    encoding: String,
+    size: u64,
 }
 
 impl BlobStore {
@@ -41,57 +69,494 @@ impl BlobStore {
 
         Ok(Self {
             location: location.to_path_buf(),
+            compression: Compression::None,
         })
     }
 
-    pub async fn store_by_integrity(&self, bytes: &[u8]) -> ServalResult<Integrity> {
+    /// Compress what's stored from now on. Whatever is already stored stays as it is.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    // Where cacache keeps the blob with the given integrity.
+    fn content_path(&self, integrity: &Integrity) -> PathBuf {
+        let (algorithm, hex) = integrity.to_hex();
+        self.location
+            .join(CACACHE_CONTENT_DIRECTORY)
+            .join(algorithm.to_string())
+            .join(&hex[0..2])
+            .join(&hex[2..4])
+            .join(&hex[4..])
+    }
+
+    // Find the compressed copy of a blob, if there is one.
+    async fn compressed(&self, integrity: &Integrity) -> ServalResult<Option<Compressed>> {
+        let Some(entry) = cacache::metadata(&self.location, &compressed_key(integrity)).await?
+        else {
+            return Ok(None);
+        };
+        let encoding = entry.metadata["encoding"].as_str();
+        let size = entry.metadata["size"].as_u64();
+        match (encoding, size) {
+            (Some(encoding), Some(size)) => Ok(Some(Compressed {
+                stored: entry.integrity,
+                encoding: encoding.to_string(),
+                size,
+            })),
+            _ => Err(ServalError::StorageError(format!(
+                "unreadable index entry for a compressed blob; integrity={integrity}"
+            ))),
+        }
+    }
+
+    // Compress a blob into the store, and index it under the integrity of its uncompressed data.
+    async fn store_compressed(
+        &self,
+        encoding: &str,
+        integrity: &Integrity,
+        size: u64,
+        stream: SendableStream,
+    ) -> ServalResult<Integrity> {
+        let writer = cacache::WriteOpts::new()
+            .metadata(serde_json::json!({ "encoding": encoding, "size": size }))
+            .open(&self.location, &compressed_key(integrity))
+            .await?;
+        write_stream(
+            writer,
+            self.compression.encode(check_stream(integrity, stream)),
+        )
+        .await?;
+        Ok(integrity.clone())
+    }
+
+    // Remove an index entry, responding with whether there was one.
+    async fn remove_entry(&self, key: &str) -> ServalResult<bool> {
+        if cacache::metadata(&self.location, key).await?.is_none() {
+            return Ok(false);
+        }
+        cacache::remove(&self.location, key).await?;
+        Ok(true)
+    }
+
+    // Point a key at a blob that's already stored.
+    async fn index_key(&self, key: &str, integrity: &Integrity) -> ServalResult<()> {
+        let opts = cacache::WriteOpts::new().integrity(integrity.clone());
+        cacache::index::insert_async(&self.location, key, opts).await?;
+        Ok(())
+    }
+}
+
+fn compressed_key(integrity: &Integrity) -> String {
+    format!("{COMPRESSED_PREFIX}{integrity}")
+}
+
+fn info_key(integrity: &Integrity) -> String {
+    format!("{INFO_PREFIX}{integrity}")
+}
+
+fn parse_info(integrity: &Integrity, metadata: serde_json::Value) -> ServalResult<BlobInfo> {
+    serde_json::from_value(metadata).map_err(|e| {
+        ServalError::StorageError(format!(
+            "unreadable description of a blob; integrity={integrity}; {e}"
+        ))
+    })
+}
+
+#[async_trait]
+impl StorageBackend for BlobStore {
+    fn name(&self) -> &'static str {
+        "local blobs"
+    }
+
+    async fn store_by_integrity(&self, bytes: &[u8]) -> ServalResult<Integrity> {
+        if let Some(encoding) = self.compression.encoding() {
+            let integrity = Integrity::from(bytes);
+            let stream = Box::pin(std::io::Cursor::new(bytes.to_vec()));
+            return self
+                .store_compressed(encoding, &integrity, bytes.len() as u64, stream)
+                .await;
+        }
         let integrity = cacache::write_hash(&self.location, bytes).await?;
         Ok(integrity)
     }
 
-    /// Given a content address, return a read stream for the object stored there.
-    /// Responds with an error if no object is found or if the address is invalid.
-    pub async fn stream_by_integrity(
+    async fn store_stream_by_integrity(
         &self,
         integrity: &Integrity,
-    ) -> ServalResult<ReaderStream<SendableStream>> {
+        size: u64,
+        stream: SendableStream,
+    ) -> ServalResult<Integrity> {
+        if let Some(encoding) = self.compression.encoding() {
+            return self
+                .store_compressed(encoding, integrity, size, stream)
+                .await;
+        }
+        let writer = cacache::WriteOpts::new()
+            .integrity(integrity.clone())
+            .open_hash(&self.location)
+            .await?;
+        write_stream(writer, stream).await
+    }
+
+    /// Given a content address, return a read stream for the object stored there.
+    /// Responds with an error if no object is found or if the address is invalid. cacache's
+    /// readers only check what they read if asked to at the end, so we check it ourselves.
+    async fn stream_by_integrity(&self, integrity: &Integrity) -> ServalResult<SendableStream> {
+        if let Some(compressed) = self.compressed(integrity).await? {
+            let fd = cacache::Reader::open_hash(&self.location, compressed.stored).await?;
+            let decoded = decode(&compressed.encoding, Box::pin(fd))?;
+            return Ok(verify_stream(integrity, decoded));
+        }
         let fd = cacache::Reader::open_hash(&self.location, integrity.clone()).await?;
-        let pinned: Pin<Box<dyn AsyncRead + Send + 'static>> = Box::pin(fd);
-        let stream = ReaderStream::new(pinned);
-        Ok(stream)
+        Ok(verify_stream(integrity, Box::pin(fd)))
     }
 
-    /// Checks if the given blob is in the content store, by its SRI string.
-    pub async fn data_exists_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
-        Ok(cacache::exists(&self.location, integrity).await)
+    async fn data_by_integrity(&self, integrity: &Integrity) -> ServalResult<Vec<u8>> {
+        if let Some(compressed) = self.compressed(integrity).await? {
+            let stored = cacache::read_hash(&self.location, &compressed.stored)
+                .await
+                .map_err(|e| read_error(integrity, e))?;
+            let bytes = decode_bytes(&compressed.encoding, stored)
+                .await
+                .map_err(|_| mismatch(integrity))?;
+            verify(integrity, &bytes)?;
+            return Ok(bytes);
+        }
+        cacache::read_hash(&self.location, integrity)
+            .await
+            .map_err(|e| read_error(integrity, e))
     }
 
-    /// Checks if the given job type is present in our data store, using the fully-qualified name.
-    pub async fn data_exists_by_key(&self, key: &str) -> Result<bool, ServalError> {
-        match cacache::Reader::open(&self.location, key).await {
-            Ok(_) => Ok(true),
-            Err(_) => Ok(false), // TODO: probably should handle errors more granularly
+    async fn metadata_by_integrity(&self, integrity: &Integrity) -> ServalResult<BlobMetadata> {
+        if let Some(compressed) = self.compressed(integrity).await? {
+            return Ok(BlobMetadata {
+                integrity: integrity.clone(),
+                size: compressed.size,
+            });
+        }
+        match tokio::fs::metadata(self.content_path(integrity)).await {
+            Ok(metadata) => Ok(BlobMetadata {
+                integrity: integrity.clone(),
+                size: metadata.len(),
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(ServalError::DataNotFound(integrity.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// cacache has no way to read part of a blob, so we go to the file it keeps the blob in. There's
+    /// no seeking into a compressed blob, though; those are decompressed up to the range.
+    async fn stream_range_by_integrity(
+        &self,
+        integrity: &Integrity,
+        range: ByteRange,
+    ) -> ServalResult<SendableStream> {
+        if self.compressed(integrity).await?.is_some() {
+            let stream = self.stream_by_integrity(integrity).await?;
+            return range.of_stream(stream).await;
         }
+        open_file_range(&self.content_path(integrity), range).await
     }
 
-    /// A non-streaming way to retrieve a stored data blob.. Prefer stream_by_key() if you do not
-    /// need the bytes in memory.
-    pub async fn data_by_key(&self, key: &str) -> ServalResult<Vec<u8>> {
-        let binary: Vec<u8> = cacache::read(&self.location, key).await?;
-        Ok(binary)
+    async fn encoded_stream_by_integrity(
+        &self,
+        integrity: &Integrity,
+    ) -> ServalResult<Option<EncodedStream>> {
+        let Some(compressed) = self.compressed(integrity).await? else {
+            return match cacache::exists(&self.location, integrity).await {
+                true => Ok(None),
+                false => Err(ServalError::DataNotFound(integrity.to_string())),
+            };
+        };
+        let size = tokio::fs::metadata(self.content_path(&compressed.stored))
+            .await?
+            .len();
+        let fd = cacache::Reader::open_hash(&self.location, compressed.stored).await?;
+        let encoding = known_encoding(&compressed.encoding)?;
+        Ok(Some(EncodedStream {
+            encoding,
+            size,
+            stream: verify_encoded_stream(integrity, encoding, Box::pin(fd))?,
+        }))
     }
 
-    /// Fetch a data blob by key as a read stream.
-    pub async fn stream_by_key(&self, key: &str) -> ServalResult<ReaderStream<SendableStream>> {
-        let fd = cacache::Reader::open(&self.location, key).await?;
-        let pinned: SendableStream = Box::pin(fd);
-        let stream = ReaderStream::new(pinned);
-        Ok(stream)
+    /// Checks if the given blob is in the content store, by its SRI string.
+    async fn data_exists_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
+        if let Some(compressed) = self.compressed(integrity).await? {
+            return Ok(cacache::exists(&self.location, &compressed.stored).await);
+        }
+        Ok(cacache::exists(&self.location, integrity).await)
     }
 
     /// Store data in our blob store by key. Returns the integrity checksum.
-    pub async fn store_by_key(&self, key: &str, bytes: &[u8]) -> ServalResult<Integrity> {
+    async fn store_by_key(&self, key: &str, bytes: &[u8]) -> ServalResult<Integrity> {
+        if self.compression.encoding().is_some() {
+            let integrity = self.store_by_integrity(bytes).await?;
+            self.index_key(key, &integrity).await?;
+            return Ok(integrity);
+        }
         let sri = cacache::write(&self.location, key, bytes).await?;
         Ok(sri)
     }
+
+    async fn store_stream_by_key(
+        &self,
+        key: &str,
+        integrity: &Integrity,
+        size: u64,
+        stream: SendableStream,
+    ) -> ServalResult<Integrity> {
+        if self.compression.encoding().is_some() {
+            let integrity = self
+                .store_stream_by_integrity(integrity, size, stream)
+                .await?;
+            self.index_key(key, &integrity).await?;
+            return Ok(integrity);
+        }
+        let writer = cacache::WriteOpts::new()
+            .integrity(integrity.clone())
+            .open(&self.location, key)
+            .await?;
+        write_stream(writer, stream).await
+    }
+
+    async fn link_key(&self, key: &str, integrity: &Integrity) -> ServalResult<Integrity> {
+        if !self.data_exists_by_integrity(integrity).await? {
+            return Err(ServalError::DataNotFound(integrity.to_string()));
+        }
+        self.index_key(key, integrity).await?;
+        Ok(integrity.clone())
+    }
+
+    /// Fetch a data blob by key as a read stream.
+    async fn stream_by_key(&self, key: &str) -> ServalResult<SendableStream> {
+        match cacache::metadata(&self.location, key).await? {
+            Some(metadata) => self.stream_by_integrity(&metadata.integrity).await,
+            None => Err(ServalError::DataNotFound(key.to_string())),
+        }
+    }
+
+    async fn data_by_key(&self, key: &str) -> ServalResult<Vec<u8>> {
+        match cacache::metadata(&self.location, key).await? {
+            Some(metadata) => self.data_by_integrity(&metadata.integrity).await,
+            None => Err(ServalError::DataNotFound(key.to_string())),
+        }
+    }
+
+    /// Checks if the given job type is present in our data store, using the fully-qualified name.
+    async fn data_exists_by_key(&self, key: &str) -> ServalResult<bool> {
+        match cacache::metadata(&self.location, key).await? {
+            Some(metadata) => self.data_exists_by_integrity(&metadata.integrity).await,
+            None => Ok(false),
+        }
+    }
+
+    /// The index only records sizes given to writers up front, which ours aren't (see
+    /// write_stream()), so the size comes from the content the key points to.
+    async fn metadata_by_key(&self, key: &str) -> ServalResult<BlobMetadata> {
+        match cacache::metadata(&self.location, key).await? {
+            Some(metadata) => self.metadata_by_integrity(&metadata.integrity).await,
+            None => Err(ServalError::DataNotFound(key.to_string())),
+        }
+    }
+
+    async fn list_keys(&self, prefix: &str) -> ServalResult<Vec<String>> {
+        let location = self.location.clone();
+        let prefix = prefix.to_string();
+        // cacache can only walk its index synchronously, so keep that off the async threads.
+        tokio::task::spawn_blocking(move || {
+            let mut keys = Vec::new();
+            for entry in cacache::list_sync(&location) {
+                match entry {
+                    Ok(entry)
+                        if entry.key.starts_with(COMPRESSED_PREFIX)
+                            || entry.key.starts_with(INFO_PREFIX) => {}
+                    Ok(entry) if entry.key.starts_with(&prefix) => keys.push(entry.key),
+                    Ok(_) => {}
+                    // cacache only creates its index when the first key is written.
+                    Err(cacache::Error::IoError(e, _)) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Ok(keys)
+        })
+        .await
+        .map_err(|e| ServalError::StorageError(format!("unable to list keys; error={e}")))?
+    }
+
+    async fn delete_by_key(&self, key: &str) -> ServalResult<bool> {
+        self.remove_entry(key).await
+    }
+
+    /// Removes both the compressed and the uncompressed copy of the blob, if it has both, along
+    /// with its description.
+    async fn delete_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
+        self.remove_entry(&info_key(integrity)).await?;
+        let mut found = false;
+        if let Some(compressed) = self.compressed(integrity).await? {
+            cacache::remove(&self.location, &compressed_key(integrity)).await?;
+            if cacache::exists(&self.location, &compressed.stored).await {
+                cacache::remove_hash(&self.location, &compressed.stored).await?;
+            }
+            found = true;
+        }
+        if cacache::exists(&self.location, integrity).await {
+            cacache::remove_hash(&self.location, integrity).await?;
+            found = true;
+        }
+        Ok(found)
+    }
+
+    /// Compressed blobs are listed by the integrity of their uncompressed data, but with the size
+    /// they take up compressed.
+    async fn list_blobs(&self) -> ServalResult<Vec<BlobMetadata>> {
+        // cacache has no way to list its content, but it keeps it in a predictable place.
+        let location = self.location.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut blobs = scan_content_directory(&location.join(CACACHE_CONTENT_DIRECTORY))?;
+            let mut uncompressed = HashMap::new();
+            for entry in cacache::list_sync(&location) {
+                match entry {
+                    Ok(entry) => {
+                        let Some(integrity) = entry.key.strip_prefix(COMPRESSED_PREFIX) else {
+                            continue;
+                        };
+                        if let Ok(integrity) = integrity.parse::<Integrity>() {
+                            uncompressed.insert(entry.integrity.to_string(), integrity);
+                        }
+                    }
+                    Err(cacache::Error::IoError(e, _)) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            for blob in blobs.iter_mut() {
+                if let Some(integrity) = uncompressed.remove(&blob.integrity.to_string()) {
+                    blob.integrity = integrity;
+                }
+            }
+            Ok(blobs)
+        })
+        .await
+        .map_err(|e| ServalError::StorageError(format!("unable to list blobs; error={e}")))?
+    }
+
+    async fn store_blob_info(&self, integrity: &Integrity, info: &BlobInfo) -> ServalResult<()> {
+        if !self.data_exists_by_integrity(integrity).await? {
+            return Err(ServalError::DataNotFound(integrity.to_string()));
+        }
+        let key = info_key(integrity);
+        if info.is_empty() {
+            self.remove_entry(&key).await?;
+            return Ok(());
+        }
+        let metadata = serde_json::to_value(info)
+            .map_err(|e| ServalError::StorageError(format!("unable to describe a blob; {e}")))?;
+        let opts = cacache::WriteOpts::new()
+            .integrity(integrity.clone())
+            .metadata(metadata);
+        cacache::index::insert_async(&self.location, &key, opts).await?;
+        Ok(())
+    }
+
+    async fn blob_info(&self, integrity: &Integrity) -> ServalResult<Option<BlobInfo>> {
+        match cacache::metadata(&self.location, &info_key(integrity)).await? {
+            Some(entry) => Ok(Some(parse_info(integrity, entry.metadata)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn list_blob_info(
+        &self,
+        _label: Option<&str>,
+    ) -> ServalResult<Vec<(Integrity, BlobInfo)>> {
+        let location = self.location.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut described = Vec::new();
+            for entry in cacache::list_sync(&location) {
+                match entry {
+                    Ok(entry) => {
+                        let Some(integrity) = entry.key.strip_prefix(INFO_PREFIX) else {
+                            continue;
+                        };
+                        let integrity: Integrity = integrity.parse()?;
+                        let info = parse_info(&integrity, entry.metadata)?;
+                        described.push((integrity, info));
+                    }
+                    Err(cacache::Error::IoError(e, _)) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Ok(described)
+        })
+        .await
+        .map_err(|e| ServalError::StorageError(format!("unable to list blobs; error={e}")))?
+    }
+}
+
+// cacache checks whatever it reads into memory against its integrity. Say so when that's what
+// failed, so that the read can go to another copy.
+fn read_error(integrity: &Integrity, e: cacache::Error) -> ServalError {
+    match e {
+        cacache::Error::IntegrityError(_) => mismatch(integrity),
+        e => e.into(),
+    }
+}
+
+// Copy a stream into a cacache writer and commit it. cacache checks the data against the integrity
+// it was opened with, and refuses to commit anything that doesn't match. Writers must not be given
+// the size of the data up front: for small blobs cacache then writes into a memory map, and that
+// only works if the whole blob arrives in one write.
+async fn write_stream(
+    mut writer: cacache::Writer,
+    mut stream: SendableStream,
+) -> ServalResult<Integrity> {
+    tokio::io::copy(&mut stream, &mut writer).await?;
+    Ok(writer.commit().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio_util::io::StreamReader;
+
+    use super::*;
+
+    // A stream that hands over the data a chunk at a time, as a request body does.
+    fn chunked(data: &[u8], chunk_size: usize) -> SendableStream {
+        let chunks: Vec<std::io::Result<Bytes>> = data
+            .chunks(chunk_size)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        Box::pin(StreamReader::new(futures::stream::iter(chunks)))
+    }
+
+    #[tokio::test]
+    async fn streams_small_blobs_in_several_reads() {
+        let root = std::env::temp_dir().join(format!("serval-blobs-{}", uuid::Uuid::new_v4()));
+        let store = BlobStore::new(&root).unwrap();
+        // Well under the megabyte that cacache writes into a memory map.
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let integrity = Integrity::from(&data);
+
+        let stored = store
+            .store_stream_by_integrity(&integrity, data.len() as u64, chunked(&data, 4096))
+            .await
+            .unwrap();
+        assert_eq!(stored, integrity);
+        assert_eq!(store.data_by_integrity(&integrity).await.unwrap(), data);
+
+        let key_data = &data[..256];
+        let key_integrity = Integrity::from(key_data);
+        store
+            .store_stream_by_key("streamed", &key_integrity, 256, chunked(key_data, 100))
+            .await
+            .unwrap();
+        assert_eq!(store.data_by_key("streamed").await.unwrap(), key_data);
+        assert_eq!(store.metadata_by_key("streamed").await.unwrap().size, 256);
+
+        fs::remove_dir_all(root).unwrap();
+    }
 }
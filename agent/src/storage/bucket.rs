@@ -1,113 +1,640 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
 use aws_sdk_s3 as s3;
-use s3::error::ProvideErrorMetadata;
+use futures::{StreamExt, TryStreamExt};
+use s3::error::{ProvideErrorMetadata, SdkError};
+use s3::operation::get_object::{GetObjectError, GetObjectOutput};
 use s3::primitives::ByteStream;
+use s3::types::{CompletedMultipartUpload, CompletedPart, Object};
 use ssri::Integrity;
-use urlencoding::encode;
+use tokio::io::AsyncReadExt;
+use tokio::sync::Mutex;
+use urlencoding::{decode, encode};
 use utils::errors::{ServalError, ServalResult};
+use utils::structs::api::{BlobInfo, KeyRotation};
+
+use super::backend::{check_stream, mismatch, verify, verify_stream, EncodedStream};
+use super::compression::{self, decode_bytes, known_encoding, verify_encoded_stream, Compression};
+use super::encryption::{
+    decrypt, decrypt_segments, encrypt, key_id, plaintext_len, segments_for, DataKey, Keyring,
+    DATA_KEY, KEY_ID,
+};
+use super::{BlobMetadata, ByteRange, SendableStream, StorageBackend};
+
+// Keyed data is stored by its integrity, with an index recording which integrity each key points
+// to. The index is split into this many segments, kept as JSON objects under this prefix, and each
+// key is recorded in the segment picked by the first hex digit of its sha256. We keep a copy of each
+// segment in memory, so that reads by key needn't fetch it.
+const INDEX_PREFIX: &str = "_index/";
+const INDEX_SEGMENTS: usize = 16;
+
+// How long our copy of a segment is trusted before it's checked against the bucket again. The check
+// is a conditional read, so a segment that hasn't changed isn't sent again. Keys missing from our
+// copy are always checked for, so this only bounds how long a key changed or deleted by another
+// node can go unnoticed.
+const INDEX_TTL: Duration = Duration::from_secs(10);
+
+// Writes to a segment only go through if it's still as we last saw it. When another node has
+// changed it since, it's read again and the write retried, up to this many times in all.
+const INDEX_WRITE_ATTEMPTS: usize = 8;
+
+// Buckets written before there was an index record each key in an object holding its integrity,
+// named for the key with this suffix. These are still read, so there's nothing to migrate.
+const KEYFILE_SUFFIX: &str = ".integrity";
+
+// Compressed blobs are stored with their content encoding set, and the size of their uncompressed
+// data in this piece of user metadata.
+const UNCOMPRESSED_SIZE: &str = "uncompressed-size";
+
+// What a blob is, when we've been told, is kept as JSON in an object of its own, named for the blob
+// under this prefix, so that describing a blob never means rewriting the blob.
+const INFO_PREFIX: &str = "_info/";
+
+// Each label of a described blob is recorded in an empty object named for the label and the blob
+// under this prefix, so that blobs can be found by label by listing objects rather than reading
+// every description. Labels are url-encoded, and show in the bucket even when what we store is
+// encrypted.
+const LABEL_PREFIX: &str = "_labels/";
+
+// Listing blobs' descriptions reads this many of them at a time.
+const DESCRIBE_CONCURRENCY: usize = 16;
+
+// Blobs bigger than this are uploaded in parts of at least this size. S3 wants parts of at least
+// 5 MiB, except for the last, and no more than 10,000 of them.
+const MIN_PART_SIZE: u64 = 8 * 1024 * 1024;
+const MAX_PARTS: u64 = 10_000;
 
 #[derive(Debug, Clone)]
 pub struct S3Storage {
     client: s3::Client,
     bucket: String,
+    compression: Compression,
+    encryption: Option<Arc<Keyring>>,
+    index: Arc<Vec<Mutex<IndexSegment>>>,
+}
+
+// Our copy of one segment of the bucket's index of keys.
+#[derive(Debug, Default)]
+struct IndexSegment {
+    keys: BTreeMap<String, String>,
+    // The segment object's ETag as we last saw it, or none if there was no such object.
+    etag: Option<String>,
+    checked: Option<Instant>,
+}
+
+impl IndexSegment {
+    fn is_fresh(&self) -> bool {
+        self.checked
+            .is_some_and(|checked| checked.elapsed() < INDEX_TTL)
+    }
+}
+
+// What an object is stored with besides its body.
+struct ObjectHeaders {
+    content_type: String,
+    content_encoding: Option<String>,
+    metadata: HashMap<String, String>,
+}
+
+impl ObjectHeaders {
+    fn new(content_type: &str) -> Self {
+        Self {
+            content_type: content_type.to_string(),
+            content_encoding: None,
+            metadata: HashMap::new(),
+        }
+    }
 }
 
 impl S3Storage {
     pub fn new(bucket_name: &str, config: aws_config::SdkConfig) -> ServalResult<Self> {
-        let client = s3::Client::new(&config);
+        // Local stand-ins for S3 generally want the bucket in the path rather than the host name.
+        let config = s3::config::Builder::from(&config)
+            .force_path_style(config.endpoint_url().is_some())
+            .build();
+        let client = s3::Client::from_conf(config);
 
         Ok(S3Storage {
             client,
             bucket: bucket_name.to_string(),
+            compression: Compression::None,
+            encryption: None,
+            index: Arc::new((0..INDEX_SEGMENTS).map(|_| Mutex::default()).collect()),
         })
     }
 
-    /// Check if the given data blob is present in our data store, by integrity hash. Returns a stream.
-    pub async fn stream_by_integrity(&self, integrity: &Integrity) -> ServalResult<ByteStream> {
-        let object = self
+    /// Compress what's stored from now on. Whatever is already in the bucket stays as it is.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypt what's stored from now on, under the keyring's current master key. Whatever is
+    /// already in the bucket stays as it is until the keys are rotated.
+    pub fn with_encryption(mut self, keyring: Keyring) -> Self {
+        self.encryption = Some(Arc::new(keyring));
+        self
+    }
+
+    // Stream a blob into the bucket under its (url-encoded) integrity string. The data is checked
+    // against its integrity before the upload is completed.
+    async fn put_blob_stream(
+        &self,
+        integrity: &Integrity,
+        size: u64,
+        stream: SendableStream,
+    ) -> ServalResult<Integrity> {
+        let mut headers = ObjectHeaders::new("application/octet-stream");
+        if let Some(encoding) = self.compression.encoding() {
+            headers.content_encoding = Some(encoding.to_string());
+            headers
+                .metadata
+                .insert(UNCOMPRESSED_SIZE.to_string(), size.to_string());
+        }
+        // The data is checked as it is, before it's compressed.
+        let stream = self.compression.encode(check_stream(integrity, stream));
+        self.put_object_stream(&blob_key(integrity), size, stream, headers)
+            .await?;
+        Ok(integrity.clone())
+    }
+
+    // Stream an object into the bucket, encrypting it on the way if we encrypt what we store. Big
+    // objects go up as a multipart upload, so that we never hold more than a part of one in memory.
+    async fn put_object_stream(
+        &self,
+        bucket_key: &str,
+        size: u64,
+        stream: SendableStream,
+        mut headers: ObjectHeaders,
+    ) -> ServalResult<()> {
+        let mut stream = match &self.encryption {
+            Some(keyring) => {
+                let (key, metadata) = keyring.seal_envelope(bucket_key)?;
+                headers.metadata.extend(metadata);
+                encrypt(key, stream)
+            }
+            None => stream,
+        };
+
+        if size <= MIN_PART_SIZE {
+            let mut bytes = Vec::with_capacity(size as usize);
+            stream.read_to_end(&mut bytes).await?;
+            return self.put_object(bucket_key, bytes, headers).await;
+        }
+
+        let upload = self
             .client
-            .get_object()
+            .create_multipart_upload()
             .bucket(&self.bucket)
-            .key(encode(&integrity.to_string()))
+            .key(bucket_key)
+            .content_type(headers.content_type)
+            .set_content_encoding(headers.content_encoding)
+            .set_metadata(Some(headers.metadata))
             .send()
-            .await?;
+            .await
+            .map_err(|e| {
+                ServalError::StorageError(format!(
+                    "unable to start an upload to S3; key={bucket_key}; error={e}"
+                ))
+            })?;
+        let Some(upload_id) = upload.upload_id() else {
+            return Err(ServalError::StorageError(format!(
+                "S3 started an upload without an id; key={bucket_key}"
+            )));
+        };
 
-        Ok(object.body)
+        let parts = match self.upload_parts(bucket_key, upload_id, size, stream).await {
+            Ok(parts) => parts,
+            Err(e) => {
+                // Otherwise the parts we did upload linger, and are billed for, until S3's own
+                // lifecycle rules (if any) clear them up.
+                if let Err(abort) = self
+                    .client
+                    .abort_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(bucket_key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await
+                {
+                    log::warn!("unable to abort S3 upload; key={bucket_key}; {abort:?}");
+                }
+                return Err(e);
+            }
+        };
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(bucket_key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|e| {
+                ServalError::StorageError(format!(
+                    "unable to complete an upload to S3; key={bucket_key}; error={e}"
+                ))
+            })?;
+        Ok(())
     }
 
-    pub async fn store_by_integrity(
+    // Write an object to the bucket in one go.
+    async fn put_object(
         &self,
-        integrity: &Integrity,
-        bytes: &[u8],
-    ) -> ServalResult<Integrity> {
-        let body = ByteStream::from(bytes.to_vec());
+        bucket_key: &str,
+        bytes: Vec<u8>,
+        headers: ObjectHeaders,
+    ) -> ServalResult<()> {
         let result = self
             .client
             .put_object()
             .bucket(&self.bucket)
-            .key(encode(&integrity.to_string())) // integrity string is not url-safe!
-            .body(body)
-            .content_type("application/octet-stream")
+            .key(bucket_key)
+            .body(ByteStream::from(bytes))
+            .content_type(headers.content_type)
+            .set_content_encoding(headers.content_encoding)
+            .set_metadata(Some(headers.metadata))
             .send()
             .await;
 
         match result {
-            Ok(_resp) => Ok(integrity.clone()),
+            Ok(_resp) => Ok(()),
             Err(e) => {
                 log::info!("Error storing data in s3: {e:?}");
 
                 Err(ServalError::StorageError(format!(
-                    "unable to store data! integrity={integrity}; error={}",
+                    "unable to store data! key={bucket_key}; error={}",
                     e.message().unwrap_or("cannot get error message from AWS")
                 )))
             }
         }
     }
 
-    /// Check if the given data blob is present in our data store, using its human key.
-    pub async fn data_exists_by_key(&self, key: &str) -> ServalResult<bool> {
-        let integrity = self.lookup_integrity(key).await?;
+    // Upload a stream as the parts of a multipart upload. Fails, without completing the upload, if
+    // the stream does.
+    async fn upload_parts(
+        &self,
+        bucket_key: &str,
+        upload_id: &str,
+        size: u64,
+        mut stream: SendableStream,
+    ) -> ServalResult<Vec<CompletedPart>> {
+        let part_size = MIN_PART_SIZE.max(size.div_ceil(MAX_PARTS));
+        let mut parts = Vec::new();
+        for part_number in 1.. {
+            let mut buffer = Vec::with_capacity(part_size as usize);
+            (&mut stream)
+                .take(part_size)
+                .read_to_end(&mut buffer)
+                .await?;
+            if buffer.is_empty() {
+                break;
+            }
+            let part = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(bucket_key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .content_length(buffer.len() as i64)
+                .body(ByteStream::from(buffer))
+                .send()
+                .await
+                .map_err(|e| {
+                    ServalError::StorageError(format!(
+                        "unable to upload part {part_number} to S3; key={bucket_key}; error={e}"
+                    ))
+                })?;
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(part.e_tag().map(str::to_string))
+                    .part_number(part_number)
+                    .build(),
+            );
+        }
+        Ok(parts)
+    }
+
+    // Bring our copy of a segment of the index up to date with the bucket's. If it hasn't changed
+    // since we last read it, the bucket says so rather than sending it again.
+    async fn refresh_segment(&self, number: usize, segment: &mut IndexSegment) -> ServalResult<()> {
+        let bucket_key = segment_key(number);
         let result = self
             .client
-            .head_object()
+            .get_object()
             .bucket(&self.bucket)
-            .key(&integrity)
+            .key(&bucket_key)
+            .set_if_none_match(segment.etag.clone())
             .send()
             .await;
-        Ok(result.is_ok())
+        match result {
+            Ok(object) => {
+                let etag = object.e_tag().map(str::to_string);
+                let mut body = Vec::new();
+                object.body.into_async_read().read_to_end(&mut body).await?;
+                segment.keys = serde_json::from_slice(&body).map_err(|e| {
+                    ServalError::StorageError(format!(
+                        "unable to read the S3 key index; key={bucket_key}; error={e}"
+                    ))
+                })?;
+                segment.etag = etag;
+            }
+            Err(e) => match status_of(&e) {
+                Some(304) => {}
+                Some(404) => {
+                    segment.keys.clear();
+                    segment.etag = None;
+                }
+                _ => return Err(e.into()),
+            },
+        }
+        segment.checked = Some(Instant::now());
+        Ok(())
     }
 
-    /// Fetch data from the store by key. Returns a vec of u8.
-    pub async fn data_by_key(&self, key: &str) -> ServalResult<Vec<u8>> {
-        let integrity = self.lookup_integrity(key).await?;
-        let object = self
+    // Change the segment of the index that records a key, writing it back to the bucket if
+    // anything changed. Responds with whether anything did.
+    async fn update_index<F>(&self, key: &str, change: F) -> ServalResult<bool>
+    where
+        F: Fn(&mut BTreeMap<String, String>) -> bool + Send,
+    {
+        let number = index_segment(key);
+        let mut segment = self.index[number].lock().await;
+        if !segment.is_fresh() {
+            self.refresh_segment(number, &mut segment).await?;
+        }
+        for _ in 0..INDEX_WRITE_ATTEMPTS {
+            let mut keys = segment.keys.clone();
+            if !change(&mut keys) {
+                return Ok(false);
+            }
+            let body = serde_json::to_vec(&keys).map_err(|e| {
+                ServalError::StorageError(format!("unable to write the S3 key index; {e}"))
+            })?;
+            let written = self.put_segment(number, body, segment.etag.as_deref());
+            if let Some(etag) = written.await? {
+                segment.keys = keys;
+                // Without an ETag to make the next write conditional on, it has to read first.
+                segment.checked = etag.is_some().then(Instant::now);
+                segment.etag = etag;
+                return Ok(true);
+            }
+            // Another node changed the segment since we last saw it.
+            self.refresh_segment(number, &mut segment).await?;
+        }
+        Err(ServalError::StorageError(format!(
+            "unable to write the S3 key index, which other nodes keep changing; key={}",
+            segment_key(number)
+        )))
+    }
+
+    // Write a segment of the index, if it's still as we last saw it: with the given ETag, or not
+    // there at all. Responds with the segment's new ETag if it was written, as far as S3 says, and
+    // with nothing if it wasn't.
+    async fn put_segment(
+        &self,
+        number: usize,
+        body: Vec<u8>,
+        etag: Option<&str>,
+    ) -> ServalResult<Option<Option<String>>> {
+        let bucket_key = segment_key(number);
+        let (condition, value) = match etag {
+            Some(etag) => (http::header::IF_MATCH, etag),
+            None => (http::header::IF_NONE_MATCH, "*"),
+        };
+        let value = http::HeaderValue::from_str(value).map_err(|e| {
+            ServalError::StorageError(format!("S3 sent an unusable ETag; key={bucket_key}; {e}"))
+        })?;
+        let put = self
             .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&bucket_key)
+            .body(ByteStream::from(body))
+            .content_type("application/json");
+        // The SDK doesn't know of conditional writes yet, though S3 does.
+        let result = match put.customize().await {
+            Ok(put) => {
+                put.mutate_request(|request| {
+                    request.headers_mut().insert(condition, value);
+                })
+                .send()
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(output) => Ok(Some(output.e_tag().map(str::to_string))),
+            // S3 answers 409 when another conditional write to the object is under way.
+            Err(e) if matches!(status_of(&e), Some(409 | 412)) => Ok(None),
+            Err(e) => Err(ServalError::StorageError(format!(
+                "unable to write the S3 key index; key={bucket_key}; error={e}"
+            ))),
+        }
+    }
+
+    // Record which blob a key points to.
+    async fn put_key(&self, key: &str, integrity: &Integrity) -> ServalResult<()> {
+        let integrity = integrity.to_string();
+        self.update_index(key, |keys| {
+            keys.insert(key.to_string(), integrity.clone()).as_ref() != Some(&integrity)
+        })
+        .await?;
+        Ok(())
+    }
+
+    // The body of an object as it was before it was stored, decrypted if it's encrypted. Whether
+    // it's compressed is up to the caller.
+    fn open_object(
+        &self,
+        bucket_key: &str,
+        object: GetObjectOutput,
+    ) -> ServalResult<SendableStream> {
+        let data_key = self.data_key(bucket_key, object.metadata())?;
+        let body: SendableStream = Box::pin(object.body.into_async_read());
+        match data_key {
+            Some(key) => Ok(decrypt(key, body)),
+            None => Ok(body),
+        }
+    }
+
+    // The key an object's data is encrypted with, from its metadata, if it's encrypted.
+    fn data_key(
+        &self,
+        bucket_key: &str,
+        metadata: Option<&HashMap<String, String>>,
+    ) -> ServalResult<Option<DataKey>> {
+        match (key_id(metadata), &self.encryption) {
+            (None, _) => Ok(None),
+            (Some(_), Some(keyring)) => keyring.open_envelope(bucket_key, metadata),
+            (Some(id), None) => {
+                let e =
+                    format!("{bucket_key} is encrypted with key `{id}`, but there's no keyfile");
+                Err(ServalError::StorageError(e))
+            }
+        }
+    }
+
+    // Fetch the object stored under the given (already url-encoded) bucket key, or just the given
+    // range of it. Its content encoding says whether it's compressed.
+    async fn get_blob(
+        &self,
+        bucket_key: &str,
+        range: Option<ByteRange>,
+    ) -> Result<GetObjectOutput, SdkError<GetObjectError>> {
+        self.client
             .get_object()
             .bucket(&self.bucket)
-            .key(&integrity)
+            .key(bucket_key)
+            .set_range(range.map(|range| format!("bytes={}-{}", range.start, range.last())))
             .send()
+            .await
+    }
+
+    // Read the description of the blob with the given integrity, if it has one.
+    async fn read_info(&self, integrity: &Integrity) -> ServalResult<Option<BlobInfo>> {
+        let info_key = info_key(integrity);
+        let object = match self.get_blob(&info_key, None).await {
+            Ok(object) => object,
+            Err(e) if status_of(&e) == Some(404) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut json = Vec::new();
+        self.open_object(&info_key, object)?
+            .read_to_end(&mut json)
             .await?;
-        let chunks = object.body.collect().await?;
-        Ok(chunks.into_bytes().to_vec())
+        let info = serde_json::from_slice(&json).map_err(|_| {
+            ServalError::StorageError(format!(
+                "unreadable description of a blob; integrity={integrity}"
+            ))
+        })?;
+        Ok(Some(info))
     }
 
-    /// Fetch data by key as a readable byte stream.
-    pub async fn stream_by_key(&self, key: &str) -> ServalResult<ByteStream> {
-        let integrity = self.lookup_integrity(key).await?;
-        let object = self
-            .client
-            .get_object()
+    // Describe a blob, adding and removing the objects recording its labels to match. With no
+    // description, whatever was recorded about the blob is removed.
+    async fn write_info(&self, integrity: &Integrity, info: &BlobInfo) -> ServalResult<()> {
+        let previous = self.read_info(integrity).await?.unwrap_or_default();
+        let recorded = |info: &BlobInfo| -> BTreeSet<String> {
+            info.labels
+                .iter()
+                .map(|(name, value)| label_key(name, value, integrity))
+                .collect()
+        };
+        let (before, after) = (recorded(&previous), recorded(info));
+
+        // The labels go first, so that a blob is never described with labels it can't be found by.
+        for label in after.difference(&before) {
+            let headers = ObjectHeaders::new("application/octet-stream");
+            self.put_object(label, Vec::new(), headers).await?;
+        }
+        let info_key = info_key(integrity);
+        if info.is_empty() {
+            if !previous.is_empty() {
+                self.delete_object(&info_key).await?;
+            }
+        } else {
+            let json = serde_json::to_vec(info).map_err(|e| {
+                ServalError::StorageError(format!("unable to describe a blob; {e}"))
+            })?;
+            let size = json.len() as u64;
+            let stream = Box::pin(std::io::Cursor::new(json));
+            let headers = ObjectHeaders::new("application/json");
+            self.put_object_stream(&info_key, size, stream, headers)
+                .await?;
+        }
+        for label in before.difference(&after) {
+            self.delete_object(label).await?;
+        }
+        Ok(())
+    }
+
+    // Check for an object under the given (already url-encoded) bucket key.
+    async fn has_blob(&self, bucket_key: &str) -> bool {
+        self.client
+            .head_object()
             .bucket(&self.bucket)
-            .key(&integrity)
+            .key(bucket_key)
             .send()
-            .await?;
+            .await
+            .is_ok()
+    }
 
-        Ok(object.body)
+    // List every object in the bucket whose key starts with the given prefix.
+    async fn list_objects(&self, prefix: &str) -> ServalResult<Vec<Object>> {
+        let mut objects = Vec::new();
+        let mut continuation: Option<String> = None;
+        loop {
+            let page = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(prefix)
+                .set_continuation_token(continuation.take())
+                .send()
+                .await
+                .map_err(|e| {
+                    ServalError::StorageError(format!(
+                        "unable to list objects in S3; prefix={prefix}; error={e}"
+                    ))
+                })?;
+            objects.extend(page.contents().unwrap_or_default().iter().cloned());
+            match page.next_continuation_token() {
+                Some(token) if page.is_truncated() => continuation = Some(token.to_string()),
+                _ => break,
+            }
+        }
+        Ok(objects)
+    }
+
+    // Delete the object stored under the given (already url-encoded) bucket key.
+    async fn delete_object(&self, bucket_key: &str) -> ServalResult<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(bucket_key)
+            .send()
+            .await
+            .map_err(|e| {
+                ServalError::StorageError(format!(
+                    "unable to delete from S3; key={bucket_key}; error={e}"
+                ))
+            })?;
+        Ok(())
+    }
+
+    /// Look up the integrity checksum for a given key: in our copy of its segment of the index if
+    /// it's there and fresh, then in the bucket's, and then in a keyfile written before there was an
+    /// index.
+    async fn lookup_integrity(&self, key: &str) -> ServalResult<Integrity> {
+        {
+            let number = index_segment(key);
+            let mut segment = self.index[number].lock().await;
+            if !segment.is_fresh() || !segment.keys.contains_key(key) {
+                self.refresh_segment(number, &mut segment).await?;
+            }
+            if let Some(integrity) = segment.keys.get(key) {
+                return Ok(integrity.parse()?);
+            }
+        }
+        match self.read_keyfile(key).await? {
+            Some(integrity) => Ok(integrity.parse()?),
+            None => Err(ServalError::DataNotFound(key.to_string())),
+        }
     }
 
-    /// Look up an integrity checksum for a given key. Url-encodes the integrity string.
-    /// Really cheap index. Feel free to replace.
-    async fn lookup_integrity(&self, key: &str) -> ServalResult<String> {
-        let keyfile = format!("{key}.integrity");
+    // Read the integrity checksum recorded for a given key in a keyfile, if there is one.
+    async fn read_keyfile(&self, key: &str) -> ServalResult<Option<String>> {
+        let keyfile = keyfile_key(key);
         match self
             .client
             .get_object()
@@ -117,51 +644,1250 @@ impl S3Storage {
             .await
         {
             Ok(object) => {
-                let chunks = object.body.collect().await?;
-                let bytes = chunks.into_bytes().to_vec();
-                let integrity_string = String::from_utf8(bytes)?;
-                let encoded = encode(&integrity_string);
-                Ok(encoded.to_string())
+                let mut integrity = String::new();
+                self.open_object(&keyfile, object)?
+                    .read_to_string(&mut integrity)
+                    .await?;
+                Ok(Some(integrity))
             }
+            Err(e) if status_of(&e) == Some(404) => Ok(None),
             Err(e) => {
-                log::info!(
-                    "integrity checksum not found for key={key}; keyfile={keyfile}; error={e}"
-                );
-                Err(ServalError::S3GetError(e))
+                log::info!("unable to read keyfile; key={key}; keyfile={keyfile}; error={e}");
+                Err(e.into())
             }
         }
     }
 
-    /// Store data by key.
-    pub async fn store_by_key(&self, key: &str, bytes: &[u8]) -> ServalResult<Integrity> {
+    // Re-encrypt an object under the current master key, unless it already is. Responds with
+    // whether it had to.
+    async fn reencrypt(&self, keyring: &Keyring, bucket_key: &str) -> ServalResult<bool> {
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(bucket_key)
+            .send()
+            .await?;
+        if key_id(head.metadata()) == Some(keyring.current_id()) {
+            return Ok(false);
+        }
+
+        // Everything the object was stored with stays, except for its old envelope.
+        let mut headers =
+            ObjectHeaders::new(head.content_type().unwrap_or("application/octet-stream"));
+        headers.content_encoding = head.content_encoding().map(str::to_string);
+        if let Some(metadata) = head.metadata() {
+            headers.metadata = metadata.clone();
+            headers.metadata.remove(KEY_ID);
+            headers.metadata.remove(DATA_KEY);
+        }
+        let size = unencrypted_len(head.metadata(), head.content_length());
+        let object = self.get_blob(bucket_key, None).await?;
+        let stream = self.open_object(bucket_key, object)?;
+        self.put_object_stream(bucket_key, size, stream, headers)
+            .await?;
+        Ok(true)
+    }
+}
+
+// Blobs are stored under their integrity strings, which aren't url-safe, so they're url-encoded.
+fn blob_key(integrity: &Integrity) -> String {
+    encode(&integrity.to_string()).into_owned()
+}
+
+// Which segment of the index records a key.
+fn index_segment(key: &str) -> usize {
+    let (_, hex) = Integrity::from(key).to_hex();
+    usize::from_str_radix(&hex[..1], 16).unwrap_or_default() % INDEX_SEGMENTS
+}
+
+// Where a segment of the index is kept.
+fn segment_key(number: usize) -> String {
+    format!("{INDEX_PREFIX}{}.json", encode(&format!("{number:x}")))
+}
+
+// Where a bucket written before there was an index recorded a key. Keys are url-encoded like any
+// other name, which leaves the keys of the time, made of letters, digits and `.-_`, as they were.
+fn keyfile_key(key: &str) -> String {
+    format!("{}{KEYFILE_SUFFIX}", encode(key))
+}
+
+// The HTTP status S3 responded to a request with, if it got as far as responding.
+fn status_of<E>(e: &SdkError<E>) -> Option<u16> {
+    match e {
+        SdkError::ServiceError(context) => Some(context.raw().http().status().as_u16()),
+        SdkError::ResponseError(context) => Some(context.raw().http().status().as_u16()),
+        _ => None,
+    }
+}
+
+// Where the description of a blob is kept.
+fn info_key(integrity: &Integrity) -> String {
+    format!("{INFO_PREFIX}{}", blob_key(integrity))
+}
+
+// Where a label of a blob is recorded.
+fn label_key(name: &str, value: &str, integrity: &Integrity) -> String {
+    format!(
+        "{LABEL_PREFIX}{}/{}/{}",
+        encode(name),
+        encode(value),
+        blob_key(integrity)
+    )
+}
+
+// The prefix under which the blobs with a label, as `key` or `key=value`, are recorded.
+fn label_prefix(label: &str) -> String {
+    match label.split_once('=') {
+        Some((name, value)) => format!(
+            "{LABEL_PREFIX}{}/{}/",
+            encode(name.trim()),
+            encode(value.trim())
+        ),
+        None => format!("{LABEL_PREFIX}{}/", encode(label.trim())),
+    }
+}
+
+// Whether an object holds something other than a blob's data.
+fn is_bookkeeping(bucket_key: &str) -> bool {
+    [INDEX_PREFIX, INFO_PREFIX, LABEL_PREFIX]
+        .iter()
+        .any(|prefix| bucket_key.starts_with(prefix))
+        || bucket_key.ends_with(KEYFILE_SUFFIX)
+}
+
+// How big an object is as it was before it was encrypted, given what's stored.
+fn unencrypted_len(metadata: Option<&HashMap<String, String>>, content_length: i64) -> u64 {
+    let stored = content_length.max(0) as u64;
+    match key_id(metadata) {
+        Some(_) => plaintext_len(stored),
+        None => stored,
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    fn name(&self) -> &'static str {
+        "s3 bucket"
+    }
+
+    fn is_remote(&self) -> bool {
+        true
+    }
+
+    async fn store_by_integrity(&self, bytes: &[u8]) -> ServalResult<Integrity> {
         let integrity = Integrity::from(bytes);
-        let keyfile = format!("{key}.integrity");
-        let keybody = ByteStream::from(integrity.to_string().as_bytes().to_vec());
+        let stream = Box::pin(std::io::Cursor::new(bytes.to_vec()));
+        self.put_blob_stream(&integrity, bytes.len() as u64, stream)
+            .await
+    }
+
+    async fn store_stream_by_integrity(
+        &self,
+        integrity: &Integrity,
+        size: u64,
+        stream: SendableStream,
+    ) -> ServalResult<Integrity> {
+        self.put_blob_stream(integrity, size, stream).await
+    }
 
-        if let Err(failure) = self
+    /// Fetch the given data blob from our data store, by integrity hash. Returns a stream, which
+    /// fails at its end if the blob doesn't match the hash.
+    async fn stream_by_integrity(&self, integrity: &Integrity) -> ServalResult<SendableStream> {
+        let bucket_key = blob_key(integrity);
+        let object = self.get_blob(&bucket_key, None).await?;
+        let encoding = object.content_encoding().map(str::to_string);
+        let mut stream = self.open_object(&bucket_key, object)?;
+        if let Some(encoding) = encoding {
+            stream = compression::decode(&encoding, stream)?;
+        }
+        Ok(verify_stream(integrity, stream))
+    }
+
+    async fn data_by_integrity(&self, integrity: &Integrity) -> ServalResult<Vec<u8>> {
+        let bucket_key = blob_key(integrity);
+        let object = self.get_blob(&bucket_key, None).await?;
+        let encoding = object.content_encoding().map(str::to_string);
+        let mut bytes = Vec::new();
+        self.open_object(&bucket_key, object)?
+            .read_to_end(&mut bytes)
+            .await?;
+        if let Some(encoding) = encoding {
+            known_encoding(&encoding)?;
+            bytes = decode_bytes(&encoding, bytes)
+                .await
+                .map_err(|_| mismatch(integrity))?;
+        }
+        verify(integrity, &bytes)?;
+        Ok(bytes)
+    }
+
+    /// The size of a compressed blob is the size of its data, as recorded when it was stored.
+    async fn metadata_by_integrity(&self, integrity: &Integrity) -> ServalResult<BlobMetadata> {
+        let head = self
             .client
-            .put_object()
+            .head_object()
+            .bucket(&self.bucket)
+            .key(blob_key(integrity))
+            .send()
+            .await?;
+        let recorded = head
+            .metadata()
+            .and_then(|metadata| metadata.get(UNCOMPRESSED_SIZE))
+            .and_then(|size| size.parse().ok());
+        Ok(BlobMetadata {
+            integrity: integrity.clone(),
+            size: recorded.unwrap_or(unencrypted_len(head.metadata(), head.content_length())),
+        })
+    }
+
+    /// A range of a compressed blob would be a range of what's stored rather than of the data, and
+    /// compressed data can only be decoded from its start, so a compressed blob is fetched and
+    /// decoded up to the range instead. Encrypted blobs decrypt a segment at a time, so just the
+    /// segments that hold the range are fetched.
+    async fn stream_range_by_integrity(
+        &self,
+        integrity: &Integrity,
+        range: ByteRange,
+    ) -> ServalResult<SendableStream> {
+        let bucket_key = blob_key(integrity);
+        let head = self
+            .client
+            .head_object()
             .bucket(&self.bucket)
-            .key(keyfile)
-            .body(keybody)
-            .content_type("text/plain")
+            .key(&bucket_key)
             .send()
+            .await?;
+        if head.content_encoding().is_some() {
+            let stream = self.stream_by_integrity(integrity).await?;
+            return range.of_stream(stream).await;
+        }
+        if range.start >= unencrypted_len(head.metadata(), head.content_length()) {
+            return Err(ServalError::StorageError(format!(
+                "byte range {}-{} starts past the end of the data",
+                range.start,
+                range.last()
+            )));
+        }
+        if key_id(head.metadata()).is_none() {
+            let object = self.get_blob(&bucket_key, Some(range)).await?;
+            return Ok(Box::pin(object.body.into_async_read()));
+        }
+
+        let segments = segments_for(range, head.content_length().max(0) as u64);
+        let object = self.get_blob(&bucket_key, Some(segments.stored)).await?;
+        // The object may have been re-encrypted under another data key since we looked, but never
+        // in segments of another size.
+        let Some(key) = self.data_key(&bucket_key, object.metadata())? else {
+            return Err(ServalError::StorageError(format!(
+                "{bucket_key} is no longer encrypted"
+            )));
+        };
+        let body = Box::pin(object.body.into_async_read());
+        segments
+            .within
+            .of_stream(decrypt_segments(key, body, &segments))
+            .await
+    }
+
+    async fn encoded_stream_by_integrity(
+        &self,
+        integrity: &Integrity,
+    ) -> ServalResult<Option<EncodedStream>> {
+        let bucket_key = blob_key(integrity);
+        let object = self.get_blob(&bucket_key, None).await?;
+        let Some(encoding) = object.content_encoding() else {
+            return Ok(None);
+        };
+        let encoding = known_encoding(encoding)?;
+        let size = unencrypted_len(object.metadata(), object.content_length());
+        let stream = self.open_object(&bucket_key, object)?;
+        Ok(Some(EncodedStream {
+            encoding,
+            size,
+            stream: verify_encoded_stream(integrity, encoding, stream)?,
+        }))
+    }
+
+    async fn data_exists_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
+        Ok(self.has_blob(&blob_key(integrity)).await)
+    }
+
+    /// Store data by key. The blob goes up first, so the key never points at a blob that isn't
+    /// there.
+    async fn store_by_key(&self, key: &str, bytes: &[u8]) -> ServalResult<Integrity> {
+        let integrity = Integrity::from(bytes);
+        let stream = Box::pin(std::io::Cursor::new(bytes.to_vec()));
+        if let Err(e) = self
+            .put_blob_stream(&integrity, bytes.len() as u64, stream)
             .await
         {
+            log::info!("Error storing data in s3: {e:?}");
             return Err(ServalError::StorageError(format!(
-                "failed to write integrity key file to S3; error={failure}"
+                "unable to store executable! key={key}; integrity={integrity}; error={e}"
             )));
         }
+        self.put_key(key, &integrity).await?;
+        Ok(integrity)
+    }
 
-        match self.store_by_integrity(&integrity, bytes).await {
-            Ok(integrity) => Ok(integrity),
-            Err(e) => {
-                log::info!("Error storing data in s3: {e:?}");
+    /// Store data by key from a stream. The blob goes up first, so the key never points at a blob
+    /// that isn't there.
+    async fn store_stream_by_key(
+        &self,
+        key: &str,
+        integrity: &Integrity,
+        size: u64,
+        stream: SendableStream,
+    ) -> ServalResult<Integrity> {
+        let integrity = self.put_blob_stream(integrity, size, stream).await?;
+        self.put_key(key, &integrity).await?;
+        Ok(integrity)
+    }
 
-                Err(ServalError::StorageError(format!(
-                    "unable to store executable! key={key}; integrity={integrity}; error={e}"
-                )))
+    async fn link_key(&self, key: &str, integrity: &Integrity) -> ServalResult<Integrity> {
+        if !self.has_blob(&blob_key(integrity)).await {
+            return Err(ServalError::DataNotFound(integrity.to_string()));
+        }
+        self.put_key(key, integrity).await?;
+        Ok(integrity.clone())
+    }
+
+    /// Fetch data by key as a readable byte stream, checked against the integrity the key records.
+    async fn stream_by_key(&self, key: &str) -> ServalResult<SendableStream> {
+        let integrity = self.lookup_integrity(key).await?;
+        self.stream_by_integrity(&integrity).await
+    }
+
+    /// Fetch data from the store by key. Returns a vec of u8, checked against the integrity the key
+    /// records.
+    async fn data_by_key(&self, key: &str) -> ServalResult<Vec<u8>> {
+        let integrity = self.lookup_integrity(key).await?;
+        self.data_by_integrity(&integrity).await
+    }
+
+    /// Check if the given data blob is present in our data store, using its human key.
+    async fn data_exists_by_key(&self, key: &str) -> ServalResult<bool> {
+        match self.lookup_integrity(key).await {
+            Ok(integrity) => Ok(self.has_blob(&blob_key(&integrity)).await),
+            Err(ServalError::DataNotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn metadata_by_key(&self, key: &str) -> ServalResult<BlobMetadata> {
+        let integrity = self.lookup_integrity(key).await?;
+        self.metadata_by_integrity(&integrity).await
+    }
+
+    /// Every segment of the index is brought up to date, which costs little for those that haven't
+    /// changed.
+    async fn list_keys(&self, prefix: &str) -> ServalResult<Vec<String>> {
+        let mut keys = BTreeSet::new();
+        for (number, segment) in self.index.iter().enumerate() {
+            let mut segment = segment.lock().await;
+            self.refresh_segment(number, &mut segment).await?;
+            let indexed = segment.keys.keys().filter(|key| key.starts_with(prefix));
+            keys.extend(indexed.cloned());
+        }
+        let objects = self.list_objects(&encode(prefix)).await?;
+        let keyfiles = objects.iter().filter_map(|object| {
+            let keyfile = object.key()?.strip_suffix(KEYFILE_SUFFIX)?;
+            decode(keyfile).ok()
+        });
+        keys.extend(keyfiles.map(|key| key.into_owned()));
+        Ok(keys.into_iter().collect())
+    }
+
+    /// A key recorded in a keyfile, from before there was an index, is deleted along with its
+    /// keyfile.
+    async fn delete_by_key(&self, key: &str) -> ServalResult<bool> {
+        let removed = self
+            .update_index(key, |keys| keys.remove(key).is_some())
+            .await?;
+        let keyfile = keyfile_key(key);
+        if !self.has_blob(&keyfile).await {
+            return Ok(removed);
+        }
+        self.delete_object(&keyfile).await?;
+        Ok(true)
+    }
+
+    /// A blob's description goes with it.
+    async fn delete_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
+        let bucket_key = blob_key(integrity);
+        if !self.has_blob(&bucket_key).await {
+            return Ok(false);
+        }
+        self.write_info(integrity, &BlobInfo::default()).await?;
+        self.delete_object(&bucket_key).await?;
+        Ok(true)
+    }
+
+    async fn list_blobs(&self) -> ServalResult<Vec<BlobMetadata>> {
+        // Everything in the bucket besides keys, keyfiles and descriptions is a blob stored under
+        // its integrity.
+        let blobs = self
+            .list_objects("")
+            .await?
+            .iter()
+            .filter_map(|object| {
+                let bucket_key = object.key()?;
+                if is_bookkeeping(bucket_key) {
+                    return None;
+                }
+                let integrity = decode(bucket_key).ok()?.parse().ok()?;
+                Some(BlobMetadata {
+                    integrity,
+                    size: object.size().max(0) as u64,
+                })
+            })
+            .collect();
+        Ok(blobs)
+    }
+
+    async fn store_blob_info(&self, integrity: &Integrity, info: &BlobInfo) -> ServalResult<()> {
+        if !self.has_blob(&blob_key(integrity)).await {
+            return Err(ServalError::DataNotFound(integrity.to_string()));
+        }
+        self.write_info(integrity, info).await
+    }
+
+    async fn blob_info(&self, integrity: &Integrity) -> ServalResult<Option<BlobInfo>> {
+        self.read_info(integrity).await
+    }
+
+    /// Blobs with a label are found by listing the objects that record it. Descriptions are then
+    /// read a few at a time.
+    async fn list_blob_info(
+        &self,
+        label: Option<&str>,
+    ) -> ServalResult<Vec<(Integrity, BlobInfo)>> {
+        let prefix = match label {
+            Some(label) => label_prefix(label),
+            None => INFO_PREFIX.to_string(),
+        };
+        // Descriptions and labels alike are named for the blob last, and a blob has only one value
+        // for each label.
+        let described: Vec<Integrity> = self
+            .list_objects(&prefix)
+            .await?
+            .iter()
+            .filter_map(|object| {
+                let bucket_key = object.key()?.rsplit('/').next()?;
+                decode(bucket_key).ok()?.parse().ok()
+            })
+            .collect();
+        let described: Vec<Option<(Integrity, BlobInfo)>> = futures::stream::iter(described)
+            .map(|integrity| async move {
+                // Forgotten since it was listed, if there's no description now.
+                let info = self.read_info(&integrity).await?;
+                Ok::<_, ServalError>(info.map(|info| (integrity, info)))
+            })
+            .buffer_unordered(DESCRIBE_CONCURRENCY)
+            .try_collect()
+            .await?;
+        Ok(described.into_iter().flatten().collect())
+    }
+
+    /// Re-encrypts every object in the bucket that isn't encrypted under the current master key,
+    /// keyfiles and descriptions included, and objects stored before encryption was turned on. The
+    /// index and the objects recording labels hold no data, so they're never encrypted. An object
+    /// that can't be re-encrypted is left as it was, and reported.
+    async fn rotate_keys(&self) -> ServalResult<Option<KeyRotation>> {
+        let Some(keyring) = &self.encryption else {
+            return Ok(None);
+        };
+        let mut rotation = KeyRotation {
+            backend: self.name().to_string(),
+            key_id: keyring.current_id().to_string(),
+            ..Default::default()
+        };
+        for object in self.list_objects("").await? {
+            let Some(bucket_key) = object.key() else {
+                continue;
+            };
+            if bucket_key.starts_with(INDEX_PREFIX) || bucket_key.starts_with(LABEL_PREFIX) {
+                continue;
+            }
+            match self.reencrypt(keyring, bucket_key).await {
+                Ok(true) => rotation.rotated += 1,
+                Ok(false) => rotation.current += 1,
+                Err(e) => {
+                    log::warn!("unable to re-encrypt an S3 object; key={bucket_key}; error={e}");
+                    rotation.failed.push(bucket_key.to_string());
+                }
+            }
+        }
+        Ok(Some(rotation))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use axum::body::{Body, Bytes};
+    use axum::extract::State;
+    use axum::http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use axum::Router;
+    use base64::Engine;
+    use s3::config::{Credentials, Region};
+
+    use super::*;
+
+    // Just enough of S3 to stand in for it: objects with their headers and user metadata, ranges,
+    // conditional reads and writes, paged listings and multipart uploads. It keeps everything in memory, and
+    // records every request it's sent.
+    #[derive(Debug, Default)]
+    struct Bucket {
+        objects: BTreeMap<String, StoredObject>,
+        uploads: HashMap<String, (HeaderMap, BTreeMap<i32, Bytes>)>,
+        // Every request, as "METHOD object-key status".
+        requests: Vec<String>,
+    }
+
+    #[derive(Debug, Clone)]
+    struct StoredObject {
+        body: Bytes,
+        headers: HeaderMap,
+    }
+
+    type StandIn = Arc<std::sync::Mutex<Bucket>>;
+
+    // Listings come in pages this long, so that paging gets exercised.
+    const PAGE_SIZE: usize = 3;
+
+    impl StoredObject {
+        fn new(body: Bytes, mut headers: HeaderMap) -> Self {
+            let digest = ring::digest::digest(&ring::digest::SHA256, &body);
+            let etag = format!("\"{}\"", hex::encode(&digest.as_ref()[..16]));
+            headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+            Self { body, headers }
+        }
+
+        fn metadata(&self, name: &str) -> Option<&str> {
+            let value = self.headers.get(format!("x-amz-meta-{name}"))?;
+            value.to_str().ok()
+        }
+    }
+
+    // The headers S3 stores an object with.
+    fn stored_headers(headers: &HeaderMap) -> HeaderMap {
+        headers
+            .iter()
+            .filter(|(name, _)| {
+                *name == header::CONTENT_TYPE
+                    || *name == header::CONTENT_ENCODING
+                    || name.as_str().starts_with("x-amz-meta-")
+            })
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    fn xml(body: String) -> Response {
+        ([(header::CONTENT_TYPE, "application/xml")], body).into_response()
+    }
+
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    }
+
+    async fn handle(State(bucket): State<StandIn>, request: Request<Body>) -> Response {
+        let (parts, body) = request.into_parts();
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        // Requests are path-style: /{bucket}/{object key}.
+        let path = parts.uri.path().trim_start_matches('/');
+        let key = match path.split_once('/') {
+            Some((_, key)) => decode(key).unwrap().into_owned(),
+            None => String::new(),
+        };
+        let query: HashMap<String, String> = parts
+            .uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (name.to_string(), decode(value).unwrap().into_owned())
+            })
+            .collect();
+
+        let mut bucket = bucket.lock().unwrap();
+        let response = respond(
+            &mut bucket,
+            &parts.method,
+            &parts.headers,
+            &key,
+            &query,
+            body,
+        );
+        let record = format!("{} {key} {}", parts.method, response.status().as_u16());
+        bucket.requests.push(record);
+        response
+    }
+
+    fn respond(
+        bucket: &mut Bucket,
+        method: &Method,
+        headers: &HeaderMap,
+        key: &str,
+        query: &HashMap<String, String>,
+        body: Bytes,
+    ) -> Response {
+        let upload_id = query.get("uploadId").cloned();
+        match (method, upload_id) {
+            (&Method::GET, _) if query.contains_key("list-type") => list(bucket, query),
+            (&Method::POST, None) if query.contains_key("uploads") => {
+                let upload_id = format!("upload-{}", bucket.uploads.len() + 1);
+                let upload = (stored_headers(headers), BTreeMap::new());
+                bucket.uploads.insert(upload_id.clone(), upload);
+                xml(format!(
+                    "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>{}</Key>\
+                     <UploadId>{upload_id}</UploadId></InitiateMultipartUploadResult>",
+                    escape(key)
+                ))
+            }
+            (&Method::PUT, Some(upload_id)) => {
+                let Some((_, parts)) = bucket.uploads.get_mut(&upload_id) else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                let part_number = query["partNumber"].parse().unwrap();
+                let part = StoredObject::new(body, HeaderMap::new());
+                parts.insert(part_number, part.body);
+                (StatusCode::OK, part.headers).into_response()
+            }
+            (&Method::POST, Some(upload_id)) => {
+                let Some((headers, parts)) = bucket.uploads.remove(&upload_id) else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                let body: Vec<u8> = parts.into_values().flatten().collect();
+                let object = StoredObject::new(body.into(), headers);
+                bucket.objects.insert(key.to_string(), object);
+                xml(format!(
+                    "<CompleteMultipartUploadResult><Bucket>bucket</Bucket><Key>{}</Key>\
+                     </CompleteMultipartUploadResult>",
+                    escape(key)
+                ))
+            }
+            (&Method::DELETE, Some(upload_id)) => {
+                bucket.uploads.remove(&upload_id);
+                StatusCode::NO_CONTENT.into_response()
+            }
+            (&Method::PUT, None) => {
+                let current = bucket.objects.get(key).map(|object| &object.headers);
+                let current = current.and_then(|headers| headers.get(header::ETAG));
+                let unmet = match headers.get(header::IF_MATCH) {
+                    Some(etag) => current != Some(etag),
+                    None => headers.contains_key(header::IF_NONE_MATCH) && current.is_some(),
+                };
+                if unmet {
+                    return StatusCode::PRECONDITION_FAILED.into_response();
+                }
+                let object = StoredObject::new(body, stored_headers(headers));
+                let response = (StatusCode::OK, object.headers.clone()).into_response();
+                bucket.objects.insert(key.to_string(), object);
+                response
+            }
+            (&Method::GET, None) | (&Method::HEAD, None) => match bucket.objects.get(key) {
+                Some(object) => read(object, headers),
+                None if method == Method::HEAD => StatusCode::NOT_FOUND.into_response(),
+                None => {
+                    let body = format!(
+                        "<Error><Code>NoSuchKey</Code><Message>The specified key does not \
+                         exist.</Message><Key>{}</Key></Error>",
+                        escape(key)
+                    );
+                    (StatusCode::NOT_FOUND, xml(body)).into_response()
+                }
+            },
+            (&Method::DELETE, None) => {
+                bucket.objects.remove(key);
+                StatusCode::NO_CONTENT.into_response()
+            }
+            _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        }
+    }
+
+    fn read(object: &StoredObject, headers: &HeaderMap) -> Response {
+        if headers.get(header::IF_NONE_MATCH) == object.headers.get(header::ETAG) {
+            return (StatusCode::NOT_MODIFIED, object.headers.clone()).into_response();
+        }
+        let range = headers
+            .get(header::RANGE)
+            .and_then(|range| range.to_str().ok()?.strip_prefix("bytes="))
+            .and_then(|range| range.split_once('-'))
+            .map(|(start, last)| (start.parse().unwrap(), last.parse::<usize>().unwrap()));
+        match range {
+            Some((start, _)) if start >= object.body.len() => {
+                StatusCode::RANGE_NOT_SATISFIABLE.into_response()
+            }
+            Some((start, last)) => {
+                let last = last.min(object.body.len() - 1);
+                let content_range = format!("bytes {start}-{last}/{}", object.body.len());
+                let mut headers = object.headers.clone();
+                headers.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+                let body = object.body.slice(start..=last);
+                (StatusCode::PARTIAL_CONTENT, headers, body).into_response()
+            }
+            None => (object.headers.clone(), object.body.clone()).into_response(),
+        }
+    }
+
+    fn list(bucket: &Bucket, query: &HashMap<String, String>) -> Response {
+        let prefix = query.get("prefix").cloned().unwrap_or_default();
+        let after = query.get("continuation-token").cloned().unwrap_or_default();
+        let mut matching: Vec<_> = bucket
+            .objects
+            .iter()
+            .filter(|(key, _)| key.starts_with(&prefix) && **key > after)
+            .take(PAGE_SIZE + 1)
+            .collect();
+        let truncated = matching.len() > PAGE_SIZE;
+        matching.truncate(PAGE_SIZE);
+
+        let mut body = format!(
+            "<ListBucketResult><Name>bucket</Name><Prefix>{}</Prefix><KeyCount>{}</KeyCount>\
+             <MaxKeys>{PAGE_SIZE}</MaxKeys><IsTruncated>{truncated}</IsTruncated>",
+            escape(&prefix),
+            matching.len()
+        );
+        for (key, object) in &matching {
+            body += &format!(
+                "<Contents><Key>{}</Key><Size>{}</Size></Contents>",
+                escape(key),
+                object.body.len()
+            );
+        }
+        if let (true, Some((last, _))) = (truncated, matching.last()) {
+            body += &format!(
+                "<NextContinuationToken>{}</NextContinuationToken>",
+                escape(last)
+            );
+        }
+        body += "</ListBucketResult>";
+        xml(body)
+    }
+
+    // Start a stand-in for S3 on loopback, and return storage in a bucket there along with the
+    // port it's listening on.
+    async fn stand_in() -> (S3Storage, StandIn, u16) {
+        let bucket = StandIn::default();
+        let app = Router::new().fallback(handle).with_state(bucket.clone());
+        let server = axum::Server::bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .serve(app.into_make_service());
+        let port = server.local_addr().port();
+        tokio::spawn(server);
+        (storage_at(port).await, bucket, port)
+    }
+
+    // Storage in the stand-in listening on the given port, as another node would see it.
+    async fn storage_at(port: u16) -> S3Storage {
+        let config = aws_config::from_env()
+            .region(Region::new("us-east-1"))
+            .endpoint_url(format!("http://{}:{port}", Ipv4Addr::LOCALHOST))
+            .credentials_provider(Credentials::new("stand-in", "stand-in", None, None, "test"))
+            .load()
+            .await;
+        S3Storage::new("bucket", config).unwrap()
+    }
+
+    fn keyring(current: &str) -> Keyring {
+        let key = |fill: u8| base64::engine::general_purpose::STANDARD.encode([fill; 32]);
+        let keys = HashMap::from([("old".to_string(), key(1)), ("new".to_string(), key(2))]);
+        Keyring::new(current, &keys).unwrap()
+    }
+
+    fn object_keys(bucket: &StandIn) -> Vec<String> {
+        bucket.lock().unwrap().objects.keys().cloned().collect()
+    }
+
+    fn put_behind_our_back(bucket: &StandIn, bucket_key: &str, object: StoredObject) {
+        let mut bucket = bucket.lock().unwrap();
+        bucket.objects.insert(bucket_key.to_string(), object);
+    }
+
+    fn requests(bucket: &StandIn) -> Vec<String> {
+        std::mem::take(&mut bucket.lock().unwrap().requests)
+    }
+
+    async fn read_all(mut stream: SendableStream) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        stream.read_to_end(&mut bytes).await.unwrap();
+        bytes
+    }
+
+    #[tokio::test]
+    async fn stores_blobs_under_url_safe_keys() {
+        let (storage, bucket, _) = stand_in().await;
+        let data = b"some bytes that want storing".to_vec();
+        let integrity = storage.store_by_integrity(&data).await.unwrap();
+        assert!(integrity.to_string().contains(['+', '/', '=']));
+        assert_eq!(object_keys(&bucket), vec![blob_key(&integrity)]);
+
+        assert!(storage.data_exists_by_integrity(&integrity).await.unwrap());
+        assert_eq!(storage.data_by_integrity(&integrity).await.unwrap(), data);
+        let stream = storage.stream_by_integrity(&integrity).await.unwrap();
+        assert_eq!(read_all(stream).await, data);
+        let metadata = storage.metadata_by_integrity(&integrity).await.unwrap();
+        assert_eq!(metadata.size, data.len() as u64);
+        let range = ByteRange { start: 5, len: 5 };
+        let stream = storage
+            .stream_range_by_integrity(&integrity, range)
+            .await
+            .unwrap();
+        assert_eq!(read_all(stream).await, data[5..10]);
+        assert!(storage
+            .encoded_stream_by_integrity(&integrity)
+            .await
+            .unwrap()
+            .is_none());
+
+        let blobs = storage.list_blobs().await.unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].integrity, integrity);
+        assert_eq!(blobs[0].size, data.len() as u64);
+
+        assert!(storage.delete_by_integrity(&integrity).await.unwrap());
+        assert!(!storage.delete_by_integrity(&integrity).await.unwrap());
+        assert!(!storage.data_exists_by_integrity(&integrity).await.unwrap());
+        assert!(storage.data_by_integrity(&integrity).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_blobs_that_dont_match_their_integrity() {
+        let (storage, bucket, _) = stand_in().await;
+        let integrity = Integrity::from(b"what was promised");
+        let stream = Box::pin(std::io::Cursor::new(b"what was sent".to_vec()));
+        assert!(storage
+            .store_stream_by_integrity(&integrity, 13, stream)
+            .await
+            .is_err());
+        assert!(object_keys(&bucket).is_empty());
+
+        // Data changed in the bucket behind our back fails the read.
+        let data = b"the right bytes".to_vec();
+        let integrity = storage.store_by_integrity(&data).await.unwrap();
+        let corrupt = StoredObject::new(Bytes::from_static(b"the wrong bytes"), HeaderMap::new());
+        put_behind_our_back(&bucket, &blob_key(&integrity), corrupt);
+        assert!(storage.data_by_integrity(&integrity).await.is_err());
+        let mut stream = storage.stream_by_integrity(&integrity).await.unwrap();
+        let mut bytes = Vec::new();
+        assert!(stream.read_to_end(&mut bytes).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn uploads_big_blobs_in_parts() {
+        let (storage, bucket, _) = stand_in().await;
+        let size = MIN_PART_SIZE as usize + 1000;
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let integrity = Integrity::from(&data);
+        let stream = Box::pin(std::io::Cursor::new(data.clone()));
+        storage
+            .store_stream_by_integrity(&integrity, size as u64, stream)
+            .await
+            .unwrap();
+        let bucket_key = blob_key(&integrity);
+        let uploads = requests(&bucket);
+        assert!(uploads.contains(&format!("POST {bucket_key} 200")));
+        assert_eq!(
+            uploads
+                .iter()
+                .filter(|request| request.starts_with("PUT "))
+                .count(),
+            2
+        );
+        assert_eq!(storage.data_by_integrity(&integrity).await.unwrap(), data);
+
+        // An upload of the wrong data is abandoned rather than completed.
+        let mut wrong = data;
+        wrong[size - 1] ^= 0xff;
+        let stream = Box::pin(std::io::Cursor::new(wrong));
+        let other = Integrity::from(b"something else entirely");
+        assert!(storage
+            .store_stream_by_integrity(&other, size as u64, stream)
+            .await
+            .is_err());
+        assert!(requests(&bucket).contains(&format!("DELETE {} 204", blob_key(&other))));
+        assert!(bucket.lock().unwrap().uploads.is_empty());
+        assert_eq!(object_keys(&bucket), vec![bucket_key]);
+    }
+
+    #[tokio::test]
+    async fn records_keys_in_segments_of_an_index() {
+        let (storage, bucket, _) = stand_in().await;
+        let keys = ["plain", "with/slashes", "ünïcödé"];
+        for (i, key) in keys.iter().enumerate() {
+            let data = format!("data {i}").into_bytes();
+            storage.store_by_key(key, &data).await.unwrap();
+        }
+        let data = b"streamed data".to_vec();
+        let integrity = Integrity::from(&data);
+        let stream = Box::pin(std::io::Cursor::new(data.clone()));
+        storage
+            .store_stream_by_key("streamed", &integrity, data.len() as u64, stream)
+            .await
+            .unwrap();
+        let segments: BTreeSet<String> = keys
+            .iter()
+            .chain(["streamed"].iter())
+            .map(|key| segment_key(index_segment(key)))
+            .collect();
+        let objects = object_keys(&bucket);
+        assert_eq!(objects.len(), keys.len() + 1 + segments.len());
+        for segment in &segments {
+            assert!(objects.contains(segment), "{segment}");
+        }
+        assert!(!objects.iter().any(|key| key.ends_with(KEYFILE_SUFFIX)));
+        assert_eq!(storage.list_blobs().await.unwrap().len(), keys.len() + 1);
+
+        for (i, key) in keys.iter().enumerate() {
+            let expected = format!("data {i}").into_bytes();
+            assert_eq!(storage.data_by_key(key).await.unwrap(), expected);
+            let stream = storage.stream_by_key(key).await.unwrap();
+            assert_eq!(read_all(stream).await, expected);
+            let metadata = storage.metadata_by_key(key).await.unwrap();
+            assert_eq!(metadata.integrity, Integrity::from(&expected));
+            assert!(storage.data_exists_by_key(key).await.unwrap());
+        }
+        assert_eq!(storage.data_by_key("streamed").await.unwrap(), data);
+
+        // While our copy of a key's segment is fresh, reads by the key go straight to the blob.
+        requests(&bucket);
+        storage.data_by_key("plain").await.unwrap();
+        assert_eq!(requests(&bucket).len(), 1);
+
+        assert!(!storage.data_exists_by_key("missing").await.unwrap());
+        assert!(matches!(
+            storage.data_by_key("missing").await,
+            Err(ServalError::DataNotFound(_))
+        ));
+
+        assert_eq!(
+            storage.list_keys("with").await.unwrap(),
+            vec!["with/slashes"]
+        );
+        assert_eq!(storage.list_keys("").await.unwrap().len(), keys.len() + 1);
+
+        assert!(storage.delete_by_key("plain").await.unwrap());
+        assert!(!storage.delete_by_key("plain").await.unwrap());
+        assert!(!storage.data_exists_by_key("plain").await.unwrap());
+        assert_eq!(storage.list_keys("").await.unwrap().len(), keys.len());
+    }
+
+    #[tokio::test]
+    async fn stores_and_lists_keys_with_characters_urls_reserve() {
+        let (storage, bucket, _) = stand_in().await;
+        let key = "a key with spaces, 100% ?query and #fragment";
+        storage.store_by_key(key, b"new data").await.unwrap();
+        let old = "a key from before, 50% ?off #sale";
+        let integrity = storage.store_by_integrity(b"old data").await.unwrap();
+        let keyfile = StoredObject::new(integrity.to_string().into(), HeaderMap::new());
+        put_behind_our_back(&bucket, &keyfile_key(old), keyfile);
+
+        // Every object is named as url-encoding would name it, besides the index's own segments.
+        for name in object_keys(&bucket) {
+            if !name.starts_with(INDEX_PREFIX) {
+                assert_eq!(encode(&decode(&name).unwrap()), name);
             }
         }
+        assert_eq!(storage.list_keys("a key").await.unwrap(), vec![old, key]);
+        assert_eq!(storage.list_keys("a key with").await.unwrap(), vec![key]);
+        assert_eq!(storage.data_by_key(key).await.unwrap(), b"new data");
+        assert_eq!(storage.data_by_key(old).await.unwrap(), b"old data");
+
+        assert!(storage.delete_by_key(key).await.unwrap());
+        assert!(storage.delete_by_key(old).await.unwrap());
+        assert!(storage.list_keys("a key").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sees_keys_changed_by_other_nodes() {
+        let (storage, bucket, port) = stand_in().await;
+        let other = storage_at(port).await;
+        // Keys that share a segment of the index with "first".
+        let mut neighbours = (0..)
+            .map(|i| format!("neighbour {i}"))
+            .filter(|key| index_segment(key) == index_segment("first"));
+        let (second, third, missing) = (
+            neighbours.next().unwrap(),
+            neighbours.next().unwrap(),
+            neighbours.next().unwrap(),
+        );
+        storage.store_by_key("first", b"one").await.unwrap();
+        assert_eq!(other.data_by_key("first").await.unwrap(), b"one");
+
+        // A key the other node doesn't know of yet is looked for in the bucket's index, which
+        // isn't sent again when it hasn't changed.
+        storage.store_by_key(&second, b"two").await.unwrap();
+        assert_eq!(other.data_by_key(&second).await.unwrap(), b"two");
+        requests(&bucket);
+        assert!(!other.data_exists_by_key(&missing).await.unwrap());
+        let sent = requests(&bucket);
+        let segment = segment_key(index_segment("first"));
+        assert!(sent.contains(&format!("GET {segment} 304")), "{sent:?}");
+
+        // A write to a segment another node has changed since we saw it is retried, rather than
+        // losing the other node's change.
+        storage.store_by_key(&third, b"three").await.unwrap();
+        other.store_by_key("fourth", b"four").await.unwrap();
+        other.delete_by_key(&second).await.unwrap();
+        let sent = requests(&bucket);
+        assert!(sent.contains(&format!("PUT {segment} 412")), "{sent:?}");
+        let mut keys = storage.list_keys("").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["first", "fourth", third.as_str()]);
+
+        // Both nodes' changes survive, even when they're made at the same moment.
+        let (one, two) = tokio::join!(
+            storage.store_by_key(&second, b"two"),
+            other.store_by_key(&missing, b"found"),
+        );
+        one.unwrap();
+        two.unwrap();
+        assert_eq!(storage.list_keys("").await.unwrap().len(), 5);
+        assert_eq!(other.list_keys("").await.unwrap().len(), 5);
+
+        // A key another node changes is noticed once our copy of its segment goes stale.
+        other.store_by_key("first", b"changed").await.unwrap();
+        storage.index[index_segment("first")].lock().await.checked = None;
+        assert_eq!(storage.data_by_key("first").await.unwrap(), b"changed");
+        other.delete_by_key("first").await.unwrap();
+        storage.index[index_segment("first")].lock().await.checked = None;
+        assert!(!storage.data_exists_by_key("first").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn reads_keyfiles_from_before_there_was_an_index() {
+        let (storage, bucket, _) = stand_in().await;
+        let integrity = storage.store_by_integrity(b"old data").await.unwrap();
+        let keyfile = StoredObject::new(integrity.to_string().into(), HeaderMap::new());
+        put_behind_our_back(&bucket, "sh.serval.old.manifest.toml.integrity", keyfile);
+
+        let old = "sh.serval.old.manifest.toml";
+        assert_eq!(storage.data_by_key(old).await.unwrap(), b"old data");
+        assert!(storage.data_exists_by_key(old).await.unwrap());
+        storage.store_by_key("new", b"new data").await.unwrap();
+        assert_eq!(storage.list_keys("").await.unwrap(), vec!["new", old]);
+        assert_eq!(storage.list_blobs().await.unwrap().len(), 2);
+
+        assert!(storage.delete_by_key(old).await.unwrap());
+        assert!(!object_keys(&bucket).contains(&keyfile_key(old)));
+        assert!(!storage.data_exists_by_key(old).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn encrypts_and_compresses_what_it_stores() {
+        let (storage, bucket, port) = stand_in().await;
+        let storage = storage
+            .with_compression(Compression::Zstd { level: 3 })
+            .with_encryption(keyring("old"));
+        let data = b"the same few bytes, over and over. ".repeat(1000);
+        let integrity = storage.store_by_key("secret", &data).await.unwrap();
+
+        let stored = bucket.lock().unwrap().objects.clone();
+        assert_eq!(stored.len(), 2);
+        let segment = &stored[&segment_key(index_segment("secret"))];
+        assert_eq!(segment.metadata(KEY_ID), None);
+        let blob = &stored[&blob_key(&integrity)];
+        assert_eq!(blob.metadata(KEY_ID), Some("old"));
+        assert!(!blob.body.windows(9).any(|window| window == b"the same "));
+        assert!(stored[&blob_key(&integrity)].body.len() < data.len() / 10);
+
+        assert_eq!(storage.data_by_key("secret").await.unwrap(), data);
+        let metadata = storage.metadata_by_integrity(&integrity).await.unwrap();
+        assert_eq!(metadata.size, data.len() as u64);
+        let range = ByteRange {
+            start: 100,
+            len: 100,
+        };
+        let stream = storage
+            .stream_range_by_integrity(&integrity, range)
+            .await
+            .unwrap();
+        assert_eq!(read_all(stream).await, data[100..200]);
+        let encoded = storage
+            .encoded_stream_by_integrity(&integrity)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(encoded.encoding, compression::ZSTD);
+        let compressed = read_all(encoded.stream).await;
+        assert_eq!(compressed.len() as u64, encoded.size);
+        assert_eq!(
+            decode_bytes(compression::ZSTD, compressed).await.unwrap(),
+            data
+        );
+
+        // Without the keys, nothing can be read.
+        let stranger = storage_at(port).await;
+        assert!(stranger.data_by_integrity(&integrity).await.is_err());
+
+        let rotation = storage.with_encryption(keyring("new"));
+        let rotated = rotation.rotate_keys().await.unwrap().unwrap();
+        assert_eq!((rotated.rotated, rotated.current), (1, 0));
+        assert!(rotated.failed.is_empty());
+        let blob = bucket.lock().unwrap().objects[&blob_key(&integrity)].clone();
+        assert_eq!(blob.metadata(KEY_ID), Some("new"));
+        let rotated = rotation.rotate_keys().await.unwrap().unwrap();
+        assert_eq!((rotated.rotated, rotated.current), (0, 1));
+
+        let keys = HashMap::from([(
+            "new".to_string(),
+            base64::engine::general_purpose::STANDARD.encode([2; 32]),
+        )]);
+        let reader = storage_at(port)
+            .await
+            .with_encryption(Keyring::new("new", &keys).unwrap());
+        assert_eq!(reader.data_by_key("secret").await.unwrap(), data);
+        assert!(stranger.rotate_keys().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_ranges_of_encrypted_blobs_from_the_segments_that_hold_them() {
+        let (storage, bucket, _) = stand_in().await;
+        let storage = storage.with_encryption(keyring("old"));
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let integrity = storage.store_by_integrity(&data).await.unwrap();
+        requests(&bucket);
+
+        // Within the first segment, across a boundary, and up to the end of the last.
+        for (start, len) in [(100, 100), (65_000, 2_000), (196_000, 4_000)] {
+            let range = ByteRange { start, len };
+            let stream = storage
+                .stream_range_by_integrity(&integrity, range)
+                .await
+                .unwrap();
+            let (start, len) = (start as usize, len as usize);
+            assert_eq!(read_all(stream).await, data[start..start + len]);
+        }
+        // Each range took a look at the object and one ranged fetch of it.
+        let key = blob_key(&integrity);
+        let each = vec![format!("HEAD {key} 200"), format!("GET {key} 206")];
+        assert_eq!(
+            requests(&bucket),
+            [each.clone(), each.clone(), each].concat()
+        );
+
+        let past = ByteRange {
+            start: 200_000,
+            len: 1,
+        };
+        assert!(storage
+            .stream_range_by_integrity(&integrity, past)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn describes_blobs_beside_them() {
+        let (storage, bucket, _) = stand_in().await;
+        let storage = storage
+            .with_compression(Compression::Zstd { level: 3 })
+            .with_encryption(keyring("old"));
+        let data = b"some job output".repeat(100);
+        let integrity = storage.store_by_integrity(&data).await.unwrap();
+        let other = storage.store_by_integrity(b"undescribed").await.unwrap();
+        assert_eq!(storage.blob_info(&integrity).await.unwrap(), None);
+
+        let info = BlobInfo {
+            content_type: Some("text/plain".to_string()),
+            filename: Some("output.txt".to_string()),
+            creator: None,
+            labels: BTreeMap::from([("kind".to_string(), "job output".to_string())]),
+        };
+        let stored = bucket.lock().unwrap().objects[&blob_key(&integrity)].clone();
+        storage.store_blob_info(&integrity, &info).await.unwrap();
+        assert_eq!(
+            storage.blob_info(&integrity).await.unwrap(),
+            Some(info.clone())
+        );
+        // The blob itself is left as it was, and its description is encrypted like everything else.
+        let objects = bucket.lock().unwrap().objects.clone();
+        assert_eq!(objects[&blob_key(&integrity)].body, stored.body);
+        let description = &objects[&info_key(&integrity)];
+        assert_eq!(description.metadata(KEY_ID), Some("old"));
+        assert!(!description
+            .body
+            .windows(6)
+            .any(|window| window == b"output"));
+        assert_eq!(storage.data_by_integrity(&integrity).await.unwrap(), data);
+        assert_eq!(storage.list_blobs().await.unwrap().len(), 2);
+
+        // Blobs are found by label without reading anything about the blobs that don't have it.
+        let described = vec![(integrity.clone(), info.clone())];
+        assert_eq!(storage.list_blob_info(None).await.unwrap(), described);
+        requests(&bucket);
+        for label in ["kind", "kind=job output", " kind = job output "] {
+            assert_eq!(
+                storage.list_blob_info(Some(label)).await.unwrap(),
+                described
+            );
+        }
+        assert!(!requests(&bucket)
+            .iter()
+            .any(|request| request.starts_with("HEAD")));
+        assert!(storage
+            .list_blob_info(Some("kind=job input"))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Changing a blob's labels moves it from one label to the other.
+        let relabeled = BlobInfo {
+            labels: BTreeMap::from([("kind".to_string(), "job input".to_string())]),
+            ..info.clone()
+        };
+        storage
+            .store_blob_info(&integrity, &relabeled)
+            .await
+            .unwrap();
+        assert!(storage
+            .list_blob_info(Some("kind=job output"))
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            storage
+                .list_blob_info(Some("kind=job input"))
+                .await
+                .unwrap(),
+            vec![(integrity.clone(), relabeled.clone())]
+        );
+
+        // Storing the blob again, or re-encrypting it, doesn't forget what it is.
+        storage.store_by_integrity(&data).await.unwrap();
+        let rotation = storage.clone().with_encryption(keyring("new"));
+        rotation.rotate_keys().await.unwrap();
+        assert_eq!(
+            rotation.blob_info(&integrity).await.unwrap(),
+            Some(relabeled.clone())
+        );
+
+        // An empty description forgets the blob's, and deleting the blob does too.
+        storage
+            .store_blob_info(&integrity, &BlobInfo::default())
+            .await
+            .unwrap();
+        assert_eq!(rotation.blob_info(&integrity).await.unwrap(), None);
+        assert!(!object_keys(&bucket)
+            .iter()
+            .any(|key| key.starts_with(INFO_PREFIX) || key.starts_with(LABEL_PREFIX)));
+        rotation
+            .store_blob_info(&integrity, &relabeled)
+            .await
+            .unwrap();
+        rotation.delete_by_integrity(&integrity).await.unwrap();
+        rotation.delete_by_integrity(&other).await.unwrap();
+        assert!(object_keys(&bucket).is_empty());
+        assert!(matches!(
+            storage.store_blob_info(&other, &info).await,
+            Err(ServalError::DataNotFound(_))
+        ));
     }
 }
@@ -0,0 +1,549 @@
+//! Bundles: manifests, their executables and blobs from the content-addressable store, packed into
+//! a single tar archive so that they can be carried from one mesh to another, including to meshes
+//! with no network path to this one.
+//!
+//! The first entry in a bundle is always its index, `index.json`, which lists everything else in it
+//! along with its integrity. Manifests follow as `manifests/{n}.toml`, each with its executable as
+//! `executables/{n}.wasm`, and then any other blobs as `blobs/{n}`. Importing a bundle checks every
+//! entry against the index before storing anything. Then it stores the blobs, the executables, and
+//! finally the manifests that refer to them, so that a manifest never arrives without its
+//! executable.
+//!
+//! Bundles are plain ustar archives, which any tar can list and unpack. We only write regular
+//! files, and skip anything else we find in a bundle being imported.
+
+use std::collections::HashMap;
+
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use ssri::Integrity;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_tar::{Archive, Builder, Entries, EntryType, Header};
+use tokio_util::io::StreamReader;
+use utils::errors::{ServalError, ServalResult};
+use utils::structs::api::{BundleRequest, ImportedBundle};
+use utils::structs::versions::VersionRequirement;
+use utils::structs::Manifest;
+
+use super::backend::is_integrity_mismatch;
+use super::replication::request_body;
+use super::{make_proxy_client, Storage, Upload};
+
+// The index of a bundle, and the version of the index's format.
+const INDEX_PATH: &str = "index.json";
+const FORMAT: u32 = 1;
+
+// An index this big is not an index we want to read.
+const MAX_INDEX_SIZE: u64 = 16 * 1024 * 1024;
+
+// How much of a bundle is in flight between writing it and spooling it at once.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// The index at the start of every bundle.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct BundleIndex {
+    format: u32,
+    manifests: Vec<BundledManifest>,
+    blobs: Vec<BundledBlob>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct BundledManifest {
+    name: String,
+    version: String,
+    path: String,
+    integrity: String,
+    executable: BundledBlob,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct BundledBlob {
+    path: String,
+    integrity: String,
+    size: u64,
+}
+
+impl Storage {
+    /// Pack the requested manifests, along with their executables, and blobs into a bundle. The
+    /// bundle is spooled before anything is sent, so that data that's missing or fails its integrity
+    /// check fails the export rather than leaving the caller with a truncated bundle.
+    pub async fn export_bundle(&self, request: &BundleRequest) -> ServalResult<Upload> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            let chunks = proxy.export_bundle(request).await?.bytes_stream();
+            let reader = StreamReader::new(chunks.map_err(std::io::Error::other));
+            return Upload::spool(reader, None).await;
+        }
+
+        metrics::increment_counter!("storage:bundles:export");
+        let mut index = BundleIndex {
+            format: FORMAT,
+            ..Default::default()
+        };
+        let mut manifests = Vec::new();
+        for spec in &request.manifests {
+            let (name, requirement) = VersionRequirement::split(spec)?;
+            let manifest = self.resolve_manifest(name, &requirement).await?;
+            let (name, version) = (manifest.fq_name(), manifest.version().to_string());
+            if index
+                .manifests
+                .iter()
+                .any(|bundled| bundled.name == name && bundled.version == version)
+            {
+                continue;
+            }
+            let executable = self.executable_metadata(&name, &version).await?;
+            let toml = toml::to_string(&manifest)?.into_bytes();
+            let n = index.manifests.len();
+            index.manifests.push(BundledManifest {
+                name,
+                version,
+                path: format!("manifests/{n}.toml"),
+                integrity: Integrity::from(&toml).to_string(),
+                executable: BundledBlob {
+                    path: format!("executables/{n}.wasm"),
+                    integrity: executable.integrity.to_string(),
+                    size: executable.size,
+                },
+            });
+            manifests.push(toml);
+        }
+        for address in &request.blobs {
+            let Ok(integrity) = address.parse::<Integrity>() else {
+                return Err(ServalError::BlobAddressInvalid(format!(
+                    "{address} is not a valid sub-resource integrity string"
+                )));
+            };
+            let integrity = integrity.to_string();
+            if index.blobs.iter().any(|blob| blob.integrity == integrity) {
+                continue;
+            }
+            let metadata = self.metadata_by_integrity(&integrity.parse()?).await?;
+            let n = index.blobs.len();
+            index.blobs.push(BundledBlob {
+                path: format!("blobs/{n}"),
+                integrity,
+                size: metadata.size,
+            });
+        }
+
+        // If either side fails, the other sees the pipe close and finishes too.
+        let (writer, reader) = tokio::io::duplex(BUFFER_SIZE);
+        let (written, spooled) = tokio::join!(
+            self.write_bundle(writer, &index, manifests),
+            Upload::spool(reader, None)
+        );
+        written?;
+        let bundle = spooled?;
+        log::info!(
+            "exported a bundle; manifests={}; blobs={}; size={}",
+            index.manifests.len(),
+            index.blobs.len(),
+            bundle.size()
+        );
+        Ok(bundle)
+    }
+
+    async fn write_bundle(
+        &self,
+        writer: impl AsyncWrite + Unpin + Send,
+        index: &BundleIndex,
+        manifests: Vec<Vec<u8>>,
+    ) -> ServalResult<()> {
+        let mut archive = Builder::new_non_terminated(writer);
+        let json = serde_json::to_vec_pretty(index)
+            .map_err(|e| ServalError::StorageError(format!("unable to write bundle index; {e}")))?;
+        append(&mut archive, INDEX_PATH, json.len() as u64, json.as_slice()).await?;
+        for (bundled, toml) in index.manifests.iter().zip(manifests) {
+            append(
+                &mut archive,
+                &bundled.path,
+                toml.len() as u64,
+                toml.as_slice(),
+            )
+            .await?;
+            self.append_blob(&mut archive, &bundled.executable).await?;
+        }
+        for blob in &index.blobs {
+            self.append_blob(&mut archive, blob).await?;
+        }
+        // Finishing the archive writes the empty blocks that end it.
+        archive.into_inner().await?.shutdown().await?;
+        Ok(())
+    }
+
+    // Add a blob to a bundle, checking it against its integrity as it goes in.
+    async fn append_blob(
+        &self,
+        archive: &mut Builder<impl AsyncWrite + Unpin + Send>,
+        blob: &BundledBlob,
+    ) -> ServalResult<()> {
+        let integrity: Integrity = blob.integrity.parse()?;
+        let stream = match self.cached_blob_stream(&integrity).await {
+            Some(stream) => stream,
+            None => match self.replicated_blob(&integrity).await {
+                Some(stream) => stream,
+                None => return Err(ServalError::DataNotFound(blob.integrity.clone())),
+            },
+        };
+        match append(archive, &blob.path, blob.size, stream).await {
+            Err(e) if is_integrity_mismatch(&e) => {
+                Err(ServalError::IntegrityMismatch(blob.integrity.clone()))
+            }
+            result => result,
+        }
+    }
+
+    /// Unpack a bundle and store everything in it: its blobs in the content-addressable store,
+    /// charged to the given namespace, then each manifest's executable, then the manifests. Every
+    /// entry is checked against the bundle's index first, and nothing is stored unless they all
+    /// match.
+    pub async fn import_bundle(
+        &self,
+        namespace: Option<&str>,
+        reader: impl AsyncRead + Send + 'static,
+    ) -> ServalResult<ImportedBundle> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy
+                .import_bundle(namespace, request_body(Box::pin(reader)))
+                .await;
+        }
+
+        metrics::increment_counter!("storage:bundles:import");
+        let mut archive = Archive::new(Box::pin(reader));
+        let mut entries = archive.entries().map_err(unreadable)?;
+        let index = read_index(&mut entries).await?;
+        let mut expected: HashMap<&str, Integrity> = HashMap::new();
+        for bundled in &index.manifests {
+            expected.insert(&bundled.path, bundled_integrity(&bundled.integrity)?);
+            let executable = &bundled.executable;
+            expected.insert(&executable.path, bundled_integrity(&executable.integrity)?);
+        }
+        for blob in &index.blobs {
+            expected.insert(&blob.path, bundled_integrity(&blob.integrity)?);
+        }
+
+        let mut spooled: HashMap<String, Upload> = HashMap::new();
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry.map_err(unreadable)?;
+            if entry.header().entry_type() != EntryType::Regular {
+                continue;
+            }
+            let path = entry
+                .path()
+                .map_err(unreadable)?
+                .to_string_lossy()
+                .into_owned();
+            let size = entry.header().entry_size().map_err(unreadable)?;
+            let Some(integrity) = expected.get(path.as_str()) else {
+                log::info!(
+                    "skipping something in a bundle that its index doesn't list; path={path}"
+                );
+                continue;
+            };
+            let upload = Upload::spool(&mut entry, self.upload_limit).await?;
+            if upload.size() != size {
+                return Err(invalid(format!("the bundle ends in the middle of {path}")));
+            }
+            if integrity.matches(upload.integrity()).is_none() {
+                return Err(invalid(format!("{path} does not match its integrity")));
+            }
+            spooled.insert(path, upload);
+        }
+        if let Some(missing) = expected.keys().find(|path| !spooled.contains_key(**path)) {
+            return Err(invalid(format!("the bundle is missing {missing}")));
+        }
+        let entries = spooled;
+
+        let mut manifests = Vec::with_capacity(index.manifests.len());
+        for bundled in &index.manifests {
+            let toml = String::from_utf8(entries[&bundled.path].bytes().await?)?;
+            let manifest: Manifest = toml::from_str(&toml)
+                .map_err(|e| invalid(format!("{} is not a manifest; {e}", bundled.path)))?;
+            if manifest.fq_name() != bundled.name || manifest.version() != bundled.version {
+                return Err(invalid(format!(
+                    "{} is not {}@{}",
+                    bundled.path, bundled.name, bundled.version
+                )));
+            }
+            manifests.push(manifest);
+        }
+
+        let mut imported = ImportedBundle::default();
+        for blob in &index.blobs {
+            let upload = &entries[&blob.path];
+            self.store_upload_by_integrity(namespace, upload).await?;
+            imported.blobs.push(upload.integrity().to_string());
+            imported.size += upload.size();
+        }
+        for (bundled, manifest) in index.manifests.iter().zip(&manifests) {
+            let executable = &entries[&bundled.executable.path];
+            self.store_executable(&bundled.name, &bundled.version, executable)
+                .await?;
+            self.store_manifest(manifest).await?;
+            imported
+                .manifests
+                .push(format!("{}@{}", bundled.name, bundled.version));
+            imported.size += executable.size() + entries[&bundled.path].size();
+        }
+        log::info!(
+            "imported a bundle; manifests={}; blobs={}; size={}",
+            imported.manifests.len(),
+            imported.blobs.len(),
+            imported.size
+        );
+        Ok(imported)
+    }
+}
+
+fn invalid(reason: impl Into<String>) -> ServalError {
+    ServalError::InvalidBundle(reason.into())
+}
+
+fn bundled_integrity(integrity: &str) -> ServalResult<Integrity> {
+    integrity
+        .parse()
+        .map_err(|_| invalid(format!("`{integrity}` in the index is not an integrity")))
+}
+
+// Errors reading the archive itself mean it isn't one, or has been damaged.
+fn unreadable(e: std::io::Error) -> ServalError {
+    invalid(format!("unreadable bundle; {e}"))
+}
+
+async fn read_index<R: AsyncRead + Unpin + Send>(
+    entries: &mut Entries<R>,
+) -> ServalResult<BundleIndex> {
+    let mut entry = match entries.next().await {
+        Some(entry) => entry.map_err(unreadable)?,
+        None => return Err(invalid(format!("a bundle starts with its {INDEX_PATH}"))),
+    };
+    let path = entry.path().map_err(unreadable)?.into_owned();
+    let size = entry.header().entry_size().map_err(unreadable)?;
+    if path.as_os_str() != INDEX_PATH || size > MAX_INDEX_SIZE {
+        return Err(invalid(format!("a bundle starts with its {INDEX_PATH}")));
+    }
+    let mut json = Vec::new();
+    entry.read_to_end(&mut json).await?;
+    let index: BundleIndex = serde_json::from_slice(&json)
+        .map_err(|e| invalid(format!("unreadable {INDEX_PATH}; {e}")))?;
+    if index.format != FORMAT {
+        return Err(invalid(format!(
+            "bundle format {} is not one we know",
+            index.format
+        )));
+    }
+    Ok(index)
+}
+
+// Add a regular file to a bundle. The data must be exactly as long as promised, and is read to its
+// end, so that a stream checking its data against an integrity gets to finish the check.
+async fn append(
+    archive: &mut Builder<impl AsyncWrite + Unpin + Send>,
+    path: &str,
+    size: u64,
+    data: impl AsyncRead + Unpin,
+) -> ServalResult<()> {
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_size(size);
+    let mut limited = data.take(size);
+    archive.append_data(&mut header, path, &mut limited).await?;
+    let missing = limited.limit();
+    let extra = limited.into_inner().read(&mut [0; 1]).await?;
+    if missing > 0 || extra > 0 {
+        return Err(ServalError::StorageError(format!(
+            "{path} was not the {size} bytes it should have been"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::{MemoryStorage, ReadPolicy, StorageBackend, WritePolicy};
+
+    // Tar archives are made of blocks of this size: a header block for each entry, followed by its
+    // data padded out to a whole number of blocks, with two empty blocks at the end.
+    const BLOCK_SIZE: usize = 512;
+
+    async fn archive(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut archive = Builder::new_non_terminated(Vec::new());
+        for (path, data) in entries {
+            append(&mut archive, path, data.len() as u64, data.as_slice())
+                .await
+                .unwrap();
+        }
+        archive.into_inner().await.unwrap()
+    }
+
+    fn storage() -> Storage {
+        let backends: Vec<Arc<dyn StorageBackend>> = vec![Arc::new(MemoryStorage::new())];
+        Storage::new(backends, ReadPolicy::FirstHit, WritePolicy::All)
+    }
+
+    fn manifest(version: &str) -> Manifest {
+        Manifest::from_string(&format!(
+            r#"
+name = "loudify"
+namespace = "sh.serval"
+binary = "/loudify.wasm"
+version = "{version}"
+description = "loudify version {version}"
+"#
+        ))
+        .unwrap()
+    }
+
+    async fn upload(bytes: &[u8]) -> Upload {
+        Upload::spool(bytes, None).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn refuses_entries_that_arent_the_size_they_should_be() {
+        let mut archive = Builder::new_non_terminated(Vec::new());
+        assert!(append(&mut archive, "short", 10, [0; 9].as_slice())
+            .await
+            .is_err());
+        assert!(append(&mut archive, "long", 10, [0; 11].as_slice())
+            .await
+            .is_err());
+        append(&mut archive, "exact", 10, [0; 10].as_slice())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn moves_manifests_executables_and_blobs_between_meshes() {
+        let staging = storage();
+        for (version, executable) in [("1.0.0", b"\0asm 1"), ("1.1.0", b"\0asm 2")] {
+            staging.store_manifest(&manifest(version)).await.unwrap();
+            staging
+                .store_executable("sh.serval.loudify", version, &upload(executable).await)
+                .await
+                .unwrap();
+        }
+        let input = staging
+            .store_by_integrity(None, b"some job input")
+            .await
+            .unwrap();
+
+        let request = BundleRequest {
+            manifests: vec![
+                "sh.serval.loudify@~1.0".to_string(),
+                "sh.serval.loudify".to_string(),
+                "sh.serval.loudify@latest".to_string(),
+            ],
+            blobs: vec![input.to_string()],
+        };
+        let bundle = staging.export_bundle(&request).await.unwrap();
+
+        let production = storage();
+        let imported = production
+            .import_bundle(Some("sh.serval"), bundle.open().await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            imported.manifests,
+            vec!["sh.serval.loudify@1.0.0", "sh.serval.loudify@1.1.0"]
+        );
+        assert_eq!(imported.blobs, vec![input.to_string()]);
+
+        let latest = production.manifest("sh.serval.loudify").await.unwrap();
+        assert_eq!(latest.version(), "1.1.0");
+        for (version, executable) in [("1.0.0", b"\0asm 1"), ("1.1.0", b"\0asm 2")] {
+            assert_eq!(
+                production
+                    .executable_as_bytes("sh.serval.loudify", version)
+                    .await
+                    .unwrap(),
+                executable
+            );
+        }
+        assert_eq!(
+            production.data_by_integrity(input).await.unwrap(),
+            b"some job input"
+        );
+
+        let missing = BundleRequest {
+            manifests: vec!["sh.serval.birdfeeder".to_string()],
+            blobs: Vec::new(),
+        };
+        assert!(staging.export_bundle(&missing).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stores_nothing_from_a_bundle_that_doesnt_match_its_index() {
+        let staging = storage();
+        staging.store_manifest(&manifest("1.0.0")).await.unwrap();
+        staging
+            .store_executable("sh.serval.loudify", "1.0.0", &upload(b"\0asm").await)
+            .await
+            .unwrap();
+        let input = staging.store_by_integrity(None, b"input").await.unwrap();
+        let request = BundleRequest {
+            manifests: vec!["sh.serval.loudify".to_string()],
+            blobs: vec![input.to_string()],
+        };
+        let bytes = staging
+            .export_bundle(&request)
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+
+        // Change the blob at the end of the bundle.
+        let at = bytes.len() - BLOCK_SIZE * 3;
+        let mut tampered = bytes.clone();
+        tampered[at] ^= 0xff;
+        let production = storage();
+        assert!(matches!(
+            production
+                .import_bundle(None, std::io::Cursor::new(tampered))
+                .await,
+            Err(ServalError::InvalidBundle(_))
+        ));
+        assert!(production.manifest("sh.serval.loudify").await.is_err());
+        assert!(!production.data_exists_by_integrity(&input).await.unwrap());
+
+        // Leave it out altogether.
+        let truncated = bytes[..at - BLOCK_SIZE].to_vec();
+        assert!(matches!(
+            production
+                .import_bundle(None, std::io::Cursor::new(truncated))
+                .await,
+            Err(ServalError::InvalidBundle(_))
+        ));
+
+        // Cut it off early, or damage a header.
+        let truncated = bytes[..BLOCK_SIZE * 3 + 100].to_vec();
+        assert!(matches!(
+            production
+                .import_bundle(None, std::io::Cursor::new(truncated))
+                .await,
+            Err(ServalError::InvalidBundle(_))
+        ));
+        let mut damaged = bytes.clone();
+        damaged[3] = b'X';
+        assert!(matches!(
+            production
+                .import_bundle(None, std::io::Cursor::new(damaged))
+                .await,
+            Err(ServalError::InvalidBundle(_))
+        ));
+
+        let not_a_bundle = archive(&[("something", b"else".to_vec())]).await;
+        assert!(matches!(
+            production
+                .import_bundle(None, std::io::Cursor::new(not_a_bundle))
+                .await,
+            Err(ServalError::InvalidBundle(_))
+        ));
+    }
+}
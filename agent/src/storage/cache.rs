@@ -0,0 +1,668 @@
+//! A read-through cache in front of remote storage backends, so that data we keep fetching from
+//! S3 (an executable we run over and over, say) only crosses the network once.
+//!
+//! Whenever a read has to go to a remote backend, what it fetches is also written to a local
+//! cacache store set aside for the purpose, as it's streamed to the reader. The next read of the
+//! same data is served from there.
+//! The cache only ever holds content-addressed blobs: keyed reads still ask the remote backend which
+//! blob a key points to, which is cheap, and only fetch the blob itself if the cache doesn't have
+//! it. That way a key that changes never serves stale data. The cache is bounded in size, and
+//! evicts the least-recently-used blobs to stay under it.
+
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use bytes::Bytes;
+use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use lru::LruCache;
+use ssri::Integrity;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::sync::Mutex;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::io::StreamReader;
+use tokio_util::sync::PollSender;
+use utils::errors::{ServalError, ServalResult};
+
+use super::quotas::parse_size;
+use super::{BlobStore, ByteRange, SendableStream, Storage, StorageBackend};
+
+/// A size-bounded local cache of blobs fetched from remote backends.
+#[derive(Debug, Clone)]
+pub struct ReadCache {
+    store: Arc<dyn StorageBackend>,
+    capacity: u64,
+    // What the cache holds, least-recently used first, loaded from the store the first time it's
+    // needed.
+    entries: Arc<Mutex<Option<Entries>>>,
+}
+
+#[derive(Debug)]
+struct Entries {
+    // The size of each cached blob, by the string form of its integrity.
+    blobs: LruCache<String, u64>,
+    bytes: u64,
+}
+
+impl ReadCache {
+    /// Cache blobs in the given store, up to `capacity` bytes of them. Anything in the store may be
+    /// evicted, so it must not be one that holds data anywhere else.
+    pub fn new(store: Arc<dyn StorageBackend>, capacity: u64) -> Self {
+        Self {
+            store,
+            capacity,
+            entries: Arc::default(),
+        }
+    }
+
+    /// Read cache settings from the environment. `STORAGE_CACHE_SIZE` turns the cache on and bounds
+    /// it, as a byte count like `2G`; `STORAGE_CACHE_PATH` says where to keep it, and must not be
+    /// the same place as `BLOB_STORE`.
+    pub fn from_env() -> ServalResult<Option<Self>> {
+        let Ok(size) = std::env::var("STORAGE_CACHE_SIZE") else {
+            return Ok(None);
+        };
+        let Some(capacity) = parse_size(size.trim()) else {
+            return Err(ServalError::StorageError(format!(
+                "not a valid storage cache size `{size}`; try something like 2G"
+            )));
+        };
+        let path = std::env::var("STORAGE_CACHE_PATH")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| std::env::temp_dir().join("serval_cache"));
+        let store = BlobStore::new(&path)?;
+        log::info!(
+            "read-through storage cache enabled at {}; capacity={capacity}",
+            path.display()
+        );
+        Ok(Some(Self::new(Arc::new(store), capacity)))
+    }
+
+    // Run something against our record of what's cached, loading it first if we haven't yet.
+    async fn with_entries<T>(&self, op: impl FnOnce(&mut Entries) -> T) -> T {
+        let mut guard = self.entries.lock().await;
+        if guard.is_none() {
+            let mut entries = Entries {
+                blobs: LruCache::unbounded(),
+                bytes: 0,
+            };
+            match self.store.list_blobs().await {
+                Ok(blobs) => {
+                    for blob in blobs {
+                        entries.bytes += blob.size;
+                        entries.blobs.put(blob.integrity.to_string(), blob.size);
+                    }
+                }
+                Err(e) => log::warn!("unable to list cached blobs; {e:?}"),
+            }
+            *guard = Some(entries);
+        }
+        let entries = guard.as_mut().expect("cache entries were just loaded");
+        op(entries)
+    }
+
+    // The store cached blobs are kept in.
+    pub(super) fn store(&self) -> &dyn StorageBackend {
+        self.store.as_ref()
+    }
+
+    /// Stream a blob from the cache, if it holds it.
+    pub async fn stream(&self, integrity: &Integrity) -> Option<SendableStream> {
+        let found = self.store.stream_by_integrity(integrity).await;
+        self.record_lookup(integrity, found).await
+    }
+
+    /// Stream part of a blob from the cache, if it holds it.
+    pub async fn stream_range(
+        &self,
+        integrity: &Integrity,
+        range: ByteRange,
+    ) -> Option<SendableStream> {
+        let found = self.store.stream_range_by_integrity(integrity, range).await;
+        self.record_lookup(integrity, found).await
+    }
+
+    // Count a lookup as a hit or a miss, and mark a blob we found as recently used.
+    async fn record_lookup(
+        &self,
+        integrity: &Integrity,
+        found: ServalResult<SendableStream>,
+    ) -> Option<SendableStream> {
+        match found {
+            Ok(stream) => {
+                metrics::increment_counter!("storage:cache:hit");
+                self.with_entries(|entries| {
+                    entries.blobs.get(&integrity.to_string());
+                })
+                .await;
+                Some(stream)
+            }
+            Err(_) => {
+                metrics::increment_counter!("storage:cache:miss");
+                None
+            }
+        }
+    }
+
+    /// Copy a blob into the cache as it's read from `stream`, which must be the whole of the blob
+    /// with the given integrity and size, and respond with a stream of the same data. The blob is
+    /// only cached if all of it is read and it matches its integrity. Blobs bigger than the whole
+    /// cache are passed straight through.
+    pub fn fill(&self, integrity: &Integrity, size: u64, stream: SendableStream) -> SendableStream {
+        if size > self.capacity {
+            metrics::increment_counter!("storage:cache:too_big");
+            return stream;
+        }
+        let (sender, receiver) = tokio::sync::mpsc::channel(FILL_CHUNKS);
+        let cache = self.clone();
+        let integrity = integrity.clone();
+        tokio::spawn(async move {
+            let copy = StreamReader::new(ReceiverStream::new(receiver));
+            cache.insert(&integrity, size, Box::pin(copy)).await;
+        });
+        Box::pin(Filling {
+            inner: stream,
+            copy: Some(PollSender::new(sender)),
+        })
+    }
+
+    // Add a blob to the cache, evicting the least-recently-used ones to make room.
+    async fn insert(&self, integrity: &Integrity, size: u64, stream: SendableStream) {
+        if let Err(e) = self
+            .store
+            .store_stream_by_integrity(integrity, size, stream)
+            .await
+        {
+            log::warn!("unable to cache blob; integrity={integrity}; {e:?}");
+            return;
+        }
+
+        let evicted = self
+            .with_entries(|entries| {
+                if let Some(previous) = entries.blobs.put(integrity.to_string(), size) {
+                    entries.bytes -= previous;
+                }
+                entries.bytes += size;
+                let mut evicted = Vec::new();
+                while entries.bytes > self.capacity {
+                    let Some((blob, size)) = entries.blobs.pop_lru() else {
+                        break;
+                    };
+                    entries.bytes -= size;
+                    evicted.push(blob);
+                }
+                metrics::gauge!("storage:cache:bytes", entries.bytes as f64);
+                evicted
+            })
+            .await;
+
+        for blob in evicted {
+            let Ok(integrity) = blob.parse::<Integrity>() else {
+                continue;
+            };
+            match self.store.delete_by_integrity(&integrity).await {
+                Ok(_) => metrics::increment_counter!("storage:cache:evicted"),
+                Err(e) => log::warn!("unable to evict cached blob; integrity={blob}; {e:?}"),
+            }
+        }
+    }
+
+    /// Drop a blob from the cache, because it was deleted.
+    pub async fn forget(&self, integrity: &Integrity) {
+        if let Err(e) = self.store.delete_by_integrity(integrity).await {
+            log::warn!("unable to drop cached blob; integrity={integrity}; {e:?}");
+        }
+        self.with_entries(|entries| {
+            if let Some(size) = entries.blobs.pop(&integrity.to_string()) {
+                entries.bytes -= size;
+            }
+        })
+        .await;
+    }
+}
+
+impl Storage {
+    // Read something as a stream from the first backend that has it. Local backends are asked
+    // directly. Before a remote backend is asked for the data, the cache is asked for the blob the
+    // remote backend says it holds, and anything the remote backend sends, along with its size, is
+    // copied into the cache as it's read.
+    async fn read_through<'a>(
+        &'a self,
+        description: &str,
+        local: impl Fn(&'a dyn StorageBackend) -> BoxFuture<'a, ServalResult<SendableStream>>,
+        held: impl Fn(&'a dyn StorageBackend) -> BoxFuture<'a, ServalResult<Integrity>>,
+        remote: impl Fn(&'a dyn StorageBackend) -> BoxFuture<'a, ServalResult<(u64, SendableStream)>>,
+    ) -> Option<SendableStream> {
+        let Some(cache) = &self.cache else {
+            return self.read(description, local).await;
+        };
+
+        for backend in &self.backends {
+            if !backend.is_remote() {
+                match local(backend.as_ref()).await {
+                    Ok(stream) => {
+                        log::info!("serving from {}; {description}", backend.name());
+                        return Some(stream);
+                    }
+                    Err(e) => {
+                        log::info!("error reading {}; {description}; {e:?}", backend.name());
+                        continue;
+                    }
+                }
+            }
+
+            let held = held(backend.as_ref()).await;
+            if let Ok(integrity) = &held {
+                if let Some(stream) = cache.stream(integrity).await {
+                    log::info!("serving from cache; {description}");
+                    return Some(stream);
+                }
+            }
+            match remote(backend.as_ref()).await {
+                Ok((size, stream)) => {
+                    log::info!("serving from {}; {description}", backend.name());
+                    return Some(match &held {
+                        Ok(integrity) => cache.fill(integrity, size, stream),
+                        Err(_) => stream,
+                    });
+                }
+                Err(e) => {
+                    log::info!("error reading {}; {description}; {e:?}", backend.name());
+                }
+            }
+        }
+        None
+    }
+
+    // Stream a blob, through the cache.
+    pub(super) async fn cached_blob_stream(&self, integrity: &Integrity) -> Option<SendableStream> {
+        self.read_through(
+            &integrity.to_string(),
+            |backend| backend.stream_by_integrity(integrity),
+            |_| futures::future::ready(Ok(integrity.clone())).boxed(),
+            |backend| {
+                async move {
+                    let metadata = backend.metadata_by_integrity(integrity).await?;
+                    let stream = backend.stream_by_integrity(integrity).await?;
+                    Ok((metadata.size, stream))
+                }
+                .boxed()
+            },
+        )
+        .await
+    }
+
+    // Stream part of a blob, from the first of our backends that has it, or from the cache in
+    // front of a remote one. Partial reads aren't added to the cache, because that would mean
+    // fetching the whole blob.
+    pub(super) async fn cached_blob_range(
+        &self,
+        integrity: &Integrity,
+        range: ByteRange,
+    ) -> Option<SendableStream> {
+        let description = format!("{integrity}; bytes {}-{}", range.start, range.last());
+        let Some(cache) = &self.cache else {
+            return self
+                .read(&description, |backend| {
+                    backend.stream_range_by_integrity(integrity, range)
+                })
+                .await;
+        };
+
+        for backend in &self.backends {
+            if backend.is_remote() {
+                if let Some(stream) = cache.stream_range(integrity, range).await {
+                    log::info!("serving from cache; {description}");
+                    return Some(stream);
+                }
+            }
+            match backend.stream_range_by_integrity(integrity, range).await {
+                Ok(stream) => {
+                    log::info!("serving from {}; {description}", backend.name());
+                    return Some(stream);
+                }
+                Err(e) => {
+                    log::info!("error reading {}; {description}; {e:?}", backend.name());
+                }
+            }
+        }
+        None
+    }
+
+    // Load a blob into memory, through the cache. Local backends are read into memory too, so that
+    // one whose copy turns out to be corrupt is passed over for the next.
+    pub(super) async fn cached_blob(&self, integrity: &Integrity) -> Option<Vec<u8>> {
+        let stream = self
+            .read_through(
+                &integrity.to_string(),
+                |backend| {
+                    backend
+                        .data_by_integrity(integrity)
+                        .map_ok(in_memory)
+                        .boxed()
+                },
+                |_| futures::future::ready(Ok(integrity.clone())).boxed(),
+                |backend| {
+                    backend
+                        .data_by_integrity(integrity)
+                        .map_ok(sized_in_memory)
+                        .boxed()
+                },
+            )
+            .await?;
+        read_all(stream).await
+    }
+
+    // Stream the data stored under a key, through the cache.
+    pub(super) async fn cached_key_stream(
+        &self,
+        key: &str,
+        description: &str,
+    ) -> Option<SendableStream> {
+        self.read_through(
+            description,
+            |backend| backend.stream_by_key(key),
+            |backend| {
+                backend
+                    .metadata_by_key(key)
+                    .map_ok(|metadata| metadata.integrity)
+                    .boxed()
+            },
+            |backend| {
+                async move {
+                    let metadata = backend.metadata_by_key(key).await?;
+                    let stream = backend.stream_by_key(key).await?;
+                    Ok((metadata.size, stream))
+                }
+                .boxed()
+            },
+        )
+        .await
+    }
+
+    // Load the data stored under a key into memory, through the cache, passing over corrupt copies
+    // like cached_blob().
+    pub(super) async fn cached_key(&self, key: &str, description: &str) -> Option<Vec<u8>> {
+        let stream = self
+            .read_through(
+                description,
+                |backend| backend.data_by_key(key).map_ok(in_memory).boxed(),
+                |backend| {
+                    backend
+                        .metadata_by_key(key)
+                        .map_ok(|metadata| metadata.integrity)
+                        .boxed()
+                },
+                |backend| backend.data_by_key(key).map_ok(sized_in_memory).boxed(),
+            )
+            .await?;
+        read_all(stream).await
+    }
+
+    // Drop a deleted blob from the cache, if we have one.
+    pub(super) async fn uncache(&self, integrity: &Integrity) {
+        if let Some(cache) = &self.cache {
+            cache.forget(integrity).await;
+        }
+    }
+}
+
+fn in_memory(bytes: Vec<u8>) -> SendableStream {
+    Box::pin(Cursor::new(bytes))
+}
+
+fn sized_in_memory(bytes: Vec<u8>) -> (u64, SendableStream) {
+    (bytes.len() as u64, in_memory(bytes))
+}
+
+// How many chunks of a blob being read can be on their way into the cache at once.
+const FILL_CHUNKS: usize = 16;
+
+// A stream of a blob from a remote backend that copies what's read from it into the cache.
+struct Filling {
+    inner: SendableStream,
+    // Where the copy goes, until the blob ends or the cache stops taking it.
+    copy: Option<PollSender<std::io::Result<Bytes>>>,
+}
+
+impl AsyncRead for Filling {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        // Make room for the copy before reading, so that nothing is read that can't be copied.
+        // This holds the reader to the pace of the cache, which is local and quick.
+        if let Some(copy) = this.copy.as_mut() {
+            if ready!(copy.poll_reserve(cx)).is_err() {
+                this.copy = None;
+            }
+        }
+
+        let before = buf.filled().len();
+        let result = ready!(this.inner.as_mut().poll_read(cx, buf));
+        if let Some(mut copy) = this.copy.take() {
+            let read = &buf.filled()[before..];
+            // Dropping the sender at the end of the blob ends the copy too. An error is passed
+            // along so that the cache throws away what it has.
+            let chunk = match &result {
+                Ok(()) if read.is_empty() => None,
+                Ok(()) => Some(Ok(Bytes::copy_from_slice(read))),
+                Err(e) => Some(Err(std::io::Error::new(e.kind(), e.to_string()))),
+            };
+            if let Some(chunk) = chunk {
+                let failed = chunk.is_err();
+                if copy.send_item(chunk).is_ok() && !failed {
+                    this.copy = Some(copy);
+                }
+            }
+        }
+        Poll::Ready(result)
+    }
+}
+
+async fn read_all(mut stream: SendableStream) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match stream.read_to_end(&mut bytes).await {
+        Ok(_) => Some(bytes),
+        Err(e) => {
+            log::warn!("error reading stored data; {e:?}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::storage::{BlobMetadata, MemoryStorage, ReadPolicy, WritePolicy};
+
+    // A memory store that claims to be remote, so that reads from it are cached.
+    #[derive(Debug, Default)]
+    struct RemoteStorage(MemoryStorage);
+
+    #[async_trait]
+    impl StorageBackend for RemoteStorage {
+        fn name(&self) -> &'static str {
+            "remote"
+        }
+
+        fn is_remote(&self) -> bool {
+            true
+        }
+
+        async fn store_by_integrity(&self, bytes: &[u8]) -> ServalResult<Integrity> {
+            self.0.store_by_integrity(bytes).await
+        }
+
+        async fn stream_by_integrity(&self, integrity: &Integrity) -> ServalResult<SendableStream> {
+            self.0.stream_by_integrity(integrity).await
+        }
+
+        async fn data_exists_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
+            self.0.data_exists_by_integrity(integrity).await
+        }
+
+        async fn store_by_key(&self, key: &str, bytes: &[u8]) -> ServalResult<Integrity> {
+            self.0.store_by_key(key, bytes).await
+        }
+
+        async fn stream_by_key(&self, key: &str) -> ServalResult<SendableStream> {
+            self.0.stream_by_key(key).await
+        }
+
+        async fn data_exists_by_key(&self, key: &str) -> ServalResult<bool> {
+            self.0.data_exists_by_key(key).await
+        }
+
+        async fn list_keys(&self, prefix: &str) -> ServalResult<Vec<String>> {
+            self.0.list_keys(prefix).await
+        }
+
+        async fn delete_by_key(&self, key: &str) -> ServalResult<bool> {
+            self.0.delete_by_key(key).await
+        }
+
+        async fn delete_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
+            self.0.delete_by_integrity(integrity).await
+        }
+
+        async fn list_blobs(&self) -> ServalResult<Vec<BlobMetadata>> {
+            self.0.list_blobs().await
+        }
+    }
+
+    // Copies into the cache are made in the background as blobs are read, so they land a moment
+    // after the read that made them. Wait for the cache to hold the blobs it should, and none of
+    // the ones it shouldn't.
+    async fn settle(cached: &MemoryStorage, present: &[&Integrity], absent: &[&Integrity]) {
+        for _ in 0..100 {
+            let mut settled = true;
+            for integrity in present {
+                settled &= cached.data_exists_by_integrity(integrity).await.unwrap();
+            }
+            for integrity in absent {
+                settled &= !cached.data_exists_by_integrity(integrity).await.unwrap();
+            }
+            if settled {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("the cache never settled");
+    }
+
+    #[tokio::test]
+    async fn caches_remote_reads_and_evicts_the_least_recently_used() {
+        let remote = Arc::new(RemoteStorage::default());
+        let cached = Arc::new(MemoryStorage::new());
+        let backends: Vec<Arc<dyn StorageBackend>> = vec![remote.clone()];
+        let storage = Storage::new(backends, ReadPolicy::FirstHit, WritePolicy::Any)
+            .with_cache(ReadCache::new(cached.clone(), 10));
+
+        let first = remote.store_by_integrity(b"aaaa").await.unwrap();
+        let second = remote.store_by_integrity(b"bbbb").await.unwrap();
+        let third = remote.store_by_integrity(b"cccc").await.unwrap();
+
+        assert_eq!(storage.cached_blob(&first).await.unwrap(), b"aaaa");
+        settle(&cached, &[&first], &[]).await;
+        assert_eq!(storage.cached_blob(&second).await.unwrap(), b"bbbb");
+        settle(&cached, &[&first, &second], &[]).await;
+        // Reading the first blob again makes the second the least recently used, so it's the one
+        // that makes room for the third.
+        assert_eq!(storage.cached_blob(&first).await.unwrap(), b"aaaa");
+        assert_eq!(storage.cached_blob(&third).await.unwrap(), b"cccc");
+        settle(&cached, &[&first, &third], &[&second]).await;
+
+        // The cache serves a blob even after the remote loses it, until it's deleted through us.
+        remote.delete_by_integrity(&first).await.unwrap();
+        assert_eq!(storage.cached_blob(&first).await.unwrap(), b"aaaa");
+        storage.uncache(&first).await;
+        assert!(storage.cached_blob(&first).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn keyed_reads_follow_the_remote_key() {
+        let remote = Arc::new(RemoteStorage::default());
+        let cached = Arc::new(MemoryStorage::new());
+        let backends: Vec<Arc<dyn StorageBackend>> = vec![remote.clone()];
+        let storage = Storage::new(backends, ReadPolicy::FirstHit, WritePolicy::Any)
+            .with_cache(ReadCache::new(cached.clone(), 1024));
+
+        remote
+            .store_by_key("sh.serval.loudify.1.0.0.wasm", b"old")
+            .await
+            .unwrap();
+        let old = storage
+            .cached_key("sh.serval.loudify.1.0.0.wasm", "executable")
+            .await
+            .unwrap();
+        assert_eq!(old, b"old");
+        settle(&cached, &[&Integrity::from(b"old")], &[]).await;
+
+        // Storing over the key elsewhere means the cached copy of the old blob no longer applies.
+        remote
+            .store_by_key("sh.serval.loudify.1.0.0.wasm", b"new")
+            .await
+            .unwrap();
+        let new = storage
+            .cached_key("sh.serval.loudify.1.0.0.wasm", "executable")
+            .await
+            .unwrap();
+        assert_eq!(new, b"new");
+    }
+
+    #[tokio::test]
+    async fn caches_streamed_reads_as_they_go() {
+        let remote = Arc::new(RemoteStorage::default());
+        let cached = Arc::new(MemoryStorage::new());
+        let backends: Vec<Arc<dyn StorageBackend>> = vec![remote.clone()];
+        let storage = Storage::new(backends, ReadPolicy::FirstHit, WritePolicy::Any)
+            .with_cache(ReadCache::new(cached.clone(), 1024 * 1024));
+        let data: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+        let integrity = remote.store_by_integrity(&data).await.unwrap();
+
+        // A reader that gives up partway doesn't leave part of the blob in the cache.
+        let mut stream = storage.cached_blob_stream(&integrity).await.unwrap();
+        let mut start = vec![0; 1000];
+        stream.read_exact(&mut start).await.unwrap();
+        drop(stream);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!cached.data_exists_by_integrity(&integrity).await.unwrap());
+
+        let stream = storage.cached_blob_stream(&integrity).await.unwrap();
+        assert_eq!(read_all(stream).await.unwrap(), data);
+        settle(&cached, &[&integrity], &[]).await;
+
+        // From then on, the remote isn't needed.
+        remote.delete_by_integrity(&integrity).await.unwrap();
+        let stream = storage.cached_blob_stream(&integrity).await.unwrap();
+        assert_eq!(read_all(stream).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn streams_blobs_too_big_to_cache_straight_through() {
+        let remote = Arc::new(RemoteStorage::default());
+        let cached = Arc::new(MemoryStorage::new());
+        let backends: Vec<Arc<dyn StorageBackend>> = vec![remote.clone()];
+        let storage = Storage::new(backends, ReadPolicy::FirstHit, WritePolicy::Any)
+            .with_cache(ReadCache::new(cached.clone(), 10));
+        let integrity = remote
+            .store_by_integrity(b"much too big to cache")
+            .await
+            .unwrap();
+
+        let stream = storage.cached_blob_stream(&integrity).await.unwrap();
+        assert_eq!(read_all(stream).await.unwrap(), b"much too big to cache");
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(cached.list_blobs().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,409 @@
+//! Compression of stored blobs at rest. Wasm executables and most job inputs compress well, so the
+//! backends that hold a lot of data can be told to keep what they store compressed with zstd.
+//!
+//! A compressed blob keeps the content address of its uncompressed data, so turning compression on
+//! or off changes no addresses: reads decompress, and blobs stored either way stay readable. Clients
+//! that accept zstd can be sent a compressed blob just as it's stored.
+
+use std::io::{ErrorKind, Write};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use async_compression::Level;
+use serde::Serialize;
+use ssri::{Integrity, IntegrityChecker};
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader, ReadBuf};
+use utils::errors::{ServalError, ServalResult};
+
+use super::backend::{verify_stream_with, Check, EncodedStream};
+use super::{SendableStream, Storage};
+
+/// The content coding of zstd-compressed data, as both HTTP and S3 name it.
+pub const ZSTD: &str = "zstd";
+
+// zstd's own default, which compresses about as well as gzip does, and much faster.
+const DEFAULT_LEVEL: u32 = 3;
+const MAX_LEVEL: u32 = 22;
+
+/// How a backend compresses what it stores.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum Compression {
+    /// Store data as it is.
+    #[default]
+    None,
+    /// Compress data with zstd at the given level, from 1 (fastest) to 22 (smallest).
+    Zstd { level: u32 },
+}
+
+impl FromStr for Compression {
+    type Err = ServalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            ServalError::StorageError(format!(
+                "not a valid compression `{s}`; try none, zstd, or zstd:<level from 1 to 22>"
+            ))
+        };
+        match s.trim().split_once(':') {
+            None if s.trim() == "none" => Ok(Compression::None),
+            None if s.trim() == ZSTD => Ok(Compression::Zstd {
+                level: DEFAULT_LEVEL,
+            }),
+            Some((ZSTD, level)) => match level.trim().parse() {
+                Ok(level) if (1..=MAX_LEVEL).contains(&level) => Ok(Compression::Zstd { level }),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Compression {
+    /// Read a backend's compression from the given environment variable. Unset means none.
+    pub fn from_env(variable: &str) -> ServalResult<Self> {
+        match std::env::var(variable) {
+            Ok(compression) => compression.parse(),
+            Err(_) => Ok(Compression::None),
+        }
+    }
+
+    /// The content coding this compresses data with, if it compresses it at all.
+    pub fn encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd { .. } => Some(ZSTD),
+        }
+    }
+
+    /// Compress a stream as it's read. Without compression, the stream is handed back as it is.
+    pub(crate) fn encode(&self, stream: SendableStream) -> SendableStream {
+        match self {
+            Compression::None => stream,
+            Compression::Zstd { level } => Box::pin(ZstdEncoder::with_quality(
+                BufReader::new(stream),
+                Level::Precise(*level),
+            )),
+        }
+    }
+}
+
+/// Decompress a stream compressed with the given content coding as it's read. Data that won't
+/// decompress fails the read with an `InvalidData` error, just as data that doesn't match its
+/// integrity does.
+pub(crate) fn decode(encoding: &str, stream: SendableStream) -> ServalResult<SendableStream> {
+    match encoding {
+        ZSTD => Ok(Box::pin(Decoded(Box::pin(ZstdDecoder::new(
+            BufReader::new(Source(stream)),
+        ))))),
+        _ => Err(unknown_encoding(encoding)),
+    }
+}
+
+/// Decompress data in memory.
+pub(crate) async fn decode_bytes(encoding: &str, bytes: Vec<u8>) -> ServalResult<Vec<u8>> {
+    let mut decoded = Vec::new();
+    decode(encoding, Box::pin(std::io::Cursor::new(bytes)))?
+        .read_to_end(&mut decoded)
+        .await?;
+    Ok(decoded)
+}
+
+/// Check a stream of compressed data against the integrity of what it decompresses to as it's
+/// read, passing it on still compressed.
+pub(crate) fn verify_encoded_stream(
+    integrity: &Integrity,
+    encoding: &str,
+    stream: SendableStream,
+) -> ServalResult<SendableStream> {
+    match encoding {
+        ZSTD => {
+            let checker = CheckedOutput(IntegrityChecker::new(integrity.clone()));
+            let decoder = zstd::stream::write::Decoder::new(checker)?;
+            let check = DecodingCheck {
+                decoder: Some(decoder),
+            };
+            Ok(verify_stream_with(integrity, stream, Box::new(check)))
+        }
+        _ => Err(unknown_encoding(encoding)),
+    }
+}
+
+/// The content coding with the given name, if it's one we know.
+pub(crate) fn known_encoding(encoding: &str) -> ServalResult<&'static str> {
+    match encoding {
+        ZSTD => Ok(ZSTD),
+        _ => Err(unknown_encoding(encoding)),
+    }
+}
+
+fn unknown_encoding(encoding: &str) -> ServalError {
+    ServalError::StorageError(format!("unknown content encoding `{encoding}`"))
+}
+
+// zstd reports data it can't decompress, and data that stops partway through a frame, as plain
+// I/O errors. Either way, the data is corrupt. Errors reading the compressed data itself, say from
+// the network, are tagged by `Source` on the way in and passed on as they were.
+struct Decoded(SendableStream);
+
+impl AsyncRead for Decoded {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match ready!(self.0.as_mut().poll_read(cx, buf)) {
+            Err(e) if e.get_ref().is_some_and(|inner| inner.is::<SourceError>()) => {
+                let source = e.into_inner().unwrap().downcast::<SourceError>().unwrap();
+                Poll::Ready(Err(source.0))
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::Other | ErrorKind::UnexpectedEof) => {
+                Poll::Ready(Err(std::io::Error::new(ErrorKind::InvalidData, e)))
+            }
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+// The compressed data going into the decoder, with its errors tagged so they aren't mistaken for
+// zstd's own.
+struct Source(SendableStream);
+
+#[derive(Debug)]
+struct SourceError(std::io::Error);
+
+impl std::fmt::Display for SourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+impl AsyncRead for Source {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match ready!(self.0.as_mut().poll_read(cx, buf)) {
+            Err(e) => Poll::Ready(Err(std::io::Error::new(e.kind(), SourceError(e)))),
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+// Checks compressed data by decompressing it into an integrity checker.
+struct DecodingCheck {
+    // Dropped if the data won't decompress.
+    decoder: Option<zstd::stream::write::Decoder<'static, CheckedOutput>>,
+}
+
+struct CheckedOutput(IntegrityChecker);
+
+impl Write for CheckedOutput {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.0.input(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Check for DecodingCheck {
+    fn input(&mut self, data: &[u8]) {
+        if let Some(decoder) = self.decoder.as_mut() {
+            if decoder.write_all(data).is_err() {
+                self.decoder = None;
+            }
+        }
+    }
+
+    fn matches(self: Box<Self>) -> bool {
+        match self.decoder {
+            Some(mut decoder) => decoder.flush().is_ok() && decoder.into_inner().0.result().is_ok(),
+            None => false,
+        }
+    }
+}
+
+impl Storage {
+    /// Fetch the blob with the given integrity compressed, just as it's stored, if the backend
+    /// that would serve it keeps it that way. None means it should be fetched the usual way.
+    pub async fn encoded_stream_by_integrity(
+        &self,
+        integrity: &Integrity,
+    ) -> Option<EncodedStream> {
+        for backend in &self.backends {
+            // Reads from remote backends go through the read cache, which keeps data uncompressed.
+            if backend.is_remote() && self.cache.is_some() {
+                return None;
+            }
+            match backend.encoded_stream_by_integrity(integrity).await {
+                Ok(encoded) => return encoded,
+                Err(e) => {
+                    log::info!(
+                        "error reading {}; integrity={integrity}; {e:?}",
+                        backend.name()
+                    );
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::backend::{is_integrity_mismatch, verify_stream};
+    use crate::storage::{BlobStore, StorageBackend};
+
+    #[test]
+    fn parses_compression() {
+        assert_eq!("none".parse::<Compression>().unwrap(), Compression::None);
+        assert_eq!(
+            "zstd".parse::<Compression>().unwrap(),
+            Compression::Zstd {
+                level: DEFAULT_LEVEL
+            }
+        );
+        assert_eq!(
+            " zstd:19 ".parse::<Compression>().unwrap(),
+            Compression::Zstd { level: 19 }
+        );
+        assert!("zstd:0".parse::<Compression>().is_err());
+        assert!("zstd:23".parse::<Compression>().is_err());
+        assert!("gzip".parse::<Compression>().is_err());
+    }
+
+    #[tokio::test]
+    async fn stores_blobs_compressed_under_their_own_integrity() {
+        let root =
+            std::env::temp_dir().join(format!("serval-compression-{}", uuid::Uuid::new_v4()));
+        let store = BlobStore::new(&root)
+            .unwrap()
+            .with_compression(Compression::Zstd { level: 3 });
+        let data = b"the same few bytes, over and over. ".repeat(1000);
+
+        let integrity = store.store_by_integrity(&data).await.unwrap();
+        assert_eq!(integrity, Integrity::from(&data));
+        assert_eq!(store.data_by_integrity(&integrity).await.unwrap(), data);
+        assert_eq!(
+            store.metadata_by_integrity(&integrity).await.unwrap().size,
+            data.len() as u64
+        );
+        let blobs = store.list_blobs().await.unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].integrity, integrity);
+        assert!(blobs[0].size < data.len() as u64 / 10);
+
+        // Clients that take zstd get the blob as it's stored, and can decompress it themselves.
+        let encoded = store
+            .encoded_stream_by_integrity(&integrity)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(encoded.encoding, ZSTD);
+        assert_eq!(encoded.size, blobs[0].size);
+        let mut compressed = Vec::new();
+        let mut stream = encoded.stream;
+        stream.read_to_end(&mut compressed).await.unwrap();
+        assert_eq!(decode_bytes(ZSTD, compressed).await.unwrap(), data);
+
+        // Blobs stored uncompressed, before compression was turned on, are still there, and keys
+        // work the same either way.
+        let plain = BlobStore::new(&root).unwrap();
+        let old = plain.store_by_key("old", b"stored as it is").await.unwrap();
+        let new = store
+            .store_by_key("new", b"stored compressed")
+            .await
+            .unwrap();
+        assert_eq!(store.data_by_key("old").await.unwrap(), b"stored as it is");
+        assert_eq!(
+            plain.data_by_key("new").await.unwrap(),
+            b"stored compressed"
+        );
+        assert!(store
+            .encoded_stream_by_integrity(&old)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store.data_exists_by_key("new").await.unwrap());
+        assert_eq!(
+            store.list_keys("").await.unwrap().len(),
+            2,
+            "the index of compressed blobs isn't made of keys"
+        );
+
+        assert!(store.delete_by_integrity(&new).await.unwrap());
+        assert!(!store.data_exists_by_integrity(&new).await.unwrap());
+        assert!(!store.delete_by_integrity(&new).await.unwrap());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn catches_corrupt_compressed_data() {
+        let data = b"the same few bytes, over and over. ".repeat(1000);
+        let integrity = Integrity::from(&data);
+        let compression = Compression::Zstd { level: 3 };
+        let mut compressed = Vec::new();
+        compression
+            .encode(Box::pin(std::io::Cursor::new(data.clone())))
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+
+        let mut corrupt = compressed.clone();
+        let middle = corrupt.len() / 2;
+        corrupt[middle] ^= 0xff;
+        // Corrupt data may not decompress at all, or may decompress to the wrong thing.
+        let decoded = decode(ZSTD, Box::pin(std::io::Cursor::new(corrupt.clone()))).unwrap();
+        let mut stream = verify_stream(&integrity, decoded);
+        let e = stream.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert!(is_integrity_mismatch(&ServalError::IoError(e)));
+
+        let read = |bytes: Vec<u8>| async {
+            let mut stream =
+                verify_encoded_stream(&integrity, ZSTD, Box::pin(std::io::Cursor::new(bytes)))
+                    .unwrap();
+            let mut passed = Vec::new();
+            stream.read_to_end(&mut passed).await.map(|_| passed)
+        };
+        assert_eq!(read(compressed.clone()).await.unwrap(), compressed);
+        assert!(read(corrupt).await.is_err());
+        assert!(read(compressed[..middle].to_vec()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn passes_on_errors_reading_compressed_data() {
+        struct Failing;
+        impl AsyncRead for Failing {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                _buf: &mut ReadBuf<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Err(std::io::Error::other("connection reset")))
+            }
+        }
+
+        // A failed read from the backend isn't corrupt data, and mustn't be repaired as if it were.
+        let e = decode(ZSTD, Box::pin(Failing))
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Other);
+        assert_eq!(e.to_string(), "connection reset");
+        assert!(!is_integrity_mismatch(&ServalError::IoError(e)));
+
+        let e = decode_bytes(ZSTD, b"not zstd at all".to_vec())
+            .await
+            .unwrap_err();
+        assert!(is_integrity_mismatch(&e));
+    }
+}
@@ -0,0 +1,207 @@
+//! Descriptions of blobs: what each blob in the content-addressable store is, such as its content
+//! type, the file it came from, who stored it, and labels to find it by. The store itself only
+//! knows blobs by their integrity, which says nothing about what they are.
+//!
+//! Each backend keeps descriptions alongside the blobs they describe, however suits it: cacache in
+//! the metadata of an index entry, and S3 in an object of its own next to the blob's, with an index
+//! of labels beside it. A description goes wherever the blob is, and goes away when the blob is
+//! deleted. Peers' replicas aren't described.
+
+use std::collections::BTreeMap;
+
+use ssri::Integrity;
+use utils::errors::{ServalError, ServalResult};
+use utils::structs::api::{BlobInfo, DescribedBlob};
+
+use super::{make_proxy_client, Storage, WritePolicy};
+
+impl Storage {
+    /// Record what a blob in the content-addressable store is, replacing whatever was recorded about
+    /// it before. An empty description forgets it.
+    pub async fn describe_blob(&self, integrity: &Integrity, info: &BlobInfo) -> ServalResult<()> {
+        info.validate()?;
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.describe_blob(&integrity.to_string(), info).await;
+        }
+
+        metrics::increment_counter!("storage:blobs:describe");
+        let mut described = false;
+        let mut failures: Vec<String> = Vec::new();
+        for backend in &self.backends {
+            if !backend.data_exists_by_integrity(integrity).await? {
+                continue;
+            }
+            match backend.store_blob_info(integrity, info).await {
+                Ok(()) => described = true,
+                Err(e) => {
+                    log::warn!(
+                        "error describing blob in {}; integrity={integrity}; {e:?}",
+                        backend.name()
+                    );
+                    failures.push(format!("{}: {e}", backend.name()));
+                }
+            }
+        }
+
+        match (self.write_policy, described) {
+            (_, false) if failures.is_empty() => {
+                Err(ServalError::DataNotFound(integrity.to_string()))
+            }
+            (WritePolicy::Any, true) => Ok(()),
+            (WritePolicy::All, true) if failures.is_empty() => Ok(()),
+            _ => Err(ServalError::StorageError(format!(
+                "unable to describe blob {integrity}; {}",
+                failures.join("; ")
+            ))),
+        }
+    }
+
+    /// What's recorded about a blob in the content-addressable store. The description is empty if
+    /// nothing is.
+    pub async fn blob_info(&self, integrity: &Integrity) -> ServalResult<BlobInfo> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.blob_info(&integrity.to_string()).await;
+        }
+
+        for backend in &self.backends {
+            match backend.blob_info(integrity).await {
+                Ok(Some(info)) => return Ok(info),
+                Ok(None) | Err(ServalError::DataNotFound(_)) => {}
+                Err(e) => {
+                    log::info!(
+                        "error reading blob description from {}; integrity={integrity}; {e:?}",
+                        backend.name()
+                    );
+                }
+            }
+        }
+        Ok(BlobInfo::default())
+    }
+
+    /// List the blobs in the content-addressable store that have descriptions, ordered by
+    /// integrity. Pass a label, as `key` or `key=value`, to list only the blobs that have it.
+    pub async fn described_blobs(&self, label: Option<&str>) -> ServalResult<Vec<DescribedBlob>> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.described_blobs(label).await;
+        }
+
+        metrics::increment_counter!("storage:blobs:list");
+        let mut described: BTreeMap<String, BlobInfo> = BTreeMap::new();
+        let mut answered = false;
+        for backend in &self.backends {
+            match backend.list_blob_info(label).await {
+                Ok(listing) => {
+                    answered = true;
+                    for (integrity, info) in listing {
+                        described.entry(integrity.to_string()).or_insert(info);
+                    }
+                }
+                Err(e) => {
+                    log::warn!(
+                        "error listing blob descriptions in {}; {e:?}",
+                        backend.name()
+                    );
+                }
+            }
+        }
+        if !answered {
+            return Err(ServalError::StorageError(
+                "unable to list blob descriptions".to_string(),
+            ));
+        }
+
+        Ok(described
+            .into_iter()
+            .filter(|(_, info)| label.is_none_or(|label| info.has_label(label)))
+            .map(|(integrity, info)| DescribedBlob { integrity, info })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::{BlobStore, MemoryStorage, ReadPolicy, StorageBackend};
+
+    fn info(filename: &str, kind: &str) -> BlobInfo {
+        BlobInfo {
+            content_type: Some("text/plain".to_string()),
+            filename: Some(filename.to_string()),
+            creator: Some("sh.serval.loudify@1.0.0".to_string()),
+            labels: BTreeMap::from([("kind".to_string(), kind.to_string())]),
+        }
+    }
+
+    #[tokio::test]
+    async fn describes_blobs_and_finds_them_by_label() {
+        let root =
+            std::env::temp_dir().join(format!("serval-descriptions-{}", uuid::Uuid::new_v4()));
+        let backends: Vec<Arc<dyn StorageBackend>> = vec![
+            Arc::new(BlobStore::new(&root).unwrap()),
+            Arc::new(MemoryStorage::new()),
+        ];
+        let storage = Storage::new(backends, ReadPolicy::FirstHit, WritePolicy::All);
+
+        let input = storage.store_by_integrity(None, b"input").await.unwrap();
+        let output = storage.store_by_integrity(None, b"output").await.unwrap();
+        let other = storage.store_by_integrity(None, b"other").await.unwrap();
+        assert!(storage.blob_info(&input).await.unwrap().is_empty());
+
+        storage
+            .describe_blob(&input, &info("in.txt", "input"))
+            .await
+            .unwrap();
+        storage
+            .describe_blob(&output, &info("out.txt", "output"))
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.blob_info(&output).await.unwrap(),
+            info("out.txt", "output")
+        );
+
+        let outputs = storage.described_blobs(Some("kind=output")).await.unwrap();
+        assert_eq!(
+            outputs,
+            vec![DescribedBlob {
+                integrity: output.to_string(),
+                info: info("out.txt", "output"),
+            }]
+        );
+        let labeled = storage.described_blobs(Some("kind")).await.unwrap();
+        assert_eq!(labeled.len(), 2);
+        assert!(!labeled
+            .iter()
+            .any(|blob| blob.integrity == other.to_string()));
+
+        // Descriptions don't keep their blobs from being collected, and go with them.
+        let collected = storage.collect_garbage(false).await.unwrap();
+        for integrity in [&input, &output, &other] {
+            assert!(collected
+                .removed
+                .iter()
+                .any(|blob| blob.integrity == integrity.to_string()));
+        }
+        assert!(storage.described_blobs(None).await.unwrap().is_empty());
+        assert!(matches!(
+            storage
+                .describe_blob(&input, &info("in.txt", "input"))
+                .await,
+            Err(ServalError::DataNotFound(_))
+        ));
+
+        let unprintable = BlobInfo {
+            filename: Some("line\nbreak".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            storage.describe_blob(&other, &unprintable).await,
+            Err(ServalError::InvalidBlobInfo(_))
+        ));
+    }
+}
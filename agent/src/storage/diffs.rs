@@ -0,0 +1,285 @@
+//! Patches between stored blobs, so that a client holding one version of something (an executable,
+//! say) can fetch just what changed in the next one instead of the whole thing.
+//!
+//! Patches are bsdiff patches, the format the CAS PATCH endpoint applies. Making one is slow and
+//! needs both blobs in memory, so blobs over a size limit are refused, and requests for the same
+//! patch while it's being made wait for it rather than making it again. Blobs never change, so
+//! neither does the patch between two of them: each patch is kept, by the pair of integrities it
+//! joins, in a size-bounded cache in memory.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use lru::LruCache;
+use ssri::Integrity;
+use tokio::sync::{Mutex, OnceCell};
+use utils::diffs::make_patch;
+use utils::errors::{ServalError, ServalResult};
+
+use super::quotas::parse_size;
+use super::{make_proxy_client, Storage};
+
+// How much memory the cache of patches may use unless `STORAGE_DIFF_CACHE_SIZE` says otherwise.
+const DEFAULT_CAPACITY: u64 = 64 * 1024 * 1024;
+
+// The biggest blob we'll make a patch from or to unless `STORAGE_DIFF_MAX_BLOB_SIZE` says otherwise.
+const DEFAULT_MAX_BLOB_SIZE: u64 = 64 * 1024 * 1024;
+
+// A patch that's being made, which everyone asking for it shares.
+type PendingPatch = Arc<OnceCell<Arc<Vec<u8>>>>;
+
+/// A size-bounded cache of patches between blobs, evicting the least-recently-used ones first.
+#[derive(Debug, Clone)]
+pub struct DiffCache {
+    capacity: u64,
+    max_blob_size: u64,
+    entries: Arc<Mutex<Entries>>,
+    pending: Arc<Mutex<HashMap<(String, String), PendingPatch>>>,
+}
+
+#[derive(Debug)]
+struct Entries {
+    // Each patch, by the string forms of the integrities it goes from and to.
+    patches: LruCache<(String, String), Arc<Vec<u8>>>,
+    bytes: u64,
+}
+
+impl DiffCache {
+    /// Cache up to `capacity` bytes of patches. A capacity of zero caches nothing.
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            max_blob_size: DEFAULT_MAX_BLOB_SIZE,
+            entries: Arc::new(Mutex::new(Entries {
+                patches: LruCache::unbounded(),
+                bytes: 0,
+            })),
+            pending: Arc::default(),
+        }
+    }
+
+    /// Refuse to make patches from or to blobs bigger than this many bytes.
+    pub fn with_max_blob_size(mut self, max_blob_size: u64) -> Self {
+        self.max_blob_size = max_blob_size;
+        self
+    }
+
+    /// Read the cache's size from `STORAGE_DIFF_CACHE_SIZE`, and the biggest blob to make patches
+    /// between from `STORAGE_DIFF_MAX_BLOB_SIZE`, both as byte counts like `64M`.
+    pub fn from_env() -> ServalResult<Self> {
+        let size_from_env = |var: &str, default: u64| match std::env::var(var) {
+            Ok(size) => parse_size(size.trim()).ok_or_else(|| {
+                ServalError::StorageError(format!(
+                    "not a valid size for {var} `{size}`; try something like 64M"
+                ))
+            }),
+            Err(_) => Ok(default),
+        };
+        Ok(
+            Self::new(size_from_env("STORAGE_DIFF_CACHE_SIZE", DEFAULT_CAPACITY)?)
+                .with_max_blob_size(size_from_env(
+                    "STORAGE_DIFF_MAX_BLOB_SIZE",
+                    DEFAULT_MAX_BLOB_SIZE,
+                )?),
+        )
+    }
+
+    /// The cached patch from one blob to another, if there is one.
+    pub async fn get(&self, from: &Integrity, to: &Integrity) -> Option<Arc<Vec<u8>>> {
+        let key = (from.to_string(), to.to_string());
+        let found = self.entries.lock().await.patches.get(&key).cloned();
+        match found {
+            Some(_) => metrics::increment_counter!("storage:diff:cache:hit"),
+            None => metrics::increment_counter!("storage:diff:cache:miss"),
+        }
+        found
+    }
+
+    /// Remember the patch from one blob to another, evicting others to make room. Patches bigger
+    /// than the whole cache aren't kept.
+    pub async fn insert(&self, from: &Integrity, to: &Integrity, patch: Arc<Vec<u8>>) {
+        let size = patch.len() as u64;
+        if size > self.capacity {
+            return;
+        }
+        let mut entries = self.entries.lock().await;
+        if let Some(previous) = entries
+            .patches
+            .put((from.to_string(), to.to_string()), patch)
+        {
+            entries.bytes -= previous.len() as u64;
+        }
+        entries.bytes += size;
+        while entries.bytes > self.capacity {
+            let Some((_, evicted)) = entries.patches.pop_lru() else {
+                break;
+            };
+            entries.bytes -= evicted.len() as u64;
+        }
+    }
+}
+
+impl DiffCache {
+    // The patch from one blob to another that's being made, or somewhere to make it.
+    async fn pending(&self, from: &Integrity, to: &Integrity) -> PendingPatch {
+        let key = (from.to_string(), to.to_string());
+        self.pending.lock().await.entry(key).or_default().clone()
+    }
+
+    // Stop sharing a patch once it's made, or once making it failed, so that the next request tries
+    // again.
+    async fn finished(&self, from: &Integrity, to: &Integrity, patch: &PendingPatch) {
+        let key = (from.to_string(), to.to_string());
+        let mut pending = self.pending.lock().await;
+        if pending
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, patch))
+        {
+            pending.remove(&key);
+        }
+    }
+}
+
+impl Default for DiffCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl Storage {
+    /// Make a bsdiff patch that turns the blob `from` into the blob `to`, or fetch it from the
+    /// cache if we've made it before. Blobs over the size limit are refused.
+    pub async fn diff_between(&self, from: &Integrity, to: &Integrity) -> ServalResult<Vec<u8>> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.diff_between(&from.to_string(), &to.to_string()).await;
+        }
+
+        if let Some(patch) = self.diffs.get(from, to).await {
+            return Ok(patch.to_vec());
+        }
+        let pending = self.diffs.pending(from, to).await;
+        let patch = pending
+            .get_or_try_init(|| self.make_patch(from, to))
+            .await
+            .cloned();
+        self.diffs.finished(from, to, &pending).await;
+        Ok(patch?.to_vec())
+    }
+
+    // Make the patch from one blob to another, and cache it.
+    async fn make_patch(&self, from: &Integrity, to: &Integrity) -> ServalResult<Arc<Vec<u8>>> {
+        for integrity in [from, to] {
+            let size = self.metadata_by_integrity(integrity).await?.size;
+            if size > self.diffs.max_blob_size {
+                return Err(ServalError::PayloadTooLarge(format!(
+                    "{integrity} is {size} bytes; patches are only made between blobs of up to {} \
+                     bytes",
+                    self.diffs.max_blob_size
+                )));
+            }
+        }
+        let source = self.data_by_integrity(from.clone()).await?;
+        let target = self.data_by_integrity(to.clone()).await?;
+        // bsdiff is all computation, and can take a while for big blobs.
+        let patch = tokio::task::spawn_blocking(move || make_patch(&source, &target))
+            .await
+            .map_err(|e| ServalError::StorageError(format!("unable to make a patch; {e}")))??;
+        metrics::increment_counter!("storage:diff:made");
+        log::info!("made patch; from={from}; to={to}; size={}", patch.len());
+
+        let patch = Arc::new(patch);
+        self.diffs.insert(from, to, patch.clone()).await;
+        Ok(patch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::diffs::apply_patch;
+
+    use super::*;
+    use crate::storage::{MemoryStorage, ReadPolicy, StorageBackend, WritePolicy};
+
+    #[tokio::test]
+    async fn makes_and_caches_patches_between_blobs() {
+        let backend = Arc::new(MemoryStorage::new());
+        let backends: Vec<Arc<dyn StorageBackend>> = vec![backend.clone()];
+        let storage = Storage::new(backends, ReadPolicy::FirstHit, WritePolicy::All);
+
+        let source: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        let mut target = source.clone();
+        target[1000..1010].copy_from_slice(b"0123456789");
+        let from = backend.store_by_integrity(&source).await.unwrap();
+        let to = backend.store_by_integrity(&target).await.unwrap();
+
+        let patch = storage.diff_between(&from, &to).await.unwrap();
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+        assert_eq!(storage.diffs.get(&from, &to).await.as_deref(), Some(&patch));
+        assert!(storage.diffs.get(&to, &from).await.is_none());
+
+        assert!(matches!(
+            storage
+                .diff_between(&from, &Integrity::from(b"nowhere"))
+                .await,
+            Err(ServalError::DataNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_blobs_over_the_size_limit() {
+        let backend = Arc::new(MemoryStorage::new());
+        let backends: Vec<Arc<dyn StorageBackend>> = vec![backend.clone()];
+        let storage = Storage::new(backends, ReadPolicy::FirstHit, WritePolicy::All)
+            .with_diff_cache(DiffCache::default().with_max_blob_size(100));
+
+        let small = backend.store_by_integrity(&[1; 100]).await.unwrap();
+        let big = backend.store_by_integrity(&[2; 101]).await.unwrap();
+        storage.diff_between(&small, &small).await.unwrap();
+        for (from, to) in [(&small, &big), (&big, &small)] {
+            assert!(matches!(
+                storage.diff_between(from, to).await,
+                Err(ServalError::PayloadTooLarge(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn shares_patches_being_made() {
+        let cache = DiffCache::new(0);
+        let [a, b] = [b"a", b"b"].map(Integrity::from);
+
+        let first = cache.pending(&a, &b).await;
+        let second = cache.pending(&a, &b).await;
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &cache.pending(&b, &a).await));
+
+        // Everyone waiting gets the one patch that was made.
+        let made = Arc::new(vec![1, 2, 3]);
+        let (one, two) = tokio::join!(
+            first.get_or_init(|| async { made.clone() }),
+            second.get_or_init(|| async { unreachable!("the patch was made twice") }),
+        );
+        assert!(Arc::ptr_eq(one, two));
+
+        cache.finished(&a, &b, &first).await;
+        assert!(!Arc::ptr_eq(&first, &cache.pending(&a, &b).await));
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_patches() {
+        let cache = DiffCache::new(10);
+        let [a, b, c] = [b"a", b"b", b"c"].map(Integrity::from);
+
+        cache.insert(&a, &b, Arc::new(vec![0; 4])).await;
+        cache.insert(&b, &c, Arc::new(vec![0; 4])).await;
+        assert!(cache.get(&a, &b).await.is_some());
+        cache.insert(&a, &c, Arc::new(vec![0; 4])).await;
+        assert!(cache.get(&b, &c).await.is_none());
+        assert!(cache.get(&a, &b).await.is_some());
+        assert!(cache.get(&a, &c).await.is_some());
+
+        cache.insert(&c, &a, Arc::new(vec![0; 11])).await;
+        assert!(cache.get(&c, &a).await.is_none());
+    }
+}
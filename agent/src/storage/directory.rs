@@ -0,0 +1,555 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+use async_trait::async_trait;
+use ssri::{Integrity, IntegrityChecker};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use utils::errors::{ServalError, ServalResult};
+use utils::structs::Manifest;
+use uuid::Uuid;
+
+use super::backend::{open_file_range, scan_content_directory, verify, verify_stream};
+use super::{BlobMetadata, ByteRange, SendableStream, StorageBackend};
+
+/// A storage backend that keeps everything as plain files in a directory, laid out so that a human
+/// can find their way around it and standard tools can copy and back it up.
+///
+/// Content-addressed blobs live under their hash, split to keep directories small: the sha256 hash
+/// `abcdef...` is stored at `sha256/ab/cdef...`. Manifests and executables are laid out by what
+/// their keys name, at `namespace/name/manifest.toml` for the latest manifest, and
+/// `namespace/name/version/manifest.toml` and `namespace/name/version/executable.wasm` for each
+/// version; any other key is used as a path as it is. Keyed data is also stored by its hash; the two
+/// files are hard links to each other where the filesystem allows it.
+#[derive(Clone, Debug)]
+pub struct DirectoryStorage {
+    location: PathBuf,
+}
+
+impl DirectoryStorage {
+    /// Create a new directory store, passing in a path to a writeable directory. The directory is
+    /// created if it does not exist.
+    pub fn new(location: &PathBuf) -> ServalResult<Self> {
+        if !location.exists() {
+            fs::create_dir_all(location)?;
+        }
+        if !location.is_dir() {
+            return Err(ServalError::IoError(ErrorKind::PermissionDenied.into()));
+        }
+        let md = fs::metadata(location)?;
+        if md.permissions().readonly() {
+            return Err(ServalError::IoError(ErrorKind::PermissionDenied.into()));
+        }
+
+        Ok(Self {
+            location: location.to_path_buf(),
+        })
+    }
+
+    // Where the blob with the given integrity lives.
+    fn integrity_path(&self, integrity: &Integrity) -> PathBuf {
+        let (algorithm, hex) = integrity.to_hex();
+        let (fanout, rest) = hex.split_at(2);
+        self.location
+            .join(algorithm.to_string())
+            .join(fanout)
+            .join(rest)
+    }
+
+    // Where the data for the given key lives. Keys arrive from API callers, so we refuse anything
+    // that might escape our directory, land on top of the content-addressed store, or be listed as
+    // some other key.
+    fn key_path(&self, key: &str) -> ServalResult<PathBuf> {
+        let laid_out = layout_path(key).unwrap_or_else(|| key.to_string());
+        if path_key(&laid_out) != key {
+            return Err(ServalError::StorageError(format!(
+                "`{key}` is not a valid storage key"
+            )));
+        }
+        let relative = Path::new(&laid_out);
+        let mut components = relative.components().peekable();
+        let first = components.peek().cloned();
+        let valid = components.all(|component| matches!(component, Component::Normal(_)));
+        match first {
+            Some(Component::Normal(first)) if valid && !is_hash_algorithm(first.to_str()) => {
+                Ok(self.location.join(relative))
+            }
+            _ => Err(ServalError::StorageError(format!(
+                "`{key}` is not a valid storage key"
+            ))),
+        }
+    }
+
+    // Write the data to the given path, creating directories as needed. The data is written to a
+    // temporary file and then renamed into place, so readers never see a partial write.
+    async fn write_file(path: &Path, bytes: &[u8]) -> ServalResult<()> {
+        let temporary = temporary_path(path);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::File::create(&temporary).await?;
+        file.write_all(bytes).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&temporary, path).await?;
+        Ok(())
+    }
+
+    // Tidy up after a deletion by removing any directories it left empty, so that the store stays
+    // easy to browse. Failing to do this is harmless.
+    async fn remove_empty_parents(&self, path: &Path) {
+        let mut directory = path.parent();
+        while let Some(current) = directory {
+            if current == self.location || tokio::fs::remove_dir(current).await.is_err() {
+                break;
+            }
+            directory = current.parent();
+        }
+    }
+
+    // Like write_file(), but copying the data from a stream, and checking it against the integrity
+    // it's meant to have before it's renamed into place.
+    async fn write_stream(
+        path: &Path,
+        integrity: &Integrity,
+        mut stream: SendableStream,
+    ) -> ServalResult<()> {
+        let temporary = temporary_path(path);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::File::create(&temporary).await?;
+        let mut checker = IntegrityChecker::new(integrity.clone());
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = stream.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            checker.input(&buffer[..read]);
+            file.write_all(&buffer[..read]).await?;
+        }
+        file.sync_all().await?;
+        if let Err(e) = checker.result() {
+            let _ = tokio::fs::remove_file(&temporary).await;
+            return Err(e.into());
+        }
+        tokio::fs::rename(&temporary, path).await?;
+        Ok(())
+    }
+
+    // Make the file at `path` have the same contents as the blob at `source`, sharing its storage
+    // if we can.
+    async fn link_file(source: &Path, path: &Path) -> ServalResult<()> {
+        let temporary = temporary_path(path);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        if tokio::fs::hard_link(source, &temporary).await.is_ok() {
+            tokio::fs::rename(&temporary, path).await?;
+            return Ok(());
+        }
+        // Some filesystems can't do this; a copy is just as good, only bigger.
+        tokio::fs::copy(source, &temporary).await?;
+        tokio::fs::rename(&temporary, path).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for DirectoryStorage {
+    fn name(&self) -> &'static str {
+        "local directory"
+    }
+
+    async fn store_by_integrity(&self, bytes: &[u8]) -> ServalResult<Integrity> {
+        let integrity = Integrity::from(bytes);
+        let path = self.integrity_path(&integrity);
+        // Content-addressed data never changes, so there's no need to write it twice.
+        if !path.exists() {
+            Self::write_file(&path, bytes).await?;
+        }
+        Ok(integrity)
+    }
+
+    async fn store_stream_by_integrity(
+        &self,
+        integrity: &Integrity,
+        _size: u64,
+        stream: SendableStream,
+    ) -> ServalResult<Integrity> {
+        let path = self.integrity_path(integrity);
+        if !path.exists() {
+            Self::write_stream(&path, integrity, stream).await?;
+        }
+        Ok(integrity.clone())
+    }
+
+    async fn stream_by_integrity(&self, integrity: &Integrity) -> ServalResult<SendableStream> {
+        match tokio::fs::File::open(self.integrity_path(integrity)).await {
+            Ok(file) => Ok(verify_stream(integrity, Box::pin(file))),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(ServalError::DataNotFound(integrity.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn data_by_integrity(&self, integrity: &Integrity) -> ServalResult<Vec<u8>> {
+        match tokio::fs::read(self.integrity_path(integrity)).await {
+            Ok(bytes) => {
+                verify(integrity, &bytes)?;
+                Ok(bytes)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(ServalError::DataNotFound(integrity.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn metadata_by_integrity(&self, integrity: &Integrity) -> ServalResult<BlobMetadata> {
+        match tokio::fs::metadata(self.integrity_path(integrity)).await {
+            Ok(metadata) => Ok(BlobMetadata {
+                integrity: integrity.clone(),
+                size: metadata.len(),
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(ServalError::DataNotFound(integrity.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn stream_range_by_integrity(
+        &self,
+        integrity: &Integrity,
+        range: ByteRange,
+    ) -> ServalResult<SendableStream> {
+        match open_file_range(&self.integrity_path(integrity), range).await {
+            Err(ServalError::IoError(e)) if e.kind() == ErrorKind::NotFound => {
+                Err(ServalError::DataNotFound(integrity.to_string()))
+            }
+            result => result,
+        }
+    }
+
+    async fn data_exists_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
+        Ok(self.integrity_path(integrity).is_file())
+    }
+
+    async fn store_by_key(&self, key: &str, bytes: &[u8]) -> ServalResult<Integrity> {
+        let path = self.key_path(key)?;
+        let integrity = self.store_by_integrity(bytes).await?;
+        Self::link_file(&self.integrity_path(&integrity), &path).await?;
+        Ok(integrity)
+    }
+
+    async fn store_stream_by_key(
+        &self,
+        key: &str,
+        integrity: &Integrity,
+        size: u64,
+        stream: SendableStream,
+    ) -> ServalResult<Integrity> {
+        let path = self.key_path(key)?;
+        let integrity = self
+            .store_stream_by_integrity(integrity, size, stream)
+            .await?;
+        Self::link_file(&self.integrity_path(&integrity), &path).await?;
+        Ok(integrity)
+    }
+
+    async fn link_key(&self, key: &str, integrity: &Integrity) -> ServalResult<Integrity> {
+        let path = self.key_path(key)?;
+        if !self.data_exists_by_integrity(integrity).await? {
+            return Err(ServalError::DataNotFound(integrity.to_string()));
+        }
+        Self::link_file(&self.integrity_path(integrity), &path).await?;
+        Ok(integrity.clone())
+    }
+
+    /// Nothing records the integrity of a key's file, so it isn't checked here. It shares its
+    /// storage with the blob's file where it can, which is checked on reads by integrity and by
+    /// scrubs.
+    async fn stream_by_key(&self, key: &str) -> ServalResult<SendableStream> {
+        match tokio::fs::File::open(self.key_path(key)?).await {
+            Ok(file) => Ok(Box::pin(file)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(ServalError::DataNotFound(key.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn data_exists_by_key(&self, key: &str) -> ServalResult<bool> {
+        Ok(self.key_path(key)?.is_file())
+    }
+
+    async fn list_keys(&self, prefix: &str) -> ServalResult<Vec<String>> {
+        let location = self.location.clone();
+        let prefix = prefix.to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut paths = Vec::new();
+            collect_paths(&location, "", &mut paths)?;
+            Ok(paths
+                .iter()
+                .map(|path| path_key(path))
+                .filter(|key| key.starts_with(&prefix))
+                .collect())
+        })
+        .await
+        .map_err(|e| ServalError::StorageError(format!("unable to list keys; error={e}")))?
+    }
+
+    async fn delete_by_key(&self, key: &str) -> ServalResult<bool> {
+        let path = self.key_path(key)?;
+        let removed = remove_file(&path).await?;
+        if removed {
+            self.remove_empty_parents(&path).await;
+        }
+        Ok(removed)
+    }
+
+    async fn delete_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
+        let path = self.integrity_path(integrity);
+        let removed = remove_file(&path).await?;
+        if removed {
+            self.remove_empty_parents(&path).await;
+        }
+        Ok(removed)
+    }
+
+    async fn list_blobs(&self) -> ServalResult<Vec<BlobMetadata>> {
+        let location = self.location.clone();
+        tokio::task::spawn_blocking(move || scan_content_directory(&location))
+            .await
+            .map_err(|e| ServalError::StorageError(format!("unable to list blobs; error={e}")))?
+    }
+
+    /// Keyed files are usually hard links to the blob's file, so replacing the blob's file would
+    /// leave them with the corrupt data. Instead, the good copy is checked in a file of its own and
+    /// then copied over the corrupt one, in place, which repairs every link to it.
+    async fn restore_stream_by_integrity(
+        &self,
+        integrity: &Integrity,
+        _size: u64,
+        stream: SendableStream,
+    ) -> ServalResult<Integrity> {
+        let path = self.integrity_path(integrity);
+        let staged = temporary_path(&path);
+        Self::write_stream(&staged, integrity, stream).await?;
+        let copied = tokio::fs::copy(&staged, &path).await;
+        let _ = tokio::fs::remove_file(&staged).await;
+        copied?;
+        Ok(integrity.clone())
+    }
+}
+
+// Remove a file, responding with whether it was there to remove.
+async fn remove_file(path: &Path) -> ServalResult<bool> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Walk the directory tree below `directory`, which is at `parent` in the store, adding the path of
+// every file. Skips the content-addressed store and any half-written files.
+fn collect_paths(directory: &Path, parent: &str, paths: &mut Vec<String>) -> ServalResult<()> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if name.starts_with('.') || (parent.is_empty() && is_hash_algorithm(Some(&name))) {
+            continue;
+        }
+        let path = format!("{parent}{name}");
+        if entry.file_type()?.is_dir() {
+            collect_paths(&entry.path(), &format!("{path}/"), paths)?;
+        } else {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Where a manifest or executable key is laid out in the store, or None for any other key.
+fn layout_path(key: &str) -> Option<String> {
+    let (fq_name, version, file) = match Manifest::parse_executable_key(key) {
+        Some((fq_name, version)) => (fq_name, Some(version), "executable.wasm"),
+        None => {
+            let (fq_name, version) = Manifest::parse_manifest_key(key)?;
+            (fq_name, version, "manifest.toml")
+        }
+    };
+    let (namespace, name) = fq_name.rsplit_once('.')?;
+    match version {
+        Some(version) => Some(format!("{namespace}/{name}/{version}/{file}")),
+        None => Some(format!("{namespace}/{name}/{file}")),
+    }
+}
+
+// The key whose data is at the given path in the store: the reverse of layout_path().
+fn path_key(path: &str) -> String {
+    let key = match path.split('/').collect::<Vec<&str>>()[..] {
+        [namespace, name, "manifest.toml"] => {
+            Manifest::make_manifest_key(&format!("{namespace}.{name}"))
+        }
+        [namespace, name, version, "manifest.toml"] => {
+            Manifest::make_versioned_manifest_key(&format!("{namespace}.{name}"), version)
+        }
+        [namespace, name, version, "executable.wasm"] => {
+            Manifest::make_executable_key(&format!("{namespace}.{name}"), version)
+        }
+        _ => return path.to_string(),
+    };
+    // Only paths that a key would have been laid out at are taken for one.
+    match layout_path(&key) {
+        Some(laid_out) if laid_out == path => key,
+        _ => path.to_string(),
+    }
+}
+
+// The top-level directories of the content-addressed store are named after hash algorithms.
+fn is_hash_algorithm(name: Option<&str>) -> bool {
+    matches!(name, Some("sha1" | "sha256" | "sha384" | "sha512" | "xxh3"))
+}
+
+// A unique name next to the given path, for writing to before renaming into place. The leading dot
+// keeps it out of the way of anyone browsing the store.
+fn temporary_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{name}.{}.tmp", Uuid::new_v4()))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    fn store() -> (DirectoryStorage, PathBuf) {
+        let location = std::env::temp_dir().join(format!("serval-directory-{}", Uuid::new_v4()));
+        (DirectoryStorage::new(&location).unwrap(), location)
+    }
+
+    #[tokio::test]
+    async fn lays_out_blobs_and_keys_as_browsable_files() {
+        let (store, location) = store();
+
+        let integrity = store.store_by_integrity(b"hello").await.unwrap();
+        let (_, hex) = integrity.to_hex();
+        let blob = location.join("sha256").join(&hex[..2]).join(&hex[2..]);
+        assert_eq!(fs::read(blob).unwrap(), b"hello");
+        assert!(store.data_exists_by_integrity(&integrity).await.unwrap());
+
+        let key = "sh.serval.loudify.1.0.0.wasm";
+        let keyed = store.store_by_key(key, b"\0asm").await.unwrap();
+        let path = location.join("sh.serval/loudify/1.0.0/executable.wasm");
+        assert_eq!(fs::read(path).unwrap(), b"\0asm");
+        assert!(store.data_exists_by_key(key).await.unwrap());
+        assert_eq!(store.data_by_integrity(&keyed).await.unwrap(), b"\0asm");
+
+        // Overwriting a key replaces what it points to, but not the old content.
+        let replaced = store.store_by_key(key, b"\0asm2").await.unwrap();
+        let mut stream = store.stream_by_key(key).await.unwrap();
+        let mut bytes = Vec::new();
+        stream.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(bytes, b"\0asm2");
+        assert!(store.data_exists_by_integrity(&replaced).await.unwrap());
+        assert!(store.data_exists_by_integrity(&keyed).await.unwrap());
+
+        assert!(matches!(
+            store.data_by_key("sh.serval.missing.manifest.toml").await,
+            Err(ServalError::DataNotFound(_))
+        ));
+        assert!(matches!(
+            store.data_by_integrity(&Integrity::from(b"nowhere")).await,
+            Err(ServalError::DataNotFound(_))
+        ));
+
+        let manifest = "sh.serval.loudify.manifest.toml";
+        store.store_by_key(manifest, b"name = ...").await.unwrap();
+        let versioned = "sh.serval.loudify.1.0.0.manifest.toml";
+        store.store_by_key(versioned, b"name = ...").await.unwrap();
+        assert!(location.join("sh.serval/loudify/manifest.toml").is_file());
+        assert!(location
+            .join("sh.serval/loudify/1.0.0/manifest.toml")
+            .is_file());
+        store.store_by_key("_pins/sha256/abc", b"").await.unwrap();
+        assert!(location.join("_pins/sha256/abc").is_file());
+
+        let mut keys = store.list_keys("sh.serval.").await.unwrap();
+        keys.sort();
+        assert_eq!(keys, vec![versioned, key, manifest]);
+        assert!(store.list_keys("sh.serval.other").await.unwrap().is_empty());
+        assert_eq!(
+            store.list_keys("_pins/").await.unwrap(),
+            vec!["_pins/sha256/abc"]
+        );
+
+        fs::remove_dir_all(location).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_keys_outside_its_directory() {
+        let (store, location) = store();
+
+        for key in [
+            "../escape",
+            "/etc/passwd",
+            "sh.serval/../../escape",
+            "sha256/ab/cdef",
+            // This is where a manifest's key is laid out, so it would be listed as that key.
+            "sh.serval/loudify/manifest.toml",
+            "",
+        ] {
+            assert!(store.store_by_key(key, b"nope").await.is_err(), "{key}");
+            assert!(store.data_exists_by_key(key).await.is_err(), "{key}");
+        }
+
+        fs::remove_dir_all(location).unwrap();
+    }
+
+    #[tokio::test]
+    async fn deletes_files_and_lists_blobs() {
+        let (store, location) = store();
+
+        let key = "sh.serval.loudify.1.0.0.wasm";
+        let keyed = store.store_by_key(key, b"\0asm").await.unwrap();
+        let loose = store.store_by_integrity(b"loose").await.unwrap();
+
+        let mut blobs = store.list_blobs().await.unwrap();
+        blobs.sort_by_key(|blob| blob.size);
+        assert_eq!(
+            blobs,
+            vec![
+                BlobMetadata {
+                    integrity: keyed.clone(),
+                    size: 4
+                },
+                BlobMetadata {
+                    integrity: loose.clone(),
+                    size: 5
+                },
+            ]
+        );
+
+        assert!(store.delete_by_key(key).await.unwrap());
+        assert!(!store.delete_by_key(key).await.unwrap());
+        // Deleting the key leaves no empty directories behind, and leaves the blob alone.
+        assert!(!location.join("sh.serval").exists());
+        assert!(store.data_exists_by_integrity(&keyed).await.unwrap());
+
+        assert!(store.delete_by_integrity(&loose).await.unwrap());
+        assert!(!store.delete_by_integrity(&loose).await.unwrap());
+        assert_eq!(store.list_blobs().await.unwrap().len(), 1);
+
+        fs::remove_dir_all(location).unwrap();
+    }
+}
@@ -0,0 +1,563 @@
+//! Client-side encryption of what we store in S3, so that the bucket only ever holds ciphertext.
+//!
+//! Encryption is by envelope: each object is encrypted with a data key made just for it, and the
+//! data key is kept in the object's metadata, itself encrypted with a master key from a local
+//! keyfile and labeled with that key's id. Objects encrypted under older master keys stay readable
+//! for as long as the keyfile has those keys; rotating keys re-encrypts them under the current one.
+//!
+//! Objects are encrypted with AES-256-GCM in segments, so that they can be encrypted and decrypted
+//! as they stream by. Each segment's nonce is its position in the object, with the last segment
+//! marked as such, so that segments can't be reordered, dropped or cut off without it showing.
+//!
+//! The keyfile is TOML naming the current master key and listing every key by its id, as 32 random
+//! bytes encoded in base64 (`openssl rand -base64 32` makes one):
+//!
+//! ```toml
+//! current = "2023-06"
+//!
+//! [keys]
+//! 2023-06 = "..."
+//! 2023-01 = "..."
+//! ```
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use base64::Engine as _;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, ReadBuf};
+use utils::errors::{ServalError, ServalResult};
+use utils::structs::api::KeyRotation;
+
+use super::{make_proxy_client, ByteRange, SendableStream, Storage};
+
+/// The object metadata naming the master key that encrypts an object's data key.
+pub const KEY_ID: &str = "key-id";
+/// The object metadata holding an object's data key, encrypted.
+pub const DATA_KEY: &str = "data-key";
+
+// How much of an object each encrypted segment holds; the last may hold less.
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// The master keys that encrypt objects' data keys, one of which encrypts new objects.
+pub struct Keyring {
+    current: String,
+    keys: HashMap<String, LessSafeKey>,
+    random: SystemRandom,
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never the keys themselves.
+        f.debug_struct("Keyring")
+            .field("current", &self.current)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct Keyfile {
+    current: String,
+    keys: HashMap<String, String>,
+}
+
+/// The key that encrypts one object.
+pub struct DataKey(LessSafeKey);
+
+impl Keyring {
+    /// Read a keyring from a keyfile.
+    pub fn from_file(path: &Path) -> ServalResult<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let keyfile: Keyfile = toml::from_str(&text).map_err(|e| {
+            ServalError::StorageError(format!("unreadable keyfile {}; {e}", path.display()))
+        })?;
+        Self::new(&keyfile.current, &keyfile.keys)
+    }
+
+    /// Read the keyring from the keyfile named by `STORAGE_BUCKET_KEYFILE`, if one is named.
+    pub fn from_env() -> ServalResult<Option<Self>> {
+        match std::env::var("STORAGE_BUCKET_KEYFILE") {
+            Ok(path) => Ok(Some(Self::from_file(Path::new(&path))?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Make a keyring from master keys encoded in base64, by their ids.
+    pub fn new(current: &str, encoded: &HashMap<String, String>) -> ServalResult<Self> {
+        let mut keys = HashMap::new();
+        for (id, key) in encoded {
+            // Key ids go into object metadata, which S3 only allows to be printable ASCII.
+            if id.is_empty() || !id.chars().all(|c| c.is_ascii_graphic()) {
+                return Err(ServalError::StorageError(format!(
+                    "key ids must be printable ASCII without spaces; id=`{id}`"
+                )));
+            }
+            let bytes = base64::engine::general_purpose::STANDARD
+                .decode(key.trim())
+                .ok()
+                .filter(|bytes| bytes.len() == KEY_LEN)
+                .ok_or_else(|| {
+                    ServalError::StorageError(format!(
+                        "key `{id}` is not {KEY_LEN} bytes encoded in base64"
+                    ))
+                })?;
+            keys.insert(id.clone(), aes_key(&bytes)?);
+        }
+        if !keys.contains_key(current) {
+            return Err(ServalError::StorageError(format!(
+                "the current key `{current}` is not among the keys"
+            )));
+        }
+        Ok(Self {
+            current: current.to_string(),
+            keys,
+            random: SystemRandom::new(),
+        })
+    }
+
+    /// The id of the master key that encrypts new objects.
+    pub fn current_id(&self) -> &str {
+        &self.current
+    }
+
+    /// Make a data key for a new object, along with the object metadata that records it. The data
+    /// key is tied to the name of the object it encrypts.
+    pub fn seal_envelope(&self, object: &str) -> ServalResult<(DataKey, HashMap<String, String>)> {
+        let mut data_key = [0; KEY_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        self.random.fill(&mut data_key).map_err(|_| randomless())?;
+        self.random.fill(&mut nonce).map_err(|_| randomless())?;
+
+        let mut sealed = data_key.to_vec();
+        self.keys[&self.current]
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(object.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| ServalError::StorageError("unable to seal a data key".to_string()))?;
+        let envelope = [nonce.as_slice(), &sealed].concat();
+
+        let metadata = HashMap::from([
+            (KEY_ID.to_string(), self.current.clone()),
+            (
+                DATA_KEY.to_string(),
+                base64::engine::general_purpose::STANDARD.encode(envelope),
+            ),
+        ]);
+        Ok((DataKey(aes_key(&data_key)?), metadata))
+    }
+
+    /// Recover an object's data key from the object's metadata. None means the object isn't
+    /// encrypted.
+    pub fn open_envelope(
+        &self,
+        object: &str,
+        metadata: Option<&HashMap<String, String>>,
+    ) -> ServalResult<Option<DataKey>> {
+        let Some(id) = key_id(metadata) else {
+            return Ok(None);
+        };
+        let Some(master) = self.keys.get(id) else {
+            return Err(ServalError::StorageError(format!(
+                "{object} is encrypted with key `{id}`, which the keyfile doesn't have"
+            )));
+        };
+        let unopenable = || {
+            ServalError::StorageError(format!(
+                "unable to decrypt the data key of {object}; key id={id}"
+            ))
+        };
+        let envelope = metadata
+            .and_then(|metadata| metadata.get(DATA_KEY))
+            .and_then(|envelope| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(envelope)
+                    .ok()
+            })
+            .filter(|envelope| envelope.len() == NONCE_LEN + KEY_LEN + TAG_LEN)
+            .ok_or_else(unopenable)?;
+        let (nonce, sealed) = envelope.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| unopenable())?;
+        let mut sealed = sealed.to_vec();
+        let data_key = master
+            .open_in_place(nonce, Aad::from(object.as_bytes()), &mut sealed)
+            .map_err(|_| unopenable())?;
+        Ok(Some(DataKey(aes_key(data_key)?)))
+    }
+}
+
+/// The id of the master key an object is encrypted under, from its metadata, if it's encrypted.
+pub fn key_id(metadata: Option<&HashMap<String, String>>) -> Option<&str> {
+    metadata?.get(KEY_ID).map(String::as_str)
+}
+
+/// Encrypt a stream with an object's data key as it's read.
+pub(crate) fn encrypt(key: DataKey, stream: SendableStream) -> SendableStream {
+    Box::pin(Segments::new(key, stream, true))
+}
+
+/// Decrypt a stream with an object's data key as it's read. Data that doesn't decrypt, because
+/// it was changed or cut short, fails the read with an `InvalidData` error, just as data that
+/// doesn't match its integrity does.
+pub(crate) fn decrypt(key: DataKey, stream: SendableStream) -> SendableStream {
+    Box::pin(Segments::new(key, stream, false))
+}
+
+/// Where the segments of an encrypted object that hold a range of its data are stored, as worked
+/// out by segments_for().
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentRange {
+    /// The stored bytes to fetch: every segment the range touches, whole.
+    pub stored: ByteRange,
+    /// The position in the object of the first of those segments.
+    pub first: u64,
+    /// Whether the last of those segments is the last of the object.
+    pub to_end: bool,
+    /// Where the range lies in what those segments decrypt to.
+    pub within: ByteRange,
+}
+
+/// Find the segments of an encrypted object, stored in the given number of bytes, that hold the
+/// given range of its data. Each segment decrypts on its own, so a range can be read without
+/// fetching everything before it.
+pub fn segments_for(range: ByteRange, ciphertext_len: u64) -> SegmentRange {
+    let plain = SEGMENT_SIZE as u64;
+    let sealed = (SEGMENT_SIZE + TAG_LEN) as u64;
+    let first = range.start / plain;
+    let start = first * sealed;
+    let end = ((range.last() / plain + 1) * sealed).min(ciphertext_len);
+    SegmentRange {
+        stored: ByteRange {
+            start,
+            len: end.saturating_sub(start),
+        },
+        first,
+        to_end: end == ciphertext_len,
+        within: ByteRange {
+            start: range.start - first * plain,
+            len: range.len,
+        },
+    }
+}
+
+/// Decrypt the segments of an object found by segments_for() as they're read.
+pub(crate) fn decrypt_segments(
+    key: DataKey,
+    stream: SendableStream,
+    segments: &SegmentRange,
+) -> SendableStream {
+    let mut reader = Segments::new(key, stream, false);
+    reader.segments = segments.first;
+    reader.to_end = segments.to_end;
+    Box::pin(reader)
+}
+
+/// How many bytes an encrypted object of the given size decrypts to.
+pub fn plaintext_len(ciphertext_len: u64) -> u64 {
+    let segments = ciphertext_len
+        .div_ceil((SEGMENT_SIZE + TAG_LEN) as u64)
+        .max(1);
+    ciphertext_len.saturating_sub(segments * TAG_LEN as u64)
+}
+
+fn aes_key(bytes: &[u8]) -> ServalResult<LessSafeKey> {
+    let key = UnboundKey::new(&AES_256_GCM, bytes)
+        .map_err(|_| ServalError::StorageError("not a valid AES-256 key".to_string()))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn randomless() -> ServalError {
+    ServalError::StorageError("unable to get random bytes from the system".to_string())
+}
+
+// The nonce of a segment: its position, and whether it's the last.
+fn segment_nonce(position: u64, last: bool) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[3..11].copy_from_slice(&position.to_be_bytes());
+    nonce[11] = u8::from(last);
+    Nonce::assume_unique_for_key(nonce)
+}
+
+// Encrypts or decrypts a stream a segment at a time.
+struct Segments {
+    inner: SendableStream,
+    key: DataKey,
+    sealing: bool,
+    // How much of the inner stream makes up a segment.
+    chunk: usize,
+    // Read from the inner stream, but not yet sealed or opened. A byte more than a segment is read
+    // before the segment is sealed or opened, to tell whether it's the last.
+    input: Box<[u8]>,
+    filled: usize,
+    inner_done: bool,
+    // The last segment sealed or opened, of which everything from `position` on hasn't been
+    // handed out yet.
+    output: Vec<u8>,
+    position: usize,
+    segments: u64,
+    // Whether the inner stream runs to the end of the object, so that its last segment is the
+    // object's last.
+    to_end: bool,
+    finished: bool,
+}
+
+impl Segments {
+    fn new(key: DataKey, inner: SendableStream, sealing: bool) -> Self {
+        let chunk = if sealing {
+            SEGMENT_SIZE
+        } else {
+            SEGMENT_SIZE + TAG_LEN
+        };
+        Self {
+            inner,
+            key,
+            sealing,
+            chunk,
+            input: vec![0; chunk + 1].into_boxed_slice(),
+            filled: 0,
+            inner_done: false,
+            output: Vec::with_capacity(SEGMENT_SIZE + TAG_LEN),
+            position: 0,
+            segments: 0,
+            to_end: true,
+            finished: false,
+        }
+    }
+
+    // Seal or open the next segment, now in `output`.
+    fn transform(&mut self, last: bool) -> std::io::Result<()> {
+        let nonce = segment_nonce(self.segments, last);
+        self.segments += 1;
+        if self.sealing {
+            return self
+                .key
+                .0
+                .seal_in_place_append_tag(nonce, Aad::empty(), &mut self.output)
+                .map_err(|_| std::io::Error::other("unable to encrypt data"));
+        }
+        match self
+            .key
+            .0
+            .open_in_place(nonce, Aad::empty(), &mut self.output)
+        {
+            Ok(plaintext) => {
+                let len = plaintext.len();
+                self.output.truncate(len);
+                Ok(())
+            }
+            Err(_) => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "encrypted data has been changed or cut short",
+            )),
+        }
+    }
+}
+
+impl AsyncRead for Segments {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.position < this.output.len() {
+                let count = (this.output.len() - this.position).min(buf.remaining());
+                buf.put_slice(&this.output[this.position..this.position + count]);
+                this.position += count;
+                return Poll::Ready(Ok(()));
+            }
+            if this.finished {
+                return Poll::Ready(Ok(()));
+            }
+
+            while !this.inner_done && this.filled < this.input.len() {
+                let mut chunk = ReadBuf::new(&mut this.input[this.filled..]);
+                ready!(this.inner.as_mut().poll_read(cx, &mut chunk))?;
+                match chunk.filled().len() {
+                    0 => this.inner_done = true,
+                    read => this.filled += read,
+                }
+            }
+
+            let last = this.filled <= this.chunk;
+            let take = this.filled.min(this.chunk);
+            this.output.clear();
+            this.output.extend_from_slice(&this.input[..take]);
+            this.input.copy_within(take..this.filled, 0);
+            this.filled -= take;
+            this.position = 0;
+            this.finished = last;
+            this.transform(last && this.to_end)?;
+        }
+    }
+}
+
+impl Storage {
+    /// Re-encrypt everything each encrypting backend stores under anything but its current master
+    /// key, including anything stored before it encrypted at all.
+    pub async fn rotate_keys(&self) -> ServalResult<Vec<KeyRotation>> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.rotate_keys().await;
+        }
+
+        let mut rotations = Vec::new();
+        for backend in &self.backends {
+            if let Some(rotation) = backend.rotate_keys().await? {
+                metrics::counter!("storage:keys:rotated", rotation.rotated as u64);
+                log::info!(
+                    "rotated keys; backend={}; key={}; rotated={}; failed={}",
+                    rotation.backend,
+                    rotation.key_id,
+                    rotation.rotated,
+                    rotation.failed.len()
+                );
+                rotations.push(rotation);
+            }
+        }
+        Ok(rotations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    fn keyring(current: &str) -> Keyring {
+        let keys = HashMap::from([
+            ("old".to_string(), base64_key(1)),
+            ("new".to_string(), base64_key(2)),
+        ]);
+        Keyring::new(current, &keys).unwrap()
+    }
+
+    fn base64_key(fill: u8) -> String {
+        base64::engine::general_purpose::STANDARD.encode([fill; KEY_LEN])
+    }
+
+    async fn read_all(mut stream: SendableStream) -> std::io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        stream.read_to_end(&mut bytes).await?;
+        Ok(bytes)
+    }
+
+    async fn seal(
+        keyring: &Keyring,
+        object: &str,
+        data: &[u8],
+    ) -> (Vec<u8>, HashMap<String, String>) {
+        let (key, metadata) = keyring.seal_envelope(object).unwrap();
+        let stream = encrypt(key, Box::pin(std::io::Cursor::new(data.to_vec())));
+        (read_all(stream).await.unwrap(), metadata)
+    }
+
+    async fn open(
+        keyring: &Keyring,
+        object: &str,
+        ciphertext: Vec<u8>,
+        metadata: &HashMap<String, String>,
+    ) -> std::io::Result<Vec<u8>> {
+        let key = keyring
+            .open_envelope(object, Some(metadata))
+            .unwrap()
+            .unwrap();
+        read_all(decrypt(key, Box::pin(std::io::Cursor::new(ciphertext)))).await
+    }
+
+    #[tokio::test]
+    async fn round_trips_data_of_any_size() {
+        let keyring = keyring("new");
+        for size in [
+            0,
+            1,
+            SEGMENT_SIZE - 1,
+            SEGMENT_SIZE,
+            SEGMENT_SIZE + 1,
+            3 * SEGMENT_SIZE + 5,
+        ] {
+            let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let (ciphertext, metadata) = seal(&keyring, "blob", &data).await;
+            if size > 0 {
+                assert_ne!(&ciphertext[..size.min(64)], &data[..size.min(64)]);
+            }
+            assert_eq!(plaintext_len(ciphertext.len() as u64), size as u64);
+            assert_eq!(metadata[KEY_ID], "new");
+            assert_eq!(
+                open(&keyring, "blob", ciphertext, &metadata).await.unwrap(),
+                data
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_changed_or_shortened_data() {
+        let keyring = keyring("new");
+        let data = vec![7; 2 * SEGMENT_SIZE + 100];
+        let (ciphertext, metadata) = seal(&keyring, "blob", &data).await;
+
+        let mut changed = ciphertext.clone();
+        changed[SEGMENT_SIZE + 50] ^= 1;
+        let e = open(&keyring, "blob", changed, &metadata)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        // Dropping whole segments from the end leaves a last segment that wasn't sealed as one.
+        let shortened = ciphertext[..2 * (SEGMENT_SIZE + TAG_LEN)].to_vec();
+        assert!(open(&keyring, "blob", shortened, &metadata).await.is_err());
+        assert!(open(&keyring, "blob", Vec::new(), &metadata).await.is_err());
+
+        // A data key only opens for the object it was made for.
+        assert!(keyring.open_envelope("other", Some(&metadata)).is_err());
+    }
+
+    #[tokio::test]
+    async fn opens_objects_sealed_under_older_keys() {
+        let (ciphertext, metadata) = seal(&keyring("old"), "blob", b"secret").await;
+        let rotated = keyring("new");
+        assert_eq!(metadata[KEY_ID], "old");
+        assert_eq!(
+            open(&rotated, "blob", ciphertext, &metadata).await.unwrap(),
+            b"secret"
+        );
+
+        let keys = HashMap::from([("new".to_string(), base64_key(2))]);
+        let forgetful = Keyring::new("new", &keys).unwrap();
+        assert!(forgetful.open_envelope("blob", Some(&metadata)).is_err());
+        assert!(forgetful.open_envelope("blob", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn reads_keyfiles() {
+        let path = std::env::temp_dir().join(format!("serval-keys-{}", uuid::Uuid::new_v4()));
+        let keyfile = format!(
+            "current = \"2023-06\"\n\n[keys]\n2023-06 = \"{}\"\n2023-01 = \"{}\"\n",
+            base64_key(1),
+            base64_key(2)
+        );
+        std::fs::write(&path, keyfile).unwrap();
+        let keyring = Keyring::from_file(&path).unwrap();
+        assert_eq!(keyring.current_id(), "2023-06");
+        assert!(!format!("{keyring:?}").contains(&base64_key(1)));
+        std::fs::remove_file(&path).unwrap();
+
+        let short = HashMap::from([("a".to_string(), "c2hvcnQ=".to_string())]);
+        assert!(Keyring::new("a", &short).is_err());
+        let keys = HashMap::from([("a".to_string(), base64_key(1))]);
+        assert!(Keyring::new("b", &keys).is_err());
+        let spaced = HashMap::from([("a b".to_string(), base64_key(1))]);
+        assert!(Keyring::new("a b", &spaced).is_err());
+    }
+}
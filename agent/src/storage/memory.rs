@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use ssri::Integrity;
+use utils::errors::{ServalError, ServalResult};
+use utils::structs::api::BlobInfo;
+
+use super::{BlobMetadata, SendableStream, StorageBackend};
+
+/// A storage backend that keeps everything in memory, and forgets it all when the agent exits.
+/// Useful for testing, and for short-lived agents that only need somewhere to put things while
+/// they're running.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    // Blobs, indexed by the string form of their integrity hash.
+    blobs: Arc<RwLock<HashMap<String, Arc<Vec<u8>>>>>,
+    // Which blob each human-readable key points to.
+    keys: Arc<RwLock<HashMap<String, Integrity>>>,
+    // Descriptions of blobs, indexed like the blobs themselves.
+    info: Arc<RwLock<HashMap<String, BlobInfo>>>,
+}
+
+impl MemoryStorage {
+    /// Create a new, empty, memory store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn blob(&self, integrity: &Integrity) -> ServalResult<Arc<Vec<u8>>> {
+        self.blobs
+            .read()
+            .unwrap()
+            .get(&integrity.to_string())
+            .cloned()
+            .ok_or_else(|| ServalError::DataNotFound(integrity.to_string()))
+    }
+
+    fn integrity_for_key(&self, key: &str) -> ServalResult<Integrity> {
+        self.keys
+            .read()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or_else(|| ServalError::DataNotFound(key.to_string()))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn store_by_integrity(&self, bytes: &[u8]) -> ServalResult<Integrity> {
+        let integrity = Integrity::from(bytes);
+        self.blobs
+            .write()
+            .unwrap()
+            .insert(integrity.to_string(), Arc::new(bytes.to_vec()));
+        Ok(integrity)
+    }
+
+    async fn stream_by_integrity(&self, integrity: &Integrity) -> ServalResult<SendableStream> {
+        let blob = self.blob(integrity)?;
+        // Cursor needs something that is AsRef<[u8]>; cloning the Arc avoids copying the blob.
+        Ok(Box::pin(Cursor::new(ArcBytes(blob))))
+    }
+
+    async fn data_by_integrity(&self, integrity: &Integrity) -> ServalResult<Vec<u8>> {
+        Ok(self.blob(integrity)?.to_vec())
+    }
+
+    async fn data_exists_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
+        Ok(self
+            .blobs
+            .read()
+            .unwrap()
+            .contains_key(&integrity.to_string()))
+    }
+
+    async fn store_by_key(&self, key: &str, bytes: &[u8]) -> ServalResult<Integrity> {
+        let integrity = self.store_by_integrity(bytes).await?;
+        self.keys
+            .write()
+            .unwrap()
+            .insert(key.to_string(), integrity.clone());
+        Ok(integrity)
+    }
+
+    async fn link_key(&self, key: &str, integrity: &Integrity) -> ServalResult<Integrity> {
+        self.blob(integrity)?;
+        self.keys
+            .write()
+            .unwrap()
+            .insert(key.to_string(), integrity.clone());
+        Ok(integrity.clone())
+    }
+
+    async fn stream_by_key(&self, key: &str) -> ServalResult<SendableStream> {
+        let integrity = self.integrity_for_key(key)?;
+        self.stream_by_integrity(&integrity).await
+    }
+
+    async fn data_by_key(&self, key: &str) -> ServalResult<Vec<u8>> {
+        let integrity = self.integrity_for_key(key)?;
+        self.data_by_integrity(&integrity).await
+    }
+
+    async fn data_exists_by_key(&self, key: &str) -> ServalResult<bool> {
+        Ok(self.keys.read().unwrap().contains_key(key))
+    }
+
+    async fn metadata_by_key(&self, key: &str) -> ServalResult<BlobMetadata> {
+        let integrity = self.integrity_for_key(key)?;
+        let size = self.blob(&integrity)?.len() as u64;
+        Ok(BlobMetadata { integrity, size })
+    }
+
+    async fn list_keys(&self, prefix: &str) -> ServalResult<Vec<String>> {
+        Ok(self
+            .keys
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn delete_by_key(&self, key: &str) -> ServalResult<bool> {
+        Ok(self.keys.write().unwrap().remove(key).is_some())
+    }
+
+    async fn delete_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
+        self.info.write().unwrap().remove(&integrity.to_string());
+        Ok(self
+            .blobs
+            .write()
+            .unwrap()
+            .remove(&integrity.to_string())
+            .is_some())
+    }
+
+    async fn list_blobs(&self) -> ServalResult<Vec<BlobMetadata>> {
+        let blobs = self.blobs.read().unwrap();
+        let mut listing = Vec::with_capacity(blobs.len());
+        for (integrity, blob) in blobs.iter() {
+            listing.push(BlobMetadata {
+                integrity: integrity.parse()?,
+                size: blob.len() as u64,
+            });
+        }
+        Ok(listing)
+    }
+
+    async fn store_blob_info(&self, integrity: &Integrity, info: &BlobInfo) -> ServalResult<()> {
+        self.blob(integrity)?;
+        let mut described = self.info.write().unwrap();
+        if info.is_empty() {
+            described.remove(&integrity.to_string());
+        } else {
+            described.insert(integrity.to_string(), info.clone());
+        }
+        Ok(())
+    }
+
+    async fn blob_info(&self, integrity: &Integrity) -> ServalResult<Option<BlobInfo>> {
+        Ok(self
+            .info
+            .read()
+            .unwrap()
+            .get(&integrity.to_string())
+            .cloned())
+    }
+
+    async fn list_blob_info(
+        &self,
+        _label: Option<&str>,
+    ) -> ServalResult<Vec<(Integrity, BlobInfo)>> {
+        let described = self.info.read().unwrap();
+        let mut listing = Vec::with_capacity(described.len());
+        for (integrity, info) in described.iter() {
+            listing.push((integrity.parse()?, info.clone()));
+        }
+        Ok(listing)
+    }
+}
+
+// A shared blob that a Cursor can read from.
+struct ArcBytes(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for ArcBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
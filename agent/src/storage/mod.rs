@@ -1,410 +1,1674 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
 
 use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_s3::config::Region;
 use axum::body::StreamBody;
-use bytes::Bytes;
+use base64::Engine as _;
+use futures::future::BoxFuture;
 use once_cell::sync::OnceCell;
 use serval_client::ServalApiClient;
-use ssri::Integrity;
-use tokio::io::AsyncRead;
-use tokio_util::io::{ReaderStream, StreamReader};
+use ssri::{Algorithm, Integrity, IntegrityOpts};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::ReaderStream;
+use utils::diffs::apply_patch;
 use utils::errors::{ServalError, ServalResult};
 use utils::mesh::ServalRole;
+use utils::structs::api::{
+    CollectedBlob, GarbageCollection, ManifestListing, ManifestQuery, ManifestSummary,
+    ManifestVersion, NamespaceUsage,
+};
+use utils::structs::versions::{compare_versions, parse_version, VersionRequirement};
 use utils::structs::Manifest;
 
+pub mod backend;
+pub use backend::{BlobMetadata, ByteRange, StorageBackend};
+
 pub mod blobs;
 pub use blobs::*;
 
+pub mod cache;
+pub use cache::ReadCache;
+
 pub mod bucket;
 pub use bucket::S3Storage;
 
+pub mod bundles;
+
+pub mod compression;
+pub use compression::Compression;
+
+pub mod descriptions;
+
+pub mod diffs;
+pub use diffs::DiffCache;
+
+pub mod encryption;
+pub use encryption::Keyring;
+
+pub mod directory;
+pub use directory::DirectoryStorage;
+
+pub mod memory;
+pub use memory::MemoryStorage;
+
+pub mod quotas;
+pub use quotas::Quotas;
+use quotas::{
+    charge_key, key_charge_key, namespace_of, Charge, ChargeLocks, UsageChange, UsageRecord,
+    SHARED_NAMESPACE, USAGE_PREFIX,
+};
+
+pub mod references;
+use references::ReferenceIndex;
+
+pub mod replication;
+pub use replication::Replication;
+
+pub mod scrub;
+
+pub mod uploads;
+pub use uploads::Upload;
+
 use crate::structures::MESH;
 
+// Retention pins are stored as keys under this prefix, each holding a record of the pin that names
+// the pinned blob, so that the garbage collector sees the blob as referenced without a second
+// copy. Pin keys look like `_pins/sha256/abcd...`. A job's keys start with its namespace, which
+// can't start with an underscore, so they never collide with pins or any of storage's own records.
+const PIN_PREFIX: &str = "_pins/";
+
 // A convenient alias for an often-used stream type.
 type SendableStream = Pin<Box<dyn AsyncRead + Send + 'static>>;
 
 /// Our fully-configured storage object, with all of its details hidden.
 pub static STORAGE: OnceCell<Storage> = OnceCell::new();
 
-/// Initialize our local storage and a proxy option if we have no storage ourselves.
-pub async fn initialize(path: Option<PathBuf>) -> ServalResult<()> {
-    let local = if let Some(blobpath) = path {
-        match BlobStore::new(&blobpath) {
-            Ok(v) => Some(v),
-            Err(e) => {
-                log::warn!(
-                    "We requested a cacache store at {} but failed! error={e}",
-                    blobpath.display()
-                );
-                None
+/// Initialize our local storage and a proxy option if we have no storage ourselves. The instance id
+/// is this agent's, so that replication can tell our place among the mesh's storage nodes.
+pub async fn initialize(path: Option<PathBuf>, instance_id: &str) -> ServalResult<()> {
+    let mut backends: Vec<Arc<dyn StorageBackend>> = Vec::new();
+
+    // Local storage comes first, so reads are served from it whenever it can.
+    if let Some(blobpath) = path {
+        let kind = std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| "cacache".to_string());
+        match kind.as_str() {
+            "memory" => {
+                log::info!("in-memory storage enabled; nothing stored will outlive this agent");
+                backends.push(Arc::new(MemoryStorage::new()));
+            }
+            "cacache" => match BlobStore::new(&blobpath) {
+                Ok(v) => {
+                    let compression = Compression::from_env("STORAGE_COMPRESSION")?;
+                    if compression != Compression::None {
+                        log::info!("local blobs are compressed at rest; {compression:?}");
+                    }
+                    backends.push(Arc::new(v.with_compression(compression)));
+                }
+                Err(e) => {
+                    log::warn!(
+                        "We requested a cacache store at {} but failed! error={e}",
+                        blobpath.display()
+                    );
+                }
+            },
+            "directory" => match DirectoryStorage::new(&blobpath) {
+                Ok(v) => {
+                    log::info!("directory storage enabled at {}", blobpath.display());
+                    backends.push(Arc::new(v));
+                }
+                Err(e) => {
+                    log::warn!(
+                        "We requested a directory store at {} but failed! error={e}",
+                        blobpath.display()
+                    );
+                }
+            },
+            _ => {
+                log::warn!("Invalid value for STORAGE_BACKEND environment variable: {kind}");
             }
         }
-    } else {
-        None
-    };
+    }
 
-    let bucket = if let Ok(bucket_name) = std::env::var("STORAGE_BUCKET") {
+    if let Ok(bucket_name) = std::env::var("STORAGE_BUCKET") {
         let region_provider = RegionProviderChain::first_try(
             std::env::var("AWS_DEFAULT_REGION").ok().map(Region::new),
         )
         .or_default_provider()
         .or_else(Region::new("us-east-2"));
-        let config = aws_config::from_env().region(region_provider).load().await;
-        let bucket = S3Storage::new(&bucket_name, config)?;
-        log::info!("s3 storage bucket enabled at {bucket_name}");
-        Some(bucket)
-    } else {
-        None
+        let mut loader = aws_config::from_env().region(region_provider);
+        // For S3-compatible stores other than S3 itself, such as one running locally for testing.
+        if let Ok(endpoint) = std::env::var("STORAGE_BUCKET_ENDPOINT") {
+            loader = loader.endpoint_url(endpoint);
+        }
+        let config = loader.load().await;
+        let compression = Compression::from_env("STORAGE_BUCKET_COMPRESSION")?;
+        let mut bucket = S3Storage::new(&bucket_name, config)?.with_compression(compression);
+        log::info!("s3 storage bucket enabled at {bucket_name}; compression={compression:?}");
+        if let Some(keyring) = Keyring::from_env()? {
+            log::info!(
+                "s3 storage is encrypted; current key={}",
+                keyring.current_id()
+            );
+            bucket = bucket.with_encryption(keyring);
+        }
+        backends.push(Arc::new(bucket));
+    }
+
+    let read_policy = match std::env::var("STORAGE_READ_POLICY") {
+        Ok(policy) => policy.parse()?,
+        Err(_) => ReadPolicy::FirstHit,
+    };
+    let write_policy = match std::env::var("STORAGE_WRITE_POLICY") {
+        Ok(policy) => policy.parse()?,
+        Err(_) => WritePolicy::Any,
     };
 
-    let store = Storage::new(bucket, local);
+    let has_remote = backends.iter().any(|backend| backend.is_remote());
+    let mut store = Storage::new(backends, read_policy, write_policy)
+        .with_quotas(Quotas::from_env()?)
+        .with_diff_cache(DiffCache::from_env()?)
+        .with_upload_limit(uploads::upload_limit()?);
+    if let Some(cache) = ReadCache::from_env()? {
+        if has_remote {
+            store = store.with_cache(cache);
+        } else {
+            log::info!("no remote storage to cache reads from; ignoring STORAGE_CACHE_SIZE");
+        }
+    }
+    if store.has_storage() {
+        if let Some(replication) = Replication::from_env(instance_id)? {
+            log::info!(
+                "storage replication enabled; replicas={}",
+                replication.factor()
+            );
+            store = store.with_replication(replication);
+        }
+    }
     STORAGE.set(store).unwrap();
     Ok(())
 }
 
-/// This struct holds all the logic for juggling our three different ways of persisting data.
+/// How `Storage` chooses which backend answers a read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadPolicy {
+    /// Ask each backend in order, and answer with the first one that has the data.
+    FirstHit,
+}
+
+impl FromStr for ReadPolicy {
+    type Err = ServalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first-hit" => Ok(ReadPolicy::FirstHit),
+            _ => Err(ServalError::StorageError(format!(
+                "not a valid storage read policy `{s}`; try first-hit"
+            ))),
+        }
+    }
+}
+
+/// How `Storage` decides whether a write succeeded. Either way, every backend is asked to store
+/// the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// The write only succeeds if every backend stored the data.
+    All,
+    /// The write succeeds if any backend stored the data.
+    Any,
+}
+
+impl FromStr for WritePolicy {
+    type Err = ServalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "write-all" | "all" => Ok(WritePolicy::All),
+            "write-any" | "any" => Ok(WritePolicy::Any),
+            _ => Err(ServalError::StorageError(format!(
+                "not a valid storage write policy `{s}`; try write-all or write-any"
+            ))),
+        }
+    }
+}
+
+/// This struct holds all the logic for juggling the different ways we have of persisting data.
 ///
-/// If it has no storage options configured, it immediately proxies all reads and writes to
+/// If it has no storage backends configured, it immediately proxies all reads and writes to
 /// a freshly-discovered peer that advertises the role. If it has any storage configured,
 /// it will never attempt to proxy, lest we proxy in infinite loops.
 ///
-/// If the operation is a read operation, it tries local storage first then falls back to s3 storage
-/// if that is available. If it's a write operation, it will always try all configured options.
+/// Otherwise, reads and writes go to the backends, in order, as the read and write policies say.
 #[derive(Debug, Clone)]
 pub struct Storage {
-    bucket: Option<S3Storage>,
-    local: Option<BlobStore>,
+    backends: Vec<Arc<dyn StorageBackend>>,
+    read_policy: ReadPolicy,
+    write_policy: WritePolicy,
+    quotas: Quotas,
+    // Every namespace's usage, loaded from the backends the first time it's needed. The lock also
+    // keeps concurrent uploads from all squeezing in under the same quota. Once usage is loaded,
+    // it's only held while usage is checked and changed, never while records are read or written.
+    usage: Arc<tokio::sync::Mutex<Option<HashMap<String, UsageRecord>>>>,
+    // Each charge is locked while it's read and changed, so that two writes to the same key can't
+    // both be charged as if the other hadn't happened.
+    charges: ChargeLocks,
+    // Held while usage and charge records are written, and the records they replace discarded.
+    records: Arc<tokio::sync::Mutex<()>>,
+    // What every stored key refers to, built the first time it's needed.
+    references: Arc<tokio::sync::Mutex<Option<ReferenceIndex>>>,
+    replication: Option<Replication>,
+    cache: Option<ReadCache>,
+    diffs: DiffCache,
+    upload_limit: Option<u64>,
 }
 
 impl Storage {
-    pub fn new(bucket: Option<S3Storage>, local: Option<BlobStore>) -> Self {
-        Self { bucket, local }
+    pub fn new(
+        backends: Vec<Arc<dyn StorageBackend>>,
+        read_policy: ReadPolicy,
+        write_policy: WritePolicy,
+    ) -> Self {
+        Self {
+            backends,
+            read_policy,
+            write_policy,
+            quotas: Quotas::default(),
+            usage: Arc::default(),
+            charges: ChargeLocks::default(),
+            records: Arc::default(),
+            references: Arc::default(),
+            replication: None,
+            cache: None,
+            diffs: DiffCache::default(),
+            upload_limit: None,
+        }
     }
 
-    fn has_storage(&self) -> bool {
-        self.bucket.is_some() || self.local.is_some()
+    /// Limit how much each namespace may store.
+    pub fn with_quotas(mut self, quotas: Quotas) -> Self {
+        self.quotas = quotas;
+        self
     }
 
-    // This implementation is just a bunch of painful by-hand delegation logic.
-    // I'd like to golf it down.
+    /// Keep copies of what we store on other storage nodes in the mesh.
+    pub fn with_replication(mut self, replication: Replication) -> Self {
+        self.replication = Some(replication);
+        self
+    }
 
-    /// Store a blob of data in the content-addressable store, responding with the
-    /// integrity hash of the data.
-    pub async fn store_by_integrity(&self, bytes: &[u8]) -> ServalResult<Integrity> {
-        if !self.has_storage() {
-            let proxy = make_proxy_client().await?;
-            return proxy.store_by_integrity(bytes.to_vec()).await;
-        }
+    /// Keep local copies of what we read from remote backends.
+    pub fn with_cache(mut self, cache: ReadCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
 
-        let local_result = if let Some(local) = &self.local {
-            Some(local.store_by_integrity(bytes).await)
-        } else {
-            None
-        };
+    /// Keep the patches we make between blobs in this cache.
+    pub fn with_diff_cache(mut self, diffs: DiffCache) -> Self {
+        self.diffs = diffs;
+        self
+    }
 
-        let bucket_result = if let Some(bucket) = &self.bucket {
-            let integrity = Integrity::from(bytes);
-            Some(bucket.store_by_integrity(&integrity, bytes).await)
-        } else {
-            None
-        };
+    /// Refuse uploads bigger than this many bytes.
+    pub fn with_upload_limit(mut self, limit: Option<u64>) -> Self {
+        self.upload_limit = limit;
+        self
+    }
 
-        if let Some(result) = local_result {
-            result
-        } else if let Some(result) = bucket_result {
-            result
-        } else {
-            let integrity = Integrity::from(bytes);
-            Err(ServalError::StorageError(format!(
-                "all storage attempts failed for data blob; len={}; calculated integrity={integrity}",
-                bytes.len()
-            )))
-        }
+    /// The most bytes a single upload may hold, if there's a limit.
+    pub fn upload_limit(&self) -> Option<u64> {
+        self.upload_limit
     }
 
-    pub async fn stream_by_integrity(
-        &self,
-        integrity: Integrity,
-    ) -> ServalResult<StreamBody<ReaderStream<SendableStream>>> {
-        if !self.has_storage() {
-            let proxy = make_proxy_client().await?;
-            let bytes = proxy.stream_by_integrity(&integrity.to_string()).await?;
-            let reader = ReaderStream::new(vec_to_byte_stream(bytes));
-            return Ok(StreamBody::new(reader));
-        }
+    fn has_storage(&self) -> bool {
+        !self.backends.is_empty()
+    }
 
-        if let Some(local) = &self.local {
-            if let Ok(v) = local.stream_by_integrity(&integrity).await {
-                log::info!("serving from local blobs; {integrity}");
-                return Ok(StreamBody::new(v));
+    // Perform a read against our backends according to our read policy. Returns None if no backend
+    // could answer; what went wrong with each one is logged.
+    async fn read<'a, T>(
+        &'a self,
+        description: &str,
+        op: impl Fn(&'a dyn StorageBackend) -> BoxFuture<'a, ServalResult<T>>,
+    ) -> Option<T> {
+        match self.read_policy {
+            ReadPolicy::FirstHit => {
+                for backend in &self.backends {
+                    match op(backend.as_ref()).await {
+                        Ok(v) => {
+                            log::info!("serving from {}; {description}", backend.name());
+                            return Some(v);
+                        }
+                        Err(e) => {
+                            log::info!("error reading {}; {description}; {e:?}", backend.name());
+                        }
+                    }
+                }
+                None
             }
         }
+    }
 
-        if let Some(bucket) = &self.bucket {
-            if let Ok(bytestream) = bucket.stream_by_integrity(&integrity).await {
-                log::info!("serving from s3 bucket; {integrity}");
-                let readable = bytestream.into_async_read();
-                let pinned: SendableStream = Box::pin(readable);
-                let rs = ReaderStream::new(pinned);
-                return Ok(StreamBody::new(rs));
+    // Ask every backend whether it has something; true if any of them does.
+    async fn exists<'a>(
+        &'a self,
+        op: impl Fn(&'a dyn StorageBackend) -> BoxFuture<'a, ServalResult<bool>>,
+    ) -> bool {
+        for backend in &self.backends {
+            if let Ok(true) = op(backend.as_ref()).await {
+                return true;
             }
         }
-
-        Err(ServalError::DataNotFound(integrity.to_string()))
+        false
     }
 
-    /// Load data by its integrity into memory.
-    pub async fn data_by_integrity(&self, integrity: Integrity) -> ServalResult<Vec<u8>> {
-        let integrity_string = integrity.to_string();
-
-        if !self.has_storage() {
-            let proxy = make_proxy_client().await?;
-            let bytes = proxy.stream_by_integrity(&integrity_string).await?;
-            return Ok(bytes);
-        }
-
-        if let Some(local) = &self.local {
-            if let Ok(bytes) = local.data_by_key(&integrity_string).await {
-                log::info!("serving from local blobs; {integrity}");
-                return Ok(bytes);
+    // List the keys starting with the given prefix in all of our backends, combined. Fails only if
+    // no backend could answer.
+    async fn list_keys(&self, prefix: &str) -> ServalResult<Vec<String>> {
+        let mut keys: Vec<String> = Vec::new();
+        let mut answered = false;
+        for backend in &self.backends {
+            match backend.list_keys(prefix).await {
+                Ok(found) => {
+                    answered = true;
+                    keys.extend(found);
+                }
+                Err(e) => {
+                    log::warn!(
+                        "error listing keys in {}; prefix={prefix}; {e:?}",
+                        backend.name()
+                    );
+                }
             }
         }
+        if !answered {
+            return Err(ServalError::StorageError(format!(
+                "unable to list stored keys; prefix={prefix}"
+            )));
+        }
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
 
-        if let Some(bucket) = &self.bucket {
-            if let Ok(bytes) = bucket.data_by_key(&integrity_string).await {
-                log::info!("serving from s3 bucket; {integrity}");
-                return Ok(bytes);
+    // Perform a write against all of our backends, and decide whether it worked according to our
+    // write policy.
+    async fn write<'a>(
+        &'a self,
+        description: &str,
+        op: impl Fn(&'a dyn StorageBackend) -> BoxFuture<'a, ServalResult<Integrity>>,
+    ) -> ServalResult<Integrity> {
+        let mut stored: Option<Integrity> = None;
+        let mut failures: Vec<String> = Vec::new();
+        for backend in &self.backends {
+            match op(backend.as_ref()).await {
+                Ok(integrity) => {
+                    stored.get_or_insert(integrity);
+                }
+                Err(e) => {
+                    log::warn!("error writing {}; {description}; {e:?}", backend.name());
+                    failures.push(format!("{}: {e}", backend.name()));
+                }
             }
         }
 
-        Err(ServalError::DataNotFound(integrity.to_string()))
+        match (self.write_policy, stored) {
+            (WritePolicy::Any, Some(integrity)) => Ok(integrity),
+            (WritePolicy::All, Some(integrity)) if failures.is_empty() => Ok(integrity),
+            _ => Err(ServalError::StorageError(format!(
+                "storage attempts failed for {description}; {}",
+                failures.join("; ")
+            ))),
+        }
     }
 
-    /// Check if the given manifest is present in our store, using the fully-qualified name.
-    ///
-    /// Never checks a proxy; this is intended to be a local check.
-    pub async fn data_exists_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
-        if let Some(local) = &self.local {
-            if let Ok(_v) = local.data_exists_by_integrity(integrity).await {
-                return Ok(true);
+    // Perform a deletion against all of our backends. Responds with whether any backend had the
+    // thing to delete; fails only if no backend could answer.
+    async fn delete<'a>(
+        &'a self,
+        description: &str,
+        op: impl Fn(&'a dyn StorageBackend) -> BoxFuture<'a, ServalResult<bool>>,
+    ) -> ServalResult<bool> {
+        let mut deleted = false;
+        let mut failures: Vec<String> = Vec::new();
+        for backend in &self.backends {
+            match op(backend.as_ref()).await {
+                Ok(found) => deleted |= found,
+                Err(e) => {
+                    log::warn!(
+                        "error deleting from {}; {description}; {e:?}",
+                        backend.name()
+                    );
+                    failures.push(format!("{}: {e}", backend.name()));
+                }
             }
         }
+        if failures.len() == self.backends.len() {
+            return Err(ServalError::StorageError(format!(
+                "deletion failed for {description}; {}",
+                failures.join("; ")
+            )));
+        }
+        Ok(deleted)
+    }
 
-        if let Some(bucket) = &self.bucket {
-            if let Ok(_v) = bucket.data_exists_by_key(&integrity.to_string()).await {
-                return Ok(true);
-            }
+    // Every namespace's usage, held under the usage lock, and loaded from our backends the first
+    // time it's needed.
+    async fn lock_usage(
+        &self,
+    ) -> ServalResult<tokio::sync::MappedMutexGuard<'_, HashMap<String, UsageRecord>>> {
+        let mut usage = self.usage.lock().await;
+        if usage.is_none() {
+            *usage = Some(self.load_usage().await?);
         }
+        Ok(tokio::sync::MutexGuard::map(usage, |usage| {
+            usage.get_or_insert_with(HashMap::new)
+        }))
+    }
 
-        Ok(false)
+    // Make a change to a namespace's usage record under the usage lock, and persist the record once
+    // the lock is released. The update may refuse the change by returning an error, in which case
+    // nothing is persisted.
+    async fn update_usage<T>(
+        &self,
+        namespace: &str,
+        update: impl FnOnce(&mut UsageRecord) -> ServalResult<T>,
+    ) -> ServalResult<T> {
+        let result = {
+            let mut usage = self.lock_usage().await?;
+            update(usage.entry(namespace.to_string()).or_default())?
+        };
+        self.persist_usage(namespace).await;
+        Ok(result)
     }
 
-    /// Check if the given manifest is present in our store, using the fully-qualified name.
-    ///
-    /// Never checks a proxy; this is intended to be a local check.
-    pub async fn data_exists_by_key(&self, fq_name: &str) -> ServalResult<bool> {
-        let key = Manifest::make_manifest_key(fq_name);
+    // Charge a change to a namespace's usage, refusing it if it would exceed the namespace's quota.
+    async fn charge(&self, namespace: &str, change: &UsageChange) -> ServalResult<()> {
+        self.update_usage(namespace, |record| {
+            self.quotas.admit(namespace, record, change)?;
+            record.apply(change);
+            Ok(())
+        })
+        .await
+    }
 
-        // If we make a successful local check, we return only if we found it.
-        // We're going to fall back to bucket storage if we have it.
-        if let Some(local) = &self.local {
-            if let Ok(v) = local.data_exists_by_key(&key).await {
-                if v {
-                    return Ok(v);
-                }
-            }
+    // Record a change to a namespace's usage without checking its quota: for deletions, and for
+    // undoing a charge when the write it paid for failed. Failing to record it is logged, but
+    // isn't worth failing the caller over.
+    async fn adjust_usage(&self, namespace: &str, change: &UsageChange) {
+        let adjusted = self
+            .update_usage(namespace, |record| {
+                record.apply(change);
+                Ok(())
+            })
+            .await;
+        if let Err(e) = adjusted {
+            log::warn!("unable to update storage usage; namespace={namespace}; {e:?}");
         }
+    }
 
-        if let Some(bucket) = &self.bucket {
-            if let Ok(v) = bucket.data_exists_by_key(&key).await {
-                return Ok(v);
-            }
+    // Charge a namespace for a content-addressed blob, unless someone has already paid for it, and
+    // record who paid next to the blob. Responds with whether the namespace was charged.
+    async fn charge_blob(
+        &self,
+        namespace: &str,
+        integrity: &Integrity,
+        size: u64,
+    ) -> ServalResult<bool> {
+        let key = charge_key(integrity);
+        let _charge = self.charges.lock(&key).await;
+        if self
+            .exists(|backend| backend.data_exists_by_key(&key))
+            .await
+        {
+            return Ok(false);
+        }
+        self.charge(namespace, &UsageChange::added(size)).await?;
+        let charge = Charge {
+            namespace: namespace.to_string(),
+            bytes: size,
+        };
+        self.persist_charge(&key, &charge).await;
+        Ok(true)
+    }
+
+    // Credit whichever namespace was charged for a content-addressed blob, now that it's gone.
+    async fn credit_blob(&self, integrity: &Integrity) {
+        if let Err(e) = self.uncharge_blob(integrity, None).await {
+            log::warn!("unable to update storage usage; integrity={integrity}; {e:?}");
         }
+    }
 
-        Ok(false)
+    // Take back the charge for a content-addressed blob, if there is one and it was charged to the
+    // given namespace, or to anyone if none is given. Responds with the charge taken back.
+    async fn uncharge_blob(
+        &self,
+        integrity: &Integrity,
+        namespace: Option<&str>,
+    ) -> ServalResult<Option<Charge>> {
+        self.take_charge(&charge_key(integrity), namespace).await
     }
 
-    /// Fetch a manifest by its fully-qualified name.
-    pub async fn manifest(&self, fq_name: &str) -> ServalResult<Manifest> {
-        if !self.has_storage() {
-            let proxy = make_proxy_client().await?;
-            return proxy.get_manifest(fq_name).await;
+    // Credit whichever namespace was charged for what a key holds, now that it's gone or holds a
+    // replica a peer paid for. Keys that never held anything charged here have no charge to credit.
+    async fn credit_key(&self, key: &str) {
+        if let Err(e) = self.take_charge(&key_charge_key(key), None).await {
+            log::warn!("unable to update storage usage; key={key}; {e:?}");
         }
+    }
 
-        let key = Manifest::make_manifest_key(fq_name);
+    // Take back the charge recorded under the given key, if there is one and it was charged to the
+    // given namespace, or to anyone if none is given. Responds with the charge taken back.
+    async fn take_charge(
+        &self,
+        key: &str,
+        namespace: Option<&str>,
+    ) -> ServalResult<Option<Charge>> {
+        let _charge = self.charges.lock(key).await;
+        let Some(json) = self
+            .read(&format!("charge {key}"), |backend| backend.data_by_key(key))
+            .await
+        else {
+            return Ok(None);
+        };
+        let charge: Charge =
+            serde_json::from_slice(&json).map_err(|e| ServalError::StorageError(e.to_string()))?;
+        if namespace.is_some_and(|namespace| namespace != charge.namespace) {
+            return Ok(None);
+        }
+        {
+            let _records = self.records.lock().await;
+            self.delete_key(&format!("charge {key}"), key).await?;
+            self.discard_record(&Integrity::from(&json)).await;
+        }
+        let change = UsageChange::removed(charge.bytes);
+        self.update_usage(&charge.namespace, |record| {
+            record.apply(&change);
+            Ok(())
+        })
+        .await?;
+        Ok(Some(charge))
+    }
 
-        if let Some(local) = &self.local {
-            if let Ok(bytes) = local.data_by_key(&key).await {
-                if let Ok(data) = String::from_utf8(bytes) {
-                    let manifest: Manifest = toml::from_str(&data)?;
-                    return Ok(manifest);
+    // The charge recorded under the given key, if there is one.
+    async fn charge_recorded(&self, key: &str) -> ServalResult<Option<Charge>> {
+        let Some(json) = self
+            .read(&format!("charge {key}"), |backend| backend.data_by_key(key))
+            .await
+        else {
+            return Ok(None);
+        };
+        serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(|e| ServalError::StorageError(e.to_string()))
+    }
+
+    // Read every namespace's usage record from our backends.
+    async fn load_usage(&self) -> ServalResult<HashMap<String, UsageRecord>> {
+        let mut records = HashMap::new();
+        for key in self.list_keys(USAGE_PREFIX).await? {
+            let Some(bytes) = self
+                .read(&format!("usage {key}"), |backend| backend.data_by_key(&key))
+                .await
+            else {
+                continue;
+            };
+            let record: UsageRecord = match serde_json::from_slice(&bytes) {
+                Ok(record) => record,
+                Err(e) => {
+                    log::warn!("ignoring unreadable storage usage record; key={key}; {e:?}");
+                    continue;
                 }
-            }
+            };
+            let namespace = key[USAGE_PREFIX.len()..].to_string();
+            records.insert(namespace, record);
         }
+        Ok(records)
+    }
 
-        if let Some(bucket) = &self.bucket {
-            if let Ok(bytes) = bucket.data_by_key(&key).await {
-                if let Ok(data) = String::from_utf8(bytes) {
-                    let manifest: Manifest = toml::from_str(&data)?;
-                    return Ok(manifest);
-                }
+    // Write a namespace's usage record to our backends, discarding the one it replaces. Records are
+    // written one at a time, each with the namespace's usage as it is by then rather than as it was
+    // when it changed, so the last one written is never out of date.
+    async fn persist_usage(&self, namespace: &str) {
+        let _records = self.records.lock().await;
+        let record = match self.lock_usage().await {
+            Ok(usage) => usage.get(namespace).cloned().unwrap_or_default(),
+            Err(e) => {
+                log::warn!("unable to persist storage usage; namespace={namespace}; {e:?}");
+                return;
+            }
+        };
+        let key = format!("{USAGE_PREFIX}{namespace}");
+        let previous = self
+            .read(&format!("usage {namespace}"), |backend| {
+                backend.metadata_by_key(&key)
+            })
+            .await;
+        let persisted = match serde_json::to_vec(&record) {
+            Ok(json) => {
+                self.write_key(&format!("usage {namespace}"), &key, |backend| {
+                    backend.store_by_key(&key, &json)
+                })
+                .await
+            }
+            Err(e) => Err(ServalError::StorageError(e.to_string())),
+        };
+        match (persisted, previous) {
+            (Ok(stored), Some(previous)) if stored != previous.integrity => {
+                self.discard_record(&previous.integrity).await;
+            }
+            (Ok(_), _) => {}
+            (Err(e), _) => {
+                log::warn!("unable to persist storage usage; namespace={namespace}; {e:?}");
             }
         }
+    }
 
-        Err(ServalError::ManifestNotFound(fq_name.to_string()))
+    // Write a record of who paid for something to our backends under the given key, discarding the
+    // record it replaces. The charge's lock is held by the caller.
+    async fn persist_charge(&self, key: &str, charge: &Charge) {
+        let _records = self.records.lock().await;
+        let previous = self
+            .read(&format!("charge {key}"), |backend| {
+                backend.metadata_by_key(key)
+            })
+            .await;
+        let persisted = match serde_json::to_vec(charge) {
+            Ok(json) => {
+                self.write_key(&format!("charge {key}"), key, |backend| {
+                    backend.store_by_key(key, &json)
+                })
+                .await
+            }
+            Err(e) => Err(ServalError::StorageError(e.to_string())),
+        };
+        match (persisted, previous) {
+            (Ok(stored), Some(previous)) if stored != previous.integrity => {
+                self.discard_record(&previous.integrity).await;
+            }
+            (Ok(_), _) => {}
+            (Err(e), _) => {
+                log::warn!("unable to persist storage charge; key={key}; {e:?}");
+            }
+        }
     }
 
-    /// Store a Wasm manifest. Returns the integrity checksum.
-    pub async fn store_manifest(&self, manifest: &Manifest) -> ServalResult<Integrity> {
-        if !self.has_storage() {
-            let proxy = make_proxy_client().await?;
-            return proxy.store_manifest(manifest).await;
+    // Delete the blob that held one of our usage or charge records before the record was rewritten
+    // or deleted, so that they don't pile up until garbage is collected. Records are written under
+    // the records lock, and so is this. The same bytes may still be another key's data, or a blob that
+    // someone uploaded and paid for; then they stay.
+    async fn discard_record(&self, integrity: &Integrity) {
+        match self.is_referenced(integrity).await {
+            Ok(false) => {}
+            Ok(true) => return,
+            Err(e) => {
+                log::warn!("unable to discard a superseded record; integrity={integrity}; {e:?}");
+                return;
+            }
+        }
+        let key = charge_key(integrity);
+        if self
+            .exists(|backend| backend.data_exists_by_key(&key))
+            .await
+        {
+            return;
         }
+        let discarded = self
+            .delete(&format!("record {integrity}"), |backend| {
+                backend.delete_by_integrity(integrity)
+            })
+            .await;
+        if let Err(e) = discarded {
+            log::warn!("unable to discard a superseded record; integrity={integrity}; {e:?}");
+        }
+        self.uncache(integrity).await;
+    }
 
-        let toml = toml::to_string(manifest)?;
-        let key = manifest.manifest_key();
+    // The size of whatever is stored under the given key, if anything is.
+    async fn size_of_key(&self, key: &str) -> Option<u64> {
+        self.read(&format!("key {key}"), |backend| {
+            backend.metadata_by_key(key)
+        })
+        .await
+        .map(|metadata| metadata.size)
+    }
 
-        let local_result = if let Some(local) = &self.local {
-            Some(local.store_by_key(&key, toml.as_bytes()).await)
-        } else {
-            None
+    // Store `size` bytes of data by key with the given write, on behalf of a namespace, charging it
+    // for the data if its quota allows. Replacing data the namespace already paid for here only
+    // charges the difference; replacing anything else, such as a replica a peer sent us, is charged
+    // in full.
+    async fn store_charged_by_key<'a>(
+        &'a self,
+        namespace: &str,
+        key: &str,
+        description: &str,
+        size: u64,
+        op: impl Fn(&'a dyn StorageBackend) -> BoxFuture<'a, ServalResult<Integrity>>,
+    ) -> ServalResult<Integrity> {
+        let charge_key = key_charge_key(key);
+        // Held until this write's charge is recorded, so that another write to the key goes by it.
+        let charge_lock = self.charges.lock(&charge_key).await;
+        let change = match self.charge_recorded(&charge_key).await? {
+            Some(previous) => UsageChange::replaced(previous.bytes, size),
+            None => UsageChange::added(size),
         };
+        self.charge(namespace, &change).await?;
+        let stored = self.write_key(description, key, op).await;
+        match &stored {
+            Ok(_) => {
+                let charge = Charge {
+                    namespace: namespace.to_string(),
+                    bytes: size,
+                };
+                self.persist_charge(&charge_key, &charge).await;
+                drop(charge_lock);
+                self.replicate_key(key).await;
+            }
+            Err(_) => self.adjust_usage(namespace, &change.reversed()).await,
+        }
+        stored
+    }
 
-        let bucket_result = if let Some(bucket) = &self.bucket {
-            Some(bucket.store_by_key(&key, toml.as_bytes()).await)
-        } else {
-            None
-        };
+    // Store a blob with the given integrity and size in the content-addressable store with the
+    // given write, charging the namespace for it unless we already have it.
+    async fn store_charged_blob<'a>(
+        &'a self,
+        namespace: Option<&str>,
+        integrity: &Integrity,
+        size: u64,
+        op: impl Fn(&'a dyn StorageBackend) -> BoxFuture<'a, ServalResult<Integrity>>,
+    ) -> ServalResult<Integrity> {
+        let description = format!("data blob; len={size}");
+        // Someone has already paid for a blob we have.
+        if self.data_exists_by_integrity(integrity).await? {
+            let stored = self.write(&description, op).await?;
+            self.replicate_blob(&stored).await;
+            return Ok(stored);
+        }
 
-        if let Some(result) = local_result {
-            result
-        } else if let Some(result) = bucket_result {
-            result
-        } else {
-            Err(ServalError::StorageError(format!(
-                "all storage attempts failed for manifest {}",
-                manifest.fq_name()
-            )))
+        let namespace = namespace.unwrap_or(SHARED_NAMESPACE);
+        let charged = self.charge_blob(namespace, integrity, size).await?;
+
+        let stored = self.write(&description, op).await;
+        match &stored {
+            Ok(stored) => self.replicate_blob(stored).await,
+            Err(_) if charged => self.credit_blob(integrity).await,
+            Err(_) => {}
         }
+        stored
     }
 
-    /// Fetch an executable by key as a read stream.
-    pub async fn executable_as_stream(
+    /// Store a blob of data in the content-addressable store, responding with the
+    /// integrity hash of the data. New blobs count against the storage quota of the given
+    /// namespace, or of a namespace shared by everyone who doesn't give one.
+    pub async fn store_by_integrity(
         &self,
-        name: &str,
-        version: &str,
-    ) -> ServalResult<StreamBody<ReaderStream<SendableStream>>> {
-        // Here we do gear changing to shift the disparate types from the various
-        // clients into the singular type that the agent callers expect.
+        namespace: Option<&str>,
+        bytes: &[u8],
+    ) -> ServalResult<Integrity> {
         if !self.has_storage() {
             let proxy = make_proxy_client().await?;
-            let bytes = proxy.get_executable(name, version).await?;
-            let reader = ReaderStream::new(vec_to_byte_stream(bytes));
-            return Ok(StreamBody::new(reader));
+            return proxy.store_by_integrity(namespace, bytes.to_vec()).await;
         }
 
-        let key = Manifest::make_executable_key(name, version);
+        let integrity = Integrity::from(bytes);
+        self.store_charged_blob(namespace, &integrity, bytes.len() as u64, |backend| {
+            backend.store_by_integrity(bytes)
+        })
+        .await
+    }
 
-        if let Some(local) = &self.local {
-            match local.stream_by_key(&key).await {
-                Ok(reader) => {
-                    let body = StreamBody::new(reader);
-                    return Ok(body);
-                }
-                Err(e) => {
-                    log::info!("error reading blob storage; key={name}@{version}; {e:?}");
-                }
-            }
+    /// Store an upload in the content-addressable store, streaming it to each backend in turn.
+    /// Otherwise just like store_by_integrity().
+    pub async fn store_upload_by_integrity(
+        &self,
+        namespace: Option<&str>,
+        upload: &Upload,
+    ) -> ServalResult<Integrity> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy
+                .store_by_integrity(namespace, upload.bytes().await?)
+                .await;
         }
 
-        if let Some(bucket) = &self.bucket {
-            match bucket.stream_by_key(&key).await {
-                Ok(bytestream) => {
-                    let readable = bytestream.into_async_read();
-                    let pinned: SendableStream = Box::pin(readable);
-                    let rs = ReaderStream::new(pinned);
-                    let body = StreamBody::new(rs);
-                    return Ok(body);
-                }
-                Err(e) => {
-                    log::info!("error reading bucket storage; key={name}@{version}; {e:?}");
-                }
-            }
-        }
+        self.store_charged_blob(namespace, upload.integrity(), upload.size(), |backend| {
+            Box::pin(async move {
+                let stream = upload.open().await?;
+                backend
+                    .store_stream_by_integrity(upload.integrity(), upload.size(), stream)
+                    .await
+            })
+        })
+        .await
+    }
 
-        Err(ServalError::ExecutableNotFound(format!("{name}@{version}")))
+    pub async fn stream_by_integrity(
+        &self,
+        integrity: Integrity,
+    ) -> ServalResult<StreamBody<ReaderStream<SendableStream>>> {
+        let stream = self.blob_stream(&integrity).await?;
+        Ok(StreamBody::new(ReaderStream::new(stream)))
     }
 
-    /// Fetch the bytes of the named executable so we can run it.
-    pub async fn executable_as_bytes(&self, name: &str, version: &str) -> ServalResult<Vec<u8>> {
+    // Read a blob from wherever it can be found: the proxy if we have no storage of our own, our
+    // backends through the cache, or our peers.
+    async fn blob_stream(&self, integrity: &Integrity) -> ServalResult<SendableStream> {
         if !self.has_storage() {
             let proxy = make_proxy_client().await?;
-            return proxy.get_executable(name, version).await;
+            let bytes = proxy.stream_by_integrity(&integrity.to_string()).await?;
+            return Ok(vec_to_byte_stream(bytes));
         }
 
-        let key = Manifest::make_executable_key(name, version);
-
-        if let Some(local) = &self.local {
-            if let Ok(v) = local.data_by_key(&key).await {
-                return Ok(v);
-            }
+        match self.cached_blob_stream(integrity).await {
+            Some(stream) => Ok(stream),
+            None => self
+                .replicated_blob(integrity)
+                .await
+                .ok_or_else(|| ServalError::DataNotFound(integrity.to_string())),
         }
+    }
 
-        if let Some(bucket) = &self.bucket {
-            if let Ok(v) = bucket.data_by_key(&key).await {
-                return Ok(v);
+    /// Apply a bsdiff patch to the blob with the given integrity, and store the result, charging
+    /// it to the given namespace. bsdiff needs the original and the patch in memory to work, but
+    /// the original is read as a stream from wherever it is, and the patch from where its upload was
+    /// spooled. Results bigger than an upload may be are refused.
+    pub async fn patch_by_integrity(
+        &self,
+        namespace: Option<&str>,
+        integrity: &Integrity,
+        patch: &Upload,
+    ) -> ServalResult<BlobMetadata> {
+        let mut original = Vec::new();
+        self.blob_stream(integrity)
+            .await?
+            .read_to_end(&mut original)
+            .await?;
+        let patch = patch.bytes().await?;
+        // Like making a patch, applying one is all computation.
+        let updated = tokio::task::spawn_blocking(move || apply_patch(&original, &patch))
+            .await
+            .map_err(|e| ServalError::StorageError(format!("unable to apply a patch; {e}")))?
+            .map_err(|_| ServalError::StorageError("patch could not be applied".to_string()))?;
+        let size = updated.len() as u64;
+        if let Some(limit) = self.upload_limit() {
+            if size > limit {
+                return Err(ServalError::PayloadTooLarge(format!(
+                    "uploads may be at most {limit} bytes"
+                )));
             }
         }
-
-        Err(ServalError::ExecutableNotFound(format!("{name}@{version}")))
+        let integrity = self.store_by_integrity(namespace, &updated).await?;
+        Ok(BlobMetadata { integrity, size })
     }
 
-    /// Store an executable in the target node's blob store by its fully-qualified
-    /// manifest name and a version string.
-    pub async fn store_executable(
-        &self,
-        name: &str,
-        version: &str,
-        bytes: &[u8],
-    ) -> ServalResult<Integrity> {
+    /// Load data by its integrity into memory.
+    pub async fn data_by_integrity(&self, integrity: Integrity) -> ServalResult<Vec<u8>> {
+        let integrity_string = integrity.to_string();
+
         if !self.has_storage() {
             let proxy = make_proxy_client().await?;
-            return proxy.store_executable(name, version, bytes.to_vec()).await;
+            let bytes = proxy.stream_by_integrity(&integrity_string).await?;
+            return Ok(bytes);
         }
 
-        let key = Manifest::make_executable_key(name, version);
-        let local_result = if let Some(local) = &self.local {
-            Some(local.store_by_key(&key, bytes).await)
-        } else {
-            None
-        };
+        if let Some(bytes) = self.cached_blob(&integrity).await {
+            return Ok(bytes);
+        }
+        self.replicated_blob_data(&integrity)
+            .await
+            .ok_or(ServalError::DataNotFound(integrity_string))
+    }
 
-        let bucket_result = if let Some(bucket) = &self.bucket {
-            Some(bucket.store_by_key(&key, bytes).await)
-        } else {
-            None
-        };
+    /// Describe the blob with the given integrity, without fetching it if we can help it.
+    pub async fn metadata_by_integrity(&self, integrity: &Integrity) -> ServalResult<BlobMetadata> {
+        if !self.has_storage() {
+            let bytes = self.data_by_integrity(integrity.clone()).await?;
+            return Ok(BlobMetadata {
+                integrity: integrity.clone(),
+                size: bytes.len() as u64,
+            });
+        }
 
-        if let Some(result) = local_result {
-            result
-        } else if let Some(result) = bucket_result {
-            result
-        } else {
-            Err(ServalError::StorageError(format!(
-                "all storage attempts failed for executable {}@{}",
-                name, version
-            )))
+        let description = integrity.to_string();
+        if let Some(metadata) = self
+            .read(&description, |backend| {
+                backend.metadata_by_integrity(integrity)
+            })
+            .await
+        {
+            return Ok(metadata);
+        }
+        match self.replicated_blob_size(integrity).await {
+            Some(size) => Ok(BlobMetadata {
+                integrity: integrity.clone(),
+                size,
+            }),
+            None => Err(ServalError::DataNotFound(description)),
         }
     }
-}
 
-// Convenience function to make a proxy client for a freshly-selected peer.
-async fn make_proxy_client() -> ServalResult<ServalApiClient> {
-    let mesh = MESH.get().expect("Peer network not initialized!"); // yes, we crash in this case
-    let peers = mesh.peers_with_role(&ServalRole::Storage).await;
-    let iter = peers.iter();
+    /// Fetch part of the blob with the given integrity as a stream. The range must lie within the
+    /// blob; see metadata_by_integrity() for its size.
+    pub async fn stream_range_by_integrity(
+        &self,
+        integrity: &Integrity,
+        range: ByteRange,
+    ) -> ServalResult<StreamBody<ReaderStream<SendableStream>>> {
+        if !self.has_storage() {
+            let bytes = self.data_by_integrity(integrity.clone()).await?;
+            let reader = ReaderStream::new(vec_to_byte_stream(range.of_bytes(bytes)?));
+            return Ok(StreamBody::new(reader));
+        }
+
+        match self.cached_blob_range(integrity, range).await {
+            Some(stream) => Ok(StreamBody::new(ReaderStream::new(stream))),
+            None => match self.replicated_blob(integrity).await {
+                Some(stream) => Ok(StreamBody::new(ReaderStream::new(
+                    range.of_stream(stream).await?,
+                ))),
+                None => Err(ServalError::DataNotFound(integrity.to_string())),
+            },
+        }
+    }
+
+    /// Check if the given manifest is present in our store, using the fully-qualified name.
+    ///
+    /// Never checks a proxy; this is intended to be a local check.
+    pub async fn data_exists_by_integrity(&self, integrity: &Integrity) -> ServalResult<bool> {
+        Ok(self
+            .exists(|backend| backend.data_exists_by_integrity(integrity))
+            .await)
+    }
+
+    /// Check if the given manifest is present in our store, using the fully-qualified name.
+    ///
+    /// Never checks a proxy; this is intended to be a local check.
+    pub async fn data_exists_by_key(&self, fq_name: &str) -> ServalResult<bool> {
+        let key = Manifest::make_manifest_key(fq_name);
+        Ok(self
+            .exists(|backend| backend.data_exists_by_key(&key))
+            .await)
+    }
+
+    /// Fetch a manifest by its fully-qualified name.
+    pub async fn manifest(&self, fq_name: &str) -> ServalResult<Manifest> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.get_manifest(fq_name).await;
+        }
+
+        let key = Manifest::make_manifest_key(fq_name);
+        self.read_manifest(&key, fq_name).await
+    }
+
+    /// Fetch a specific version of a manifest by its fully-qualified name.
+    pub async fn manifest_version(&self, fq_name: &str, version: &str) -> ServalResult<Manifest> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.get_manifest_version(fq_name, version).await;
+        }
+
+        let key = Manifest::make_versioned_manifest_key(fq_name, version);
+        match self
+            .read_manifest(&key, &format!("{fq_name}@{version}"))
+            .await
+        {
+            // Manifests stored before every version was kept have only the key for the latest.
+            Err(ServalError::ManifestNotFound(missing)) => match self.manifest(fq_name).await {
+                Ok(latest) if latest.version() == version => Ok(latest),
+                _ => Err(ServalError::ManifestNotFound(missing)),
+            },
+            result => result,
+        }
+    }
+
+    /// Fetch the highest stored version of a manifest that satisfies the requirement.
+    pub async fn resolve_manifest(
+        &self,
+        fq_name: &str,
+        requirement: &VersionRequirement,
+    ) -> ServalResult<Manifest> {
+        let versions: Vec<String> = if self.has_storage() {
+            self.stored_versions(fq_name).await?
+        } else {
+            let proxy = make_proxy_client().await?;
+            let listing = proxy.manifest_versions(fq_name).await?;
+            listing.into_iter().map(|v| v.version).collect()
+        };
+
+        let Some(version) = requirement.resolve(versions.iter().map(String::as_str)) else {
+            return Err(ServalError::ManifestNotFound(format!(
+                "{fq_name}@{requirement}"
+            )));
+        };
+        self.manifest_version(fq_name, version).await
+    }
+
+    // Load and parse the manifest stored under the given key.
+    async fn read_manifest(&self, key: &str, description: &str) -> ServalResult<Manifest> {
+        let local = self
+            .read(&format!("manifest {description}"), |backend| {
+                backend.data_by_key(key)
+            })
+            .await;
+        let Some(bytes) = (match local {
+            Some(bytes) => Some(bytes),
+            None => self.replicated_key_data(key).await,
+        }) else {
+            return Err(ServalError::ManifestNotFound(description.to_string()));
+        };
+        let data = String::from_utf8(bytes)?;
+        let manifest: Manifest = toml::from_str(&data)?;
+        Ok(manifest)
+    }
+
+    // Every version of the named manifest that our backends hold, in order of precedence.
+    async fn stored_versions(&self, fq_name: &str) -> ServalResult<Vec<String>> {
+        let keys = self.list_keys(&Manifest::make_key_prefix(fq_name)).await?;
+        let mut versions: Vec<String> = keys
+            .iter()
+            .filter_map(|key| match Manifest::parse_manifest_key(key) {
+                Some((name, Some(version))) if name == fq_name => Some(version),
+                _ => None,
+            })
+            .collect();
+        // Manifests stored before every version was kept have only the key for the latest.
+        if versions.is_empty() && keys.contains(&Manifest::make_manifest_key(fq_name)) {
+            versions.push(self.manifest(fq_name).await?.version().to_string());
+        }
+        if versions.is_empty() {
+            return Err(ServalError::ManifestNotFound(fq_name.to_string()));
+        }
+        versions.sort_by(|left, right| compare_versions(left, right));
+        Ok(versions)
+    }
+
+    /// Store a Wasm manifest. Returns the integrity checksum. Its version must be a valid semantic
+    /// version.
+    pub async fn store_manifest(&self, manifest: &Manifest) -> ServalResult<Integrity> {
+        parse_version(manifest.version())?;
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.store_manifest(manifest).await;
+        }
+
+        let toml = toml::to_string(manifest)?;
+        let description = format!("manifest {}@{}", manifest.fq_name(), manifest.version());
+        // Keep this version around for good. This is the copy its namespace pays for.
+        let versioned_key = manifest.versioned_manifest_key();
+        let integrity = self
+            .store_charged_by_key(
+                manifest.namespace(),
+                &versioned_key,
+                &description,
+                toml.len() as u64,
+                |backend| backend.store_by_key(&versioned_key, toml.as_bytes()),
+            )
+            .await?;
+
+        // Then make it the one you get by name alone, if it's the latest by the rules `@latest`
+        // uses. Storing an older version, or a pre-release, doesn't change what callers get.
+        let is_latest = match self.manifest(&manifest.fq_name()).await {
+            Ok(current) => {
+                VersionRequirement::Latest.resolve([current.version(), manifest.version()])
+                    == Some(manifest.version())
+            }
+            Err(_) => true,
+        };
+        if !is_latest {
+            return Ok(integrity);
+        }
+        let key = manifest.manifest_key();
+        let stored = self
+            .write_key(&description, &key, |backend| {
+                backend.store_by_key(&key, toml.as_bytes())
+            })
+            .await?;
+        self.replicate_key(&key).await;
+        Ok(stored)
+    }
+
+    /// List the latest versions of the stored manifests that match the query, a page at a time.
+    pub async fn list_manifests(&self, query: &ManifestQuery) -> ServalResult<ManifestListing> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.list_manifests(query).await;
+        }
+
+        let prefix = match &query.namespace {
+            Some(namespace) => format!("{namespace}."),
+            None => String::new(),
+        };
+        let names: Vec<String> = self
+            .list_keys(&prefix)
+            .await?
+            .iter()
+            .filter_map(|key| match Manifest::parse_manifest_key(key) {
+                Some((name, None)) => Some(name),
+                _ => None,
+            })
+            .collect();
+        let (page, total) = query.apply(names);
+
+        let offset = query.offset.unwrap_or(0);
+        let next_offset = (offset + page.len() < total).then_some(offset + page.len());
+        let mut manifests = Vec::with_capacity(page.len());
+        for name in page {
+            let manifest = self.manifest(&name).await?;
+            manifests.push(ManifestSummary::from(&manifest));
+        }
+
+        Ok(ManifestListing {
+            manifests,
+            total,
+            next_offset,
+        })
+    }
+
+    /// List every stored version of the named manifest, along with the executable stored for each.
+    pub async fn manifest_versions(&self, fq_name: &str) -> ServalResult<Vec<ManifestVersion>> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.manifest_versions(fq_name).await;
+        }
+
+        let versions = self.stored_versions(fq_name).await?;
+        let mut listing = Vec::with_capacity(versions.len());
+        for version in versions {
+            let key = Manifest::make_executable_key(fq_name, &version);
+            let description = format!("executable {fq_name}@{version}");
+            let metadata = self
+                .read(&description, |backend| backend.metadata_by_key(&key))
+                .await;
+            listing.push(ManifestVersion {
+                version,
+                integrity: metadata.as_ref().map(|m| m.integrity.to_string()),
+                size: metadata.map(|m| m.size),
+            });
+        }
+        Ok(listing)
+    }
+
+    /// Fetch an executable by key as a read stream.
+    pub async fn executable_as_stream(
+        &self,
+        name: &str,
+        version: &str,
+    ) -> ServalResult<StreamBody<ReaderStream<SendableStream>>> {
+        let stream = self.executable_stream(name, version).await?;
+        Ok(StreamBody::new(ReaderStream::new(stream)))
+    }
+
+    // Here we do gear changing to shift the disparate types from the various
+    // clients into the singular type that the agent callers expect.
+    async fn executable_stream(&self, name: &str, version: &str) -> ServalResult<SendableStream> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            let bytes = proxy.get_executable(name, version).await?;
+            return Ok(vec_to_byte_stream(bytes));
+        }
+
+        let key = Manifest::make_executable_key(name, version);
+        let description = format!("executable {name}@{version}");
+        match self.cached_key_stream(&key, &description).await {
+            Some(stream) => Ok(stream),
+            None => self
+                .replicated_key(&key)
+                .await
+                .ok_or_else(|| ServalError::ExecutableNotFound(format!("{name}@{version}"))),
+        }
+    }
+
+    /// Fetch the bytes of the named executable so we can run it.
+    pub async fn executable_as_bytes(&self, name: &str, version: &str) -> ServalResult<Vec<u8>> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.get_executable(name, version).await;
+        }
+
+        let key = Manifest::make_executable_key(name, version);
+        let description = format!("executable {name}@{version}");
+        if let Some(bytes) = self.cached_key(&key, &description).await {
+            return Ok(bytes);
+        }
+        self.replicated_key_data(&key)
+            .await
+            .ok_or_else(|| ServalError::ExecutableNotFound(format!("{name}@{version}")))
+    }
+
+    /// Describe the named executable, without fetching it if we can help it.
+    pub async fn executable_metadata(
+        &self,
+        name: &str,
+        version: &str,
+    ) -> ServalResult<BlobMetadata> {
+        if !self.has_storage() {
+            // The proxy can only hand us the whole executable, so read it through to describe it.
+            let stream = self.executable_stream(name, version).await?;
+            return describe_stream(stream).await;
+        }
+
+        let key = Manifest::make_executable_key(name, version);
+        let description = format!("executable {name}@{version}");
+        if let Some(metadata) = self
+            .read(&description, |backend| backend.metadata_by_key(&key))
+            .await
+        {
+            return Ok(metadata);
+        }
+        self.replicated_key_metadata(&key)
+            .await
+            .ok_or_else(|| ServalError::ExecutableNotFound(format!("{name}@{version}")))
+    }
+
+    /// Fetch part of the named executable as a stream. The range must lie within the executable;
+    /// see executable_metadata() for its size.
+    pub async fn executable_range_as_stream(
+        &self,
+        name: &str,
+        version: &str,
+        range: ByteRange,
+    ) -> ServalResult<StreamBody<ReaderStream<SendableStream>>> {
+        if self.has_storage() {
+            let key = Manifest::make_executable_key(name, version);
+            let description = format!("executable {name}@{version}");
+            if let Some(metadata) = self
+                .read(&description, |backend| backend.metadata_by_key(&key))
+                .await
+            {
+                if let Some(stream) = self.cached_blob_range(&metadata.integrity, range).await {
+                    return Ok(StreamBody::new(ReaderStream::new(stream)));
+                }
+            }
+        }
+        let stream = self.executable_stream(name, version).await?;
+        let stream = range.of_stream(stream).await?;
+        Ok(StreamBody::new(ReaderStream::new(stream)))
+    }
+
+    /// Store an uploaded executable in the target node's blob store by its fully-qualified
+    /// manifest name and a version string, which must be a valid semantic version. The upload is
+    /// streamed to each backend in turn.
+    pub async fn store_executable(
+        &self,
+        name: &str,
+        version: &str,
+        upload: &Upload,
+    ) -> ServalResult<Integrity> {
+        parse_version(version)?;
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy
+                .store_executable(name, version, upload.bytes().await?)
+                .await;
+        }
+
+        let key = Manifest::make_executable_key(name, version);
+        let description = format!("executable {name}@{version}");
+        self.store_charged_by_key(
+            namespace_of(name),
+            &key,
+            &description,
+            upload.size(),
+            |backend| {
+                let key = &key;
+                Box::pin(async move {
+                    let stream = upload.open().await?;
+                    backend
+                        .store_stream_by_key(key, upload.integrity(), upload.size(), stream)
+                        .await
+                })
+            },
+        )
+        .await
+    }
+
+    /// Make a blob that's already in the content-addressable store the executable for the given
+    /// version of the named manifest, without storing it again. If the blob was charged to the
+    /// manifest's namespace, as a blob a patch made would be, the namespace pays for it as the
+    /// executable instead.
+    pub async fn store_executable_from_blob(
+        &self,
+        name: &str,
+        version: &str,
+        integrity: &Integrity,
+    ) -> ServalResult<Integrity> {
+        parse_version(version)?;
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy
+                .store_executable_from_blob(name, version, &integrity.to_string())
+                .await;
+        }
+
+        let size = self.metadata_by_integrity(integrity).await?.size;
+        let namespace = namespace_of(name);
+        let released = self.uncharge_blob(integrity, Some(namespace)).await?;
+        let key = Manifest::make_executable_key(name, version);
+        let description = format!("executable {name}@{version}");
+        let stored = self
+            .store_charged_by_key(namespace, &key, &description, size, |backend| {
+                backend.link_key(&key, integrity)
+            })
+            .await;
+        if let (Err(_), Some(charge)) = (&stored, released) {
+            if let Err(e) = self
+                .charge_blob(&charge.namespace, integrity, charge.bytes)
+                .await
+            {
+                log::warn!("unable to update storage usage; integrity={integrity}; {e:?}");
+            }
+        }
+        stored
+    }
+
+    /// Delete every version of the named manifest, along with their executables. The blobs they
+    /// referred to stay in the content-addressable store until garbage is collected.
+    pub async fn delete_manifest(&self, fq_name: &str) -> ServalResult<()> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.delete_manifest(fq_name).await;
+        }
+
+        // Manifests in namespaces that extend this name share the prefix, so they're left out.
+        let keys: Vec<String> = self
+            .list_keys(&Manifest::make_key_prefix(fq_name))
+            .await?
+            .into_iter()
+            .filter(|key| {
+                let manifest = Manifest::parse_manifest_key(key).map(|(name, _)| name);
+                let executable = Manifest::parse_executable_key(key).map(|(name, _)| name);
+                manifest.or(executable).as_deref() == Some(fq_name)
+            })
+            .collect();
+        if keys.is_empty() {
+            return Err(ServalError::ManifestNotFound(fq_name.to_string()));
+        }
+        for key in keys {
+            let deleted = self.delete_key(&format!("key {key}"), &key).await?;
+            self.delete_replicated_key(&key).await;
+            if deleted {
+                self.credit_key(&key).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete one version of the named manifest, along with its executable. If it was the version
+    /// you get by name alone, the next-latest version takes its place.
+    pub async fn delete_manifest_version(&self, fq_name: &str, version: &str) -> ServalResult<()> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.delete_manifest_version(fq_name, version).await;
+        }
+
+        let description = format!("manifest {fq_name}@{version}");
+        let versioned_key = Manifest::make_versioned_manifest_key(fq_name, version);
+        if self.size_of_key(&versioned_key).await.is_none() {
+            // Manifests stored before every version was kept have only the key for the latest.
+            let is_latest = matches!(
+                self.manifest(fq_name).await,
+                Ok(latest) if latest.version() == version
+            );
+            if !is_latest {
+                return Err(ServalError::ManifestNotFound(format!(
+                    "{fq_name}@{version}"
+                )));
+            }
+        }
+        self.delete_key(&description, &versioned_key).await?;
+        self.delete_replicated_key(&versioned_key).await;
+        self.credit_key(&versioned_key).await;
+        let executable_key = Manifest::make_executable_key(fq_name, version);
+        if self.size_of_key(&executable_key).await.is_some() {
+            self.delete_key(&format!("executable {fq_name}@{version}"), &executable_key)
+                .await?;
+            self.delete_replicated_key(&executable_key).await;
+            self.credit_key(&executable_key).await;
+        }
+
+        let current = match self.manifest(fq_name).await {
+            Ok(current) => current,
+            Err(_) => return Ok(()),
+        };
+        if current.version() != version {
+            return Ok(());
+        }
+        let key = Manifest::make_manifest_key(fq_name);
+        let mut remaining = match self.stored_versions(fq_name).await {
+            Ok(remaining) => remaining,
+            Err(ServalError::ManifestNotFound(_)) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        // Which would still include this version if it was only ever stored as the latest.
+        remaining.retain(|remaining| remaining != version);
+        match VersionRequirement::Latest.resolve(remaining.iter().map(String::as_str)) {
+            Some(latest) => {
+                let manifest = self.manifest_version(fq_name, latest).await?;
+                let toml = toml::to_string(&manifest)?;
+                self.write_key(&description, &key, |backend| {
+                    backend.store_by_key(&key, toml.as_bytes())
+                })
+                .await?;
+                self.replicate_key(&key).await;
+            }
+            None => {
+                self.delete_key(&description, &key).await?;
+                self.delete_replicated_key(&key).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete a blob from the content-addressable store. Blobs that a manifest, an executable or a
+    /// pin still refers to can't be deleted.
+    pub async fn delete_by_integrity(&self, integrity: &Integrity) -> ServalResult<()> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.delete_by_integrity(&integrity.to_string()).await;
+        }
+
+        if self.is_referenced(integrity).await? {
+            return Err(ServalError::BlobInUse(integrity.to_string()));
+        }
+        let description = integrity.to_string();
+        if !self
+            .delete(&description, |backend| {
+                backend.delete_by_integrity(integrity)
+            })
+            .await?
+        {
+            return Err(ServalError::DataNotFound(integrity.to_string()));
+        }
+        self.credit_blob(integrity).await;
+        self.uncache(integrity).await;
+        self.delete_replicated_blob(integrity).await;
+        Ok(())
+    }
+
+    /// Pin a blob in the content-addressable store, so that garbage collection keeps it even if
+    /// nothing else refers to it.
+    pub async fn pin(&self, integrity: &Integrity) -> ServalResult<()> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.pin(&integrity.to_string()).await;
+        }
+
+        if !self.data_exists_by_integrity(integrity).await? {
+            return Err(ServalError::DataNotFound(integrity.to_string()));
+        }
+        // The pin's key names the blob; the record itself only says which blob it is for anyone
+        // reading it.
+        let key = pin_key(integrity);
+        let record = integrity.to_string();
+        self.write_key(&format!("pin {integrity}"), &key, |backend| {
+            backend.store_by_key(&key, record.as_bytes())
+        })
+        .await?;
+        self.replicate_key(&key).await;
+        Ok(())
+    }
+
+    /// Remove the pin from a blob. The blob itself stays until garbage is collected.
+    pub async fn unpin(&self, integrity: &Integrity) -> ServalResult<()> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.unpin(&integrity.to_string()).await;
+        }
+
+        let key = pin_key(integrity);
+        if !self.delete_key(&format!("pin {integrity}"), &key).await? {
+            return Err(ServalError::DataNotFound(integrity.to_string()));
+        }
+        self.delete_replicated_key(&key).await;
+        Ok(())
+    }
+
+    /// List the integrities of every pinned blob.
+    pub async fn list_pins(&self) -> ServalResult<Vec<String>> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.list_pins().await;
+        }
+
+        let mut pins: Vec<String> = self
+            .list_keys(PIN_PREFIX)
+            .await?
+            .iter()
+            .filter_map(|key| pinned_integrity(key))
+            .map(|integrity| integrity.to_string())
+            .collect();
+        pins.sort();
+        Ok(pins)
+    }
+
+    /// Report how much each namespace is storing, and its quota. Namespaces that have never stored
+    /// anything are only listed if they have a quota of their own.
+    pub async fn usage(&self) -> ServalResult<Vec<NamespaceUsage>> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.storage_usage().await;
+        }
+
+        let records = self.lock_usage().await?;
+        let mut namespaces: Vec<&str> = records
+            .keys()
+            .map(String::as_str)
+            .chain(self.quotas.namespaces())
+            .collect();
+        namespaces.sort();
+        namespaces.dedup();
+
+        Ok(namespaces
+            .into_iter()
+            .map(|namespace| {
+                let record = records.get(namespace).cloned().unwrap_or_default();
+                let quota = self.quotas.for_namespace(namespace);
+                NamespaceUsage {
+                    namespace: namespace.to_string(),
+                    bytes: record.bytes,
+                    objects: record.objects,
+                    byte_quota: quota.bytes,
+                    object_quota: quota.objects,
+                }
+            })
+            .collect())
+    }
+
+    /// Remove every blob in the content-addressable store that no manifest, executable or pin
+    /// refers to, from every backend. In a dry run, report what would be removed instead.
+    pub async fn collect_garbage(&self, dry_run: bool) -> ServalResult<GarbageCollection> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.collect_garbage(dry_run).await;
+        }
+
+        metrics::increment_counter!("storage:gc:run");
+        // List the blobs before marking, so that a blob written in the meantime is either missing
+        // from the listing or already has its key.
+        let mut listings = Vec::with_capacity(self.backends.len());
+        for backend in &self.backends {
+            match backend.list_blobs().await {
+                Ok(blobs) => listings.push((backend, blobs)),
+                Err(e) => log::warn!("unable to list blobs in {}; {e:?}", backend.name()),
+            }
+        }
+        let referenced = self.referenced_integrities().await?;
+
+        let mut report = GarbageCollection {
+            dry_run,
+            ..Default::default()
+        };
+        for (backend, blobs) in listings {
+            for blob in blobs {
+                if referenced.contains(&blob.integrity.to_string()) {
+                    report.kept += 1;
+                    continue;
+                }
+                if !dry_run {
+                    if let Err(e) = backend.delete_by_integrity(&blob.integrity).await {
+                        log::warn!(
+                            "unable to collect garbage in {}; integrity={}; {e:?}",
+                            backend.name(),
+                            blob.integrity
+                        );
+                        continue;
+                    }
+                }
+                report.reclaimed_bytes += blob.size;
+                report.removed.push(CollectedBlob {
+                    backend: backend.name().to_string(),
+                    integrity: blob.integrity.to_string(),
+                    size: blob.size,
+                });
+            }
+        }
+
+        if !dry_run {
+            // Crediting namespaces rewrites their usage records, so wait until the sweep is done:
+            // a rewritten record might match one we were about to sweep.
+            for blob in &report.removed {
+                if let Ok(integrity) = blob.integrity.parse() {
+                    self.credit_blob(&integrity).await;
+                    self.uncache(&integrity).await;
+                }
+            }
+            metrics::counter!("storage:gc:removed", report.removed.len() as u64);
+            metrics::counter!("storage:gc:reclaimed_bytes", report.reclaimed_bytes);
+        }
+        log::info!(
+            "collected garbage; dry_run={dry_run}; kept={}; removed={}; reclaimed_bytes={}",
+            report.kept,
+            report.removed.len(),
+            report.reclaimed_bytes
+        );
+        Ok(report)
+    }
+}
+
+// The key a pin on the given blob is stored under.
+fn pin_key(integrity: &Integrity) -> String {
+    let (algorithm, hex) = integrity.to_hex();
+    format!("{PIN_PREFIX}{algorithm}/{hex}")
+}
+
+// The blob a pin key pins, if the key is a pin key.
+fn pinned_integrity(key: &str) -> Option<Integrity> {
+    let (algorithm, hex) = key.strip_prefix(PIN_PREFIX)?.split_once('/')?;
+    let digest = hex::decode(hex).ok()?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(digest);
+    format!("{algorithm}-{encoded}").parse().ok()
+}
+
+// Convenience function to make a proxy client for a freshly-selected peer.
+async fn make_proxy_client() -> ServalResult<ServalApiClient> {
+    let mesh = MESH.get().expect("Peer network not initialized!"); // yes, we crash in this case
+    let peers = mesh.peers_with_role(&ServalRole::Storage).await;
+    let iter = peers.iter();
     for peer in iter {
         if let Some(addr) = peer.http_address() {
             let proxy = ServalApiClient::new_with_version(1, addr.to_string());
@@ -417,14 +1681,943 @@ async fn make_proxy_client() -> ServalResult<ServalApiClient> {
     ))
 }
 
-// Convenience function used by executable_as_stream().
+// Serve data we already hold in memory, such as a peer's replica, as a stream.
 fn vec_to_byte_stream(bytes: Vec<u8>) -> SendableStream {
-    let stream = futures::stream::iter(
-        bytes
+    Box::pin(std::io::Cursor::new(bytes))
+}
+
+// Work out the integrity and size of data by reading it through, without holding on to it.
+async fn describe_stream(mut stream: SendableStream) -> ServalResult<BlobMetadata> {
+    let mut hasher = IntegrityOpts::new().algorithm(Algorithm::Sha256);
+    let mut buffer = vec![0; 64 * 1024];
+    let mut size: u64 = 0;
+    loop {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        size += read as u64;
+        hasher.input(&buffer[..read]);
+    }
+    Ok(BlobMetadata {
+        integrity: hasher.result(),
+        size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use super::*;
+
+    async fn upload(bytes: &[u8]) -> Upload {
+        Upload::spool(bytes, None).await.unwrap()
+    }
+
+    // A backend that refuses to do anything at all.
+    #[derive(Debug)]
+    struct BrokenStorage;
+
+    #[async_trait]
+    impl StorageBackend for BrokenStorage {
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+
+        async fn store_by_integrity(&self, _bytes: &[u8]) -> ServalResult<Integrity> {
+            Err(ServalError::StorageError("broken".to_string()))
+        }
+
+        async fn stream_by_integrity(&self, integrity: &Integrity) -> ServalResult<SendableStream> {
+            Err(ServalError::DataNotFound(integrity.to_string()))
+        }
+
+        async fn data_exists_by_integrity(&self, _integrity: &Integrity) -> ServalResult<bool> {
+            Err(ServalError::StorageError("broken".to_string()))
+        }
+
+        async fn store_by_key(&self, _key: &str, _bytes: &[u8]) -> ServalResult<Integrity> {
+            Err(ServalError::StorageError("broken".to_string()))
+        }
+
+        async fn stream_by_key(&self, key: &str) -> ServalResult<SendableStream> {
+            Err(ServalError::DataNotFound(key.to_string()))
+        }
+
+        async fn data_exists_by_key(&self, _key: &str) -> ServalResult<bool> {
+            Err(ServalError::StorageError("broken".to_string()))
+        }
+
+        async fn list_keys(&self, _prefix: &str) -> ServalResult<Vec<String>> {
+            Err(ServalError::StorageError("broken".to_string()))
+        }
+
+        async fn delete_by_key(&self, _key: &str) -> ServalResult<bool> {
+            Err(ServalError::StorageError("broken".to_string()))
+        }
+
+        async fn delete_by_integrity(&self, _integrity: &Integrity) -> ServalResult<bool> {
+            Err(ServalError::StorageError("broken".to_string()))
+        }
+
+        async fn list_blobs(&self) -> ServalResult<Vec<BlobMetadata>> {
+            Err(ServalError::StorageError("broken".to_string()))
+        }
+    }
+
+    fn storage(backends: Vec<Arc<dyn StorageBackend>>, write_policy: WritePolicy) -> Storage {
+        Storage::new(backends, ReadPolicy::FirstHit, write_policy)
+    }
+
+    #[tokio::test]
+    async fn write_policies_decide_what_counts_as_stored() {
+        let memory = Arc::new(MemoryStorage::new());
+        let backends: Vec<Arc<dyn StorageBackend>> = vec![Arc::new(BrokenStorage), memory.clone()];
+
+        let lenient = storage(backends.clone(), WritePolicy::Any);
+        let integrity = lenient.store_by_integrity(None, b"hello").await.unwrap();
+        assert_eq!(integrity, Integrity::from(b"hello"));
+        assert!(memory.data_exists_by_integrity(&integrity).await.unwrap());
+
+        let strict = storage(backends, WritePolicy::All);
+        assert!(strict.store_by_integrity(None, b"goodbye").await.is_err());
+        // Every backend is still asked, so the healthy one has the data anyway.
+        assert!(memory
+            .data_exists_by_integrity(&Integrity::from(b"goodbye"))
+            .await
+            .unwrap());
+
+        let broken = storage(vec![Arc::new(BrokenStorage)], WritePolicy::Any);
+        assert!(broken.store_by_integrity(None, b"hello").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_fall_through_to_the_first_backend_with_the_data() {
+        let first = Arc::new(MemoryStorage::new());
+        let second = Arc::new(MemoryStorage::new());
+        let storage = storage(
+            vec![Arc::new(BrokenStorage), first.clone(), second.clone()],
+            WritePolicy::All,
+        );
+
+        first.store_by_key("key", b"from the first").await.unwrap();
+        second
+            .store_by_key("key", b"from the second")
+            .await
+            .unwrap();
+        let only_second = second.store_by_integrity(b"only here").await.unwrap();
+
+        assert_eq!(
+            storage
+                .data_by_integrity(only_second.clone())
+                .await
+                .unwrap(),
+            b"only here"
+        );
+        assert!(storage
+            .data_exists_by_integrity(&only_second)
+            .await
+            .unwrap());
+        assert!(storage
+            .data_by_integrity(Integrity::from(b"nowhere"))
+            .await
+            .is_err());
+        assert!(!storage
+            .data_exists_by_integrity(&Integrity::from(b"nowhere"))
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn streams_uploads_into_every_kind_of_backend() {
+        let root = std::env::temp_dir().join(format!("serval-streams-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        let backends: Vec<Arc<dyn StorageBackend>> = vec![
+            Arc::new(MemoryStorage::new()),
+            Arc::new(BlobStore::new(&root.join("blobs")).unwrap()),
+            Arc::new(DirectoryStorage::new(&root.join("directory")).unwrap()),
+        ];
+        // Big enough to arrive in several reads, and small enough that cacache would like to
+        // write it all at once.
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let upload = upload(&data).await;
+        for backend in &backends {
+            let stream = upload.open().await.unwrap();
+            let stored = backend
+                .store_stream_by_integrity(upload.integrity(), upload.size(), stream)
+                .await
+                .unwrap();
+            assert_eq!(&stored, upload.integrity(), "{}", backend.name());
+            assert_eq!(backend.data_by_integrity(&stored).await.unwrap(), data);
+
+            let key = "sh.serval.loudify.1.0.0.wasm";
+            let stream = upload.open().await.unwrap();
+            backend
+                .store_stream_by_key(key, upload.integrity(), upload.size(), stream)
+                .await
+                .unwrap();
+            assert_eq!(
+                backend.data_by_key(key).await.unwrap(),
+                data,
+                "{}",
+                backend.name()
+            );
+        }
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_ranges_of_stored_data() {
+        let root = std::env::temp_dir().join(format!("serval-ranges-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        let backends: Vec<Arc<dyn StorageBackend>> = vec![
+            Arc::new(MemoryStorage::new()),
+            Arc::new(BlobStore::new(&root.join("blobs")).unwrap()),
+            Arc::new(DirectoryStorage::new(&root.join("directory")).unwrap()),
+        ];
+        let data: Vec<u8> = (0..=255).collect();
+        for backend in &backends {
+            let integrity = backend.store_by_integrity(&data).await.unwrap();
+            let metadata = backend.metadata_by_integrity(&integrity).await.unwrap();
+            assert_eq!(metadata.size, 256, "{}", backend.name());
+
+            let mut part = Vec::new();
+            backend
+                .stream_range_by_integrity(&integrity, ByteRange { start: 10, len: 5 })
+                .await
+                .unwrap()
+                .read_to_end(&mut part)
+                .await
+                .unwrap();
+            assert_eq!(part, &data[10..15], "{}", backend.name());
+            assert!(backend
+                .metadata_by_integrity(&Integrity::from(b"nowhere"))
+                .await
+                .is_err());
+        }
+
+        let storage = storage(vec![backends[1].clone()], WritePolicy::All);
+        storage
+            .store_executable("sh.serval.loudify", "1.0.0", &upload(&data).await)
+            .await
+            .unwrap();
+        let metadata = storage
+            .executable_metadata("sh.serval.loudify", "1.0.0")
+            .await
+            .unwrap();
+        assert_eq!(metadata.integrity, Integrity::from(&data));
+        assert_eq!(metadata.size, 256);
+        let range = ByteRange { start: 250, len: 6 };
+        let body = storage
+            .executable_range_as_stream("sh.serval.loudify", "1.0.0", range)
+            .await
+            .unwrap();
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), &data[250..]);
+        let body = storage
+            .stream_range_by_integrity(&metadata.integrity, range)
+            .await
+            .unwrap();
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), &data[250..]);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn checked_streams_never_hand_out_all_of_corrupt_data() {
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let integrity = Integrity::from(&data);
+
+        let mut good = Vec::new();
+        backend::verify_stream(&integrity, vec_to_byte_stream(data.clone()))
+            .read_to_end(&mut good)
+            .await
+            .unwrap();
+        assert_eq!(good, data);
+
+        let mut corrupt = data.clone();
+        corrupt[150_000] ^= 0xff;
+        let mut stream = backend::verify_stream(&integrity, vec_to_byte_stream(corrupt));
+        let mut read = Vec::new();
+        let error = stream.read_to_end(&mut read).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(read.len() < data.len());
+    }
+
+    #[tokio::test]
+    async fn stores_and_reads_manifests_and_executables() {
+        let storage = storage(vec![Arc::new(MemoryStorage::new())], WritePolicy::All);
+        let manifest = Manifest::from_string(
+            r#"
+name = "loudify"
+namespace = "sh.serval"
+binary = "/loudify.wasm"
+version = "0.1.0"
+description = "make text louder"
+"#,
+        )
+        .unwrap();
+
+        storage.store_manifest(&manifest).await.unwrap();
+        assert!(storage
+            .data_exists_by_key(&manifest.fq_name())
+            .await
+            .unwrap());
+        let stored = storage.manifest(&manifest.fq_name()).await.unwrap();
+        assert_eq!(stored.fq_name(), manifest.fq_name());
+        assert_eq!(stored.version(), manifest.version());
+
+        storage
+            .store_executable(
+                &manifest.fq_name(),
+                manifest.version(),
+                &upload(b"\0asm").await,
+            )
+            .await
+            .unwrap();
+        let executable = storage
+            .executable_as_bytes(&manifest.fq_name(), manifest.version())
+            .await
+            .unwrap();
+        assert_eq!(executable, b"\0asm");
+        assert!(storage
+            .executable_as_bytes(&manifest.fq_name(), "9.9.9")
+            .await
+            .is_err());
+    }
+
+    fn manifest(namespace: &str, name: &str, version: &str) -> Manifest {
+        Manifest::from_string(&format!(
+            r#"
+name = "{name}"
+namespace = "{namespace}"
+binary = "/{name}.wasm"
+version = "{version}"
+description = "{name} version {version}"
+"#
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn lists_manifests_and_their_versions() {
+        let storage = storage(vec![Arc::new(MemoryStorage::new())], WritePolicy::All);
+        for manifest in [
+            manifest("sh.serval", "loudify", "0.1.0"),
+            manifest("sh.serval", "loudify", "0.2.0"),
+            manifest("sh.serval", "birdfeeder", "1.0.0"),
+            manifest("com.example", "loudify", "3.0.0"),
+        ] {
+            storage.store_manifest(&manifest).await.unwrap();
+        }
+        storage
+            .store_executable("sh.serval.loudify", "0.2.0", &upload(b"\0asm").await)
+            .await
+            .unwrap();
+
+        let listing = storage
+            .list_manifests(&ManifestQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(listing.total, 3);
+        assert_eq!(listing.next_offset, None);
+        let names: Vec<(&str, &str)> = listing
+            .manifests
+            .iter()
+            .map(|summary| (summary.name.as_str(), summary.version.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("com.example.loudify", "3.0.0"),
+                ("sh.serval.birdfeeder", "1.0.0"),
+                ("sh.serval.loudify", "0.2.0"),
+            ]
+        );
+
+        let first_page = storage
+            .list_manifests(&ManifestQuery {
+                namespace: Some("sh.serval".to_string()),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(first_page.total, 2);
+        assert_eq!(first_page.manifests[0].name, "sh.serval.birdfeeder");
+        assert_eq!(first_page.next_offset, Some(1));
+
+        let versions = storage
+            .manifest_versions("sh.serval.loudify")
+            .await
+            .unwrap();
+        assert_eq!(
+            versions,
+            vec![
+                ManifestVersion {
+                    version: "0.1.0".to_string(),
+                    integrity: None,
+                    size: None,
+                },
+                ManifestVersion {
+                    version: "0.2.0".to_string(),
+                    integrity: Some(Integrity::from(b"\0asm").to_string()),
+                    size: Some(4),
+                },
+            ]
+        );
+        assert!(matches!(
+            storage.manifest_versions("sh.serval.nothing").await,
+            Err(ServalError::ManifestNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn resolves_version_requirements_against_stored_versions() {
+        let storage = storage(vec![Arc::new(MemoryStorage::new())], WritePolicy::All);
+        for version in ["1.2.0", "1.10.0", "2.0.0", "0.9.0"] {
+            storage
+                .store_manifest(&manifest("sh.serval", "loudify", version))
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            storage
+                .store_manifest(&manifest("sh.serval", "loudify", "3"))
+                .await,
+            Err(ServalError::InvalidVersion(_))
+        ));
+        assert!(matches!(
+            storage
+                .store_executable("sh.serval.loudify", "latest", &upload(b"\0asm").await)
+                .await,
+            Err(ServalError::InvalidVersion(_))
+        ));
+
+        let resolve = |spec: &'static str| {
+            let storage = storage.clone();
+            async move {
+                let (name, requirement) = VersionRequirement::split(spec).unwrap();
+                storage
+                    .resolve_manifest(name, &requirement)
+                    .await
+                    .map(|manifest| manifest.version().to_string())
+            }
+        };
+        assert_eq!(resolve("sh.serval.loudify").await.unwrap(), "2.0.0");
+        assert_eq!(resolve("sh.serval.loudify@1.2").await.unwrap(), "1.10.0");
+        assert_eq!(resolve("sh.serval.loudify@=1.2.0").await.unwrap(), "1.2.0");
+        assert!(matches!(
+            resolve("sh.serval.loudify@^3").await,
+            Err(ServalError::ManifestNotFound(_))
+        ));
+
+        // Storing an older version doesn't change what you get by name alone.
+        let latest = storage.manifest("sh.serval.loudify").await.unwrap();
+        assert_eq!(latest.version(), "2.0.0");
+        let versions: Vec<String> = storage
+            .manifest_versions("sh.serval.loudify")
+            .await
+            .unwrap()
             .into_iter()
-            .map(|xs| Ok::<Bytes, std::io::Error>(Bytes::copy_from_slice(&[xs]))),
-    );
-    let sr = StreamReader::new(stream);
+            .map(|v| v.version)
+            .collect();
+        assert_eq!(versions, vec!["0.9.0", "1.2.0", "1.10.0", "2.0.0"]);
+    }
+
+    #[tokio::test]
+    async fn runs_manifests_stored_before_every_version_was_kept() {
+        let backend = Arc::new(MemoryStorage::new());
+        let storage = storage(vec![backend.clone()], WritePolicy::All);
+        // Written the way they were before versions had keys of their own.
+        let manifest = manifest("sh.serval", "loudify", "1.0.0");
+        let toml = toml::to_string(&manifest).unwrap();
+        backend
+            .store_by_key("sh.serval.loudify.manifest.toml", toml.as_bytes())
+            .await
+            .unwrap();
+        backend
+            .store_by_key("sh.serval.loudify.1.0.0.wasm", b"\0asm")
+            .await
+            .unwrap();
+
+        let (name, requirement) = VersionRequirement::split("sh.serval.loudify").unwrap();
+        let resolved = storage.resolve_manifest(name, &requirement).await.unwrap();
+        assert_eq!(resolved.version(), "1.0.0");
+        let executable = storage
+            .executable_as_bytes(name, resolved.version())
+            .await
+            .unwrap();
+        assert_eq!(executable, b"\0asm");
+        let versions = storage.manifest_versions(name).await.unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].size, Some(4));
+
+        storage
+            .delete_manifest_version(name, "1.0.0")
+            .await
+            .unwrap();
+        assert!(matches!(
+            storage.manifest(name).await,
+            Err(ServalError::ManifestNotFound(_))
+        ));
+        assert!(!backend
+            .data_exists_by_key("sh.serval.loudify.1.0.0.wasm")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn deletes_manifest_versions_and_falls_back_to_the_next_latest() {
+        let storage = storage(vec![Arc::new(MemoryStorage::new())], WritePolicy::All);
+        for version in ["1.0.0", "1.1.0"] {
+            storage
+                .store_manifest(&manifest("sh.serval", "loudify", version))
+                .await
+                .unwrap();
+            storage
+                .store_executable(
+                    "sh.serval.loudify",
+                    version,
+                    &upload(version.as_bytes()).await,
+                )
+                .await
+                .unwrap();
+        }
 
-    Box::pin(sr)
+        storage
+            .delete_manifest_version("sh.serval.loudify", "1.1.0")
+            .await
+            .unwrap();
+        let latest = storage.manifest("sh.serval.loudify").await.unwrap();
+        assert_eq!(latest.version(), "1.0.0");
+        assert!(storage
+            .executable_as_bytes("sh.serval.loudify", "1.1.0")
+            .await
+            .is_err());
+        assert!(matches!(
+            storage
+                .delete_manifest_version("sh.serval.loudify", "1.1.0")
+                .await,
+            Err(ServalError::ManifestNotFound(_))
+        ));
+
+        storage.delete_manifest("sh.serval.loudify").await.unwrap();
+        assert!(!storage
+            .data_exists_by_key("sh.serval.loudify")
+            .await
+            .unwrap());
+        assert!(matches!(
+            storage.manifest_versions("sh.serval.loudify").await,
+            Err(ServalError::ManifestNotFound(_))
+        ));
+        assert!(matches!(
+            storage.delete_manifest("sh.serval.loudify").await,
+            Err(ServalError::ManifestNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn collects_blobs_nothing_refers_to() {
+        let first = Arc::new(MemoryStorage::new());
+        let second = Arc::new(MemoryStorage::new());
+        let storage = storage(vec![first.clone(), second.clone()], WritePolicy::All);
+
+        storage
+            .store_manifest(&manifest("sh.serval", "loudify", "1.0.0"))
+            .await
+            .unwrap();
+        let executable = storage
+            .store_executable("sh.serval.loudify", "1.0.0", &upload(b"\0asm").await)
+            .await
+            .unwrap();
+        let pinned = storage.store_by_integrity(None, b"pinned").await.unwrap();
+        let garbage = storage.store_by_integrity(None, b"garbage").await.unwrap();
+        storage.pin(&pinned).await.unwrap();
+        assert_eq!(storage.list_pins().await.unwrap(), vec![pinned.to_string()]);
+        // A pin names the blob rather than holding a copy of it.
+        assert_eq!(
+            first.data_by_key(&pin_key(&pinned)).await.unwrap(),
+            pinned.to_string().into_bytes()
+        );
+        assert!(matches!(
+            storage.pin(&Integrity::from(b"missing")).await,
+            Err(ServalError::DataNotFound(_))
+        ));
+
+        // Referenced blobs can't be deleted by hand.
+        assert!(matches!(
+            storage.delete_by_integrity(&executable).await,
+            Err(ServalError::BlobInUse(_))
+        ));
+        assert!(matches!(
+            storage.delete_by_integrity(&pinned).await,
+            Err(ServalError::BlobInUse(_))
+        ));
+
+        // Only count the blobs we're interested in.
+        let removed = |report: &GarbageCollection, integrity: &Integrity| {
+            report
+                .removed
+                .iter()
+                .filter(|blob| blob.integrity == integrity.to_string())
+                .count()
+        };
+
+        // A dry run reports the garbage in each backend, and leaves it alone.
+        let report = storage.collect_garbage(true).await.unwrap();
+        assert!(report.dry_run);
+        assert_eq!(removed(&report, &garbage), 2);
+        assert_eq!(removed(&report, &executable), 0);
+        assert_eq!(removed(&report, &pinned), 0);
+        assert!(second.data_exists_by_integrity(&garbage).await.unwrap());
+
+        let report = storage.collect_garbage(false).await.unwrap();
+        assert_eq!(removed(&report, &garbage), 2);
+        assert_eq!(
+            report.reclaimed_bytes,
+            report.removed.iter().map(|blob| blob.size).sum::<u64>()
+        );
+        for backend in [&first, &second] {
+            assert!(!backend.data_exists_by_integrity(&garbage).await.unwrap());
+            assert!(backend.data_exists_by_integrity(&executable).await.unwrap());
+            assert!(backend.data_exists_by_integrity(&pinned).await.unwrap());
+        }
+
+        // Once unpinned, a blob is fair game.
+        storage.unpin(&pinned).await.unwrap();
+        assert!(storage.list_pins().await.unwrap().is_empty());
+        let report = storage.collect_garbage(false).await.unwrap();
+        assert_eq!(removed(&report, &pinned), 2);
+        assert!(!storage.data_exists_by_integrity(&pinned).await.unwrap());
+
+        // And once its manifest is gone, so is an executable.
+        storage.delete_manifest("sh.serval.loudify").await.unwrap();
+        storage.collect_garbage(false).await.unwrap();
+        assert!(!storage.data_exists_by_integrity(&executable).await.unwrap());
+
+        // Pins come and go without garbage collection noticing, and deletion keeps up.
+        let kept = storage.store_by_integrity(None, b"kept").await.unwrap();
+        storage.pin(&kept).await.unwrap();
+        assert!(matches!(
+            storage.delete_by_integrity(&kept).await,
+            Err(ServalError::BlobInUse(_))
+        ));
+        storage.unpin(&kept).await.unwrap();
+        storage.delete_by_integrity(&kept).await.unwrap();
+
+        let unreferenced = storage
+            .store_by_integrity(None, b"unreferenced")
+            .await
+            .unwrap();
+        storage.delete_by_integrity(&unreferenced).await.unwrap();
+        assert!(matches!(
+            storage.delete_by_integrity(&unreferenced).await,
+            Err(ServalError::DataNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn enforces_and_persists_namespace_quotas() {
+        let memory = Arc::new(MemoryStorage::new());
+        let quotas = Quotas::new("1K/3".parse().unwrap())
+            .with_namespace("com.example", "/1".parse().unwrap());
+        let storage = storage(vec![memory.clone()], WritePolicy::All).with_quotas(quotas.clone());
+
+        let loudify = manifest("sh.serval", "loudify", "1.0.0");
+        storage.store_manifest(&loudify).await.unwrap();
+        let manifest_size = toml::to_string(&loudify).unwrap().len() as u64;
+        storage
+            .store_executable("sh.serval.loudify", "1.0.0", &upload(&[0; 20]).await)
+            .await
+            .unwrap();
+        // Replacing an executable only charges the difference.
+        storage
+            .store_executable("sh.serval.loudify", "1.0.0", &upload(&[1; 10]).await)
+            .await
+            .unwrap();
+        assert!(matches!(
+            storage
+                .store_executable("sh.serval.loudify", "1.0.0", &upload(&[2; 1025]).await)
+                .await,
+            Err(ServalError::PayloadTooLarge(_))
+        ));
+        assert!(matches!(
+            storage
+                .store_executable("sh.serval.loudify", "1.0.0", &upload(&[3; 1000]).await)
+                .await,
+            Err(ServalError::QuotaExceeded(_))
+        ));
+
+        // Blobs are charged to the namespace they name, and to nobody if they're already stored.
+        storage
+            .store_by_integrity(Some("sh.serval"), b"blob")
+            .await
+            .unwrap();
+        storage
+            .store_by_integrity(Some("com.example"), b"blob")
+            .await
+            .unwrap();
+        assert!(matches!(
+            storage
+                .store_by_integrity(Some("sh.serval"), b"one too many")
+                .await,
+            Err(ServalError::QuotaExceeded(_))
+        ));
+        storage
+            .store_by_integrity(None, b"anonymous")
+            .await
+            .unwrap();
+        storage
+            .store_by_integrity(Some("com.example"), b"first")
+            .await
+            .unwrap();
+        assert!(storage
+            .store_by_integrity(Some("com.example"), b"second")
+            .await
+            .is_err());
+
+        let usage = |namespace: &str, bytes: u64, objects: u64| {
+            let quota = quotas.for_namespace(namespace);
+            NamespaceUsage {
+                namespace: namespace.to_string(),
+                bytes,
+                objects,
+                byte_quota: quota.bytes,
+                object_quota: quota.objects,
+            }
+        };
+        let expected = vec![
+            usage("_shared", 9, 1),
+            usage("com.example", 5, 1),
+            usage("sh.serval", manifest_size + 14, 3),
+        ];
+        assert_eq!(storage.usage().await.unwrap(), expected);
+
+        // Usage is kept with the data, so a fresh start over the same backends picks it up.
+        let restarted = Storage::new(vec![memory.clone()], ReadPolicy::FirstHit, WritePolicy::All)
+            .with_quotas(quotas.clone());
+        assert_eq!(restarted.usage().await.unwrap(), expected);
+
+        // Deleting things gives their namespace its space back.
+        restarted
+            .delete_manifest_version("sh.serval.loudify", "1.0.0")
+            .await
+            .unwrap();
+        let blob = Integrity::from(b"blob");
+        restarted.delete_by_integrity(&blob).await.unwrap();
+        let after = restarted.usage().await.unwrap();
+        assert_eq!(after[2], usage("sh.serval", 0, 0));
+
+        // Usage records hold only totals, and who paid for a blob is kept next to it.
+        assert_eq!(
+            memory.data_by_key("_usage/com.example").await.unwrap(),
+            br#"{"bytes":5,"objects":1}"#
+        );
+        let charge = memory
+            .data_by_key(&charge_key(&Integrity::from(b"first")))
+            .await
+            .unwrap();
+        let charge: Charge = serde_json::from_slice(&charge).unwrap();
+        assert_eq!(charge.namespace, "com.example");
+        assert!(!memory.data_exists_by_key(&charge_key(&blob)).await.unwrap());
+
+        // Rewritten and deleted records don't pile up waiting to be collected.
+        let report = restarted.collect_garbage(true).await.unwrap();
+        for blob in report.removed {
+            let data = memory
+                .data_by_integrity(&blob.integrity.parse().unwrap())
+                .await
+                .unwrap();
+            assert!(serde_json::from_slice::<UsageRecord>(&data).is_err());
+            assert!(serde_json::from_slice::<Charge>(&data).is_err());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn charges_concurrent_writes_to_a_key_once() {
+        let memory = Arc::new(MemoryStorage::new());
+        let storage = storage(vec![memory.clone()], WritePolicy::All);
+        let writes: Vec<_> = (1..=16u8)
+            .map(|size| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    let executable = upload(&vec![size; size as usize]).await;
+                    storage
+                        .store_executable("sh.serval.loudify", "1.0.0", &executable)
+                        .await
+                })
+            })
+            .collect();
+        for write in writes {
+            write.await.unwrap().unwrap();
+        }
+
+        // Only what the key holds now is charged, however the writes interleaved.
+        let key = Manifest::make_executable_key("sh.serval.loudify", "1.0.0");
+        let stored = storage.size_of_key(&key).await.unwrap();
+        let usage = storage.usage().await.unwrap();
+        assert_eq!((usage[0].bytes, usage[0].objects), (stored, 1));
+        let restarted = Storage::new(vec![memory], ReadPolicy::FirstHit, WritePolicy::All);
+        assert_eq!(restarted.usage().await.unwrap(), usage);
+    }
+
+    #[tokio::test]
+    async fn charges_replacing_a_replica_in_full() {
+        let storage = storage(vec![Arc::new(MemoryStorage::new())], WritePolicy::All);
+        let usage = |storage: &Storage| {
+            let storage = storage.clone();
+            async move {
+                let usage = storage.usage().await.unwrap();
+                let usage = usage.iter().find(|usage| usage.namespace == "sh.serval");
+                usage.map_or((0, 0), |usage| (usage.bytes, usage.objects))
+            }
+        };
+        let loudify = manifest("sh.serval", "loudify", "1.0.0");
+        let key = Manifest::make_executable_key("sh.serval.loudify", "1.0.0");
+
+        // A peer paid for its replicas, so they cost us nothing.
+        storage
+            .store_replica_by_key(&key, &upload(&[0; 20]).await)
+            .await
+            .unwrap();
+        assert_eq!(usage(&storage).await, (0, 0));
+
+        // Replacing a replica with our own copy costs all of our copy, and replacing that only
+        // costs the difference.
+        storage.store_manifest(&loudify).await.unwrap();
+        let manifest_size = toml::to_string(&loudify).unwrap().len() as u64;
+        storage
+            .store_executable("sh.serval.loudify", "1.0.0", &upload(&[1; 10]).await)
+            .await
+            .unwrap();
+        assert_eq!(usage(&storage).await, (manifest_size + 10, 2));
+        storage
+            .store_executable("sh.serval.loudify", "1.0.0", &upload(&[2; 30]).await)
+            .await
+            .unwrap();
+        assert_eq!(usage(&storage).await, (manifest_size + 30, 2));
+
+        // A peer's replica replacing our copy means the peer paid for it instead.
+        storage
+            .store_replica_by_key(&key, &upload(&[3; 5]).await)
+            .await
+            .unwrap();
+        assert_eq!(usage(&storage).await, (manifest_size, 1));
+
+        // Deleting gives back exactly what was charged.
+        storage
+            .store_executable("sh.serval.loudify", "1.0.0", &upload(&[4; 8]).await)
+            .await
+            .unwrap();
+        assert_eq!(usage(&storage).await, (manifest_size + 8, 2));
+        storage
+            .delete_manifest_version("sh.serval.loudify", "1.0.0")
+            .await
+            .unwrap();
+        assert_eq!(usage(&storage).await, (0, 0));
+    }
+
+    #[tokio::test]
+    async fn stores_executables_from_blobs_without_charging_twice() {
+        let memory = Arc::new(MemoryStorage::new());
+        let storage = storage(vec![memory.clone()], WritePolicy::All);
+        let usage = |storage: &Storage, namespace: &'static str| {
+            let storage = storage.clone();
+            async move {
+                let usage = storage.usage().await.unwrap();
+                let usage = usage.iter().find(|usage| usage.namespace == namespace);
+                usage.map(|usage| (usage.bytes, usage.objects))
+            }
+        };
+        for version in ["1.0.0", "1.1.0"] {
+            storage
+                .store_manifest(&manifest("sh.serval", "loudify", version))
+                .await
+                .unwrap();
+        }
+        let old: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        let mut new = old.clone();
+        new[100..110].copy_from_slice(b"0123456789");
+        storage
+            .store_executable("sh.serval.loudify", "1.0.0", &upload(&old).await)
+            .await
+            .unwrap();
+        let (bytes, objects) = usage(&storage, "sh.serval").await.unwrap();
+
+        // A patch is applied to the old executable and the result stored as a blob, charged to the
+        // namespace; then the blob becomes the new executable, and is paid for as that instead.
+        let patched = storage
+            .store_by_integrity(Some("sh.serval"), &new)
+            .await
+            .unwrap();
+        assert_eq!(
+            usage(&storage, "sh.serval").await,
+            Some((bytes + 4096, objects + 1))
+        );
+        let stored = storage
+            .store_executable_from_blob("sh.serval.loudify", "1.1.0", &patched)
+            .await
+            .unwrap();
+        assert_eq!(stored, patched);
+        assert_eq!(
+            storage
+                .executable_as_bytes("sh.serval.loudify", "1.1.0")
+                .await
+                .unwrap(),
+            new
+        );
+        assert_eq!(
+            usage(&storage, "sh.serval").await,
+            Some((bytes + 4096, objects + 1))
+        );
+        assert!(!memory
+            .data_exists_by_key(&charge_key(&patched))
+            .await
+            .unwrap());
+
+        // A blob someone else paid for stays theirs; the namespace pays for its executable.
+        let shared = storage
+            .store_by_integrity(Some("com.example"), b"\0asm shared")
+            .await
+            .unwrap();
+        storage
+            .store_executable_from_blob("sh.serval.loudify", "1.1.0", &shared)
+            .await
+            .unwrap();
+        assert_eq!(usage(&storage, "com.example").await, Some((11, 1)));
+        assert_eq!(
+            usage(&storage, "sh.serval").await,
+            Some((bytes + 11, objects + 1))
+        );
+
+        // Asking for a blob that isn't stored changes nothing, and the whole executable can still
+        // be uploaded instead.
+        assert!(matches!(
+            storage
+                .store_executable_from_blob("sh.serval.loudify", "1.1.0", &Integrity::from(b"?"))
+                .await,
+            Err(ServalError::DataNotFound(_))
+        ));
+        assert_eq!(
+            usage(&storage, "sh.serval").await,
+            Some((bytes + 11, objects + 1))
+        );
+        storage
+            .store_executable("sh.serval.loudify", "1.1.0", &upload(&new).await)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .executable_as_bytes("sh.serval.loudify", "1.1.0")
+                .await
+                .unwrap(),
+            new
+        );
+        assert_eq!(
+            usage(&storage, "sh.serval").await,
+            Some((bytes + 4096, objects + 1))
+        );
+    }
 }
@@ -0,0 +1,396 @@
+//! Per-namespace storage quotas, and the usage accounting that enforces them.
+//!
+//! Everything a storage node keeps is charged to the manifest namespace it belongs to: manifest
+//! versions and executables to the namespace in their name, and content-addressed blobs to the
+//! namespace the uploader named, or to a shared namespace if they didn't. Each namespace's usage is
+//! persisted as a small JSON record of its totals under `_usage/` in the storage backends
+//! themselves, so it survives restarts along with the data it describes. Which namespace paid for a
+//! blob is recorded next to the blob, under `_charges/`, so that it can be credited when the blob is
+//! deleted without every upload rewriting a list of everything the namespace has stored. Keys get a
+//! charge record of their own too, since not everything a key holds was paid for: replicas sent by
+//! a peer were paid for on the peer.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use serde::{Deserialize, Serialize};
+use ssri::Integrity;
+use utils::errors::{ServalError, ServalResult};
+
+/// The namespace that content-addressed uploads are charged to when they don't name one.
+pub const SHARED_NAMESPACE: &str = "_shared";
+
+// Usage records are stored as keys under this prefix, one per namespace. Namespaces can't contain
+// a slash, so the namespace is all that follows it.
+pub(crate) const USAGE_PREFIX: &str = "_usage/";
+
+// Charges are stored as keys under this prefix. Charges for content-addressed blobs are named like
+// pins are, `_charges/sha256/abcd...`; charges for keyed data are named after the key, as in
+// `_charges/key/sh.serval/...`.
+pub(crate) const CHARGE_PREFIX: &str = "_charges/";
+
+/// Limits on how much one namespace may store. A missing limit means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// The most bytes the namespace may store.
+    pub bytes: Option<u64>,
+    /// The most manifests, executables and blobs the namespace may store.
+    pub objects: Option<u64>,
+}
+
+impl Quota {
+    // Refuse a change that would take the given usage over this quota. Changes that don't add to
+    // usage are always allowed, so that a namespace over its quota can still clean up.
+    fn admit(
+        &self,
+        namespace: &str,
+        usage: &UsageRecord,
+        change: &UsageChange,
+    ) -> ServalResult<()> {
+        if let Some(limit) = self.bytes {
+            if change.bytes_added > limit {
+                return Err(ServalError::PayloadTooLarge(format!(
+                    "{} bytes is more than the {limit} byte quota for namespace `{namespace}`",
+                    change.bytes_added
+                )));
+            }
+            let after = (usage.bytes + change.bytes_added).saturating_sub(change.bytes_removed);
+            if change.bytes_added > change.bytes_removed && after > limit {
+                return Err(ServalError::QuotaExceeded(format!(
+                    "namespace `{namespace}` is using {} of its {limit} bytes; storing {} more \
+                     would exceed that",
+                    usage.bytes, change.bytes_added
+                )));
+            }
+        }
+        if let Some(limit) = self.objects {
+            let after =
+                (usage.objects + change.objects_added).saturating_sub(change.objects_removed);
+            if change.objects_added > change.objects_removed && after > limit {
+                return Err(ServalError::QuotaExceeded(format!(
+                    "namespace `{namespace}` is already storing {} of its {limit} objects",
+                    usage.objects
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Quota {
+    type Err = ServalError;
+
+    /// Parse a quota written as `bytes/objects`, where either half may be left out: `10G/1000`,
+    /// `512M`, or `/50`. Byte counts may end in K, M, G or T, in powers of 1024.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (bytes, objects) = s.trim().split_once('/').unwrap_or((s.trim(), ""));
+        let invalid = || {
+            ServalError::StorageError(format!(
+                "not a valid storage quota `{s}`; try something like 10G/1000"
+            ))
+        };
+        let bytes = match bytes.trim() {
+            "" => None,
+            bytes => Some(parse_size(bytes).ok_or_else(invalid)?),
+        };
+        let objects = match objects.trim() {
+            "" => None,
+            objects => Some(objects.parse().map_err(|_| invalid())?),
+        };
+        Ok(Quota { bytes, objects })
+    }
+}
+
+// Parse a byte count with an optional binary-unit suffix.
+pub(crate) fn parse_size(size: &str) -> Option<u64> {
+    let upper = size.to_ascii_uppercase();
+    let digits = upper.trim_end_matches(['K', 'M', 'G', 'T']);
+    let multiplier = match &upper[digits.len()..] {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// The quotas for every namespace: a default, and overrides for particular namespaces.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Quotas {
+    default: Quota,
+    namespaces: HashMap<String, Quota>,
+}
+
+impl Quotas {
+    /// Quotas that apply the given limits to every namespace.
+    pub fn new(default: Quota) -> Self {
+        Self {
+            default,
+            namespaces: HashMap::new(),
+        }
+    }
+
+    /// Give one namespace different limits from the rest.
+    pub fn with_namespace(mut self, namespace: &str, quota: Quota) -> Self {
+        self.namespaces.insert(namespace.to_string(), quota);
+        self
+    }
+
+    /// Read quotas from the environment. `STORAGE_QUOTA` sets the default for every namespace, and
+    /// `STORAGE_QUOTAS` overrides it for particular ones, as a comma-separated list like
+    /// `sh.serval=10G/1000,com.example=512M`. Without either, nothing is limited.
+    pub fn from_env() -> ServalResult<Self> {
+        let mut quotas = match std::env::var("STORAGE_QUOTA") {
+            Ok(quota) => Quotas::new(quota.parse()?),
+            Err(_) => Quotas::default(),
+        };
+        if let Ok(overrides) = std::env::var("STORAGE_QUOTAS") {
+            for entry in overrides
+                .split(',')
+                .filter(|entry| !entry.trim().is_empty())
+            {
+                let Some((namespace, quota)) = entry.split_once('=') else {
+                    return Err(ServalError::StorageError(format!(
+                        "not a valid namespace quota `{entry}`; try sh.serval=10G/1000"
+                    )));
+                };
+                quotas = quotas.with_namespace(namespace.trim(), quota.parse()?);
+            }
+        }
+        Ok(quotas)
+    }
+
+    /// The limits that apply to the given namespace.
+    pub fn for_namespace(&self, namespace: &str) -> Quota {
+        self.namespaces
+            .get(namespace)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// Every namespace with limits of its own.
+    pub fn namespaces(&self) -> impl Iterator<Item = &str> {
+        self.namespaces.keys().map(String::as_str)
+    }
+
+    /// Check a change to a namespace's usage against its quota.
+    pub(crate) fn admit(
+        &self,
+        namespace: &str,
+        usage: &UsageRecord,
+        change: &UsageChange,
+    ) -> ServalResult<()> {
+        self.for_namespace(namespace)
+            .admit(namespace, usage, change)
+    }
+}
+
+/// What a namespace has stored, as far as its quota is concerned.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct UsageRecord {
+    pub bytes: u64,
+    pub objects: u64,
+}
+
+impl UsageRecord {
+    /// Apply a change to this record.
+    pub fn apply(&mut self, change: &UsageChange) {
+        self.bytes = (self.bytes + change.bytes_added).saturating_sub(change.bytes_removed);
+        self.objects = (self.objects + change.objects_added).saturating_sub(change.objects_removed);
+    }
+}
+
+/// Which namespace paid for a content-addressed blob or for what a key holds, and how much.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct Charge {
+    pub namespace: String,
+    pub bytes: u64,
+}
+
+/// The key the charge for the given blob is stored under.
+pub(crate) fn charge_key(integrity: &Integrity) -> String {
+    let (algorithm, hex) = integrity.to_hex();
+    format!("{CHARGE_PREFIX}{algorithm}/{hex}")
+}
+
+/// The key the charge for what the given key holds is stored under. No hash algorithm is called
+/// `key`, so these never collide with a blob's charge.
+pub(crate) fn key_charge_key(key: &str) -> String {
+    format!("{CHARGE_PREFIX}key/{key}")
+}
+
+/// Locks on charges, by the key each is recorded under, so that only one thing at a time reads and
+/// changes a given charge. Locks that nobody holds or waits for are forgotten.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ChargeLocks(Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>);
+
+impl ChargeLocks {
+    /// Wait for the lock on the charge recorded under the given key, and hold it until the guard
+    /// is dropped.
+    pub async fn lock(&self, key: &str) -> ChargeGuard {
+        let lock = self
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        ChargeGuard {
+            locks: self.clone(),
+            key: key.to_string(),
+            held: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+/// The lock on one charge, held until this is dropped.
+pub(crate) struct ChargeGuard {
+    locks: ChargeLocks,
+    key: String,
+    held: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for ChargeGuard {
+    fn drop(&mut self) {
+        self.held.take();
+        let mut locks = self.locks.0.lock().unwrap_or_else(PoisonError::into_inner);
+        // Anyone waiting for the lock holds it too.
+        if locks
+            .get(&self.key)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+/// A change to what a namespace has stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct UsageChange {
+    pub bytes_added: u64,
+    pub bytes_removed: u64,
+    pub objects_added: u64,
+    pub objects_removed: u64,
+}
+
+impl UsageChange {
+    /// Storing a new object of the given size.
+    pub fn added(bytes: u64) -> Self {
+        Self {
+            bytes_added: bytes,
+            objects_added: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Removing an object of the given size.
+    pub fn removed(bytes: u64) -> Self {
+        Self {
+            bytes_removed: bytes,
+            objects_removed: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Replacing an object of one size with another of a different size.
+    pub fn replaced(old_bytes: u64, new_bytes: u64) -> Self {
+        Self {
+            bytes_added: new_bytes,
+            bytes_removed: old_bytes,
+            ..Default::default()
+        }
+    }
+
+    /// The change that undoes this one.
+    pub fn reversed(&self) -> Self {
+        Self {
+            bytes_added: self.bytes_removed,
+            bytes_removed: self.bytes_added,
+            objects_added: self.objects_removed,
+            objects_removed: self.objects_added,
+        }
+    }
+}
+
+/// The namespace a fully-qualified job name belongs to. Job names never contain dots, so the last
+/// dot is the one that ends the namespace.
+pub fn namespace_of(fq_name: &str) -> &str {
+    fq_name
+        .rsplit_once('.')
+        .map(|(namespace, _)| namespace)
+        .unwrap_or(fq_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quotas() {
+        assert_eq!(
+            "10G/1000".parse::<Quota>().unwrap(),
+            Quota {
+                bytes: Some(10 << 30),
+                objects: Some(1000)
+            }
+        );
+        assert_eq!(
+            "512k".parse::<Quota>().unwrap(),
+            Quota {
+                bytes: Some(512 << 10),
+                objects: None
+            }
+        );
+        assert_eq!(
+            "/50".parse::<Quota>().unwrap(),
+            Quota {
+                bytes: None,
+                objects: Some(50)
+            }
+        );
+        assert!("10X".parse::<Quota>().is_err());
+        assert!("10G/lots".parse::<Quota>().is_err());
+    }
+
+    #[test]
+    fn refuses_changes_over_quota() {
+        let quotas = Quotas::new("100/2".parse().unwrap()).with_namespace("big", Quota::default());
+        let mut usage = UsageRecord::default();
+
+        quotas
+            .admit("sh.serval", &usage, &UsageChange::added(60))
+            .unwrap();
+        usage.apply(&UsageChange::added(60));
+        assert!(matches!(
+            quotas.admit("sh.serval", &usage, &UsageChange::added(50)),
+            Err(ServalError::QuotaExceeded(_))
+        ));
+        assert!(matches!(
+            quotas.admit("sh.serval", &usage, &UsageChange::added(101)),
+            Err(ServalError::PayloadTooLarge(_))
+        ));
+        // Shrinking an object is fine, even though growing it wouldn't be.
+        quotas
+            .admit("sh.serval", &usage, &UsageChange::replaced(60, 30))
+            .unwrap();
+        assert!(quotas
+            .admit("sh.serval", &usage, &UsageChange::replaced(60, 110))
+            .is_err());
+
+        usage.apply(&UsageChange::added(10));
+        assert!(matches!(
+            quotas.admit("sh.serval", &usage, &UsageChange::added(1)),
+            Err(ServalError::QuotaExceeded(_))
+        ));
+        assert!(quotas
+            .admit("big", &usage, &UsageChange::added(1 << 40))
+            .is_ok());
+
+        usage.apply(&UsageChange::added(60).reversed());
+        assert_eq!(usage.bytes, 10);
+        assert_eq!(usage.objects, 1);
+    }
+}
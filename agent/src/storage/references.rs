@@ -0,0 +1,174 @@
+//! An index of what each stored key refers to: manifests, executables, pins, and our own records.
+//! Blobs that a key refers to are in use, and can't be deleted.
+//!
+//! Finding that out from the backends means reading the metadata of every key, so we do it once
+//! and then keep the index up to date as we write and delete keys ourselves. Other nodes sharing
+//! our backends can add keys behind our back, so the index is rebuilt from scratch once it's a few
+//! minutes old, and whenever garbage is collected.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use ssri::Integrity;
+use utils::errors::{ServalError, ServalResult};
+
+use super::{pinned_integrity, Storage, StorageBackend};
+
+// How long we trust the index before reading every key again.
+const MAX_INDEX_AGE: Duration = Duration::from_secs(300);
+
+/// What every stored key refers to, with a count of the keys referring to each blob.
+#[derive(Debug)]
+pub struct ReferenceIndex {
+    keys: HashMap<String, Vec<String>>,
+    counts: HashMap<String, usize>,
+    built: Instant,
+}
+
+impl ReferenceIndex {
+    fn new() -> Self {
+        Self {
+            keys: HashMap::new(),
+            counts: HashMap::new(),
+            built: Instant::now(),
+        }
+    }
+
+    // Note that a key now holds data with the given integrity, replacing whatever it held before.
+    fn record(&mut self, key: &str, integrity: &Integrity) {
+        self.forget(key);
+        let mut referenced = vec![integrity.to_string()];
+        // A pin holds a record of the pin rather than the pinned blob, so both are in use.
+        if let Some(pinned) = pinned_integrity(key) {
+            referenced.push(pinned.to_string());
+        }
+        for blob in &referenced {
+            *self.counts.entry(blob.clone()).or_default() += 1;
+        }
+        self.keys.insert(key.to_string(), referenced);
+    }
+
+    // Note that a key is gone.
+    fn forget(&mut self, key: &str) {
+        for blob in self.keys.remove(key).unwrap_or_default() {
+            if let Some(count) = self.counts.get_mut(&blob) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(&blob);
+                }
+            }
+        }
+    }
+
+    fn contains(&self, integrity: &Integrity) -> bool {
+        self.counts.contains_key(&integrity.to_string())
+    }
+
+    fn is_stale(&self) -> bool {
+        self.built.elapsed() > MAX_INDEX_AGE
+    }
+}
+
+impl Storage {
+    // The integrity of everything a key refers to, read afresh from our backends. This is the mark
+    // phase of garbage collection. If we can't tell what a key refers to, we fail rather than risk
+    // treating its data as garbage.
+    pub(super) async fn referenced_integrities(&self) -> ServalResult<HashSet<String>> {
+        let mut references = self.references.lock().await;
+        let index = self.build_reference_index().await?;
+        let referenced = index.counts.keys().cloned().collect();
+        *references = Some(index);
+        Ok(referenced)
+    }
+
+    // Whether any key refers to the given blob.
+    pub(super) async fn is_referenced(&self, integrity: &Integrity) -> ServalResult<bool> {
+        let mut references = self.references.lock().await;
+        match references.as_ref() {
+            Some(index) if !index.is_stale() => Ok(index.contains(integrity)),
+            _ => {
+                let index = self.build_reference_index().await?;
+                let referenced = index.contains(integrity);
+                *references = Some(index);
+                Ok(referenced)
+            }
+        }
+    }
+
+    // Store data under a key with the given write, and note what the key now refers to.
+    pub(super) async fn write_key<'a>(
+        &'a self,
+        description: &str,
+        key: &str,
+        op: impl Fn(&'a dyn StorageBackend) -> BoxFuture<'a, ServalResult<Integrity>>,
+    ) -> ServalResult<Integrity> {
+        let integrity = self.write(description, op).await?;
+        if let Some(index) = self.references.lock().await.as_mut() {
+            index.record(key, &integrity);
+        }
+        Ok(integrity)
+    }
+
+    // Delete a key from our backends, and note that it no longer refers to anything. Responds with
+    // whether any backend had it.
+    pub(super) async fn delete_key(&self, description: &str, key: &str) -> ServalResult<bool> {
+        let deleted = self
+            .delete(description, |backend| backend.delete_by_key(key))
+            .await?;
+        if let Some(index) = self.references.lock().await.as_mut() {
+            index.forget(key);
+        }
+        Ok(deleted)
+    }
+
+    async fn build_reference_index(&self) -> ServalResult<ReferenceIndex> {
+        let mut index = ReferenceIndex::new();
+        for key in self.list_keys("").await? {
+            let Some(metadata) = self
+                .read(&format!("key {key}"), |backend| {
+                    backend.metadata_by_key(&key)
+                })
+                .await
+            else {
+                return Err(ServalError::StorageError(format!(
+                    "unable to find out what a stored key refers to; key={key}"
+                )));
+            };
+            index.record(&key, &metadata.integrity);
+        }
+        Ok(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::pin_key;
+
+    #[test]
+    fn counts_every_key_that_refers_to_a_blob() {
+        let shared = Integrity::from(b"shared");
+        let other = Integrity::from(b"other");
+        let mut index = ReferenceIndex::new();
+        index.record("sh.serval.one.manifest.toml", &shared);
+        index.record("sh.serval.two.manifest.toml", &shared);
+        assert!(index.contains(&shared));
+
+        index.forget("sh.serval.one.manifest.toml");
+        assert!(index.contains(&shared));
+        index.record("sh.serval.two.manifest.toml", &other);
+        assert!(!index.contains(&shared));
+        assert!(index.contains(&other));
+
+        // A pin refers to the pinned blob as well as its own record.
+        let pinned = Integrity::from(b"pinned");
+        let record = Integrity::from(pinned.to_string().as_bytes());
+        index.record(&pin_key(&pinned), &record);
+        assert!(index.contains(&pinned));
+        assert!(index.contains(&record));
+        index.forget(&pin_key(&pinned));
+        assert!(!index.contains(&pinned));
+        assert!(!index.contains(&record));
+    }
+}
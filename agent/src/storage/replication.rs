@@ -0,0 +1,968 @@
+//! Replication of stored data across the mesh's storage peers, so that losing one storage node
+//! doesn't lose what it held.
+//!
+//! Whichever storage node a write arrives at keeps a copy, and also copies it to the peers that a
+//! consistent-hash ring over every storage node picks for the data's integrity. Manifests are small
+//! and every node needs them to list and resolve versions, so they go to every storage peer
+//! instead. Reads that miss locally fall back to those peers, deletions are sent to every storage
+//! peer, and a background task periodically pushes anything a peer should hold but doesn't.
+//!
+//! Peers talk to each other through the `/v1/storage/replicas` routes, which read and write a
+//! node's own backends and never replicate any further. Only the mesh's storage peers may use
+//! them: requests must be signed with the mesh secret if there is one, or come from a storage
+//! peer's address if there isn't.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use futures::{StreamExt, TryStreamExt};
+use serval_client::ServalApiClient;
+use sha2::{Digest, Sha256};
+use ssri::Integrity;
+use tokio::io::AsyncReadExt;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::io::{ReaderStream, StreamReader};
+use utils::errors::{ServalError, ServalResult};
+use utils::mesh::{KaboodlePeer, PeerMetadata, ServalRole};
+use utils::structs::Manifest;
+
+use super::backend::verify_stream;
+use super::quotas::{CHARGE_PREFIX, USAGE_PREFIX};
+use super::{BlobMetadata, SendableStream, Storage, Upload, STORAGE};
+use crate::structures::MESH;
+
+// How many points each node gets on the ring. More points spread data more evenly.
+const VIRTUAL_NODES: usize = 64;
+
+// How often we look for replicas to repair, unless told otherwise.
+const DEFAULT_REPAIR_INTERVAL: Duration = Duration::from_secs(300);
+
+/// A consistent-hash ring over storage nodes, identified by instance id. Each node appears at many
+/// points on the ring, and a piece of data belongs to the nodes found walking clockwise from its
+/// own hash. Adding or removing a node only moves the data next to that node's points.
+#[derive(Debug, Clone, Default)]
+pub struct HashRing {
+    points: Vec<(u64, String)>,
+}
+
+impl HashRing {
+    /// Build a ring over the given nodes.
+    pub fn new<S: AsRef<str>>(nodes: impl IntoIterator<Item = S>) -> Self {
+        let mut points: Vec<(u64, String)> = nodes
+            .into_iter()
+            .flat_map(|node| {
+                let node = node.as_ref().to_string();
+                (0..VIRTUAL_NODES).map(move |i| (ring_hash(&format!("{node}#{i}")), node.clone()))
+            })
+            .collect();
+        points.sort();
+        points.dedup();
+        Self { points }
+    }
+
+    /// The first `count` distinct nodes responsible for the given placement key, in order of
+    /// preference. Fewer if the ring doesn't have that many nodes.
+    pub fn replicas(&self, placement: &str, count: usize) -> Vec<&str> {
+        let start = self
+            .points
+            .partition_point(|(point, _)| *point < ring_hash(placement));
+        let mut chosen: Vec<&str> = Vec::with_capacity(count);
+        for (_, node) in self.points[start..].iter().chain(&self.points[..start]) {
+            if chosen.len() == count {
+                break;
+            }
+            if !chosen.contains(&node.as_str()) {
+                chosen.push(node);
+            }
+        }
+        chosen
+    }
+}
+
+// A hash that every agent agrees on, whatever version of Rust it was built with.
+fn ring_hash(value: &str) -> u64 {
+    let digest = Sha256::digest(value.as_bytes());
+    let mut prefix = [0; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(prefix)
+}
+
+/// How many copies of each piece of data the storage nodes should keep between them.
+#[derive(Debug, Clone)]
+pub struct Replication {
+    factor: usize,
+    instance_id: String,
+    // The storage peers to replicate among, when they're fixed rather than whichever ones the mesh
+    // knows about. Only tests fix them.
+    peers: Option<Vec<PeerMetadata>>,
+}
+
+impl Replication {
+    /// Keep `factor` copies of everything, counting our own when the ring picks us.
+    pub fn new(factor: usize, instance_id: &str) -> Self {
+        Self {
+            factor,
+            instance_id: instance_id.to_string(),
+            peers: None,
+        }
+    }
+
+    /// Read the replication factor from `STORAGE_REPLICAS`. Responds with None, meaning don't
+    /// replicate, unless it asks for more than one copy.
+    pub fn from_env(instance_id: &str) -> ServalResult<Option<Self>> {
+        let Ok(factor) = std::env::var("STORAGE_REPLICAS") else {
+            return Ok(None);
+        };
+        let factor: usize = factor.parse().map_err(|_| {
+            ServalError::StorageError(format!("not a valid number of storage replicas `{factor}`"))
+        })?;
+        Ok((factor > 1).then(|| Self::new(factor, instance_id)))
+    }
+
+    /// How many copies of everything we keep.
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    // Every storage peer but us.
+    async fn storage_peers(&self) -> Vec<PeerMetadata> {
+        let peers = match &self.peers {
+            Some(peers) => peers.clone(),
+            None => mesh_storage_peers().await,
+        };
+        peers
+            .into_iter()
+            .filter(|peer| peer.instance_id() != self.instance_id)
+            .collect()
+    }
+
+    // The peers other than us that should hold a copy of data with the given integrity, in order of
+    // preference.
+    fn choose(&self, integrity: &Integrity, peers: &[PeerMetadata]) -> Vec<ServalApiClient> {
+        let ring = HashRing::new(
+            peers
+                .iter()
+                .map(PeerMetadata::instance_id)
+                .chain([self.instance_id.as_str()]),
+        );
+        ring.replicas(&integrity.to_string(), self.factor)
+            .into_iter()
+            .filter_map(|id| peers.iter().find(|peer| peer.instance_id() == id))
+            .filter_map(client_for)
+            .collect()
+    }
+
+    /// The peers other than us that should hold a copy of data with the given integrity.
+    pub async fn peers_for(&self, integrity: &Integrity) -> Vec<ServalApiClient> {
+        self.choose(integrity, &self.storage_peers().await)
+    }
+
+    /// Every storage peer other than us.
+    pub async fn all_peers(&self) -> Vec<ServalApiClient> {
+        self.storage_peers()
+            .await
+            .iter()
+            .filter_map(client_for)
+            .collect()
+    }
+
+    // The peers that should hold a copy of the data stored under the given key. Manifests go
+    // everywhere; everything else is placed by its integrity.
+    async fn peers_for_key(&self, key: &str, integrity: &Integrity) -> Vec<ServalApiClient> {
+        if Manifest::parse_manifest_key(key).is_some() {
+            self.all_peers().await
+        } else {
+            self.peers_for(integrity).await
+        }
+    }
+}
+
+async fn mesh_storage_peers() -> Vec<PeerMetadata> {
+    match MESH.get() {
+        Some(mesh) => mesh.peers_with_role(&ServalRole::Storage).await,
+        None => Vec::new(),
+    }
+}
+
+fn client_for(peer: &PeerMetadata) -> Option<ServalApiClient> {
+    let address = peer.http_address()?;
+    Some(ServalApiClient::new_with_version(1, address.to_string()))
+}
+
+// Usage and charge records are about this node's own accounting, so they stay here.
+fn is_replicated_key(key: &str) -> bool {
+    !key.starts_with(USAGE_PREFIX) && !key.starts_with(CHARGE_PREFIX)
+}
+
+// Read the body of a peer's response as it arrives.
+fn response_stream(response: reqwest::Response) -> SendableStream {
+    let chunks = response.bytes_stream().map_err(std::io::Error::other);
+    Box::pin(StreamReader::new(chunks))
+}
+
+impl Storage {
+    /// Whether a request from the given address could have come from one of our storage peers.
+    /// Used to tell who may use the replica routes when the mesh has no secret to sign requests
+    /// with.
+    pub async fn is_storage_peer(&self, address: IpAddr) -> bool {
+        let peers = match &self.replication {
+            Some(replication) => replication.storage_peers().await,
+            None => mesh_storage_peers().await,
+        };
+        let address = address.to_canonical();
+        peers
+            .iter()
+            .any(|peer| peer.address().to_canonical() == address)
+    }
+
+    // Send a peer a copy of one of our blobs, streaming it from our own backends.
+    async fn send_blob(&self, peer: &ServalApiClient, integrity: &Integrity) -> ServalResult<()> {
+        let Some(stream) = self
+            .read(&format!("replica {integrity}"), |backend| {
+                backend.stream_by_integrity(integrity)
+            })
+            .await
+        else {
+            return Err(ServalError::DataNotFound(integrity.to_string()));
+        };
+        peer.store_replica(integrity, request_body(stream)).await?;
+        Ok(())
+    }
+
+    // Send a peer a copy of what one of our keys holds, streaming it from our own backends.
+    async fn send_key(
+        &self,
+        peer: &ServalApiClient,
+        key: &str,
+        integrity: &Integrity,
+    ) -> ServalResult<()> {
+        let Some(stream) = self
+            .read(&format!("replica key {key}"), |backend| {
+                backend.stream_by_key(key)
+            })
+            .await
+        else {
+            return Err(ServalError::DataNotFound(key.to_string()));
+        };
+        peer.store_replica_by_key(key, integrity, request_body(stream))
+            .await?;
+        Ok(())
+    }
+
+    // The integrity of what one of our keys holds.
+    async fn integrity_of_key(&self, key: &str) -> Option<Integrity> {
+        self.read(&format!("key {key}"), |backend| {
+            backend.metadata_by_key(key)
+        })
+        .await
+        .map(|metadata| metadata.integrity)
+    }
+
+    // Copy a blob we've just stored to the peers that should also hold it. Failures are logged,
+    // and left for the repair task to fix.
+    pub(super) async fn replicate_blob(&self, integrity: &Integrity) {
+        let Some(replication) = &self.replication else {
+            return;
+        };
+        for peer in replication.peers_for(integrity).await {
+            match self.send_blob(&peer, integrity).await {
+                Ok(_) => metrics::increment_counter!("storage:replication:written"),
+                Err(e) => {
+                    metrics::increment_counter!("storage:replication:failed");
+                    log::warn!("unable to replicate blob; integrity={integrity}; {e:?}");
+                }
+            }
+        }
+    }
+
+    // Copy keyed data we've just stored to the peers that should also hold it.
+    pub(super) async fn replicate_key(&self, key: &str) {
+        let Some(replication) = &self.replication else {
+            return;
+        };
+        if !is_replicated_key(key) {
+            return;
+        }
+        let Some(integrity) = self.integrity_of_key(key).await else {
+            return;
+        };
+        for peer in replication.peers_for_key(key, &integrity).await {
+            match self.send_key(&peer, key, &integrity).await {
+                Ok(_) => metrics::increment_counter!("storage:replication:written"),
+                Err(e) => {
+                    metrics::increment_counter!("storage:replication:failed");
+                    log::warn!("unable to replicate keyed data; key={key}; {e:?}");
+                }
+            }
+        }
+    }
+
+    // Look for a blob we don't have on the peers that should hold it, and stream it from the first
+    // that does. The stream fails at the end if the peer's copy doesn't match the integrity we asked
+    // for.
+    pub(super) async fn replicated_blob(&self, integrity: &Integrity) -> Option<SendableStream> {
+        let replication = self.replication.as_ref()?;
+        for peer in replication.peers_for(integrity).await {
+            let Ok(response) = peer.get_replica(&integrity.to_string()).await else {
+                continue;
+            };
+            log::info!("serving from a replica; integrity={integrity}");
+            metrics::increment_counter!("storage:replication:read");
+            return Some(verify_stream(integrity, response_stream(response)));
+        }
+        None
+    }
+
+    // Load a blob we don't have from the peers that should hold it into memory, for callers that
+    // need all of it at once.
+    pub(super) async fn replicated_blob_data(&self, integrity: &Integrity) -> Option<Vec<u8>> {
+        let stream = self.replicated_blob(integrity).await?;
+        read_replica(stream, &format!("integrity={integrity}")).await
+    }
+
+    // Ask the peers that should hold a blob we don't have how big it is, without fetching it.
+    pub(super) async fn replicated_blob_size(&self, integrity: &Integrity) -> Option<u64> {
+        let replication = self.replication.as_ref()?;
+        for peer in replication.peers_for(integrity).await {
+            if let Ok(Some(size)) = peer.replica_size(&integrity.to_string()).await {
+                return Some(size);
+            }
+        }
+        None
+    }
+
+    // Look for keyed data we don't have on our peers, and stream it from the first that has it.
+    // Without the data we can't know its integrity, so we ask every storage peer.
+    pub(super) async fn replicated_key(&self, key: &str) -> Option<SendableStream> {
+        let replication = self.replication.as_ref()?;
+        for peer in replication.all_peers().await {
+            if let Ok(response) = peer.get_replica_by_key(key).await {
+                log::info!("serving from a replica; key={key}");
+                metrics::increment_counter!("storage:replication:read");
+                return Some(response_stream(response));
+            }
+        }
+        None
+    }
+
+    // Describe keyed data we don't have from the first of our peers that has it, without fetching
+    // it.
+    pub(super) async fn replicated_key_metadata(&self, key: &str) -> Option<BlobMetadata> {
+        let replication = self.replication.as_ref()?;
+        for peer in replication.all_peers().await {
+            if let Ok(Some((integrity, size))) = peer.replica_metadata_by_key(key).await {
+                return Some(BlobMetadata { integrity, size });
+            }
+        }
+        None
+    }
+
+    // Load keyed data we don't have from our peers into memory.
+    pub(super) async fn replicated_key_data(&self, key: &str) -> Option<Vec<u8>> {
+        let stream = self.replicated_key(key).await?;
+        read_replica(stream, &format!("key={key}")).await
+    }
+
+    // Delete a blob from every peer, so the repair task can't bring it back from a stray copy.
+    pub(super) async fn delete_replicated_blob(&self, integrity: &Integrity) {
+        let Some(replication) = &self.replication else {
+            return;
+        };
+        for peer in replication.all_peers().await {
+            if let Err(e) = peer.delete_replica(&integrity.to_string()).await {
+                log::warn!("unable to delete replica; integrity={integrity}; {e:?}");
+            }
+        }
+    }
+
+    // Delete keyed data from every peer.
+    pub(super) async fn delete_replicated_key(&self, key: &str) {
+        let Some(replication) = &self.replication else {
+            return;
+        };
+        for peer in replication.all_peers().await {
+            if let Err(e) = peer.delete_replica_by_key(key).await {
+                log::warn!("unable to delete replica; key={key}; {e:?}");
+            }
+        }
+    }
+
+    /// Make sure that every peer holds a copy of everything of ours that it should, copying
+    /// whatever is missing. A copy that fails is logged and left for the next pass. Responds with
+    /// how many copies were made.
+    pub async fn repair_replicas(&self) -> ServalResult<usize> {
+        let Some(replication) = &self.replication else {
+            return Ok(0);
+        };
+        let peers = replication.storage_peers().await;
+        if peers.is_empty() {
+            return Ok(0);
+        }
+
+        let mut repaired = 0;
+        let mut failed = 0;
+        for key in self.list_keys("").await? {
+            if !is_replicated_key(&key) {
+                continue;
+            }
+            let Some(integrity) = self.integrity_of_key(&key).await else {
+                continue;
+            };
+            let targets = if Manifest::parse_manifest_key(&key).is_some() {
+                peers.iter().filter_map(client_for).collect()
+            } else {
+                replication.choose(&integrity, &peers)
+            };
+            for peer in targets {
+                if let Ok(false) = peer.has_replica_by_key(&key).await {
+                    match self.send_key(&peer, &key, &integrity).await {
+                        Ok(()) => repaired += 1,
+                        Err(e) => {
+                            failed += 1;
+                            log::warn!("unable to repair replica; key={key}; {e:?}");
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for backend in &self.backends {
+            for blob in backend.list_blobs().await? {
+                if !seen.insert(blob.integrity.to_string()) {
+                    continue;
+                }
+                let address = blob.integrity.to_string();
+                for peer in replication.choose(&blob.integrity, &peers) {
+                    if let Ok(false) = peer.has_replica(&address).await {
+                        match self.send_blob(&peer, &blob.integrity).await {
+                            Ok(()) => repaired += 1,
+                            Err(e) => {
+                                failed += 1;
+                                log::warn!("unable to repair replica; integrity={address}; {e:?}");
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if repaired > 0 {
+            metrics::counter!("storage:replication:repaired", repaired as u64);
+            log::info!("repaired missing storage replicas; copies={repaired}");
+        }
+        if failed > 0 {
+            metrics::counter!("storage:replication:failed", failed as u64);
+            log::warn!("unable to repair some storage replicas; failures={failed}");
+        }
+        Ok(repaired)
+    }
+
+    /// Store a replica of a blob sent by a peer, in our own backends only.
+    pub async fn store_replica(&self, upload: &Upload) -> ServalResult<Integrity> {
+        let description = format!("replica blob; len={}", upload.size());
+        self.write(&description, |backend| {
+            Box::pin(async move {
+                let stream = upload.open().await?;
+                backend
+                    .store_stream_by_integrity(upload.integrity(), upload.size(), stream)
+                    .await
+            })
+        })
+        .await
+    }
+
+    /// Store a replica of keyed data sent by a peer, in our own backends only. The peer paid for
+    /// it, so if what it replaces was paid for here, that charge is credited.
+    pub async fn store_replica_by_key(
+        &self,
+        key: &str,
+        upload: &Upload,
+    ) -> ServalResult<Integrity> {
+        let stored = self
+            .write_key(&format!("replica key {key}"), key, |backend| {
+                Box::pin(async move {
+                    let stream = upload.open().await?;
+                    backend
+                        .store_stream_by_key(key, upload.integrity(), upload.size(), stream)
+                        .await
+                })
+            })
+            .await?;
+        self.credit_key(key).await;
+        Ok(stored)
+    }
+
+    /// Stream a blob from our own backends only, for a peer.
+    pub async fn replica(&self, integrity: &Integrity) -> ServalResult<SendableStream> {
+        self.read(&format!("replica {integrity}"), |backend| {
+            backend.stream_by_integrity(integrity)
+        })
+        .await
+        .ok_or_else(|| ServalError::DataNotFound(integrity.to_string()))
+    }
+
+    /// Describe a blob in our own backends only, for a peer.
+    pub async fn replica_metadata(&self, integrity: &Integrity) -> Option<BlobMetadata> {
+        self.read(&format!("replica {integrity}"), |backend| {
+            backend.metadata_by_integrity(integrity)
+        })
+        .await
+    }
+
+    /// Stream keyed data from our own backends only, for a peer.
+    pub async fn replica_by_key(&self, key: &str) -> ServalResult<SendableStream> {
+        self.read(&format!("replica key {key}"), |backend| {
+            backend.stream_by_key(key)
+        })
+        .await
+        .ok_or_else(|| ServalError::DataNotFound(key.to_string()))
+    }
+
+    /// Describe keyed data in our own backends only, for a peer.
+    pub async fn replica_metadata_by_key(&self, key: &str) -> Option<BlobMetadata> {
+        self.read(&format!("replica key {key}"), |backend| {
+            backend.metadata_by_key(key)
+        })
+        .await
+    }
+
+    /// Delete a blob from our own backends only, at a peer's request. The peer only knows what its
+    /// own keys refer to, so blobs that one of ours still refers to are refused.
+    pub async fn delete_replica(&self, integrity: &Integrity) -> ServalResult<bool> {
+        if self.is_referenced(integrity).await? {
+            return Err(ServalError::BlobInUse(integrity.to_string()));
+        }
+        let deleted = self
+            .delete(&format!("replica {integrity}"), |backend| {
+                backend.delete_by_integrity(integrity)
+            })
+            .await?;
+        if deleted {
+            self.credit_blob(integrity).await;
+        }
+        Ok(deleted)
+    }
+
+    /// Delete keyed data from our own backends only, at a peer's request.
+    pub async fn delete_replica_by_key(&self, key: &str) -> ServalResult<bool> {
+        let deleted = self.delete_key(&format!("replica key {key}"), key).await?;
+        if deleted {
+            self.credit_key(key).await;
+        }
+        Ok(deleted)
+    }
+}
+
+// Read all of a replica a peer is streaming to us, logging why if we can't.
+async fn read_replica(mut stream: SendableStream, description: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match stream.read_to_end(&mut bytes).await {
+        Ok(_) => Some(bytes),
+        Err(e) => {
+            log::warn!("unable to read a replica; {description}; {e:?}");
+            None
+        }
+    }
+}
+
+// Turn a stream of stored data into a request body without reading it all into memory first. Request
+// bodies have to be shareable between threads and our streams aren't, so the stream is read on a
+// task of its own and handed over a chunk at a time.
+pub(super) fn request_body(stream: SendableStream) -> reqwest::Body {
+    let (sender, receiver) = tokio::sync::mpsc::channel(4);
+    tokio::spawn(async move {
+        let mut chunks = ReaderStream::new(stream);
+        while let Some(chunk) = chunks.next().await {
+            if sender.send(chunk).await.is_err() {
+                break;
+            }
+        }
+    });
+    reqwest::Body::wrap_stream(ReceiverStream::new(receiver))
+}
+
+/// Repair missing replicas every so often, forever. The interval comes from
+/// `STORAGE_REPAIR_INTERVAL`, in seconds. Does nothing unless our storage replicates.
+pub async fn repair_replicas_forever() {
+    let Some(storage) = STORAGE.get() else {
+        return;
+    };
+    if storage.replication.is_none() {
+        return;
+    }
+    let seconds = std::env::var("STORAGE_REPAIR_INTERVAL")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok());
+    let period = seconds.map_or(DEFAULT_REPAIR_INTERVAL, Duration::from_secs);
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(e) = storage.repair_replicas().await {
+            log::warn!("unable to repair storage replicas; {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+    use std::sync::Arc;
+
+    use once_cell::sync::OnceCell;
+
+    use super::*;
+    use crate::api::v1::storage::replica_routes;
+    use crate::storage::{
+        ByteRange, MemoryStorage, ReadPolicy, StorageBackend, Upload, WritePolicy,
+    };
+
+    // A storage node with a single in-memory backend, serving the replica routes on loopback.
+    struct Node {
+        storage: &'static Storage,
+        backend: Arc<MemoryStorage>,
+        client: ServalApiClient,
+    }
+
+    // Start storage nodes that keep `factor` copies of everything between them. Each node's peers
+    // are the other nodes, along with any `strangers` that don't exist.
+    fn start_nodes(count: usize, factor: usize, strangers: &[PeerMetadata]) -> Vec<Node> {
+        let listeners: Vec<TcpListener> = (0..count)
+            .map(|_| TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap())
+            .collect();
+        let peers: Vec<PeerMetadata> = listeners
+            .iter()
+            .enumerate()
+            .map(|(i, listener)| {
+                let port = listener.local_addr().unwrap().port();
+                storage_peer(&format!("node-{i}"), port, Ipv4Addr::LOCALHOST)
+            })
+            .chain(strangers.iter().cloned())
+            .collect();
+
+        listeners
+            .into_iter()
+            .enumerate()
+            .map(|(i, listener)| {
+                let backend = Arc::new(MemoryStorage::new());
+                let backends: Vec<Arc<dyn StorageBackend>> = vec![backend.clone()];
+                let replication = Replication {
+                    factor,
+                    instance_id: format!("node-{i}"),
+                    peers: Some(peers.clone()),
+                };
+                let storage = Storage::new(backends, ReadPolicy::FirstHit, WritePolicy::All)
+                    .with_replication(replication);
+                let cell: &'static OnceCell<Storage> = Box::leak(Box::new(OnceCell::new()));
+                cell.set(storage).ok();
+
+                let address = listener.local_addr().unwrap();
+                let app = replica_routes::<()>(cell);
+                let server = axum::Server::from_tcp(listener)
+                    .unwrap()
+                    .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+                tokio::spawn(server);
+
+                Node {
+                    storage: cell.get().unwrap(),
+                    backend,
+                    client: ServalApiClient::new_with_version(1, address.to_string()),
+                }
+            })
+            .collect()
+    }
+
+    fn storage_peer(instance_id: &str, port: u16, address: Ipv4Addr) -> PeerMetadata {
+        PeerMetadata::new(
+            instance_id.to_string(),
+            Some(port),
+            vec![ServalRole::Storage],
+            Vec::new(),
+            address.into(),
+        )
+    }
+
+    fn loudify() -> Manifest {
+        Manifest::from_string(
+            r#"
+name = "loudify"
+namespace = "sh.serval"
+binary = "/loudify.wasm"
+version = "0.1.0"
+description = "make text louder"
+"#,
+        )
+        .unwrap()
+    }
+
+    async fn read_all(mut stream: SendableStream) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        stream.read_to_end(&mut bytes).await.unwrap();
+        bytes
+    }
+
+    #[test]
+    fn ring_chooses_distinct_nodes_stably() {
+        let ring = HashRing::new(["a", "b", "c", "d"]);
+        let replicas = ring.replicas("sha256-abc", 3);
+        assert_eq!(replicas.len(), 3);
+        let distinct: HashSet<&str> = replicas.iter().copied().collect();
+        assert_eq!(distinct.len(), 3);
+        assert_eq!(ring.replicas("sha256-abc", 3), replicas);
+        // Asking for more nodes than there are gets you all of them.
+        assert_eq!(ring.replicas("sha256-abc", 10).len(), 4);
+        assert!(HashRing::default().replicas("sha256-abc", 2).is_empty());
+
+        // The order of the nodes doesn't matter; every agent builds the same ring.
+        let shuffled = HashRing::new(["c", "a", "d", "b"]);
+        assert_eq!(shuffled.replicas("sha256-abc", 3), replicas);
+    }
+
+    #[test]
+    fn adding_a_node_moves_little_data() {
+        let before = HashRing::new(["a", "b", "c", "d"]);
+        let after = HashRing::new(["a", "b", "c", "d", "e"]);
+        let placements: Vec<String> = (0..1000).map(|i| format!("blob-{i}")).collect();
+        let moved = placements
+            .iter()
+            .filter(|placement| before.replicas(placement, 1) != after.replicas(placement, 1))
+            .count();
+        // Ideally a fifth of the data moves to the new node, and nothing else moves at all.
+        assert!(moved < 350, "{moved} of 1000 placements moved");
+        for placement in &placements {
+            let now = after.replicas(placement, 1);
+            assert!(now == before.replicas(placement, 1) || now == vec!["e"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_replicas_its_own_keys_refer_to() {
+        let backends: Vec<Arc<dyn StorageBackend>> = vec![Arc::new(MemoryStorage::new())];
+        let storage = Storage::new(backends, ReadPolicy::FirstHit, WritePolicy::All);
+        let pinned = storage.store_by_integrity(None, b"pinned").await.unwrap();
+        let stray = storage.store_by_integrity(None, b"stray").await.unwrap();
+        storage.pin(&pinned).await.unwrap();
+
+        assert!(matches!(
+            storage.delete_replica(&pinned).await,
+            Err(ServalError::BlobInUse(_))
+        ));
+        assert!(storage.data_exists_by_integrity(&pinned).await.unwrap());
+        assert!(storage.delete_replica(&stray).await.unwrap());
+        assert!(!storage.delete_replica(&stray).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn copies_writes_to_peers() {
+        let nodes = start_nodes(3, 3, &[]);
+        let data = b"three copies of this, please";
+        let integrity = nodes[0]
+            .storage
+            .store_by_integrity(None, data)
+            .await
+            .unwrap();
+        let manifest = loudify();
+        nodes[0].storage.store_manifest(&manifest).await.unwrap();
+
+        for node in &nodes {
+            assert_eq!(
+                node.backend.data_by_integrity(&integrity).await.unwrap(),
+                data
+            );
+            assert!(node
+                .backend
+                .data_exists_by_key(&manifest.manifest_key())
+                .await
+                .unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_what_it_lacks_from_peers() {
+        let nodes = start_nodes(2, 2, &[]);
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let integrity = nodes[0]
+            .storage
+            .store_by_integrity(None, &data)
+            .await
+            .unwrap();
+        let manifest = loudify();
+        nodes[0].storage.store_manifest(&manifest).await.unwrap();
+        let reader = &nodes[1];
+        reader
+            .backend
+            .delete_by_integrity(&integrity)
+            .await
+            .unwrap();
+        reader
+            .backend
+            .delete_by_key(&manifest.manifest_key())
+            .await
+            .unwrap();
+
+        let metadata = reader
+            .storage
+            .metadata_by_integrity(&integrity)
+            .await
+            .unwrap();
+        assert_eq!(metadata.size, data.len() as u64);
+        let stream = reader.storage.replicated_blob(&integrity).await.unwrap();
+        assert_eq!(read_all(stream).await, data);
+        assert_eq!(
+            reader
+                .storage
+                .data_by_integrity(integrity.clone())
+                .await
+                .unwrap(),
+            data
+        );
+        let range = ByteRange {
+            start: 150_000,
+            len: 10,
+        };
+        let body = reader
+            .storage
+            .stream_range_by_integrity(&integrity, range)
+            .await
+            .unwrap();
+        let bytes = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(bytes.as_ref(), &data[150_000..150_010]);
+
+        let stored = reader.storage.manifest(&manifest.fq_name()).await.unwrap();
+        assert_eq!(stored.version(), manifest.version());
+
+        // Reading from a replica doesn't quietly store a copy here.
+        assert!(!reader
+            .backend
+            .data_exists_by_integrity(&integrity)
+            .await
+            .unwrap());
+
+        // A peer that doesn't have it either can't help.
+        nodes[0]
+            .backend
+            .delete_by_integrity(&integrity)
+            .await
+            .unwrap();
+        assert!(reader
+            .storage
+            .metadata_by_integrity(&integrity)
+            .await
+            .is_err());
+        assert!(reader.storage.replicated_blob(&integrity).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn describes_and_reads_parts_of_executables_it_lacks_from_peers() {
+        let nodes = start_nodes(2, 2, &[]);
+        let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+        let upload = Upload::spool(&data[..], None).await.unwrap();
+        let integrity = nodes[0]
+            .storage
+            .store_executable("sh.serval.loudify", "1.0.0", &upload)
+            .await
+            .unwrap();
+        let reader = &nodes[1];
+        let key = Manifest::make_executable_key("sh.serval.loudify", "1.0.0");
+        reader.backend.delete_by_key(&key).await.unwrap();
+        reader
+            .backend
+            .delete_by_integrity(&integrity)
+            .await
+            .unwrap();
+
+        let metadata = reader
+            .storage
+            .executable_metadata("sh.serval.loudify", "1.0.0")
+            .await
+            .unwrap();
+        assert_eq!(metadata.integrity, integrity);
+        assert_eq!(metadata.size, data.len() as u64);
+        let range = ByteRange {
+            start: 150_000,
+            len: 10,
+        };
+        let body = reader
+            .storage
+            .executable_range_as_stream("sh.serval.loudify", "1.0.0", range)
+            .await
+            .unwrap();
+        let bytes = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(bytes.as_ref(), &data[150_000..150_010]);
+    }
+
+    #[tokio::test]
+    async fn repairs_missing_replicas() {
+        let nodes = start_nodes(3, 3, &[]);
+        let integrity = nodes[0]
+            .storage
+            .store_by_integrity(None, b"keep me")
+            .await
+            .unwrap();
+        for node in &nodes[1..] {
+            node.backend.delete_by_integrity(&integrity).await.unwrap();
+        }
+
+        // The in-memory backend keeps keyed data as blobs too, so more than our blob goes over.
+        assert!(nodes[0].storage.repair_replicas().await.unwrap() >= 2);
+        for node in &nodes {
+            assert!(node
+                .backend
+                .data_exists_by_integrity(&integrity)
+                .await
+                .unwrap());
+        }
+        assert_eq!(nodes[0].storage.repair_replicas().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn only_storage_peers_may_replicate() {
+        // The node's only peer is somewhere else entirely, so requests from loopback aren't from
+        // a peer.
+        let stranger = storage_peer("stranger", 8100, Ipv4Addr::new(192, 0, 2, 1));
+        let node = &start_nodes(1, 2, &[stranger])[0];
+        let held = node.backend.store_by_integrity(b"held").await.unwrap();
+        node.backend.store_by_key("held", b"held").await.unwrap();
+
+        let sneaky = Integrity::from(b"sneaky");
+        assert!(node
+            .client
+            .store_replica(&sneaky, b"sneaky".to_vec())
+            .await
+            .is_err());
+        assert!(!node
+            .backend
+            .data_exists_by_integrity(&sneaky)
+            .await
+            .unwrap());
+        assert!(node.client.get_replica(&held.to_string()).await.is_err());
+        assert!(node.client.delete_replica_by_key("held").await.is_err());
+        assert!(node.backend.data_exists_by_key("held").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn refuses_replicas_that_are_not_what_they_claim() {
+        let nodes = start_nodes(2, 2, &[]);
+        let claimed = Integrity::from(b"claimed");
+        assert!(nodes[0]
+            .client
+            .store_replica(&claimed, b"something else".to_vec())
+            .await
+            .is_err());
+        assert!(nodes[0]
+            .client
+            .store_replica_by_key("key", &claimed, b"something else".to_vec())
+            .await
+            .is_err());
+        assert!(!nodes[0].backend.data_exists_by_key("key").await.unwrap());
+
+        let stored = nodes[0]
+            .client
+            .store_replica(&claimed, b"claimed".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(stored, claimed);
+    }
+}
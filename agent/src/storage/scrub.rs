@@ -0,0 +1,331 @@
+//! Scrubbing: reading back everything we store to find copies that no longer match their
+//! integrity, whether from bit rot on a local disk or from something changing objects in a bucket
+//! behind our back.
+//!
+//! A scrub walks every blob in every backend, and in the read cache, checking each one as it reads
+//! it. Corrupt copies are reported. A repairing scrub also replaces each one with a good copy from
+//! another backend or from a peer's replica; corrupt copies in the read cache are just dropped,
+//! since the next read will fetch them again. Repairing scrubs can also run in the background.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use ssri::Integrity;
+use utils::errors::{ServalError, ServalResult};
+use utils::structs::api::{CorruptBlob, Scrub};
+
+use super::backend::is_integrity_mismatch;
+use super::{make_proxy_client, SendableStream, Storage, StorageBackend, STORAGE};
+
+impl Storage {
+    /// Read back every blob in every backend and check it against its integrity, reporting the
+    /// copies that don't match. With `repair`, replace each corrupt copy with a good one, if we can
+    /// find one.
+    pub async fn scrub(&self, repair: bool) -> ServalResult<Scrub> {
+        if !self.has_storage() {
+            let proxy = make_proxy_client().await?;
+            return proxy.scrub(repair).await;
+        }
+
+        metrics::increment_counter!("storage:scrub:run");
+        let mut report = Scrub {
+            repair,
+            ..Default::default()
+        };
+        for backend in &self.backends {
+            for integrity in self.scrub_backend(backend.as_ref(), &mut report).await {
+                let repaired = repair && self.repair_blob(backend, &integrity).await;
+                report.corrupt.push(CorruptBlob {
+                    backend: backend.name().to_string(),
+                    integrity: integrity.to_string(),
+                    repaired,
+                });
+            }
+        }
+        if let Some(cache) = &self.cache {
+            for integrity in self.scrub_backend(cache.store(), &mut report).await {
+                if repair {
+                    cache.forget(&integrity).await;
+                }
+                report.corrupt.push(CorruptBlob {
+                    backend: "read cache".to_string(),
+                    integrity: integrity.to_string(),
+                    repaired: repair,
+                });
+            }
+        }
+
+        let repaired = report.corrupt.iter().filter(|blob| blob.repaired).count();
+        metrics::counter!("storage:scrub:corrupt", report.corrupt.len() as u64);
+        metrics::counter!("storage:scrub:repaired", repaired as u64);
+        log::info!(
+            "scrubbed storage; repair={repair}; checked={}; corrupt={}; repaired={repaired}",
+            report.checked,
+            report.corrupt.len()
+        );
+        Ok(report)
+    }
+
+    // Check every blob in one backend, counting them in the report and responding with the ones
+    // that are corrupt. Blobs we can't read at all are logged and skipped: they may have been
+    // deleted since we listed them.
+    async fn scrub_backend(
+        &self,
+        backend: &dyn StorageBackend,
+        report: &mut Scrub,
+    ) -> Vec<Integrity> {
+        let blobs = match backend.list_blobs().await {
+            Ok(blobs) => blobs,
+            Err(e) => {
+                log::warn!("unable to list blobs to scrub in {}; {e:?}", backend.name());
+                return Vec::new();
+            }
+        };
+        let mut corrupt = Vec::new();
+        for blob in blobs {
+            match check_blob(backend, &blob.integrity).await {
+                Ok(true) => {}
+                Ok(false) => corrupt.push(blob.integrity),
+                Err(e) => {
+                    log::warn!(
+                        "unable to scrub {}; integrity={}; {e:?}",
+                        backend.name(),
+                        blob.integrity
+                    );
+                    continue;
+                }
+            }
+            report.checked += 1;
+        }
+        corrupt
+    }
+
+    // Replace the corrupt copy of a blob in one of our backends with a good copy from another of
+    // them, or failing that, from a peer. Responds with whether it could.
+    async fn repair_blob(&self, corrupt: &Arc<dyn StorageBackend>, integrity: &Integrity) -> bool {
+        let Some((size, stream)) = self.good_copy(corrupt, integrity).await else {
+            log::warn!(
+                "no good copy of a corrupt blob to repair it with; backend={}; integrity={integrity}",
+                corrupt.name()
+            );
+            return false;
+        };
+        // The backend checks the good copy against the integrity as it stores it.
+        match corrupt
+            .restore_stream_by_integrity(integrity, size, stream)
+            .await
+        {
+            Ok(_) => {
+                log::info!(
+                    "repaired corrupt blob; backend={}; integrity={integrity}",
+                    corrupt.name()
+                );
+                true
+            }
+            Err(e) => {
+                log::warn!(
+                    "unable to repair corrupt blob; backend={}; integrity={integrity}; {e:?}",
+                    corrupt.name()
+                );
+                false
+            }
+        }
+    }
+
+    // Find a copy of a blob that isn't the corrupt one, along with its size.
+    async fn good_copy(
+        &self,
+        corrupt: &Arc<dyn StorageBackend>,
+        integrity: &Integrity,
+    ) -> Option<(u64, SendableStream)> {
+        for backend in &self.backends {
+            if Arc::ptr_eq(backend, corrupt) {
+                continue;
+            }
+            // Restoring from another corrupt copy would fail, and we'd lose the chance to try the
+            // next one.
+            if !matches!(check_blob(backend.as_ref(), integrity).await, Ok(true)) {
+                continue;
+            }
+            let Ok(metadata) = backend.metadata_by_integrity(integrity).await else {
+                continue;
+            };
+            if let Ok(stream) = backend.stream_by_integrity(integrity).await {
+                return Some((metadata.size, stream));
+            }
+        }
+        let size = self.replicated_blob_size(integrity).await?;
+        Some((size, self.replicated_blob(integrity).await?))
+    }
+}
+
+// Read a blob back from a backend, responding with whether it matches its integrity. Backends
+// check what they read, so all we have to do is read all of it.
+async fn check_blob(backend: &dyn StorageBackend, integrity: &Integrity) -> ServalResult<bool> {
+    let read = async {
+        let mut stream = backend.stream_by_integrity(integrity).await?;
+        tokio::io::copy(&mut stream, &mut tokio::io::sink()).await?;
+        Ok::<(), ServalError>(())
+    };
+    match read.await {
+        Ok(()) => Ok(true),
+        Err(e) if is_integrity_mismatch(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Scrub storage every so often, forever, repairing whatever is corrupt. The interval comes from
+/// `STORAGE_SCRUB_INTERVAL`, in seconds; scrubs read everything we store, so they don't run in the
+/// background unless it's set.
+pub async fn scrub_forever() {
+    let Some(storage) = STORAGE.get() else {
+        return;
+    };
+    let Some(seconds) = std::env::var("STORAGE_SCRUB_INTERVAL")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .filter(|seconds| *seconds > 0)
+    else {
+        return;
+    };
+    log::info!("background storage scrubs enabled; interval={seconds}s");
+    let mut interval = tokio::time::interval(Duration::from_secs(seconds));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick is immediate; give the agent a chance to settle in first.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(e) = storage.scrub(true).await {
+            log::warn!("unable to scrub storage; {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use utils::structs::Manifest;
+
+    use super::*;
+    use crate::storage::{BlobStore, DirectoryStorage, ReadPolicy, Upload, WritePolicy};
+
+    // Flip a byte in the middle of a file, keeping its size, the way bit rot would.
+    fn damage(path: &Path) {
+        let mut bytes = std::fs::read(path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        let mut permissions = std::fs::metadata(path).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        std::fs::set_permissions(path, permissions).unwrap();
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    fn cacache_path(root: &Path, integrity: &Integrity) -> PathBuf {
+        let (algorithm, hex) = integrity.to_hex();
+        root.join("content-v2")
+            .join(algorithm.to_string())
+            .join(&hex[0..2])
+            .join(&hex[2..4])
+            .join(&hex[4..])
+    }
+
+    fn directory_path(root: &Path, integrity: &Integrity) -> PathBuf {
+        let (algorithm, hex) = integrity.to_hex();
+        root.join(algorithm.to_string())
+            .join(&hex[..2])
+            .join(&hex[2..])
+    }
+
+    #[tokio::test]
+    async fn falls_back_from_and_repairs_corrupt_copies() {
+        let root = std::env::temp_dir().join(format!("serval-scrub-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        let (blobs, directory) = (root.join("blobs"), root.join("directory"));
+        let backends: Vec<Arc<dyn StorageBackend>> = vec![
+            Arc::new(BlobStore::new(&blobs).unwrap()),
+            Arc::new(DirectoryStorage::new(&directory).unwrap()),
+        ];
+        let storage = Storage::new(backends.clone(), ReadPolicy::FirstHit, WritePolicy::All);
+
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let blob = storage.store_by_integrity(None, &data).await.unwrap();
+        let executable = b"\0asm, but not really".to_vec();
+        let upload = Upload::spool(&executable[..], None).await.unwrap();
+        let keyed = storage
+            .store_executable("sh.serval.loudify", "1.0.0", &upload)
+            .await
+            .unwrap();
+
+        let clean = storage.scrub(false).await.unwrap();
+        assert!(clean.checked >= 4);
+        assert!(clean.corrupt.is_empty());
+
+        // Reads pass over the corrupt local copy and find the good one in the directory.
+        damage(&cacache_path(&blobs, &blob));
+        damage(&directory_path(&directory, &keyed));
+        assert_eq!(storage.data_by_integrity(blob.clone()).await.unwrap(), data);
+        assert!(matches!(
+            backends[0].data_by_integrity(&blob).await,
+            Err(ServalError::IntegrityMismatch(_))
+        ));
+        assert_eq!(
+            storage
+                .executable_as_bytes("sh.serval.loudify", "1.0.0")
+                .await
+                .unwrap(),
+            executable
+        );
+
+        let report = storage.scrub(false).await.unwrap();
+        assert_eq!(report.checked, clean.checked);
+        let mut corrupt: Vec<(String, String, bool)> = report
+            .corrupt
+            .into_iter()
+            .map(|blob| (blob.backend, blob.integrity, blob.repaired))
+            .collect();
+        corrupt.sort();
+        assert_eq!(
+            corrupt,
+            vec![
+                ("local blobs".to_string(), blob.to_string(), false),
+                ("local directory".to_string(), keyed.to_string(), false),
+            ]
+        );
+
+        let repaired = storage.scrub(true).await.unwrap();
+        assert_eq!(repaired.corrupt.len(), 2);
+        assert!(repaired.corrupt.iter().all(|blob| blob.repaired));
+        assert!(storage.scrub(false).await.unwrap().corrupt.is_empty());
+        assert_eq!(backends[0].data_by_integrity(&blob).await.unwrap(), data);
+        // The directory's key shares its file with the blob, and was repaired along with it.
+        let key = Manifest::make_executable_key("sh.serval.loudify", "1.0.0");
+        assert_eq!(backends[1].data_by_key(&key).await.unwrap(), executable);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_what_it_cannot_repair() {
+        let root = std::env::temp_dir().join(format!("serval-scrub-{}", uuid::Uuid::new_v4()));
+        let backends: Vec<Arc<dyn StorageBackend>> =
+            vec![Arc::new(DirectoryStorage::new(&root).unwrap())];
+        let storage = Storage::new(backends, ReadPolicy::FirstHit, WritePolicy::All);
+
+        let blob = storage
+            .store_by_integrity(None, b"the only copy")
+            .await
+            .unwrap();
+        damage(&directory_path(&root, &blob));
+        assert!(storage.data_by_integrity(blob.clone()).await.is_err());
+
+        let report = storage.scrub(true).await.unwrap();
+        assert_eq!(report.corrupt.len(), 1);
+        assert!(!report.corrupt[0].repaired);
+        // The corrupt copy is left where it is, for someone to look at.
+        assert!(directory_path(&root, &blob).exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
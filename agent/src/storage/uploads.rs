@@ -0,0 +1,199 @@
+//! Uploads too big to hold in memory. An upload is streamed to a temporary file as it arrives,
+//! hashing it on the way, so that by the time we decide where to store it we know its integrity
+//! and size: enough to check quotas, to skip blobs we already have, and to stream it to each of our
+//! backends in turn without holding more than a buffer's worth of it at once.
+
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use ssri::{Algorithm, Integrity, IntegrityOpts};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use utils::errors::{ServalError, ServalResult};
+use uuid::Uuid;
+
+use super::quotas::parse_size;
+use super::SendableStream;
+
+// How much of an upload we read at a time.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// An upload that has been streamed to a temporary file. The file is removed when this is dropped.
+#[derive(Debug)]
+pub struct Upload {
+    file: TemporaryFile,
+    integrity: Integrity,
+    size: u64,
+}
+
+impl Upload {
+    /// Stream an upload into a temporary file, computing its integrity as it arrives. Fails if more
+    /// than `limit` bytes arrive.
+    pub async fn spool(
+        mut reader: impl AsyncRead + Unpin,
+        limit: Option<u64>,
+    ) -> ServalResult<Self> {
+        let file = TemporaryFile::new();
+        let mut writer = tokio::fs::File::create(&file.0).await?;
+        let mut hasher = IntegrityOpts::new().algorithm(Algorithm::Sha256);
+        let mut buffer = vec![0; BUFFER_SIZE];
+        let mut size: u64 = 0;
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            size += read as u64;
+            if let Some(limit) = limit {
+                if size > limit {
+                    return Err(ServalError::PayloadTooLarge(format!(
+                        "uploads may be at most {limit} bytes"
+                    )));
+                }
+            }
+            hasher.input(&buffer[..read]);
+            writer.write_all(&buffer[..read]).await?;
+        }
+        writer.flush().await?;
+
+        Ok(Self {
+            file,
+            integrity: hasher.result(),
+            size,
+        })
+    }
+
+    /// The integrity of the uploaded data.
+    pub fn integrity(&self) -> &Integrity {
+        &self.integrity
+    }
+
+    /// The size of the uploaded data in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Read the uploaded data from the start. Each call gets a stream of its own.
+    pub async fn open(&self) -> ServalResult<SendableStream> {
+        let file = tokio::fs::File::open(&self.file.0).await?;
+        Ok(Box::pin(file))
+    }
+
+    /// Load all of the uploaded data into memory.
+    pub async fn bytes(&self) -> ServalResult<Vec<u8>> {
+        Ok(tokio::fs::read(&self.file.0).await?)
+    }
+
+    /// Read the uploaded data from the start, keeping the temporary file around until the stream
+    /// is dropped rather than the upload.
+    pub async fn into_stream(self) -> ServalResult<SendableStream> {
+        let file = tokio::fs::File::open(&self.file.0).await?;
+        Ok(Box::pin(UploadStream {
+            file,
+            _upload: self,
+        }))
+    }
+}
+
+// An upload being read, which owns the upload so that its file outlives the read.
+struct UploadStream {
+    file: tokio::fs::File,
+    _upload: Upload,
+}
+
+impl AsyncRead for UploadStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.file).poll_read(cx, buf)
+    }
+}
+
+/// The most an upload streamed into storage may hold, from `STORAGE_UPLOAD_LIMIT`, as a byte count
+/// like `20G`. Uploads are unlimited unless it's set.
+pub fn upload_limit() -> ServalResult<Option<u64>> {
+    match std::env::var("STORAGE_UPLOAD_LIMIT") {
+        Ok(limit) => match parse_size(limit.trim()) {
+            Some(limit) => Ok(Some(limit)),
+            None => Err(ServalError::StorageError(format!(
+                "not a valid upload limit `{limit}`; try something like 20G"
+            ))),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+// A file in the upload directory that's removed when we're done with it, however that happens. The
+// upload directory is `STORAGE_UPLOAD_PATH`, or the system's temporary directory if that isn't set;
+// it needs room for the biggest uploads we accept.
+#[derive(Debug)]
+struct TemporaryFile(PathBuf);
+
+impl TemporaryFile {
+    fn new() -> Self {
+        let directory = std::env::var("STORAGE_UPLOAD_PATH")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| std::env::temp_dir());
+        Self(directory.join(format!("serval-upload-{}", Uuid::new_v4())))
+    }
+}
+
+impl Drop for TemporaryFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("unable to remove upload file {}; {e}", self.0.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn spools_uploads_and_cleans_up_after_them() {
+        let data = vec![7u8; BUFFER_SIZE * 3 + 5];
+        let upload = Upload::spool(data.as_slice(), None).await.unwrap();
+        assert_eq!(upload.size(), data.len() as u64);
+        assert_eq!(upload.integrity(), &Integrity::from(&data));
+        assert_eq!(upload.bytes().await.unwrap(), data);
+
+        let mut reread = Vec::new();
+        upload
+            .open()
+            .await
+            .unwrap()
+            .read_to_end(&mut reread)
+            .await
+            .unwrap();
+        assert_eq!(reread, data);
+
+        let path = upload.file.0.clone();
+        drop(upload);
+        assert!(!path.exists());
+
+        assert!(matches!(
+            Upload::spool(data.as_slice(), Some(BUFFER_SIZE as u64)).await,
+            Err(ServalError::PayloadTooLarge(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn keeps_uploads_until_they_have_been_streamed() {
+        let data = vec![7u8; BUFFER_SIZE + 5];
+        let upload = Upload::spool(data.as_slice(), None).await.unwrap();
+        let path = upload.file.0.clone();
+        let mut stream = upload.into_stream().await.unwrap();
+        assert!(path.exists());
+
+        let mut reread = Vec::new();
+        stream.read_to_end(&mut reread).await.unwrap();
+        assert_eq!(reread, data);
+        drop(stream);
+        assert!(!path.exists());
+    }
+}
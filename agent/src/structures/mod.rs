@@ -4,12 +4,15 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use engine::extensions::{load_extensions, ServalExtension};
-use once_cell::sync::OnceCell;
+use once_cell::sync::{Lazy, OnceCell};
 use utils::errors::ServalError;
+use utils::mesh::events::MeshWatcher;
 use utils::mesh::ServalMesh;
 use uuid::Uuid;
 
 pub static MESH: OnceCell<ServalMesh> = OnceCell::new();
+/// Membership changes in the mesh, for anyone who wants to follow along.
+pub static MESH_EVENTS: Lazy<MeshWatcher> = Lazy::new(MeshWatcher::new);
 
 pub type ServalRouter = axum::Router<Arc<RunnerState>, hyper::Body>;
 
@@ -21,6 +24,7 @@ pub struct RunnerState {
     pub should_run_jobs: bool,
     pub should_run_scheduler: bool,
     pub has_storage: bool,
+    pub max_proxy_hops: usize,
 }
 
 impl RunnerState {
@@ -30,9 +34,10 @@ impl RunnerState {
         extensions_path: Option<PathBuf>,
         should_run_jobs: bool,
         should_run_scheduler: bool,
+        max_proxy_hops: usize,
     ) -> Result<Self, ServalError> {
         let has_storage = blob_path.is_some();
-        crate::storage::initialize(blob_path).await?;
+        crate::storage::initialize(blob_path, &instance_id.to_string()).await?;
 
         let extensions = extensions_path
             .and_then(|extensions_path| {
@@ -53,6 +58,7 @@ impl RunnerState {
             should_run_jobs,
             should_run_scheduler,
             has_storage,
+            max_proxy_hops,
         })
     }
 }
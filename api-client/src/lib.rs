@@ -9,10 +9,18 @@
 
 use std::time::Duration;
 
-use reqwest::{Response, StatusCode};
+use futures_util::{stream, Stream, StreamExt};
+use reqwest::header::{HeaderValue, CONTENT_LENGTH};
+use reqwest::{Method, Response, StatusCode};
 use ssri::Integrity;
 use utils::errors::ServalError;
-use utils::mesh::{PeerMetadata, ServalRole};
+use utils::mesh::{sign_peer_request, ServalRole, PEER_SIGNATURE_HEADER};
+use utils::structs::api::{
+    BlobInfo, BlobQuery, BundleRequest, DescribedBlob, DiffQuery, ExecutableQuery,
+    GarbageCollection, GarbageCollectionQuery, ImportedBundle, KeyRotation, ManifestListing,
+    ManifestQuery, ManifestVersion, MeshEvent, MeshMember, NamespaceUsage, PeerQuery, Scrub,
+    ScrubQuery, UploadQuery, INTEGRITY_HEADER,
+};
 use utils::structs::Manifest;
 
 type ApiResult<T> = Result<T, ServalError>;
@@ -71,9 +79,10 @@ impl ServalApiClient {
         Ok(body)
     }
 
-    /// Run a previously-stored Wasm job by its fully-qualified name. If the job
-    /// needs input, send it in as a vec of bytes. Pass a zero-length vec if the
-    /// job doesn't need input.
+    /// Run a previously-stored Wasm job by its fully-qualified name. The name may be followed by
+    /// `@` and a version requirement, such as `@1.2`, `@^2` or `@latest`; otherwise the latest
+    /// version runs. If the job needs input, send it in as a vec of bytes. Pass a zero-length vec
+    /// if the job doesn't need input.
     pub async fn run_job(&self, name: &str, input: Vec<u8>) -> ApiResult<Response> {
         let url = self.build_url(&format!("jobs/{name}/run"));
         let client = reqwest::Client::builder()
@@ -86,23 +95,65 @@ impl ServalApiClient {
     }
 
     /// Get a list of all peers the node is aware of.
-    pub async fn all_peers(&self) -> ApiResult<Vec<PeerMetadata>> {
+    pub async fn all_peers(&self) -> ApiResult<Vec<MeshMember>> {
+        self.peers(&PeerQuery::default()).await
+    }
+
+    /// Get a list of the peers the node is aware of, narrowed down and ordered by the query.
+    pub async fn peers(&self, query: &PeerQuery) -> ApiResult<Vec<MeshMember>> {
         let url = self.build_url("mesh/peers");
-        let response = reqwest::get(&url).await?;
-        let body: Vec<PeerMetadata> = response.json().await?;
+        let response = reqwest::Client::new().get(url).query(query).send().await?;
+        let body: Vec<MeshMember> = response.error_for_status()?.json().await?;
 
         Ok(body)
     }
 
     /// Get a list of all known peers advertising the given role.
-    pub async fn peers_with_role(&self, role: ServalRole) -> ApiResult<Vec<PeerMetadata>> {
+    pub async fn peers_with_role(&self, role: ServalRole) -> ApiResult<Vec<MeshMember>> {
+        self.peers_with_role_matching(role, &PeerQuery::default())
+            .await
+    }
+
+    /// Get a list of the known peers advertising the given role, narrowed down and ordered by the query.
+    pub async fn peers_with_role_matching(
+        &self,
+        role: ServalRole,
+        query: &PeerQuery,
+    ) -> ApiResult<Vec<MeshMember>> {
         let url = self.build_url(&format!("mesh/peers/{role}"));
-        let response = reqwest::get(&url).await?;
-        let body: Vec<PeerMetadata> = response.json().await?;
+        let response = reqwest::Client::new().get(url).query(query).send().await?;
+        let body: Vec<MeshMember> = response.error_for_status()?.json().await?;
 
         Ok(body)
     }
 
+    /// Subscribe to changes in the mesh's membership, as seen by this node. The stream starts with a
+    /// `Joined` event for every current member, then carries on for as long as the node keeps the
+    /// connection open; it ends if we fall too far behind, and subscribing again starts afresh.
+    pub async fn mesh_events(&self) -> ApiResult<impl Stream<Item = ApiResult<MeshEvent>>> {
+        let url = self.build_url("mesh/events");
+        let response = reqwest::get(&url).await?.error_for_status()?;
+        let chunks = response.bytes_stream();
+
+        let events = stream::unfold(
+            (chunks, Vec::new()),
+            |(mut chunks, mut buffer)| async move {
+                loop {
+                    if let Some(data) = next_sse_data(&mut buffer) {
+                        let event = serde_json::from_str::<MeshEvent>(&data)
+                            .map_err(|err| ServalError::AnyhowError(err.into()));
+                        return Some((event, (chunks, buffer)));
+                    }
+                    match chunks.next().await? {
+                        Ok(chunk) => buffer.extend_from_slice(&chunk),
+                        Err(err) => return Some((Err(err.into()), (chunks, buffer))),
+                    }
+                }
+            },
+        );
+        Ok(events)
+    }
+
     /// Store a Wasm manifest on the node.
     pub async fn store_manifest(&self, manifest: &Manifest) -> ApiResult<Integrity> {
         let client = reqwest::Client::builder()
@@ -112,13 +163,7 @@ impl ServalApiClient {
         let response = client.post(url).body(manifest.to_string()).send().await?;
 
         // StatusCode.CREATED  + ssri string
-        if response.status().is_success() {
-            let body = response.text().await?;
-            let integrity: Integrity = body.parse()?;
-            Ok(integrity)
-        } else {
-            Err(ServalError::StorageError(response.text().await?))
-        }
+        upload_response(response).await
     }
 
     /// Fetch a manifest from the node. The response will be *toml*, not json
@@ -135,6 +180,40 @@ impl ServalApiClient {
         }
     }
 
+    /// Fetch a specific version of a manifest from the node.
+    pub async fn get_manifest_version(&self, name: &str, version: &str) -> ApiResult<Manifest> {
+        let url = self.build_url(&format!("storage/manifests/{name}/versions/{version}"));
+        let response = reqwest::get(&url).await?;
+        if response.status().is_success() {
+            let text = response.text().await?;
+            let manifest = Manifest::from_string(&text)?;
+            Ok(manifest)
+        } else {
+            Err(ServalError::ManifestNotFound(response.text().await?))
+        }
+    }
+
+    /// List the latest versions of the stored manifests that match the query, a page at a time.
+    pub async fn list_manifests(&self, query: &ManifestQuery) -> ApiResult<ManifestListing> {
+        let url = self.build_url("storage/manifests");
+        let response = reqwest::Client::new().get(url).query(query).send().await?;
+        let body: ManifestListing = response.error_for_status()?.json().await?;
+
+        Ok(body)
+    }
+
+    /// List every stored version of the named manifest, with the executable stored for each.
+    pub async fn manifest_versions(&self, name: &str) -> ApiResult<Vec<ManifestVersion>> {
+        let url = self.build_url(&format!("storage/manifests/{name}/versions"));
+        let response = reqwest::get(&url).await?;
+        if response.status().is_success() {
+            let body: Vec<ManifestVersion> = response.json().await?;
+            Ok(body)
+        } else {
+            Err(ServalError::ManifestNotFound(response.text().await?))
+        }
+    }
+
     /// Check if this node has in its local storage the named manifest.
     pub async fn has_manifest(&self, name: &str) -> ApiResult<bool> {
         let url = self.build_url(&format!("storage/manifests/{name}"));
@@ -161,13 +240,23 @@ impl ServalApiClient {
             .timeout(Duration::from_secs(60))
             .build()?;
         let response = client.put(url).body(executable).send().await?;
-        if response.status().is_success() {
-            let body = response.text().await?;
-            let integrity: Integrity = body.parse()?;
-            Ok(integrity)
-        } else {
-            Err(ServalError::StorageError(response.text().await?))
-        }
+        upload_response(response).await
+    }
+
+    /// Use a blob already in the content-addressable store on the targeted peer as the executable
+    /// for the given version of the named manifest, without uploading it again.
+    pub async fn store_executable_from_blob(
+        &self,
+        name: &str,
+        version: &str,
+        integrity: &str,
+    ) -> ApiResult<Integrity> {
+        let url = self.build_url(&format!("storage/manifests/{name}/executable/{version}"));
+        let query = ExecutableQuery {
+            integrity: Some(integrity.to_string()),
+        };
+        let response = reqwest::Client::new().put(url).query(&query).send().await?;
+        upload_response(response).await
     }
 
     /// Fetch the bytes for the named Wasm executable.
@@ -193,27 +282,502 @@ impl ServalApiClient {
         }
     }
 
-    /// Store a blob of data in the content-addressable store on the targeted peer.
-    pub async fn store_by_integrity(&self, bytes: Vec<u8>) -> ApiResult<Integrity> {
+    /// Store a blob of data in the content-addressable store on the targeted peer, charging it to
+    /// the given namespace's storage quota.
+    pub async fn store_by_integrity(
+        &self,
+        namespace: Option<&str>,
+        bytes: Vec<u8>,
+    ) -> ApiResult<Integrity> {
+        self.store_described_by_integrity(namespace, &BlobInfo::default(), bytes)
+            .await
+    }
+
+    /// Store a blob of data in the content-addressable store on the targeted peer along with a
+    /// description of what it is, charging it to the given namespace's storage quota.
+    pub async fn store_described_by_integrity(
+        &self,
+        namespace: Option<&str>,
+        info: &BlobInfo,
+        bytes: Vec<u8>,
+    ) -> ApiResult<Integrity> {
         let url = self.build_url("storage/data");
         let client = reqwest::Client::builder()
             .timeout(Duration::from_secs(60))
             .build()?;
-        let response = client.post(url).body(bytes).send().await?;
-        if response.status().is_success() {
-            let body = response.text().await?;
-            let integrity: Integrity = body.parse()?;
-            Ok(integrity)
-        } else {
-            Err(ServalError::StorageError(response.text().await?))
+        let query = UploadQuery {
+            namespace: namespace.map(str::to_string),
+        };
+        let response = client
+            .post(url)
+            .query(&query)
+            .headers(info.to_headers()?)
+            .body(bytes)
+            .send()
+            .await?;
+        upload_response(response).await
+    }
+
+    /// Apply a bsdiff patch to a blob in the content-addressable store on the targeted peer, and
+    /// store the result, charging it to the given namespace's storage quota. Responds with the
+    /// integrity of the result.
+    pub async fn patch_by_integrity(
+        &self,
+        address: &str,
+        namespace: Option<&str>,
+        patch: Vec<u8>,
+    ) -> ApiResult<Integrity> {
+        let url = self.build_url(&format!("storage/data/{address}"));
+        let query = UploadQuery {
+            namespace: namespace.map(str::to_string),
+        };
+        let response = reqwest::Client::new()
+            .patch(url)
+            .query(&query)
+            .body(patch)
+            .send()
+            .await?;
+        upload_response(response).await
+    }
+
+    /// Fetch a bsdiff patch that turns one stored blob into another, so that a caller that has the
+    /// first only has to download what changed.
+    pub async fn diff_between(&self, from: &str, to: &str) -> ApiResult<Vec<u8>> {
+        let url = self.build_url("storage/diffs");
+        let query = DiffQuery {
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+        let response = reqwest::Client::new().get(url).query(&query).send().await?;
+        data_response(response).await
+    }
+
+    /// List the blobs in the content-addressable store that have descriptions, optionally only
+    /// those with the given label: `key` for any value, or `key=value`.
+    pub async fn described_blobs(&self, label: Option<&str>) -> ApiResult<Vec<DescribedBlob>> {
+        let url = self.build_url("storage/blobs");
+        let query = BlobQuery {
+            label: label.map(str::to_string),
+        };
+        let response = reqwest::Client::new().get(url).query(&query).send().await?;
+        let body: Vec<DescribedBlob> = response.error_for_status()?.json().await?;
+
+        Ok(body)
+    }
+
+    /// Fetch the description of a stored blob, which is empty if nothing is recorded about it.
+    pub async fn blob_info(&self, address: &str) -> ApiResult<BlobInfo> {
+        let url = self.build_url(&format!("storage/blobs/{address}"));
+        let response = reqwest::get(&url).await?;
+        match response.status() {
+            status if status.is_success() => Ok(response.json().await?),
+            StatusCode::NOT_FOUND => Err(ServalError::DataNotFound(address.to_string())),
+            _ => Err(ServalError::StorageError(response.text().await?)),
+        }
+    }
+
+    /// Replace the description of a stored blob. An empty description forgets it.
+    pub async fn describe_blob(&self, address: &str, info: &BlobInfo) -> ApiResult<()> {
+        let url = self.build_url(&format!("storage/blobs/{address}"));
+        let response = reqwest::Client::new().put(url).json(info).send().await?;
+        match response.status() {
+            StatusCode::BAD_REQUEST => Err(ServalError::InvalidBlobInfo(response.text().await?)),
+            _ => blob_response(response).await,
+        }
+    }
+
+    /// Delete every version of the named manifest, along with their executables.
+    pub async fn delete_manifest(&self, name: &str) -> ApiResult<()> {
+        let url = self.build_url(&format!("storage/manifests/{name}"));
+        let response = reqwest::Client::new().delete(url).send().await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::NOT_FOUND => Err(ServalError::ManifestNotFound(response.text().await?)),
+            _ => Err(ServalError::StorageError(response.text().await?)),
+        }
+    }
+
+    /// Delete one version of the named manifest, along with its executable.
+    pub async fn delete_manifest_version(&self, name: &str, version: &str) -> ApiResult<()> {
+        let url = self.build_url(&format!("storage/manifests/{name}/versions/{version}"));
+        let response = reqwest::Client::new().delete(url).send().await?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::NOT_FOUND => Err(ServalError::ManifestNotFound(response.text().await?)),
+            _ => Err(ServalError::StorageError(response.text().await?)),
+        }
+    }
+
+    /// Delete a blob from the content-addressable store. Fails if anything still refers to it.
+    pub async fn delete_by_integrity(&self, address: &str) -> ApiResult<()> {
+        let url = self.build_url(&format!("storage/data/{address}"));
+        let response = reqwest::Client::new().delete(url).send().await?;
+        blob_response(response).await
+    }
+
+    /// Pin a blob so that garbage collection keeps it even if nothing else refers to it.
+    pub async fn pin(&self, address: &str) -> ApiResult<()> {
+        let url = self.build_url(&format!("storage/pins/{address}"));
+        let response = reqwest::Client::new().put(url).send().await?;
+        blob_response(response).await
+    }
+
+    /// Remove the pin from a blob.
+    pub async fn unpin(&self, address: &str) -> ApiResult<()> {
+        let url = self.build_url(&format!("storage/pins/{address}"));
+        let response = reqwest::Client::new().delete(url).send().await?;
+        blob_response(response).await
+    }
+
+    /// List the integrities of every pinned blob.
+    pub async fn list_pins(&self) -> ApiResult<Vec<String>> {
+        let url = self.build_url("storage/pins");
+        let response = reqwest::get(&url).await?;
+        let body: Vec<String> = response.error_for_status()?.json().await?;
+
+        Ok(body)
+    }
+
+    /// Remove every unreferenced blob from storage, or with `dry_run`, report what would be removed.
+    pub async fn collect_garbage(&self, dry_run: bool) -> ApiResult<GarbageCollection> {
+        let url = self.build_url("storage/gc");
+        let query = GarbageCollectionQuery { dry_run };
+        let response = reqwest::Client::new()
+            .post(url)
+            .query(&query)
+            .send()
+            .await?;
+        let body: GarbageCollection = response.error_for_status()?.json().await?;
+
+        Ok(body)
+    }
+
+    /// Check every blob the node stores against its integrity, and with `repair`, replace corrupt
+    /// copies with good ones.
+    pub async fn scrub(&self, repair: bool) -> ApiResult<Scrub> {
+        let url = self.build_url("storage/scrub");
+        let query = ScrubQuery { repair };
+        let response = reqwest::Client::new()
+            .post(url)
+            .query(&query)
+            .send()
+            .await?;
+        let body: Scrub = response.error_for_status()?.json().await?;
+
+        Ok(body)
+    }
+
+    /// Re-encrypt everything the node stores encrypted under its current master key.
+    pub async fn rotate_keys(&self) -> ApiResult<Vec<KeyRotation>> {
+        let url = self.build_url("storage/rotate-keys");
+        let response = reqwest::Client::new().post(url).send().await?;
+        let body: Vec<KeyRotation> = response.error_for_status()?.json().await?;
+
+        Ok(body)
+    }
+
+    /// Export the requested manifests, their executables, and blobs from the content-addressable
+    /// store as a bundle: a tar archive that another mesh can import. The bundle is the body of the
+    /// response, so that callers can stream it wherever it's going.
+    pub async fn export_bundle(&self, request: &BundleRequest) -> ApiResult<Response> {
+        let url = self.build_url("storage/export");
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(600))
+            .build()?;
+        let response = client.post(url).json(request).send().await?;
+        match response.status() {
+            status if status.is_success() => Ok(response),
+            StatusCode::NOT_FOUND => Err(ServalError::DataNotFound(response.text().await?)),
+            _ => Err(ServalError::StorageError(response.text().await?)),
+        }
+    }
+
+    /// Import a bundle exported from this or another mesh, storing everything in it. Its blobs are
+    /// charged to the given namespace's storage quota.
+    pub async fn import_bundle(
+        &self,
+        namespace: Option<&str>,
+        bundle: impl Into<reqwest::Body>,
+    ) -> ApiResult<ImportedBundle> {
+        let url = self.build_url("storage/import");
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(600))
+            .build()?;
+        let query = UploadQuery {
+            namespace: namespace.map(str::to_string),
+        };
+        let response = client.post(url).query(&query).body(bundle).send().await?;
+        match response.status() {
+            status if status.is_success() => Ok(response.json().await?),
+            StatusCode::BAD_REQUEST => Err(ServalError::InvalidBundle(response.text().await?)),
+            StatusCode::INSUFFICIENT_STORAGE => {
+                Err(ServalError::QuotaExceeded(response.text().await?))
+            }
+            StatusCode::PAYLOAD_TOO_LARGE => {
+                Err(ServalError::PayloadTooLarge(response.text().await?))
+            }
+            _ => Err(ServalError::StorageError(response.text().await?)),
+        }
+    }
+
+    /// Report how much each namespace is storing on the node, along with its quota.
+    pub async fn storage_usage(&self) -> ApiResult<Vec<NamespaceUsage>> {
+        let url = self.build_url("storage/usage");
+        let response = reqwest::get(&url).await?;
+        let body: Vec<NamespaceUsage> = response.error_for_status()?.json().await?;
+
+        Ok(body)
+    }
+
+    /// Store a replica of a blob in the targeted storage peer's own backends. Replicas aren't
+    /// replicated any further, and don't count against storage quotas. The peer refuses the body
+    /// unless it has the given integrity.
+    pub async fn store_replica(
+        &self,
+        integrity: &Integrity,
+        body: impl Into<reqwest::Body>,
+    ) -> ApiResult<Integrity> {
+        let url = self.build_url("storage/replicas/data");
+        let response = send_to_peer(Method::POST, url, Some(integrity), Some(body.into())).await?;
+        upload_response(response).await
+    }
+
+    /// Store a replica of keyed data in the targeted storage peer's own backends. The peer refuses
+    /// the body unless it has the given integrity.
+    pub async fn store_replica_by_key(
+        &self,
+        key: &str,
+        integrity: &Integrity,
+        body: impl Into<reqwest::Body>,
+    ) -> ApiResult<Integrity> {
+        let url = self.build_url(&format!("storage/replicas/keys/{key}"));
+        let response = send_to_peer(Method::PUT, url, Some(integrity), Some(body.into())).await?;
+        upload_response(response).await
+    }
+
+    /// Fetch a blob from the targeted storage peer's own backends, without it asking anyone else.
+    /// The blob is the body of the response, so that callers can stream it wherever it's going.
+    pub async fn get_replica(&self, address: &str) -> ApiResult<Response> {
+        let url = self.build_url(&format!("storage/replicas/data/{address}"));
+        stream_response(send_to_peer(Method::GET, url, None, None).await?).await
+    }
+
+    /// Fetch keyed data from the targeted storage peer's own backends, as the body of the response.
+    pub async fn get_replica_by_key(&self, key: &str) -> ApiResult<Response> {
+        let url = self.build_url(&format!("storage/replicas/keys/{key}"));
+        stream_response(send_to_peer(Method::GET, url, None, None).await?).await
+    }
+
+    /// Check whether the targeted storage peer holds a blob itself.
+    pub async fn has_replica(&self, address: &str) -> ApiResult<bool> {
+        Ok(self.replica_size(address).await?.is_some())
+    }
+
+    /// Ask the targeted storage peer how big a blob it holds itself is, without fetching it.
+    /// Responds with None if the peer doesn't hold it.
+    pub async fn replica_size(&self, address: &str) -> ApiResult<Option<u64>> {
+        let url = self.build_url(&format!("storage/replicas/data/{address}"));
+        let response = send_to_peer(Method::HEAD, url, None, None).await?;
+        match response.status() {
+            status if status.is_success() => {
+                // The body of a response to HEAD is always empty, so the size has to come from the
+                // header rather than from the body.
+                let size = response
+                    .headers()
+                    .get(CONTENT_LENGTH)
+                    .and_then(|size| size.to_str().ok())
+                    .and_then(|size| size.parse().ok());
+                match size {
+                    Some(size) => Ok(Some(size)),
+                    None => Err(ServalError::StorageError(format!(
+                        "peer did not say how big {address} is"
+                    ))),
+                }
+            }
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(ServalError::StorageError(format!(
+                "peer responded to a replica check with {status}"
+            ))),
         }
     }
 
+    /// Check whether the targeted storage peer holds keyed data itself.
+    pub async fn has_replica_by_key(&self, key: &str) -> ApiResult<bool> {
+        Ok(self.replica_metadata_by_key(key).await?.is_some())
+    }
+
+    /// Ask the targeted storage peer for the integrity and size of keyed data it holds itself,
+    /// without fetching it. Responds with None if the peer doesn't hold it.
+    pub async fn replica_metadata_by_key(&self, key: &str) -> ApiResult<Option<(Integrity, u64)>> {
+        let url = self.build_url(&format!("storage/replicas/keys/{key}"));
+        let response = send_to_peer(Method::HEAD, url, None, None).await?;
+        match response.status() {
+            status if status.is_success() => {
+                let header = |name| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                };
+                let integrity = header(INTEGRITY_HEADER).and_then(|value| value.parse().ok());
+                let size = header(CONTENT_LENGTH.as_str()).and_then(|value| value.parse().ok());
+                match (integrity, size) {
+                    (Some(integrity), Some(size)) => Ok(Some((integrity, size))),
+                    _ => Err(ServalError::StorageError(format!(
+                        "peer did not say what {key} is"
+                    ))),
+                }
+            }
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(ServalError::StorageError(format!(
+                "peer responded to a replica check with {status}"
+            ))),
+        }
+    }
+
+    /// Delete a blob from the targeted storage peer's own backends. Succeeds whether or not the
+    /// peer had it.
+    pub async fn delete_replica(&self, address: &str) -> ApiResult<()> {
+        let url = self.build_url(&format!("storage/replicas/data/{address}"));
+        let response = send_to_peer(Method::DELETE, url, None, None).await?;
+        response.error_for_status()?;
+        Ok(())
+    }
+
+    /// Delete keyed data from the targeted storage peer's own backends.
+    pub async fn delete_replica_by_key(&self, key: &str) -> ApiResult<()> {
+        let url = self.build_url(&format!("storage/replicas/keys/{key}"));
+        let response = send_to_peer(Method::DELETE, url, None, None).await?;
+        response.error_for_status()?;
+        Ok(())
+    }
+
     // Convenience function to build urls repeatably.
     fn build_url(&self, path: &str) -> String {
         format!("http://{}/v{}/{path} ", self.socket_addr, self.version)
     }
 }
 
+// Turn the response to an upload into the integrity of what was stored, keeping quota errors
+// distinct so that callers can tell the user why their upload was refused.
+async fn upload_response(response: Response) -> ApiResult<Integrity> {
+    match response.status() {
+        status if status.is_success() => {
+            let body = response.text().await?;
+            let integrity: Integrity = body.parse()?;
+            Ok(integrity)
+        }
+        StatusCode::INSUFFICIENT_STORAGE => Err(ServalError::QuotaExceeded(response.text().await?)),
+        StatusCode::PAYLOAD_TOO_LARGE => Err(ServalError::PayloadTooLarge(response.text().await?)),
+        _ => Err(ServalError::StorageError(response.text().await?)),
+    }
+}
+
+// Send a request to another storage peer's replica routes. Those only answer members of the mesh,
+// so if the mesh has a secret, the request is signed with it, along with the integrity of the body.
+async fn send_to_peer(
+    method: Method,
+    url: String,
+    integrity: Option<&Integrity>,
+    body: Option<reqwest::Body>,
+) -> ApiResult<Response> {
+    let header = |value: &str| {
+        HeaderValue::from_str(value)
+            .map_err(|e| ServalError::StorageError(format!("invalid header value `{value}`; {e}")))
+    };
+    let client = reqwest::Client::new();
+    let mut request = client.request(method, url).build()?;
+    let integrity = integrity.map(Integrity::to_string);
+    if let Some(integrity) = &integrity {
+        request
+            .headers_mut()
+            .insert(INTEGRITY_HEADER, header(integrity)?);
+    }
+    let signature = sign_peer_request(
+        request.method().as_str(),
+        request.url().path(),
+        integrity.as_deref(),
+    );
+    if let Some(signature) = signature {
+        request
+            .headers_mut()
+            .insert(PEER_SIGNATURE_HEADER, header(&signature)?);
+    }
+    *request.body_mut() = body;
+    Ok(client.execute(request).await?)
+}
+
+// Hand back the response to a read of stored data for the caller to stream, if it was successful.
+async fn stream_response(response: Response) -> ApiResult<Response> {
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        StatusCode::NOT_FOUND => Err(ServalError::DataNotFound(response.text().await?)),
+        _ => Err(ServalError::StorageError(response.text().await?)),
+    }
+}
+
+// Turn the response to a read of stored data into its bytes.
+async fn data_response(response: Response) -> ApiResult<Vec<u8>> {
+    match response.status() {
+        status if status.is_success() => Ok(response.bytes().await?.to_vec()),
+        StatusCode::NOT_FOUND => Err(ServalError::DataNotFound(response.text().await?)),
+        _ => Err(ServalError::StorageError(response.text().await?)),
+    }
+}
+
+// Turn the response to a request about a single blob into a result, keeping the errors that callers
+// (and agents relaying for them) need to tell apart.
+async fn blob_response(response: Response) -> ApiResult<()> {
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        StatusCode::NOT_FOUND => Err(ServalError::DataNotFound(response.text().await?)),
+        StatusCode::CONFLICT => Err(ServalError::BlobInUse(response.text().await?)),
+        _ => Err(ServalError::StorageError(response.text().await?)),
+    }
+}
+
+// Pull the next complete server-sent event out of the buffer and return its data, skipping events
+// that don't carry any (such as keep-alive comments). Returns None if no complete event is buffered.
+fn next_sse_data(buffer: &mut Vec<u8>) -> Option<String> {
+    loop {
+        let (end, separator_len) = (0..buffer.len()).find_map(|index| {
+            if buffer[index..].starts_with(b"\r\n\r\n") {
+                Some((index, 4))
+            } else if buffer[index..].starts_with(b"\n\n") {
+                Some((index, 2))
+            } else {
+                None
+            }
+        })?;
+        let block = String::from_utf8_lossy(&buffer[..end]).into_owned();
+        buffer.drain(..end + separator_len);
+
+        let data: Vec<&str> = block
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|value| value.strip_prefix(' ').unwrap_or(value))
+            .collect();
+        if !data.is_empty() {
+            return Some(data.join("\n"));
+        }
+    }
+}
+
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_server_sent_events() {
+        let mut buffer = b":\n\nevent: joined\ndata: {\"event\":\"joined\"}\n\nevent: departed\r\ndata: one\r\ndata: two\r\n\r\nevent: par".to_vec();
+        assert_eq!(
+            next_sse_data(&mut buffer),
+            Some(String::from("{\"event\":\"joined\"}"))
+        );
+        assert_eq!(next_sse_data(&mut buffer), Some(String::from("one\ntwo")));
+        assert_eq!(next_sse_data(&mut buffer), None);
+
+        buffer.extend_from_slice(b"tial\ndata: later\n\n");
+        assert_eq!(next_sse_data(&mut buffer), Some(String::from("later")));
+    }
+}
@@ -6,6 +6,7 @@
     trivial_casts,
     unused_qualifications
 )]
+use std::cmp::Ordering;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
@@ -25,6 +26,11 @@ mod mesh;
 mod peers;
 
 use peers::api_client;
+use serval_client::ServalApiClient;
+use ssri::Integrity;
+use utils::diffs::make_patch;
+use utils::structs::api::{BundleRequest, ManifestQuery, ManifestVersion, PeerQuery, PeerSort};
+use utils::structs::versions::compare_versions;
 use utils::structs::Manifest;
 
 #[derive(Parser, Debug)]
@@ -48,11 +54,16 @@ pub enum Command {
     Store {
         /// Path to the task manifest file.
         manifest: PathBuf,
+        /// Always upload the whole Wasm executable, even when a patch against the previous
+        /// version would be smaller.
+        #[clap(long)]
+        full: bool,
     },
     /// Run the specified Wasm binary.
     #[clap(display_order = 2)]
     Run {
-        /// The name of the previously-stored job to run.
+        /// The name of the previously-stored job to run, optionally followed by a version
+        /// requirement such as @1.2, @^2 or @latest. Runs the latest version by default.
         name: String,
         /// Path to a file to pass to the binary; omit to read from stdin (if present)
         input_file: Option<PathBuf>,
@@ -65,14 +76,86 @@ pub enum Command {
         /// The name of the stored job.
         name: String,
     },
-    /// List all known peers of this node.
+    /// List stored job types, with their latest versions.
     #[clap(display_order = 4)]
-    Peers,
-    /// List all known peers with the named role.
+    List {
+        /// Only list job types in this namespace.
+        #[clap(long)]
+        namespace: Option<String>,
+        /// Only list job types whose fully-qualified names start with this.
+        #[clap(long)]
+        prefix: Option<String>,
+        /// Skip this many job types before listing any.
+        #[clap(long)]
+        offset: Option<usize>,
+        /// List at most this many job types.
+        #[clap(long)]
+        limit: Option<usize>,
+    },
+    /// List every stored version of a job type.
     #[clap(display_order = 5)]
+    Versions {
+        /// The fully-qualified name of the stored job.
+        name: String,
+    },
+    /// List all known peers of this node.
+    #[clap(display_order = 6)]
+    Peers {
+        /// Only list peers with this role; pass more than once to require several roles.
+        #[clap(long = "role")]
+        roles: Vec<ServalRole>,
+        /// List at most this many peers.
+        #[clap(long)]
+        count: Option<usize>,
+        /// Order peers by `latency` or `instance_id`.
+        #[clap(long)]
+        sort: Option<PeerSort>,
+    },
+    /// List all known peers with the named role.
+    #[clap(display_order = 7)]
     PeersWithRole {
         /// The role
         role: ServalRole,
+        /// List at most this many peers.
+        #[clap(long)]
+        count: Option<usize>,
+        /// Order peers by `latency` or `instance_id`.
+        #[clap(long)]
+        sort: Option<PeerSort>,
+    },
+    /// Check everything the node stores for corruption.
+    Scrub {
+        /// Replace corrupt copies with good ones from elsewhere, instead of only reporting them.
+        #[clap(long)]
+        repair: bool,
+    },
+    /// Re-encrypt everything the node stores encrypted under its current master key.
+    RotateKeys,
+    /// Export stored jobs and blobs as a bundle that another mesh can import.
+    Export {
+        /// The jobs to export, each optionally followed by a version requirement such as @1.2.
+        /// Exports the latest version of each by default.
+        jobs: Vec<String>,
+        /// The integrity of a blob to export; pass more than once to export several.
+        #[clap(long = "blob")]
+        blobs: Vec<String>,
+        /// Path to write the bundle to.
+        #[clap(long)]
+        output: PathBuf,
+    },
+    /// Import a bundle exported from this or another mesh.
+    Import {
+        /// Path to the bundle.
+        bundle: PathBuf,
+        /// Charge the imported blobs to this namespace's storage quota.
+        #[clap(long)]
+        namespace: Option<String>,
+    },
+    /// List stored blobs that have a description, such as job inputs and outputs.
+    Blobs {
+        /// Only list blobs with this label, as `key` for any value or `key=value`.
+        #[clap(long)]
+        label: Option<String>,
     },
     NodeStatus,
     /// Liveness check: ping at least one node on the mesh.
@@ -81,7 +164,7 @@ pub enum Command {
     Monitor,
 }
 
-async fn upload_manifest(manifest_path: PathBuf) -> Result<()> {
+async fn upload_manifest(manifest_path: PathBuf, full: bool) -> Result<()> {
     println!("Reading manifest: {}", manifest_path.display());
     let manifest = Manifest::from_file(&manifest_path)?;
 
@@ -106,18 +189,46 @@ async fn upload_manifest(manifest_path: PathBuf) -> Result<()> {
     let manifest_resp = serval.store_manifest(&manifest).await;
     let Ok(manifest_integrity) = manifest_resp else {
         table.add_row(row!["Storing the Wasm manifest failed!".bold()]);
-        table.add_row(row![format!(
-            "{:?}", manifest_resp
-        )]);
+        table.add_row(row![format!("{:?}", manifest_resp)]);
         println!("{table}");
         return Ok(());
     };
 
     table.add_row(row!["Manifest integrity:", manifest_integrity]);
 
-    let exec_resp = serval
-        .store_executable(&manifest.fq_name(), manifest.version(), executable)
-        .await;
+    let patched = if full {
+        None
+    } else {
+        match store_executable_as_patch(&serval, &manifest, &executable).await {
+            Ok(patched) => patched,
+            Err(e) => {
+                log::warn!("unable to upload the executable as a patch; uploading all of it; {e}");
+                None
+            }
+        }
+    };
+    let exec_resp = match patched {
+        Some(patched) => {
+            table.add_row(row![
+                "Uploaded as a patch:",
+                format!(
+                    "{} against version {}",
+                    format_size(patched.size, BINARY),
+                    patched.base_version
+                )
+            ]);
+            Ok(patched.integrity)
+        }
+        None => {
+            table.add_row(row![
+                "Uploaded in full:",
+                format_size(executable.len(), BINARY)
+            ]);
+            serval
+                .store_executable(&manifest.fq_name(), manifest.version(), executable)
+                .await
+        }
+    };
     if let Ok(wasm_integrity) = exec_resp {
         table.add_row(row!["Wasm integrity:", wasm_integrity]);
         table.add_row(row![
@@ -135,6 +246,79 @@ async fn upload_manifest(manifest_path: PathBuf) -> Result<()> {
     Ok(())
 }
 
+/// An executable stored by uploading a patch against one that was already stored.
+#[derive(Debug)]
+struct PatchedUpload {
+    /// The version whose executable the patch was made against.
+    base_version: String,
+    /// The size of the patch in bytes.
+    size: usize,
+    /// The integrity of the stored executable.
+    integrity: Integrity,
+}
+
+/// Store an executable by uploading a patch against the executable of the version before it, which
+/// is much less to upload when little has changed between them. Responds with None, having uploaded
+/// nothing, if there is no earlier executable or the patch wouldn't be any smaller.
+async fn store_executable_as_patch(
+    serval: &ServalApiClient,
+    manifest: &Manifest,
+    executable: &[u8],
+) -> Result<Option<PatchedUpload>> {
+    // The manifest is already stored, so this only fails if something else is wrong; in which case
+    // a full upload is as likely to work as anything.
+    let versions = serval.manifest_versions(&manifest.fq_name()).await?;
+    let Some((base_version, base)) = patch_base(versions, manifest.version()) else {
+        return Ok(None);
+    };
+
+    let source = serval.stream_by_integrity(&base).await?;
+    let patch = make_patch(&source, executable)?;
+    log::info!(
+        "made patch against version {base_version}; patch={}; executable={}",
+        patch.len(),
+        executable.len()
+    );
+    if patch.len() >= executable.len() {
+        return Ok(None);
+    }
+
+    let size = patch.len();
+    let patched = serval
+        .patch_by_integrity(&base, Some(manifest.namespace()), patch)
+        .await?;
+    let expected = Integrity::from(executable);
+    if patched.matches(&expected).is_none() {
+        anyhow::bail!(
+            "the patched executable is not the one we have; expected={expected}; got={patched}"
+        );
+    }
+    let integrity = serval
+        .store_executable_from_blob(
+            &manifest.fq_name(),
+            manifest.version(),
+            &patched.to_string(),
+        )
+        .await?;
+
+    Ok(Some(PatchedUpload {
+        base_version,
+        size,
+        integrity,
+    }))
+}
+
+/// The stored version to patch against when uploading the given one, with the integrity of its
+/// executable: the highest version below it that has an executable. The version being uploaded may
+/// already be stored, and newer ones may be, but the version before it is what it follows on from.
+fn patch_base(versions: Vec<ManifestVersion>, uploading: &str) -> Option<(String, String)> {
+    versions
+        .into_iter()
+        .filter(|stored| compare_versions(&stored.version, uploading) == Ordering::Less)
+        .filter_map(|stored| Some((stored.version, stored.integrity?)))
+        .max_by(|(left, _), (right, _)| compare_versions(left, right))
+}
+
 /// Convenience function to read an input wasm binary either from a pathbuf or from stdin.
 fn read_file_or_stdin(maybepath: Option<PathBuf>) -> Result<Vec<u8>, anyhow::Error> {
     // TODO This implementation should become a streaming implementation.
@@ -214,14 +398,203 @@ async fn get_manifest(name: String) -> Result<()> {
     Ok(())
 }
 
-async fn list_peers() -> Result<()> {
-    let body = api_client().await.all_peers().await?;
+async fn list_manifests(query: ManifestQuery) -> Result<()> {
+    let listing = api_client().await.list_manifests(&query).await?;
+    if listing.manifests.is_empty() {
+        println!("No stored job types found.");
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.add_row(row![
+        "Name".bold(),
+        "Latest version".bold(),
+        "Description".bold()
+    ]);
+    for manifest in &listing.manifests {
+        table.add_row(row![manifest.name, manifest.version, manifest.description]);
+    }
+    println!("{table}");
+
+    if let Some(next_offset) = listing.next_offset {
+        println!(
+            "Showing {} of {}; pass --offset {next_offset} for more.",
+            listing.manifests.len(),
+            listing.total
+        );
+    }
+    Ok(())
+}
+
+async fn list_versions(name: String) -> Result<()> {
+    let versions = api_client().await.manifest_versions(&name).await?;
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.add_row(row![
+        "Version".bold(),
+        "Size".bold(),
+        "Wasm integrity".bold()
+    ]);
+    for version in versions {
+        let size = version
+            .size
+            .map(|size| format_size(size, BINARY))
+            .unwrap_or_default();
+        let integrity = version
+            .integrity
+            .unwrap_or_else(|| "(no executable stored)".to_string());
+        table.add_row(row![version.version, size, integrity]);
+    }
+    println!("{table}");
+    Ok(())
+}
+
+async fn scrub(repair: bool) -> Result<()> {
+    let report = api_client().await.scrub(repair).await?;
+
+    println!("Checked {} stored blobs.", report.checked);
+    if report.corrupt.is_empty() {
+        println!("Nothing is corrupt.");
+        return Ok(());
+    }
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.add_row(row![
+        "Backend".bold(),
+        "Corrupt integrity".bold(),
+        "Repaired".bold()
+    ]);
+    for blob in report.corrupt {
+        let repaired = if blob.repaired { "yes" } else { "no" };
+        table.add_row(row![blob.backend, blob.integrity, repaired]);
+    }
+    println!("{table}");
+    Ok(())
+}
+
+async fn rotate_keys() -> Result<()> {
+    let rotations = api_client().await.rotate_keys().await?;
+
+    if rotations.is_empty() {
+        println!("Nothing is stored encrypted.");
+        return Ok(());
+    }
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.add_row(row![
+        "Backend".bold(),
+        "Key".bold(),
+        "Re-encrypted".bold(),
+        "Already current".bold(),
+        "Failed".bold()
+    ]);
+    for rotation in &rotations {
+        table.add_row(row![
+            rotation.backend,
+            rotation.key_id,
+            rotation.rotated,
+            rotation.current,
+            rotation.failed.len()
+        ]);
+    }
+    println!("{table}");
+    for failed in rotations.iter().flat_map(|rotation| &rotation.failed) {
+        println!("Could not re-encrypt {failed}");
+    }
+    Ok(())
+}
+
+async fn export_bundle(manifests: Vec<String>, blobs: Vec<String>, output: PathBuf) -> Result<()> {
+    if manifests.is_empty() && blobs.is_empty() {
+        anyhow::bail!("Name at least one job or blob to export.");
+    }
+    let request = BundleRequest { manifests, blobs };
+    let mut response = api_client().await.export_bundle(&request).await?;
+
+    let mut file = File::create(&output)?;
+    let mut written = 0;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk)?;
+        written += chunk.len();
+    }
+    println!(
+        "Exported a bundle of {} to {}",
+        format_size(written, BINARY),
+        output.display().blue().bold()
+    );
+    Ok(())
+}
+
+async fn import_bundle(bundle: PathBuf, namespace: Option<String>) -> Result<()> {
+    let file = tokio::fs::File::open(&bundle).await?;
+    let imported = api_client()
+        .await
+        .import_bundle(namespace.as_deref(), file)
+        .await?;
+
+    println!(
+        "Imported {} jobs and {} blobs; {} in all.",
+        imported.manifests.len(),
+        imported.blobs.len(),
+        format_size(imported.size, BINARY)
+    );
+    for manifest in imported.manifests {
+        println!("  {}", manifest.blue().bold());
+    }
+    for blob in imported.blobs {
+        println!("  {blob}");
+    }
+    Ok(())
+}
+
+async fn list_described_blobs(label: Option<String>) -> Result<()> {
+    let blobs = api_client().await.described_blobs(label.as_deref()).await?;
+    if blobs.is_empty() {
+        println!("No stored blobs are described.");
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.set_format(*prettytable::format::consts::FORMAT_CLEAN);
+    table.add_row(row![
+        "Integrity".bold(),
+        "Content type".bold(),
+        "Filename".bold(),
+        "Creator".bold(),
+        "Labels".bold()
+    ]);
+    for blob in blobs {
+        let labels: Vec<String> = blob
+            .info
+            .labels
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect();
+        table.add_row(row![
+            blob.integrity,
+            blob.info.content_type.unwrap_or_default(),
+            blob.info.filename.unwrap_or_default(),
+            blob.info.creator.unwrap_or_default(),
+            labels.join(", ")
+        ]);
+    }
+    println!("{table}");
+    Ok(())
+}
+
+async fn list_peers(query: PeerQuery) -> Result<()> {
+    let body = api_client().await.peers(&query).await?;
     println!("{}", serde_json::to_string_pretty(&body)?);
     Ok(())
 }
 
-async fn peers_with_role(role: ServalRole) -> Result<()> {
-    let body = api_client().await.peers_with_role(role).await?;
+async fn peers_with_role(role: ServalRole, query: PeerQuery) -> Result<()> {
+    let body = api_client()
+        .await
+        .peers_with_role_matching(role, &query)
+        .await?;
     println!("{}", serde_json::to_string_pretty(&body)?);
     Ok(())
 }
@@ -257,7 +630,7 @@ async fn main() -> Result<()> {
         .unwrap();
 
     match args.cmd {
-        Command::Store { manifest } => upload_manifest(manifest).await?,
+        Command::Store { manifest, full } => upload_manifest(manifest, full).await?,
         Command::Run {
             name,
             input_file,
@@ -272,9 +645,74 @@ async fn main() -> Result<()> {
         Command::Ping => ping().await?,
         Command::Monitor => mesh::monitor_mesh().await?,
         Command::Manifest { name } => get_manifest(name).await?,
-        Command::Peers => list_peers().await?,
-        Command::PeersWithRole { role } => peers_with_role(role).await?,
+        Command::List {
+            namespace,
+            prefix,
+            offset,
+            limit,
+        } => {
+            let query = ManifestQuery {
+                namespace,
+                prefix,
+                offset,
+                limit,
+            };
+            list_manifests(query).await?
+        }
+        Command::Versions { name } => list_versions(name).await?,
+        Command::Scrub { repair } => scrub(repair).await?,
+        Command::RotateKeys => rotate_keys().await?,
+        Command::Export {
+            jobs,
+            blobs,
+            output,
+        } => export_bundle(jobs, blobs, output).await?,
+        Command::Import { bundle, namespace } => import_bundle(bundle, namespace).await?,
+        Command::Blobs { label } => list_described_blobs(label).await?,
+        Command::Peers { roles, count, sort } => {
+            list_peers(PeerQuery::new(count, &roles, sort)).await?
+        }
+        Command::PeersWithRole { role, count, sort } => {
+            peers_with_role(role, PeerQuery::new(count, &[], sort)).await?
+        }
     };
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patches_against_the_version_before() {
+        let stored = |version: &str, integrity: Option<&str>| ManifestVersion {
+            version: version.to_string(),
+            integrity: integrity.map(str::to_string),
+            size: None,
+        };
+        let versions = vec![
+            stored("1.0.0", Some("sha256-one")),
+            stored("1.2.0", Some("sha256-onetwo")),
+            stored("1.3.0", None),
+            stored("1.4.0", Some("sha256-onefour")),
+            stored("2.0.0", Some("sha256-two")),
+        ];
+
+        // Not the version being uploaded, nor anything newer, nor a version without an executable.
+        assert_eq!(
+            patch_base(versions.clone(), "1.4.0"),
+            Some(("1.2.0".to_string(), "sha256-onetwo".to_string()))
+        );
+        assert_eq!(
+            patch_base(versions.clone(), "1.10.0"),
+            Some(("1.4.0".to_string(), "sha256-onefour".to_string()))
+        );
+        assert_eq!(
+            patch_base(versions.clone(), "3.0.0"),
+            Some(("2.0.0".to_string(), "sha256-two".to_string()))
+        );
+        assert_eq!(patch_base(versions, "1.0.0"), None);
+        assert_eq!(patch_base(Vec::new(), "1.0.0"), None);
+    }
+}
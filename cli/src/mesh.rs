@@ -22,7 +22,13 @@ pub async fn monitor_mesh() -> anyhow::Result<()> {
 
     loop {
         while let Ok((addr, identity)) = discover_rx.try_recv() {
-            let peer = PeerMetadata::from_identity(addr.ip(), identity.to_vec());
+            let peer = match PeerMetadata::from_identity(addr.ip(), identity.to_vec()) {
+                Ok(peer) => peer,
+                Err(err) => {
+                    println!("🚫 {} {addr}; {err}", "REJECTED:".yellow());
+                    continue;
+                }
+            };
             print!("✅ {} {} @ {addr}", "JOINED:".blue(), peer.instance_id(),);
             if !peer.roles().is_empty() {
                 print!(
@@ -39,9 +39,14 @@ pub async fn create_mesh_peer() -> Result<ServalMesh> {
         format!("observer@{host}"), // todo: should this just be a UUID like it is for everyone else?
         http_port,
         vec![ServalRole::Observer],
+        Vec::new(),
         interface.ip(),
     );
+    let seed_config = utils::mesh::mesh_seed_config(&interface);
     let mut mesh = ServalMesh::new(metadata, port, Some(interface)).await?;
+    if let Some(seed_config) = seed_config {
+        mesh.set_seeds(seed_config)?;
+    }
     mesh.start().await?;
     Ok(mesh)
 }
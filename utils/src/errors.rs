@@ -40,6 +40,30 @@ pub enum ServalError {
     #[error("data not found; sri: `{0}`")]
     DataNotFound(String),
 
+    /// Stored data no longer matches the integrity it was stored under; the copy is corrupt.
+    #[error("stored data does not match its integrity; sri: `{0}`")]
+    IntegrityMismatch(String),
+
+    /// Storing this would take a namespace over its storage quota.
+    #[error("storage quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    /// This upload is larger than its namespace's entire storage quota, so it can never be stored.
+    #[error("upload too large: {0}")]
+    PayloadTooLarge(String),
+
+    /// A bundle of stored data to import is malformed, incomplete, or doesn't match its index.
+    #[error("invalid bundle: {0}")]
+    InvalidBundle(String),
+
+    /// A description of a blob in the content-addressable store can't be recorded as given.
+    #[error("invalid blob description: {0}")]
+    InvalidBlobInfo(String),
+
+    /// The caller asked to delete a blob that a manifest, executable or pin still refers to.
+    #[error("blob is still in use; sri: `{0}`")]
+    BlobInUse(String),
+
     /// This job has no metadata
     #[error("no manifest found for `{0}`")]
     ManifestNotFound(String),
@@ -48,6 +72,10 @@ pub enum ServalError {
     #[error("no data found for executable `{0}`")]
     ExecutableNotFound(String),
 
+    /// A version or version requirement that isn't valid semver.
+    #[error("invalid version: {0}")]
+    InvalidVersion(String),
+
     /// Invalid role string.
     #[error("not a valid role `{0}`")]
     InvalidRole(String),
@@ -56,10 +84,26 @@ pub enum ServalError {
     #[error("std::io::Error: {0}")]
     IoError(#[from] std::io::Error),
 
+    /// A mesh peer's identity payload could not be decoded.
+    #[error("invalid peer identity: {0}")]
+    PeerIdentityInvalid(String),
+
+    /// A mesh peer's identity payload failed our checks, so we won't treat it as a member.
+    #[error("peer identity rejected: {0}")]
+    PeerIdentityRejected(String),
+
+    /// Relaying this request again would send it around in a loop.
+    #[error("proxy loop detected: {0}")]
+    ProxyLoopDetected(String),
+
     /// The searched-for service could not be found.
     #[error("service was not found before timeout")]
     ServiceNotFound,
 
+    /// Translation for errors from kaboodle.
+    #[error("kaboodle::errors::KaboodleError: {0}")]
+    KaboodleError(#[from] kaboodle::errors::KaboodleError),
+
     /// Translation for errors from reqwest.
     #[error("reqwest::Error: {0}")]
     ReqwestError(#[from] reqwest::Error),
@@ -118,7 +162,15 @@ impl IntoResponse for ServalError {
             }
             ServalError::BlobAddressInvalid(_) => StatusCode::BAD_REQUEST,
             ServalError::BlobAddressNotFound(_) => StatusCode::NOT_FOUND,
+            ServalError::BlobInUse(_) => StatusCode::CONFLICT,
+            ServalError::InvalidBundle(_) => StatusCode::BAD_REQUEST,
+            ServalError::InvalidBlobInfo(_) => StatusCode::BAD_REQUEST,
+            ServalError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            ServalError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ServalError::InvalidRole(_) => StatusCode::BAD_REQUEST,
+            ServalError::InvalidVersion(_) => StatusCode::BAD_REQUEST,
             ServalError::IoError(_) => StatusCode::NOT_FOUND,
+            ServalError::ProxyLoopDetected(_) => StatusCode::LOOP_DETECTED,
             ServalError::ServiceNotFound => StatusCode::NOT_FOUND,
             // Catch-all for anything we don't want to add specific status codes for.
             _ => StatusCode::INTERNAL_SERVER_ERROR,
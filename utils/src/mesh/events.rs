@@ -0,0 +1,183 @@
+//! Turn the mesh's membership into a stream of changes. Kaboodle tells us when a peer arrives or
+//! departs, but only by address, and only to whoever joined the mesh and asked. The watcher here
+//! remembers who it has seen, so it can describe every change in full and hand the result to any
+//! number of subscribers.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::body::Bytes;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use super::{KaboodleMesh, PeerMetadata, ServalMesh};
+use crate::structs::api::{MeshEvent, MeshMember};
+
+// How many events a subscriber may fall behind by before it gets cut off.
+const EVENT_BUFFER: usize = 256;
+
+// How often we look for changes when the mesh hasn't told us about any. Role changes and peers that
+// only our seeds know about don't come with a notification.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps track of the members of a mesh and broadcasts a `MeshEvent` whenever they change.
+#[derive(Debug)]
+pub struct MeshWatcher {
+    members: Mutex<HashMap<String, MeshMember>>,
+    sender: broadcast::Sender<MeshEvent>,
+}
+
+impl Default for MeshWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MeshWatcher {
+    /// Create a watcher that hasn't seen any members yet.
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        Self {
+            members: Mutex::new(HashMap::new()),
+            sender,
+        }
+    }
+
+    /// Subscribe to membership changes. This also returns everyone who is a member right now, so
+    /// that no change can fall between looking at the membership and subscribing to changes. A
+    /// subscriber that falls too far behind sees its receiver report a lag; the snapshot it started
+    /// from is then out of date, and it should subscribe again.
+    pub fn subscribe(&self) -> (Vec<MeshMember>, broadcast::Receiver<MeshEvent>) {
+        let members = self.members.lock().unwrap();
+        let receiver = self.sender.subscribe();
+        (members.values().cloned().collect(), receiver)
+    }
+
+    /// Compare the given peers with the ones we saw last time, and broadcast an event for each
+    /// difference. Returns the events, in the order they were sent.
+    pub fn update(&self, peers: Vec<PeerMetadata>) -> Vec<MeshEvent> {
+        let current: HashMap<String, MeshMember> = peers
+            .into_iter()
+            .map(|peer| (peer.instance_id().to_string(), MeshMember::from(peer)))
+            .collect();
+
+        let mut members = self.members.lock().unwrap();
+        let mut events = Vec::new();
+        for (instance_id, previous) in members.iter() {
+            match current.get(instance_id) {
+                None => events.push(MeshEvent::Departed {
+                    peer: previous.clone(),
+                }),
+                Some(peer) if peer.roles != previous.roles => {
+                    events.push(MeshEvent::RolesChanged {
+                        peer: peer.clone(),
+                        previous_roles: previous.roles.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        for (instance_id, peer) in current.iter() {
+            if !members.contains_key(instance_id) {
+                events.push(MeshEvent::Joined { peer: peer.clone() });
+            }
+        }
+        *members = current;
+
+        for event in &events {
+            // This only fails if nobody is subscribed, which is fine.
+            let _ = self.sender.send(event.clone());
+        }
+        events
+    }
+
+    /// Keep this watcher up to date with the given mesh, forever. Pass in the mesh's arrival and
+    /// departure channels so that we can react to changes as soon as we hear about them.
+    pub async fn follow(
+        &self,
+        mesh: &ServalMesh,
+        mut arrivals: UnboundedReceiver<(SocketAddr, Bytes)>,
+        mut departures: UnboundedReceiver<SocketAddr>,
+    ) {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                Some(_) = arrivals.recv() => {}
+                Some(_) = departures.recv() => {}
+            }
+            self.update(mesh.peers().await);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+    use crate::mesh::ServalRole;
+
+    fn peer(instance_id: &str, roles: Vec<ServalRole>) -> PeerMetadata {
+        PeerMetadata::new(
+            instance_id.to_string(),
+            Some(8100),
+            roles,
+            Vec::new(),
+            IpAddr::from([127, 0, 0, 1]),
+        )
+    }
+
+    #[test]
+    fn reports_joins_departures_and_role_changes() {
+        let watcher = MeshWatcher::new();
+        let (members, mut receiver) = watcher.subscribe();
+        assert!(members.is_empty());
+
+        let runner = peer("runner", vec![ServalRole::Runner]);
+        let storage = peer("storage", vec![ServalRole::Storage]);
+        let mut events = watcher.update(vec![runner.clone(), storage.clone()]);
+        events.sort_by(|a, b| a.peer().instance_id.cmp(&b.peer().instance_id));
+        assert_eq!(
+            events,
+            vec![
+                MeshEvent::Joined {
+                    peer: runner.clone().into()
+                },
+                MeshEvent::Joined {
+                    peer: storage.clone().into()
+                },
+            ]
+        );
+
+        // Nothing changed, so there's nothing to say.
+        assert!(watcher
+            .update(vec![runner.clone(), storage.clone()])
+            .is_empty());
+
+        let promoted = peer("runner", vec![ServalRole::Runner, ServalRole::Scheduler]);
+        let events = watcher.update(vec![promoted.clone()]);
+        assert_eq!(events.len(), 2);
+        assert!(events.contains(&MeshEvent::RolesChanged {
+            peer: promoted.clone().into(),
+            previous_roles: vec![ServalRole::Runner],
+        }));
+        assert!(events.contains(&MeshEvent::Departed {
+            peer: storage.into()
+        }));
+
+        // A late subscriber starts from the current membership.
+        let (members, _) = watcher.subscribe();
+        assert_eq!(members, vec![MeshMember::from(promoted)]);
+
+        // And an early one has seen every event along the way.
+        let mut received = 0;
+        while receiver.try_recv().is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, 4);
+    }
+}
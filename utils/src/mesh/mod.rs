@@ -0,0 +1,875 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bincode::de::read::Reader;
+use bincode::de::Decoder;
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use bincode::{Decode, Encode};
+use if_addrs::Interface;
+use kaboodle::errors::KaboodleError;
+use kaboodle::Kaboodle;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::ServalError;
+
+pub mod events;
+mod secret;
+pub use secret::{sign_peer_request, verify_peer_request, PEER_SIGNATURE_HEADER};
+pub mod seeds;
+use seeds::{SeedConfig, SeedMesh};
+
+/// A little wrapper around kaboodle so we can hide the machinery of encoding and decoding.
+/// the identity payload.
+#[async_trait]
+pub trait KaboodleMesh {
+    type A: KaboodlePeer;
+
+    /// Create a new entry for a Kaboodle peer network and add ourselves to the mesh.
+    async fn start(&mut self) -> Result<(), KaboodleError>;
+    /// Remove this peer from the mesh.
+    async fn stop(&mut self) -> Result<(), KaboodleError>;
+    /// Get a list of peers. It's the implementer's responsibility to decide if this is fresh or cached somehow.
+    async fn peers(&self) -> Vec<Self::A>;
+}
+
+/// This type encodes the responsibilities of the resources we are meshing together.
+pub trait KaboodlePeer: Sized {
+    /// Create a new peer structure from the node identity payload plus an address. Fails if the
+    /// payload is one we refuse to accept as a member of our mesh.
+    fn from_identity(address: IpAddr, encoded: Vec<u8>) -> Result<Self, ServalError>;
+    /// Create an identity payload from whatever internal information matters to your implementation.
+    fn identity(&self) -> Vec<u8>;
+    /// Get the address of this node.
+    fn address(&self) -> IpAddr;
+}
+
+// End of tiny wrapper around Kaboodle.
+
+/// These are the roles we allow peers to advertise on the mesh
+#[derive(Debug, Clone, PartialEq, Eq, Decode, Encode, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServalRole {
+    Scheduler,
+    Runner,
+    Storage,
+    Observer,
+}
+
+impl std::fmt::Display for ServalRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServalRole::Runner => write!(f, "runner"),
+            ServalRole::Scheduler => write!(f, "scheduler"),
+            ServalRole::Storage => write!(f, "storage"),
+            ServalRole::Observer => write!(f, "observer"),
+        }
+    }
+}
+
+impl FromStr for ServalRole {
+    type Err = ServalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "runner" => Ok(ServalRole::Runner),
+            "scheduler" => Ok(ServalRole::Scheduler),
+            "storage" => Ok(ServalRole::Storage),
+            "observer" => Ok(ServalRole::Observer),
+            _ => Err(ServalError::InvalidRole(s.to_string())),
+        }
+    }
+}
+
+/// The version of the identity payload this agent advertises. Bump this whenever the encoding of
+/// `MetadataInner` changes, and teach `decode_metadata()` how to read the version it replaces.
+const IDENTITY_VERSION: u8 = 2;
+
+// An envelope that holds a version number. A little bit of future-proofing
+// to allow agents with higher version numbers to decode payloads from older agents.
+// When the mesh has a shared secret, the envelope also carries an HMAC-SHA256 of the version, the
+// payload and the address the peer advertises from. It's encoded after the other fields, so agents
+// that predate it skip right over it.
+#[derive(Debug, Clone)]
+struct VersionEnvelope {
+    version: u8,
+    rest: Vec<u8>,
+    signature: Option<Vec<u8>>,
+}
+
+impl VersionEnvelope {
+    // The bytes covered by the signature. The address is the one the payload is sent from, and it
+    // isn't part of the payload itself; the receiver supplies the address it heard the peer at. A
+    // payload replayed from any other host then fails to verify, so it can't draw traffic there.
+    fn signed_bytes(&self, address: IpAddr) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.rest.len() + 17);
+        bytes.push(self.version);
+        bytes.extend_from_slice(&self.rest);
+        match address {
+            IpAddr::V4(ip) => bytes.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                // The same host, seen through a dual-stack socket.
+                Some(ip) => bytes.extend_from_slice(&ip.octets()),
+                None => bytes.extend_from_slice(&ip.octets()),
+            },
+        }
+        bytes
+    }
+}
+
+impl Encode for VersionEnvelope {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.version.encode(encoder)?;
+        self.rest.encode(encoder)?;
+        if let Some(signature) = &self.signature {
+            signature.encode(encoder)?;
+        }
+        Ok(())
+    }
+}
+
+impl Decode for VersionEnvelope {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let version = u8::decode(decoder)?;
+        let rest = Vec::<u8>::decode(decoder)?;
+        // Unsigned envelopes simply end here.
+        let signature = if decoder.reader().peek_read(1).is_some() {
+            Some(Vec::<u8>::decode(decoder)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            version,
+            rest,
+            signature,
+        })
+    }
+}
+
+/// Represents a peer within the mesh. Generally speaking, this contains the data needed to identify
+/// and communicate with a particular peer.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
+pub struct PeerMetadata {
+    address: IpAddr,
+    inner: MetadataInner,
+}
+
+// The data we need to encode our identity as a serval peer. Done with an additional
+// type to get the derive. There'll be another way to do this, I'm sure.
+// This is also the version 2 payload layout; if it ever changes, freeze a copy of the old layout
+// for `decode_metadata()` to use, or agents will stop understanding their older peers.
+#[derive(Debug, Clone, Decode, Encode, Hash, Eq, PartialEq, Deserialize, Serialize)]
+struct MetadataInner {
+    instance_id: String,
+    http_port: Option<u16>, // Observer-only mesh members will not be listening over HTTP at all
+    roles: Vec<ServalRole>,
+    capabilities: Vec<String>,
+}
+
+// The version 1 payload layout, from before peers advertised capabilities. Frozen; don't touch.
+#[derive(Debug, Clone, Decode, Encode)]
+struct MetadataV1 {
+    instance_id: String,
+    http_port: Option<u16>,
+    roles: Vec<ServalRole>,
+}
+
+impl From<MetadataV1> for MetadataInner {
+    fn from(v1: MetadataV1) -> Self {
+        MetadataInner {
+            instance_id: v1.instance_id,
+            http_port: v1.http_port,
+            roles: v1.roles,
+            capabilities: Vec::new(),
+        }
+    }
+}
+
+impl PeerMetadata {
+    /// Create a new metadata node from useful information. Capabilities are free-form names for
+    /// what this peer can do beyond its roles, such as the Wasm extensions a runner provides.
+    pub fn new(
+        instance_id: String,
+        http_port: Option<u16>,
+        roles: Vec<ServalRole>,
+        capabilities: Vec<String>,
+        address: IpAddr,
+    ) -> Self {
+        let inner = MetadataInner {
+            instance_id,
+            http_port,
+            roles,
+            capabilities,
+        };
+        Self { address, inner }
+    }
+
+    /// Get the instance_id for this peer.
+    pub fn instance_id(&self) -> &str {
+        &self.inner.instance_id
+    }
+
+    /// Get the roles this peer has chosen to advertise.
+    pub fn roles(&self) -> Vec<ServalRole> {
+        self.inner.roles.clone()
+    }
+
+    /// Get the capabilities this peer has chosen to advertise.
+    pub fn capabilities(&self) -> Vec<String> {
+        self.inner.capabilities.clone()
+    }
+
+    /// Get the advertised http address of this peer.
+    pub fn http_address(&self) -> Option<SocketAddr> {
+        self.inner.http_port.map(|port| match self.address() {
+            IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)),
+        })
+    }
+
+    // Decode an identity payload, insisting that it was signed with `secret` if we have one.
+    fn from_signed_identity(
+        address: IpAddr,
+        encoded: Vec<u8>,
+        secret: Option<&[u8]>,
+    ) -> Result<Self, ServalError> {
+        let config = bincode::config::standard();
+        let (envelope, _len): (VersionEnvelope, usize) =
+            bincode::decode_from_slice(&encoded[..], config).map_err(|err| {
+                ServalError::PeerIdentityInvalid(format!(
+                    "peer at {address} sent an unreadable identity envelope: {err}"
+                ))
+            })?;
+        if let Some(secret) = secret {
+            let Some(signature) = &envelope.signature else {
+                return Err(ServalError::PeerIdentityRejected(format!(
+                    "peer at {address} did not sign its identity"
+                )));
+            };
+            if !secret::verify(secret, &envelope.signed_bytes(address), signature) {
+                return Err(ServalError::PeerIdentityRejected(format!(
+                    "peer at {address} signed its identity with a different secret or for a different address"
+                )));
+            }
+        }
+        let inner = decode_metadata(envelope.version, &envelope.rest).map_err(|err| {
+            ServalError::PeerIdentityInvalid(format!("peer at {address} sent {err}"))
+        })?;
+        Ok(PeerMetadata { address, inner })
+    }
+
+    // Encode our identity payload, signing it with `secret` if we have one.
+    fn signed_identity(&self, secret: Option<&[u8]>) -> Vec<u8> {
+        let config = bincode::config::standard();
+        let rest: Vec<u8> = bincode::encode_to_vec(self.inner.clone(), config).unwrap_or_default();
+        let mut envelope = VersionEnvelope {
+            version: IDENTITY_VERSION,
+            rest,
+            signature: None,
+        };
+        envelope.signature =
+            secret.map(|secret| secret::sign(secret, &envelope.signed_bytes(self.address)));
+        let identity: Vec<u8> = bincode::encode_to_vec(envelope, config).unwrap_or_default();
+        identity
+    }
+}
+
+// Decode the payload inside an envelope of the given version into our current representation.
+fn decode_metadata(version: u8, rest: &[u8]) -> Result<MetadataInner, String> {
+    let config = bincode::config::standard();
+    match version {
+        1 => bincode::decode_from_slice::<MetadataV1, _>(rest, config)
+            .map(|(v1, _len)| v1.into())
+            .map_err(|err| format!("an unreadable version 1 identity: {err}")),
+        2 => bincode::decode_from_slice::<MetadataInner, _>(rest, config)
+            .map(|(inner, _len)| inner)
+            .map_err(|err| format!("an unreadable version 2 identity: {err}")),
+        _ => Err(format!(
+            "an identity with version {version}, but we only understand versions up to {IDENTITY_VERSION}"
+        )),
+    }
+}
+
+impl KaboodlePeer for PeerMetadata {
+    fn from_identity(address: IpAddr, encoded: Vec<u8>) -> Result<Self, ServalError> {
+        PeerMetadata::from_signed_identity(address, encoded, secret::mesh_secret())
+    }
+
+    fn identity(&self) -> Vec<u8> {
+        self.signed_identity(secret::mesh_secret())
+    }
+
+    fn address(&self) -> IpAddr {
+        self.address
+    }
+}
+
+// End of peer implementation. Now we dive into the mesh itself.
+
+#[derive(Debug)]
+pub struct ServalMesh {
+    kaboodle: Kaboodle,
+    metadata: PeerMetadata,
+    seeds: Option<SeedMesh>,
+}
+
+impl ServalMesh {
+    /// Create a new node, with a kaboodle instance ready to run but not yet joined.
+    pub async fn new(
+        metadata: PeerMetadata,
+        port: u16,
+        interface: Option<Interface>,
+    ) -> Result<Self, KaboodleError> {
+        let identity = metadata.identity();
+        let kaboodle = Kaboodle::new(port, interface, identity)?;
+        Ok(Self {
+            kaboodle,
+            metadata,
+            seeds: None,
+        })
+    }
+
+    /// Also join the mesh over unicast, by contacting the given seed peers directly. Use this on
+    /// networks where broadcast discovery can't reach the other peers. Like Kaboodle's own
+    /// configuration, this can only be changed while the mesh is not running.
+    pub fn set_seeds(&mut self, config: SeedConfig) -> Result<(), KaboodleError> {
+        if self.kaboodle.is_running() {
+            return Err(KaboodleError::InvalidOperation(String::from(
+                "Cannot change seeds while the mesh is running; call .stop first",
+            )));
+        }
+        // Only peers we'd accept as members get recorded or contacted, whether they reached us
+        // directly or we heard about them from someone else.
+        let check: seeds::IdentityCheck = Arc::new(|addr, identity| {
+            PeerMetadata::from_identity(addr.ip(), identity.to_vec()).is_ok()
+        });
+        self.seeds = Some(SeedMesh::new(config, self.metadata.identity(), check));
+        Ok(())
+    }
+
+    /// The address we listen on for unicast mesh traffic, if seeds are configured and we're
+    /// running. Other peers can use it as a seed.
+    pub fn seed_addr(&self) -> Option<SocketAddr> {
+        self.seeds.as_ref().and_then(SeedMesh::local_addr)
+    }
+
+    /// Returns a map of all peers with known latencies.
+    pub async fn peer_latencies(&self) -> HashMap<PeerMetadata, Duration> {
+        let mut present = HashSet::new();
+        let mut latencies = self
+            .kaboodle
+            .peer_states()
+            .await
+            .into_iter()
+            .filter_map(|(addr, peer_info)| {
+                present.insert(addr);
+                let latency = peer_info.latency?;
+                accepted_peer(addr, peer_info.identity.to_vec()).map(|peer| (peer, latency))
+            })
+            .collect::<HashMap<_, _>>();
+
+        if let Some(seeds) = &self.seeds {
+            for (addr, (identity, latency)) in seeds.peer_latencies() {
+                present.insert(addr);
+                let Some(peer) = accepted_peer(addr, identity) else {
+                    continue;
+                };
+                if !latencies
+                    .keys()
+                    .any(|known| known.instance_id() == peer.instance_id())
+                {
+                    latencies.insert(peer, latency);
+                }
+            }
+        }
+
+        forget_departed_peers(&present);
+        latencies
+    }
+
+    /// Given a specific role, look for all peers that advertise the role.
+    pub async fn peers_with_role(&self, role: &ServalRole) -> Vec<PeerMetadata> {
+        let peers = self.peers().await;
+        // A naive implementation, to understate the matter, but it gets us going.
+        peers
+            .into_iter()
+            .filter(|xs| xs.roles().contains(role) && xs.http_address().is_some())
+            .collect()
+    }
+
+    // Delegation would be nice.
+    pub fn discover_peers(
+        &mut self,
+    ) -> Result<tokio::sync::mpsc::UnboundedReceiver<(SocketAddr, axum::body::Bytes)>, KaboodleError>
+    {
+        let arrivals = self.kaboodle.discover_peers()?;
+        match &mut self.seeds {
+            Some(seeds) => Ok(merge_receivers(arrivals, seeds.discover_peers())),
+            None => Ok(arrivals),
+        }
+    }
+
+    pub fn discover_departures(
+        &mut self,
+    ) -> Result<tokio::sync::mpsc::UnboundedReceiver<SocketAddr>, KaboodleError> {
+        let departures = self.kaboodle.discover_departures()?;
+        match &mut self.seeds {
+            Some(seeds) => Ok(merge_receivers(departures, seeds.discover_departures())),
+            None => Ok(departures),
+        }
+    }
+}
+
+// The most recent reason we had for ignoring each peer, so we log it once rather than every time.
+// Peers are forgotten once they leave the mesh.
+static IGNORED_PEERS: Lazy<Mutex<HashMap<SocketAddr, String>>> = Lazy::new(Default::default);
+
+// How many ignored peers we remember at most, in case peers come and go faster than we list them.
+const MAX_IGNORED_PEERS: usize = 1024;
+
+// Decode a peer's identity payload, skipping any peer that sends a malformed payload or that we
+// won't accept into the mesh. This runs every time someone asks for the peer list, so we only warn
+// about a peer the first time it's ignored for a particular reason.
+fn accepted_peer(addr: SocketAddr, identity: Vec<u8>) -> Option<PeerMetadata> {
+    let result = PeerMetadata::from_identity(addr.ip(), identity);
+    let mut ignored = IGNORED_PEERS.lock().unwrap();
+    match result {
+        Ok(peer) => {
+            ignored.remove(&addr);
+            Some(peer)
+        }
+        Err(err) => {
+            let reason = err.to_string();
+            if ignored.get(&addr) != Some(&reason) {
+                log::warn!("ignoring mesh peer; {reason}");
+                // When we're full, make room by forgetting whichever peer comes first.
+                if ignored.len() >= MAX_IGNORED_PEERS && !ignored.contains_key(&addr) {
+                    if let Some(evicted) = ignored.keys().next().copied() {
+                        ignored.remove(&evicted);
+                    }
+                }
+                ignored.insert(addr, reason);
+            }
+            None
+        }
+    }
+}
+
+// Stop remembering why we ignored peers that are no longer in the mesh.
+fn forget_departed_peers(present: &HashSet<SocketAddr>) {
+    IGNORED_PEERS
+        .lock()
+        .unwrap()
+        .retain(|addr, _| present.contains(addr));
+}
+
+// Forward everything sent to either receiver into a single new one.
+fn merge_receivers<T: Send + 'static>(
+    mut first: tokio::sync::mpsc::UnboundedReceiver<T>,
+    mut second: tokio::sync::mpsc::UnboundedReceiver<T>,
+) -> tokio::sync::mpsc::UnboundedReceiver<T> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let second_tx = tx.clone();
+    tokio::spawn(async move {
+        while let Some(item) = first.recv().await {
+            if tx.send(item).is_err() {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(item) = second.recv().await {
+            if second_tx.send(item).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+#[async_trait]
+impl KaboodleMesh for ServalMesh {
+    type A = PeerMetadata;
+
+    async fn start(&mut self) -> Result<(), KaboodleError> {
+        self.kaboodle.start().await?;
+        if let Some(seeds) = &mut self.seeds {
+            seeds.start().await?;
+        }
+        Ok(())
+    }
+
+    async fn stop(&mut self) -> Result<(), KaboodleError> {
+        if let Some(seeds) = &mut self.seeds {
+            seeds.stop();
+        }
+        self.kaboodle.stop().await
+    }
+
+    async fn peers(&self) -> Vec<Self::A> {
+        let mut present = HashSet::new();
+        let mut peers: Vec<PeerMetadata> = self
+            .kaboodle
+            .peers()
+            .await
+            .into_iter()
+            .filter_map(|(addr, identity)| {
+                present.insert(addr);
+                accepted_peer(addr, identity.to_vec())
+            })
+            .collect();
+
+        // A peer that both mechanisms found shows up under each of its addresses; keep the first.
+        if let Some(seeds) = &self.seeds {
+            for (addr, identity) in seeds.peers() {
+                present.insert(addr);
+                let Some(peer) = accepted_peer(addr, identity) else {
+                    continue;
+                };
+                if !peers
+                    .iter()
+                    .any(|known| known.instance_id() == peer.instance_id())
+                {
+                    peers.push(peer);
+                }
+            }
+        }
+
+        forget_departed_peers(&present);
+        peers
+    }
+}
+
+/// Discover a single nearby node in the mesh, without the overhead of joining it. If seed peers are
+/// configured, we ask them as well as broadcasting, and take whichever answer comes first. Fails if
+/// the node that answers isn't one we'd accept into our mesh.
+pub async fn discover() -> Result<PeerMetadata, ServalError> {
+    let (iface, port) = mesh_interface_and_port();
+    let seeds = mesh_seed_config(&iface)
+        .map(|config| config.seeds)
+        .unwrap_or_default();
+    let (address, identity) = if seeds.is_empty() {
+        let (address, identity) = Kaboodle::discover_mesh_member(port, Some(iface)).await?;
+        (address, identity.to_vec())
+    } else {
+        tokio::select! {
+            found = Kaboodle::discover_mesh_member(port, Some(iface)) => {
+                let (address, identity) = found?;
+                (address, identity.to_vec())
+            }
+            found = seeds::discover(&seeds) => found?,
+        }
+    };
+    PeerMetadata::from_identity(address.ip(), identity)
+}
+
+pub fn mesh_interface_and_port() -> (if_addrs::Interface, u16) {
+    let mesh_port: u16 = std::env::var("MESH_PORT")
+        .ok()
+        .map(|port_str| port_str.parse().expect("Invalid value given for MESH_PORT"))
+        .unwrap_or(8181);
+    let mesh_interface = match std::env::var("MESH_INTERFACE") {
+        Ok(v) => crate::networking::get_interface(&v)
+            .expect("Failed to find interface matching MESH_INTERFACE value"),
+        Err(_) => crate::networking::best_available_interface().expect("No available interfaces"),
+    };
+    log::info!(
+        "connecting to the mesh on port {mesh_port} over {} ({})",
+        mesh_interface.name,
+        mesh_interface.ip()
+    );
+    (mesh_interface, mesh_port)
+}
+
+/// Read the unicast seed configuration from the environment, if there is any. `MESH_SEEDS` is a
+/// comma-separated list of `host:port` seed addresses to contact directly; `MESH_SEED_PORT` is the
+/// UDP port to accept unicast mesh traffic on, which peers that want to use this node as a seed must
+/// set. Setting either one turns unicast discovery on.
+pub fn mesh_seed_config(interface: &Interface) -> Option<SeedConfig> {
+    let seeds: Vec<String> = std::env::var("MESH_SEEDS")
+        .map(|seeds_str| {
+            seeds_str
+                .split(',')
+                .map(|seed| seed.trim().to_string())
+                .filter(|seed| !seed.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+    let seed_port: Option<u16> = std::env::var("MESH_SEED_PORT").ok().map(|port_str| {
+        port_str
+            .parse()
+            .expect("Invalid value given for MESH_SEED_PORT")
+    });
+    if seeds.is_empty() && seed_port.is_none() {
+        return None;
+    }
+
+    Some(SeedConfig {
+        listen_addr: SocketAddr::new(interface.ip(), seed_port.unwrap_or(0)),
+        seeds,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignored_peers_are_forgotten_when_they_leave() {
+        let garbage = hex::decode("ffffffffffffffff").unwrap();
+        let staying: SocketAddr = "192.0.2.1:8181".parse().unwrap();
+        let leaving: SocketAddr = "192.0.2.2:8181".parse().unwrap();
+        assert!(accepted_peer(staying, garbage.clone()).is_none());
+        assert!(accepted_peer(leaving, garbage).is_none());
+
+        forget_departed_peers(&HashSet::from([staying]));
+        let ignored = IGNORED_PEERS.lock().unwrap();
+        assert!(ignored.contains_key(&staying));
+        assert!(!ignored.contains_key(&leaving));
+    }
+
+    // An agent's mesh on loopback, with Kaboodle on a port of its own so that only seeds can
+    // introduce it to the others.
+    async fn loopback_agent(name: &str, http_port: u16, seeds: Vec<String>) -> ServalMesh {
+        let loopback = if_addrs::get_if_addrs()
+            .unwrap()
+            .into_iter()
+            .find(|iface| iface.is_loopback() && iface.ip().is_ipv4())
+            .expect("a loopback interface");
+        let kaboodle_port = std::net::UdpSocket::bind((loopback.ip(), 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let metadata = PeerMetadata::new(
+            name.to_string(),
+            Some(http_port),
+            vec![ServalRole::Runner],
+            Vec::new(),
+            loopback.ip(),
+        );
+        let mut mesh = ServalMesh::new(metadata, kaboodle_port, Some(loopback.clone()))
+            .await
+            .unwrap();
+        mesh.set_seeds(SeedConfig {
+            listen_addr: SocketAddr::new(loopback.ip(), 0),
+            seeds,
+        })
+        .unwrap();
+        mesh.start().await.unwrap();
+        mesh
+    }
+
+    #[tokio::test]
+    async fn agents_on_loopback_join_through_a_seed() {
+        let seed = loopback_agent("seed", 8100, vec![]).await;
+        let seed_addr = seed.seed_addr().unwrap().to_string();
+        let first = loopback_agent("first", 8101, vec![seed_addr.clone()]).await;
+        let second = loopback_agent("second", 8102, vec![seed_addr]).await;
+
+        // Kaboodle counts each agent among its own peers, so everyone should see all three.
+        let everyone = HashSet::from(["seed", "first", "second"]);
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        for agent in [&seed, &first, &second] {
+            loop {
+                let peers = agent.peers().await;
+                let seen: HashSet<&str> = peers.iter().map(|peer| peer.instance_id()).collect();
+                if seen == everyone {
+                    // Peers are reachable at their own address and advertised port.
+                    for peer in &peers {
+                        let address = peer.http_address().unwrap();
+                        assert!(address.ip().is_loopback());
+                        assert!((8100..=8102).contains(&address.port()));
+                    }
+                    break;
+                }
+                assert!(
+                    std::time::Instant::now() < deadline,
+                    "agents never found each other; {} saw {seen:?}",
+                    agent.metadata.instance_id()
+                );
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+
+    fn peer() -> PeerMetadata {
+        PeerMetadata::new(
+            String::from("7ee0f4b5-4f16-4a42-a0a2-4bd2cfe4d7c5"),
+            Some(8100),
+            vec![ServalRole::Runner, ServalRole::Storage],
+            Vec::new(),
+            IpAddr::from([127, 0, 0, 1]),
+        )
+    }
+
+    #[test]
+    fn signed_identities_need_the_same_secret() {
+        let original = peer();
+        let address = original.address();
+        let signed = original.signed_identity(Some(b"hunter2"));
+
+        let decoded = PeerMetadata::from_signed_identity(address, signed.clone(), Some(b"hunter2"))
+            .expect("signed with the same secret");
+        assert_eq!(decoded, original);
+
+        let err = PeerMetadata::from_signed_identity(address, signed.clone(), Some(b"hunter3"))
+            .expect_err("signed with a different secret");
+        assert!(matches!(err, ServalError::PeerIdentityRejected(_)));
+
+        // A node without a secret can't check signatures, so it takes the payload at face value.
+        let decoded = PeerMetadata::from_signed_identity(address, signed, None)
+            .expect("no secret to check against");
+        assert_eq!(decoded, original);
+    }
+
+    #[test]
+    fn replayed_identities_are_rejected_from_other_addresses() {
+        let original = peer();
+        let signed = original.signed_identity(Some(b"hunter2"));
+
+        let replayer = IpAddr::from([192, 0, 2, 7]);
+        let err = PeerMetadata::from_signed_identity(replayer, signed.clone(), Some(b"hunter2"))
+            .expect_err("replayed from another host");
+        assert!(matches!(err, ServalError::PeerIdentityRejected(_)));
+
+        // The same host, heard over IPv6 as an IPv4-mapped address.
+        let mapped = IpAddr::from([0, 0, 0, 0, 0, 0xffff, 0x7f00, 1]);
+        let decoded = PeerMetadata::from_signed_identity(mapped, signed, Some(b"hunter2"))
+            .expect("same host");
+        assert_eq!(decoded.instance_id(), original.instance_id());
+    }
+
+    #[test]
+    fn unsigned_identities_are_rejected_when_we_have_a_secret() {
+        let original = peer();
+        let address = original.address();
+        let unsigned = original.signed_identity(None);
+
+        let err = PeerMetadata::from_signed_identity(address, unsigned.clone(), Some(b"hunter2"))
+            .expect_err("unsigned payload");
+        assert!(matches!(err, ServalError::PeerIdentityRejected(_)));
+
+        let decoded = PeerMetadata::from_signed_identity(address, unsigned, None)
+            .expect("neither side has a secret");
+        assert_eq!(decoded, original);
+    }
+
+    #[test]
+    fn tampered_identities_are_rejected() {
+        let original = peer();
+        let address = original.address();
+        let config = bincode::config::standard();
+        let signed = original.signed_identity(Some(b"hunter2"));
+        let (mut envelope, _len): (VersionEnvelope, usize) =
+            bincode::decode_from_slice(&signed[..], config).unwrap();
+
+        let mut inner = original.inner.clone();
+        inner.roles.push(ServalRole::Scheduler);
+        envelope.rest = bincode::encode_to_vec(inner, config).unwrap();
+        let tampered = bincode::encode_to_vec(envelope, config).unwrap();
+
+        let err = PeerMetadata::from_signed_identity(address, tampered, Some(b"hunter2"))
+            .expect_err("payload changed after signing");
+        assert!(matches!(err, ServalError::PeerIdentityRejected(_)));
+    }
+
+    #[test]
+    fn signed_identities_decode_with_the_unsigned_layout() {
+        // The layout agents used before signatures existed; they must still be able to read us.
+        #[derive(Decode)]
+        struct UnsignedEnvelope {
+            version: u8,
+            rest: Vec<u8>,
+        }
+
+        let original = peer();
+        let config = bincode::config::standard();
+        let signed = original.signed_identity(Some(b"hunter2"));
+        let (envelope, _len): (UnsignedEnvelope, usize) =
+            bincode::decode_from_slice(&signed[..], config).unwrap();
+        assert_eq!(envelope.version, IDENTITY_VERSION);
+        let (inner, _len): (MetadataInner, usize) =
+            bincode::decode_from_slice(&envelope.rest[..], config).unwrap();
+        assert_eq!(inner, original.inner);
+    }
+
+    // Payloads produced by agents speaking each version of the identity protocol so far. These are
+    // frozen: whatever else changes, every future agent must still be able to read them.
+    mod compatibility {
+        use super::*;
+
+        // A runner and storage node, without a mesh secret.
+        const V1_UNSIGNED: &str = "012c2437656530663462352d346631362d346134322d613061322d34626432636665346437633501fba41f020102";
+        // The same node, signed with the secret `hunter2` before signatures covered the address.
+        const V1_SIGNED: &str = "012c2437656530663462352d346631362d346134322d613061322d34626432636665346437633501fba41f02010220f45f348b0da4c6e28f26f99d0cf38500d0f90c47c1baddf2e794da97087a807b";
+        // An observer with no http port.
+        const V1_OBSERVER: &str = "010c086f62736572766572000103";
+        // A runner advertising a couple of capabilities.
+        const V2_RUNNER: &str = "021e0672756e6e657201fba41f0101020a626972646665656465720468747470";
+
+        fn decode(fixture: &str, secret: Option<&[u8]>) -> Result<PeerMetadata, ServalError> {
+            let address = IpAddr::from([127, 0, 0, 1]);
+            PeerMetadata::from_signed_identity(address, hex::decode(fixture).unwrap(), secret)
+        }
+
+        #[test]
+        fn v1_payloads_remain_decodable() {
+            assert_eq!(decode(V1_UNSIGNED, None).unwrap(), peer());
+            assert_eq!(decode(V1_SIGNED, None).unwrap(), peer());
+            // Its signature doesn't cover the address, so it could have been replayed from anywhere.
+            let err = decode(V1_SIGNED, Some(b"hunter2")).expect_err("signature without address");
+            assert!(matches!(err, ServalError::PeerIdentityRejected(_)));
+
+            let observer = decode(V1_OBSERVER, None).unwrap();
+            assert_eq!(observer.instance_id(), "observer");
+            assert_eq!(observer.roles(), vec![ServalRole::Observer]);
+            assert_eq!(observer.http_address(), None);
+            assert!(observer.capabilities().is_empty());
+        }
+
+        #[test]
+        fn v2_payloads_remain_decodable() {
+            let runner = decode(V2_RUNNER, None).unwrap();
+            assert_eq!(runner.instance_id(), "runner");
+            assert_eq!(runner.roles(), vec![ServalRole::Runner]);
+            assert_eq!(
+                runner.capabilities(),
+                vec![String::from("birdfeeder"), String::from("http")]
+            );
+        }
+
+        #[test]
+        fn malformed_payloads_are_errors() {
+            let bytes = hex::decode(V1_SIGNED).unwrap();
+            // Every truncation of a valid payload, down to nothing at all. Since we have a secret,
+            // even the one cut off exactly where the signature starts must be refused.
+            for len in 0..bytes.len() {
+                let truncated = bytes[..len].to_vec();
+                let address = IpAddr::from([127, 0, 0, 1]);
+                let result =
+                    PeerMetadata::from_signed_identity(address, truncated, Some(b"hunter2"));
+                assert!(
+                    result.is_err(),
+                    "accepted a payload truncated to {len} bytes"
+                );
+            }
+
+            let err = decode("ffffffffffffffff", None).expect_err("garbage");
+            assert!(matches!(err, ServalError::PeerIdentityInvalid(_)));
+        }
+
+        #[test]
+        fn unknown_versions_are_errors() {
+            let mut bytes = hex::decode(V1_UNSIGNED).unwrap();
+            bytes[0] = IDENTITY_VERSION + 1;
+            let err = decode(&hex::encode(bytes), None).expect_err("version from the future");
+            assert!(matches!(err, ServalError::PeerIdentityInvalid(_)));
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Shared-secret signing of mesh identities. When `MESH_SECRET` is set, every identity payload we
+//! advertise carries an HMAC-SHA256 over its contents and the address we advertise from, and we
+//! refuse to treat peers as mesh members unless their payloads were signed with the same secret for
+//! the address we hear them at. This keeps rogue or misconfigured nodes on a shared network from
+//! joining the mesh and advertising roles, even by replaying a legitimate peer's identity.
+//!
+//! The same secret signs requests that one mesh peer makes of another over HTTP, so that the
+//! routes only peers should use can tell them apart from everyone else.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha2::Sha256;
+
+static MESH_SECRET: Lazy<Option<Vec<u8>>> = Lazy::new(|| {
+    std::env::var("MESH_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty())
+        .map(String::into_bytes)
+});
+
+/// The header that carries the signature on a request one mesh peer makes of another.
+pub const PEER_SIGNATURE_HEADER: &str = "x-serval-peer-signature";
+
+// How long a signed request stays good for, in seconds, either side of when it was signed. This
+// allows for clocks that disagree a little, and limits how long a captured request can be replayed.
+const PEER_REQUEST_LIFETIME: u64 = 300;
+
+/// The secret shared by all members of this mesh, if one has been configured.
+pub(crate) fn mesh_secret() -> Option<&'static [u8]> {
+    MESH_SECRET.as_deref()
+}
+
+// HMAC takes keys of any length, so making one can't fail.
+fn mac(key: &[u8], payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    mac
+}
+
+/// Compute the HMAC-SHA256 of the payload under the given key.
+pub(crate) fn sign(key: &[u8], payload: &[u8]) -> Vec<u8> {
+    mac(key, payload).finalize().into_bytes().to_vec()
+}
+
+/// Check a signature produced by `sign()`, in constant time.
+pub(crate) fn verify(key: &[u8], payload: &[u8], signature: &[u8]) -> bool {
+    mac(key, payload).verify_slice(signature).is_ok()
+}
+
+/// Sign a request for another mesh peer. The signature covers the request's method and path, the
+/// integrity of its body if it has one, and when it was signed. Responds with the value for the
+/// `PEER_SIGNATURE_HEADER` header, or None if this mesh has no secret to sign with.
+pub fn sign_peer_request(method: &str, path: &str, integrity: Option<&str>) -> Option<String> {
+    let secret = mesh_secret()?;
+    Some(peer_request_signature(
+        secret,
+        method,
+        path,
+        integrity,
+        unix_time(),
+    ))
+}
+
+/// Check the signature a request from a mesh peer came with, made by `sign_peer_request()`. Responds
+/// with None if this mesh has no secret to check with.
+pub fn verify_peer_request(
+    method: &str,
+    path: &str,
+    integrity: Option<&str>,
+    signature: Option<&str>,
+) -> Option<bool> {
+    let secret = mesh_secret()?;
+    let signature = signature.unwrap_or_default();
+    Some(check_peer_request(
+        secret,
+        method,
+        path,
+        integrity,
+        signature,
+        unix_time(),
+    ))
+}
+
+// A request signature is the time it was made, then the HMAC of that and the request.
+fn peer_request_signature(
+    key: &[u8],
+    method: &str,
+    path: &str,
+    integrity: Option<&str>,
+    signed_at: u64,
+) -> String {
+    let payload = peer_request_payload(method, path, integrity, signed_at);
+    format!("{signed_at}.{}", hex::encode(sign(key, &payload)))
+}
+
+fn check_peer_request(
+    key: &[u8],
+    method: &str,
+    path: &str,
+    integrity: Option<&str>,
+    signature: &str,
+    now: u64,
+) -> bool {
+    let Some((signed_at, mac)) = signature.split_once('.') else {
+        return false;
+    };
+    let (Ok(signed_at), Ok(mac)) = (signed_at.parse::<u64>(), hex::decode(mac)) else {
+        return false;
+    };
+    if signed_at.abs_diff(now) > PEER_REQUEST_LIFETIME {
+        return false;
+    }
+    let payload = peer_request_payload(method, path, integrity, signed_at);
+    verify(key, &payload, &mac)
+}
+
+// Newlines can't appear in methods, paths, or integrity strings, so they keep the fields apart.
+fn peer_request_payload(
+    method: &str,
+    path: &str,
+    integrity: Option<&str>,
+    signed_at: u64,
+) -> Vec<u8> {
+    let integrity = integrity.unwrap_or_default();
+    format!("{method}\n{path}\n{integrity}\n{signed_at}").into_bytes()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_rfc_4231_vectors() {
+        let signature = sign(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            hex::encode(signature),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        // Keys longer than a block are hashed first.
+        let signature = sign(
+            &[0xaa; 131],
+            b"Test Using Larger Than Block-Size Key - Hash Key First",
+        );
+        assert_eq!(
+            hex::encode(signature),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn verify_rejects_tampering() {
+        let signature = sign(b"secret", b"payload");
+        assert!(verify(b"secret", b"payload", &signature));
+        assert!(!verify(b"secret", b"payloaD", &signature));
+        assert!(!verify(b"other secret", b"payload", &signature));
+        assert!(!verify(b"secret", b"payload", &signature[..31]));
+    }
+
+    #[test]
+    fn peer_requests_need_a_fresh_signature_for_the_same_request() {
+        let now = 1_700_000_000;
+        let path = "/v1/storage/replicas/keys/some-key";
+        let body = Some("sha256-abc");
+        let signature = peer_request_signature(b"secret", "PUT", path, body, now);
+        let check = |method, path, integrity, signature, now| {
+            check_peer_request(b"secret", method, path, integrity, signature, now)
+        };
+        assert!(check("PUT", path, body, &signature, now));
+        assert!(check("PUT", path, body, &signature, now + 60));
+
+        let other_path = "/v1/storage/replicas/keys/other-key";
+        assert!(!check("DELETE", path, body, &signature, now));
+        assert!(!check("PUT", other_path, body, &signature, now));
+        assert!(!check("PUT", path, Some("sha256-def"), &signature, now));
+        assert!(!check("PUT", path, None, &signature, now));
+        assert!(!check("PUT", path, body, &signature, now + 3600));
+        assert!(!check("PUT", path, body, "", now));
+        assert!(!check("PUT", path, body, "garbage", now));
+        assert!(!check_peer_request(
+            b"other secret",
+            "PUT",
+            path,
+            body,
+            &signature,
+            now
+        ));
+
+        // Moving the signing time along doesn't carry the signature with it.
+        let (_, mac) = signature.split_once('.').unwrap();
+        let moved = format!("{}.{mac}", now + 3600);
+        assert!(!check("PUT", path, body, &moved, now + 3600));
+    }
+}
@@ -0,0 +1,614 @@
+// Unicast peer discovery, for networks where Kaboodle's broadcast discovery can't reach: cloud
+// VPCs, peers on other subnets, and so on. Each node listens on a UDP socket and regularly says
+// hello to a configured list of seed addresses plus every peer it has heard from. Hellos and their
+// replies carry the sender's identity payload and a few of the peers it knows about, so a node that
+// can reach a single seed learns about the rest of the mesh from it. Identities are checked before
+// we record or contact anyone, so a node we wouldn't accept can't point us at other hosts.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::body::Bytes;
+use bincode::{Decode, Encode};
+use kaboodle::errors::KaboodleError;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+/// How often we say hello to our seeds and to every peer we know about.
+const HELLO_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// How long a peer may go without saying anything to us before we consider it departed.
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest payload a UDP datagram can carry; messages hold a list of peers, so we want room.
+const INCOMING_BUFFER_SIZE: usize = 65507;
+
+/// How many peers a single message tells the recipient about. Each message carries the next page of
+/// our members, so they all get passed along in turn while messages stay far smaller than a
+/// datagram, however big the mesh gets.
+const MAX_GOSSIP_PEERS: usize = 16;
+
+/// Decides whether an identity payload, from a peer at the given address, belongs to a peer we
+/// accept into the mesh.
+pub type IdentityCheck = Arc<dyn Fn(SocketAddr, &[u8]) -> bool + Send + Sync>;
+
+/// Where to listen for unicast mesh traffic, and which peers to contact directly to join the mesh.
+#[derive(Debug, Clone)]
+pub struct SeedConfig {
+    /// The address to listen on. Other peers may list this address as one of their seeds.
+    pub listen_addr: SocketAddr,
+    /// Seed peers as `host:port` strings. Host names are resolved each time we contact the seeds,
+    /// so a DNS name with one record per seed works as the whole list.
+    pub seeds: Vec<String>,
+}
+
+#[derive(Debug, Clone, Decode, Encode)]
+enum SeedMessage {
+    /// Sent regularly to every seed and every known peer; carries our identity and a page of the
+    /// peers we have heard from directly.
+    Hello {
+        identity: Vec<u8>,
+        peers: Vec<(SocketAddr, Vec<u8>)>,
+    },
+    /// The answer to a Hello or a Probe.
+    Welcome {
+        identity: Vec<u8>,
+        peers: Vec<(SocketAddr, Vec<u8>)>,
+    },
+    /// Asks a peer to identify itself without joining the mesh.
+    Probe,
+}
+
+#[derive(Debug, Clone)]
+struct SeedPeer {
+    identity: Vec<u8>,
+    last_seen: Instant,
+    latency: Option<Duration>,
+}
+
+#[derive(Debug, Default)]
+struct Membership {
+    /// Peers we have heard from directly.
+    members: HashMap<SocketAddr, SeedPeer>,
+    /// Peers other nodes told us about that we have not heard from ourselves yet. We only say hello
+    /// to these; they become members once they answer, so departed peers can't be kept alive by
+    /// gossip alone.
+    candidates: HashSet<SocketAddr>,
+    /// When we last said hello to each address, so we can measure latency from the reply.
+    hellos: HashMap<SocketAddr, Instant>,
+    /// Where the next page of members to gossip about starts.
+    gossip_cursor: usize,
+    discovery_tx: Vec<UnboundedSender<(SocketAddr, Bytes)>>,
+    departure_tx: Vec<UnboundedSender<SocketAddr>>,
+}
+
+impl Membership {
+    fn record(&mut self, addr: SocketAddr, identity: Vec<u8>, latency: Option<Duration>) {
+        self.candidates.remove(&addr);
+        let previous = self.members.insert(
+            addr,
+            SeedPeer {
+                identity: identity.clone(),
+                last_seen: Instant::now(),
+                latency,
+            },
+        );
+        match previous {
+            Some(previous) if latency.is_none() => {
+                // Hellos from the peer tell us it is alive but say nothing about latency.
+                if let Some(member) = self.members.get_mut(&addr) {
+                    member.latency = previous.latency;
+                }
+            }
+            Some(_) => {}
+            None => {
+                let identity = Bytes::from(identity);
+                self.discovery_tx
+                    .retain(|tx| tx.send((addr, identity.clone())).is_ok());
+            }
+        }
+    }
+
+    fn learn(
+        &mut self,
+        peers: Vec<(SocketAddr, Vec<u8>)>,
+        own_identity: &[u8],
+        check: &IdentityCheck,
+    ) {
+        for (addr, identity) in peers.into_iter().take(MAX_GOSSIP_PEERS) {
+            if identity == own_identity || self.members.contains_key(&addr) {
+                continue;
+            }
+            if check(addr, &identity) {
+                self.candidates.insert(addr);
+            } else {
+                log::debug!("not contacting a gossiped mesh peer we wouldn't accept; peer={addr}");
+            }
+        }
+    }
+
+    fn expire(&mut self) {
+        let departed: Vec<SocketAddr> = self
+            .members
+            .iter()
+            .filter(|(_, peer)| peer.last_seen.elapsed() > PEER_TIMEOUT)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in departed {
+            self.members.remove(&addr);
+            self.departure_tx.retain(|tx| tx.send(addr).is_ok());
+        }
+    }
+
+    fn known_peers(&self) -> Vec<(SocketAddr, Vec<u8>)> {
+        self.members
+            .iter()
+            .map(|(addr, peer)| (*addr, peer.identity.clone()))
+            .collect()
+    }
+
+    // The next page of members to tell a peer about.
+    fn gossip(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        let mut peers = self.known_peers();
+        peers.sort_by_key(|(addr, _)| *addr);
+        if peers.len() <= MAX_GOSSIP_PEERS {
+            self.gossip_cursor = 0;
+            return peers;
+        }
+        let start = self.gossip_cursor % peers.len();
+        self.gossip_cursor = start + MAX_GOSSIP_PEERS;
+        peers
+            .into_iter()
+            .cycle()
+            .skip(start)
+            .take(MAX_GOSSIP_PEERS)
+            .collect()
+    }
+}
+
+/// Membership in the mesh as learned over unicast from seed peers. This runs alongside Kaboodle;
+/// `ServalMesh` merges what the two find.
+pub struct SeedMesh {
+    config: SeedConfig,
+    identity: Vec<u8>,
+    check: IdentityCheck,
+    state: Arc<Mutex<Membership>>,
+    local_addr: Option<SocketAddr>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl std::fmt::Debug for SeedMesh {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SeedMesh")
+            .field("config", &self.config)
+            .field("local_addr", &self.local_addr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SeedMesh {
+    /// Create a new seed mesh member with the given identity payload. We only record, and only
+    /// contact, peers whose identities pass `check`. Nothing happens until it is started.
+    pub fn new(config: SeedConfig, identity: Vec<u8>, check: IdentityCheck) -> Self {
+        Self {
+            config,
+            identity,
+            check,
+            state: Arc::new(Mutex::new(Membership::default())),
+            local_addr: None,
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Start listening for unicast mesh traffic and saying hello to our seeds.
+    pub async fn start(&mut self) -> Result<(), KaboodleError> {
+        if self.local_addr.is_some() {
+            return Ok(());
+        }
+
+        let socket = Arc::new(UdpSocket::bind(self.config.listen_addr).await?);
+        let local_addr = socket.local_addr()?;
+        log::info!(
+            "listening for unicast mesh traffic on {local_addr}; seeds={:?}",
+            self.config.seeds
+        );
+
+        self.tasks.push(tokio::spawn(say_hello(
+            socket.clone(),
+            self.config.seeds.clone(),
+            self.identity.clone(),
+            self.state.clone(),
+        )));
+        self.tasks.push(tokio::spawn(listen(
+            socket,
+            self.identity.clone(),
+            self.check.clone(),
+            self.state.clone(),
+        )));
+        self.local_addr = Some(local_addr);
+
+        Ok(())
+    }
+
+    /// Stop talking to the mesh. Peers will notice our departure once we time out.
+    pub fn stop(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        self.local_addr = None;
+    }
+
+    /// The address we are listening on, if we are running.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Every peer we have heard from directly, with its identity payload. We are not included.
+    pub fn peers(&self) -> HashMap<SocketAddr, Vec<u8>> {
+        self.state
+            .lock()
+            .unwrap()
+            .known_peers()
+            .into_iter()
+            .collect()
+    }
+
+    /// Every peer whose round-trip latency we have measured, with its identity payload.
+    pub fn peer_latencies(&self) -> HashMap<SocketAddr, (Vec<u8>, Duration)> {
+        self.state
+            .lock()
+            .unwrap()
+            .members
+            .iter()
+            .filter_map(|(addr, peer)| {
+                peer.latency
+                    .map(|latency| (*addr, (peer.identity.clone(), latency)))
+            })
+            .collect()
+    }
+
+    /// Returns a channel receiver that is sent every peer we hear from for the first time.
+    pub fn discover_peers(&mut self) -> UnboundedReceiver<(SocketAddr, Bytes)> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.state.lock().unwrap().discovery_tx.push(tx);
+        rx
+    }
+
+    /// Returns a channel receiver that is sent every peer that times out.
+    pub fn discover_departures(&mut self) -> UnboundedReceiver<SocketAddr> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.state.lock().unwrap().departure_tx.push(tx);
+        rx
+    }
+}
+
+impl Drop for SeedMesh {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Ask the given seeds to identify themselves and return the first one to answer, without joining
+/// the mesh. Keeps asking until someone answers.
+pub async fn discover(seeds: &[String]) -> Result<(SocketAddr, Vec<u8>), KaboodleError> {
+    let config = bincode::config::standard();
+    let probe = bincode::encode_to_vec(SeedMessage::Probe, config).unwrap_or_default();
+    let mut buf = vec![0; INCOMING_BUFFER_SIZE];
+
+    loop {
+        let targets = resolve(seeds).await;
+        let bind_addr = if targets.iter().any(|addr| addr.is_ipv4()) {
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+        } else {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        for target in targets
+            .iter()
+            .filter(|t| t.is_ipv4() == bind_addr.is_ipv4())
+        {
+            if let Err(err) = socket.send_to(&probe, target).await {
+                log::debug!("failed to probe seed; seed={target}; err={err}");
+            }
+        }
+
+        let deadline = Instant::now() + HELLO_INTERVAL;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            let Ok(Ok((len, sender))) = timeout(remaining, socket.recv_from(&mut buf)).await else {
+                break;
+            };
+            if let Ok((SeedMessage::Welcome { identity, .. }, _)) =
+                bincode::decode_from_slice::<SeedMessage, _>(&buf[..len], config)
+            {
+                return Ok((sender, identity));
+            }
+        }
+    }
+}
+
+/// Resolve every seed string to the addresses it names. Seeds that don't resolve are skipped; we'll
+/// try them again next time.
+async fn resolve(seeds: &[String]) -> HashSet<SocketAddr> {
+    let mut resolved = HashSet::new();
+    for seed in seeds {
+        match tokio::net::lookup_host(seed.as_str()).await {
+            Ok(addrs) => resolved.extend(addrs),
+            Err(err) => log::debug!("unable to resolve mesh seed; seed={seed}; err={err}"),
+        }
+    }
+    resolved
+}
+
+// Say hello to every seed, every member, and every candidate we've heard about, forever.
+async fn say_hello(
+    socket: Arc<UdpSocket>,
+    seeds: Vec<String>,
+    identity: Vec<u8>,
+    state: Arc<Mutex<Membership>>,
+) {
+    let config = bincode::config::standard();
+    let Ok(local_addr) = socket.local_addr() else {
+        return;
+    };
+
+    loop {
+        let mut targets = resolve(&seeds).await;
+        let hello = {
+            let mut state = state.lock().unwrap();
+            state.expire();
+            targets.extend(state.members.keys());
+            let candidates: Vec<SocketAddr> = state.candidates.drain().collect();
+            targets.extend(candidates);
+            SeedMessage::Hello {
+                identity: identity.clone(),
+                peers: state.gossip(),
+            }
+        };
+        let bytes = bincode::encode_to_vec(hello, config).unwrap_or_default();
+
+        targets.retain(|addr| *addr != local_addr && addr.is_ipv4() == local_addr.is_ipv4());
+        for target in targets {
+            state.lock().unwrap().hellos.insert(target, Instant::now());
+            if let Err(err) = socket.send_to(&bytes, target).await {
+                log::debug!("failed to say hello to mesh peer; peer={target}; err={err}");
+            }
+        }
+
+        tokio::time::sleep(HELLO_INTERVAL).await;
+    }
+}
+
+// Answer hellos and probes, and record everyone we hear from. Hellos and welcomes from peers we
+// wouldn't accept are dropped without an answer.
+async fn listen(
+    socket: Arc<UdpSocket>,
+    identity: Vec<u8>,
+    check: IdentityCheck,
+    state: Arc<Mutex<Membership>>,
+) {
+    let config = bincode::config::standard();
+    let mut buf = vec![0; INCOMING_BUFFER_SIZE];
+
+    loop {
+        let Ok((len, sender)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        let Ok((message, _)) = bincode::decode_from_slice::<SeedMessage, _>(&buf[..len], config)
+        else {
+            log::warn!("Failed to decode unicast mesh message; sender={sender}; len={len}");
+            continue;
+        };
+
+        let reply = match message {
+            // Whoever probes us hasn't told us who they are, so they don't hear about anyone else.
+            SeedMessage::Probe => SeedMessage::Welcome {
+                identity: identity.clone(),
+                peers: Vec::new(),
+            },
+            SeedMessage::Hello {
+                identity: theirs,
+                peers,
+            } => {
+                if theirs == identity {
+                    // We listed ourselves as a seed.
+                    continue;
+                }
+                if !check(sender, &theirs) {
+                    log::debug!(
+                        "ignoring hello from a mesh peer we wouldn't accept; peer={sender}"
+                    );
+                    continue;
+                }
+                let mut state = state.lock().unwrap();
+                state.record(sender, theirs, None);
+                state.learn(peers, &identity, &check);
+                SeedMessage::Welcome {
+                    identity: identity.clone(),
+                    peers: state.gossip(),
+                }
+            }
+            SeedMessage::Welcome {
+                identity: theirs,
+                peers,
+            } => {
+                if theirs == identity || !check(sender, &theirs) {
+                    continue;
+                }
+                let mut state = state.lock().unwrap();
+                let latency = state.hellos.remove(&sender).map(|sent| sent.elapsed());
+                state.record(sender, theirs, latency);
+                state.learn(peers, &identity, &check);
+                continue;
+            }
+        };
+
+        let bytes = bincode::encode_to_vec(reply, config).unwrap_or_default();
+        if let Err(err) = socket.send_to(&bytes, sender).await {
+            log::debug!("failed to welcome mesh peer; peer={sender}; err={err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loopback_member(name: &str, seeds: Vec<String>) -> SeedMesh {
+        let config = SeedConfig {
+            listen_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            seeds,
+        };
+        SeedMesh::new(config, name.as_bytes().to_vec(), accept_all_but_rogues())
+    }
+
+    // Accepts every identity except the ones that say they are rogues.
+    fn accept_all_but_rogues() -> IdentityCheck {
+        Arc::new(|_addr, identity| !identity.starts_with(b"rogue"))
+    }
+
+    async fn loopback_socket() -> UdpSocket {
+        UdpSocket::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .unwrap()
+    }
+
+    async fn send(socket: &UdpSocket, message: SeedMessage, target: SocketAddr) {
+        let bytes = bincode::encode_to_vec(message, bincode::config::standard()).unwrap();
+        socket.send_to(&bytes, target).await.unwrap();
+    }
+
+    // Whether anything at all arrives on the socket within a couple of hello intervals.
+    async fn hears_anything(socket: &UdpSocket) -> bool {
+        let mut buf = vec![0; INCOMING_BUFFER_SIZE];
+        timeout(
+            HELLO_INTERVAL * 2 + HELLO_INTERVAL / 2,
+            socket.recv_from(&mut buf),
+        )
+        .await
+        .is_ok()
+    }
+
+    fn identities(member: &SeedMesh) -> HashSet<Vec<u8>> {
+        member.peers().into_values().collect()
+    }
+
+    #[tokio::test]
+    async fn peers_find_each_other_through_a_seed() {
+        let mut seed = loopback_member("seed", vec![]);
+        seed.start().await.unwrap();
+        let seed_addr = seed.local_addr().unwrap().to_string();
+
+        let mut first = loopback_member("first", vec![seed_addr.clone()]);
+        first.start().await.unwrap();
+        let mut second = loopback_member("second", vec![seed_addr]);
+        second.start().await.unwrap();
+
+        let everyone: HashSet<Vec<u8>> = ["seed", "first", "second"]
+            .iter()
+            .map(|name| name.as_bytes().to_vec())
+            .collect();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let members = [&seed, &first, &second];
+            let seen: Vec<HashSet<Vec<u8>>> = members.iter().map(|m| identities(m)).collect();
+            let complete = members.iter().zip(seen.iter()).all(|(member, seen)| {
+                let mut expected = everyone.clone();
+                expected.remove(&member.identity);
+                *seen == expected
+            });
+            if complete {
+                break;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "peers never found each other; seen={seen:?}"
+            );
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        // The second peer only ever heard of the first through the seed, but has talked to it
+        // directly since then, so it knows the latency.
+        let first_addr = first.local_addr().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !second.peer_latencies().contains_key(&first_addr) {
+            assert!(Instant::now() < deadline, "latency was never measured");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn probing_a_seed_finds_it() {
+        let mut seed = loopback_member("seed", vec![]);
+        seed.start().await.unwrap();
+        let seed_addr = seed.local_addr().unwrap();
+
+        let (addr, identity) = timeout(Duration::from_secs(5), discover(&[seed_addr.to_string()]))
+            .await
+            .expect("probe timed out")
+            .unwrap();
+        assert_eq!(seed_addr, addr);
+        assert_eq!(b"seed".to_vec(), identity);
+
+        // Probing doesn't join the mesh.
+        assert!(seed.peers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn peers_we_would_not_accept_are_ignored() {
+        let mut seed = loopback_member("seed", vec![]);
+        seed.start().await.unwrap();
+        let seed_addr = seed.local_addr().unwrap();
+        let victim = loopback_socket().await;
+        let victim_addr = victim.local_addr().unwrap();
+
+        // A rogue's hello is dropped: it isn't recorded, answered, or used to find more peers.
+        let rogue = loopback_socket().await;
+        let hello = SeedMessage::Hello {
+            identity: b"rogue".to_vec(),
+            peers: vec![(victim_addr, b"victim".to_vec())],
+        };
+        send(&rogue, hello, seed_addr).await;
+        assert!(!hears_anything(&rogue).await);
+        assert!(!hears_anything(&victim).await);
+        assert!(seed.peers().is_empty());
+
+        // A peer we accept can't get us to contact a rogue either.
+        let member = loopback_socket().await;
+        let hello = SeedMessage::Hello {
+            identity: b"member".to_vec(),
+            peers: vec![(victim_addr, b"rogue".to_vec())],
+        };
+        send(&member, hello, seed_addr).await;
+        assert!(hears_anything(&member).await);
+        assert!(!hears_anything(&victim).await);
+        assert_eq!(
+            HashSet::from([b"member".to_vec()]),
+            identities(&seed),
+            "only the accepted peer is a member"
+        );
+    }
+
+    #[test]
+    fn gossip_pages_through_every_member() {
+        let mut membership = Membership::default();
+        let everyone: HashSet<SocketAddr> = (0..40)
+            .map(|port| SocketAddr::from((Ipv4Addr::LOCALHOST, 9000 + port)))
+            .collect();
+        for addr in &everyone {
+            membership.record(*addr, addr.to_string().into_bytes(), None);
+        }
+
+        // Three pages of sixteen cover all forty members.
+        let mut heard = HashSet::new();
+        for _ in 0..3 {
+            let page = membership.gossip();
+            assert_eq!(MAX_GOSSIP_PEERS, page.len());
+            heard.extend(page.into_iter().map(|(addr, _)| addr));
+        }
+        assert_eq!(everyone, heard);
+    }
+}
@@ -1,23 +1,703 @@
-use std::net::SocketAddr;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
 
+use axum::http::{header, HeaderMap, HeaderValue};
 use serde::{Deserialize, Serialize};
 
-use crate::mesh::PeerMetadata;
+use crate::errors::ServalError;
+use crate::mesh::{KaboodlePeer, PeerMetadata, ServalRole};
+use crate::structs::Manifest;
 
-/// A MeshMember is effectively a limited subset of information from a PeerMetadata instance. Unlike
-/// PeerMetadata, MeshMember is publicly visible via the HTTP API. The intention is for it to only
-/// contain enoug information to know how to talk to a node and who that node is.
-#[derive(Deserialize, Serialize)]
+/// A MeshMember is the publicly visible view of a PeerMetadata instance, as returned by the
+/// `/v1/mesh/peers` endpoints. It holds what you need to know to talk to a node, who that node is,
+/// and what it can do for you.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct MeshMember {
-    pub http_address: Option<SocketAddr>,
     pub instance_id: String,
+    pub address: IpAddr,
+    pub http_address: Option<SocketAddr>,
+    pub roles: Vec<ServalRole>,
+    pub capabilities: Vec<String>,
+    /// Round-trip time to this peer in milliseconds, as last measured by the node that reported it.
+    /// Missing if that node hasn't measured it yet.
+    pub latency_ms: Option<f64>,
 }
 
 impl From<PeerMetadata> for MeshMember {
     fn from(peer_metadata: PeerMetadata) -> Self {
         MeshMember {
-            http_address: peer_metadata.http_address(),
             instance_id: peer_metadata.instance_id().to_string(),
+            address: peer_metadata.address(),
+            http_address: peer_metadata.http_address(),
+            roles: peer_metadata.roles(),
+            capabilities: peer_metadata.capabilities(),
+            latency_ms: None,
+        }
+    }
+}
+
+/// The orders in which the `/v1/mesh/peers` endpoints can list peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerSort {
+    /// Lowest latency first. Peers without a measured latency come last.
+    Latency,
+    /// By instance id, alphabetically.
+    InstanceId,
+}
+
+impl FromStr for PeerSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "latency" => Ok(PeerSort::Latency),
+            "instance_id" | "instance-id" => Ok(PeerSort::InstanceId),
+            _ => Err(format!(
+                "not a valid sort order `{s}`; try latency or instance_id"
+            )),
+        }
+    }
+}
+
+/// Query parameters that narrow down and order the `/v1/mesh/peers` listings. Everything is
+/// optional; without any of them, you get every known peer in no particular order.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PeerQuery {
+    /// Return at most this many peers, after sorting.
+    pub count: Option<usize>,
+    /// Only return peers that advertise every one of these roles, as a comma-separated list.
+    pub roles: Option<String>,
+    /// The order to return peers in.
+    pub sort: Option<PeerSort>,
+}
+
+impl PeerQuery {
+    /// Create a new query from its parts. Pass an empty slice of roles to allow any role.
+    pub fn new(count: Option<usize>, roles: &[ServalRole], sort: Option<PeerSort>) -> Self {
+        let roles = (!roles.is_empty()).then(|| {
+            roles
+                .iter()
+                .map(|role| role.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        });
+        Self { count, roles, sort }
+    }
+
+    /// The roles a peer must advertise to be included.
+    pub fn required_roles(&self) -> Result<Vec<ServalRole>, ServalError> {
+        self.roles
+            .iter()
+            .flat_map(|roles| roles.split(','))
+            .map(str::trim)
+            .filter(|role| !role.is_empty())
+            .map(ServalRole::from_str)
+            .collect()
+    }
+
+    /// Filter, sort, and truncate a list of peers according to this query.
+    pub fn apply(&self, mut members: Vec<MeshMember>) -> Result<Vec<MeshMember>, ServalError> {
+        let required_roles = self.required_roles()?;
+        members.retain(|member| {
+            required_roles
+                .iter()
+                .all(|role| member.roles.contains(role))
+        });
+
+        match self.sort {
+            Some(PeerSort::Latency) => members.sort_by(|left, right| {
+                match (left.latency_ms, right.latency_ms) {
+                    (Some(left), Some(right)) => {
+                        left.partial_cmp(&right).unwrap_or(Ordering::Equal)
+                    }
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => Ordering::Equal,
+                }
+                .then_with(|| left.instance_id.cmp(&right.instance_id))
+            }),
+            Some(PeerSort::InstanceId) => {
+                members.sort_by(|left, right| left.instance_id.cmp(&right.instance_id))
+            }
+            None => {}
+        }
+
+        if let Some(count) = self.count {
+            members.truncate(count);
+        }
+        Ok(members)
+    }
+}
+
+/// A change in the mesh's membership, as streamed by the `/v1/mesh/events` endpoint.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MeshEvent {
+    /// A peer joined the mesh.
+    Joined { peer: MeshMember },
+    /// A peer left the mesh, or stopped responding. This is the last we knew of it.
+    Departed { peer: MeshMember },
+    /// A peer that we already knew about started advertising a different set of roles.
+    RolesChanged {
+        peer: MeshMember,
+        previous_roles: Vec<ServalRole>,
+    },
+}
+
+impl MeshEvent {
+    /// The name of this kind of event; also the value of its `event` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MeshEvent::Joined { .. } => "joined",
+            MeshEvent::Departed { .. } => "departed",
+            MeshEvent::RolesChanged { .. } => "roles_changed",
+        }
+    }
+
+    /// The peer this event is about.
+    pub fn peer(&self) -> &MeshMember {
+        match self {
+            MeshEvent::Joined { peer }
+            | MeshEvent::Departed { peer }
+            | MeshEvent::RolesChanged { peer, .. } => peer,
+        }
+    }
+}
+
+/// How many manifests the `/v1/storage/manifests` listing returns when not asked for a number.
+pub const DEFAULT_MANIFEST_PAGE_SIZE: usize = 100;
+
+/// Query parameters that narrow down and page through the `/v1/storage/manifests` listing.
+/// Everything is optional; without any of them, you get the first page of every stored manifest,
+/// ordered by fully-qualified name.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ManifestQuery {
+    /// Only list manifests in exactly this namespace.
+    pub namespace: Option<String>,
+    /// Only list manifests whose fully-qualified name starts with this.
+    pub prefix: Option<String>,
+    /// Skip this many matching manifests before listing any.
+    pub offset: Option<usize>,
+    /// List at most this many manifests.
+    pub limit: Option<usize>,
+}
+
+impl ManifestQuery {
+    /// Filter, sort and page a list of fully-qualified manifest names according to this query.
+    /// Returns the requested page along with how many names matched in total.
+    pub fn apply(&self, mut names: Vec<String>) -> (Vec<String>, usize) {
+        names.retain(|name| {
+            let in_namespace = match &self.namespace {
+                Some(wanted) => {
+                    name.rsplit_once('.').map(|(namespace, _)| namespace) == Some(wanted.as_str())
+                }
+                None => true,
+            };
+            let has_prefix = match &self.prefix {
+                Some(prefix) => name.starts_with(prefix.as_str()),
+                None => true,
+            };
+            in_namespace && has_prefix
+        });
+        names.sort();
+        names.dedup();
+
+        let total = names.len();
+        let page = names
+            .into_iter()
+            .skip(self.offset.unwrap_or(0))
+            .take(self.limit.unwrap_or(DEFAULT_MANIFEST_PAGE_SIZE))
+            .collect();
+        (page, total)
+    }
+}
+
+/// The latest version of a stored manifest, as listed by `/v1/storage/manifests`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ManifestSummary {
+    /// The fully-qualified name of the manifest.
+    pub name: String,
+    pub namespace: String,
+    /// The latest stored version.
+    pub version: String,
+    pub description: String,
+}
+
+impl From<&Manifest> for ManifestSummary {
+    fn from(manifest: &Manifest) -> Self {
+        ManifestSummary {
+            name: manifest.fq_name(),
+            namespace: manifest.namespace().to_string(),
+            version: manifest.version().to_string(),
+            description: manifest.description().to_string(),
+        }
+    }
+}
+
+/// One page of the `/v1/storage/manifests` listing.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ManifestListing {
+    pub manifests: Vec<ManifestSummary>,
+    /// How many manifests matched the query, across all pages.
+    pub total: usize,
+    /// The offset to ask for to get the next page, if there is one.
+    pub next_offset: Option<usize>,
+}
+
+/// A stored version of a manifest, as listed by `/v1/storage/manifests/:name/versions`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ManifestVersion {
+    pub version: String,
+    /// The integrity of the executable stored for this version. Missing if none has been stored.
+    pub integrity: Option<String>,
+    /// The size of that executable in bytes.
+    pub size: Option<u64>,
+}
+
+/// Query parameters for `/v1/storage/gc`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GarbageCollectionQuery {
+    /// Report what would be removed without removing anything.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// A blob that garbage collection removed, or would have removed in a dry run.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CollectedBlob {
+    /// The name of the storage backend the blob was in.
+    pub backend: String,
+    pub integrity: String,
+    /// The size of the blob in bytes.
+    pub size: u64,
+}
+
+/// What a run of the storage garbage collector did, as reported by `/v1/storage/gc`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GarbageCollection {
+    /// True if nothing was actually removed.
+    pub dry_run: bool,
+    /// How many blobs were kept because a manifest, executable or pin refers to them.
+    pub kept: usize,
+    pub removed: Vec<CollectedBlob>,
+    /// The total size of the removed blobs in bytes.
+    pub reclaimed_bytes: u64,
+}
+
+/// Query parameters for `/v1/storage/scrub`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ScrubQuery {
+    /// Replace corrupt copies with good ones from elsewhere, instead of only reporting them.
+    #[serde(default)]
+    pub repair: bool,
+}
+
+/// A stored copy of a blob that no longer matches its integrity.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CorruptBlob {
+    /// The name of the storage backend the copy is in.
+    pub backend: String,
+    pub integrity: String,
+    /// True if the copy was replaced with a good one.
+    pub repaired: bool,
+}
+
+/// What a storage scrub found, as reported by `/v1/storage/scrub`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Scrub {
+    /// True if corrupt copies were to be repaired.
+    pub repair: bool,
+    /// How many stored copies of blobs were read back and checked.
+    pub checked: usize,
+    pub corrupt: Vec<CorruptBlob>,
+}
+
+/// What re-encrypting one storage backend under its current master key did, as reported by
+/// `/v1/storage/rotate-keys`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct KeyRotation {
+    /// The name of the storage backend.
+    pub backend: String,
+    /// The id of the master key that everything is now encrypted with.
+    pub key_id: String,
+    /// How many objects were re-encrypted.
+    pub rotated: usize,
+    /// How many objects were already encrypted with the current key.
+    pub current: usize,
+    /// The objects that couldn't be re-encrypted, by their names in the backend.
+    pub failed: Vec<String>,
+}
+
+/// What to export in a bundle from `/v1/storage/export`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BundleRequest {
+    /// Manifests to export along with their executables, each a fully-qualified name optionally
+    /// followed by a version requirement, like `sh.serval.loudify@^1.2`. A name on its own exports
+    /// the latest version.
+    #[serde(default)]
+    pub manifests: Vec<String>,
+    /// The integrities of blobs in the content-addressable store to export.
+    #[serde(default)]
+    pub blobs: Vec<String>,
+}
+
+/// What importing a bundle at `/v1/storage/import` stored.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ImportedBundle {
+    /// The manifests stored along with their executables, as `name@version`.
+    pub manifests: Vec<String>,
+    /// The integrities of the blobs stored in the content-addressable store.
+    pub blobs: Vec<String>,
+    /// The size in bytes of everything stored.
+    pub size: u64,
+}
+
+/// The header that names the file a blob in the content-addressable store was uploaded from.
+pub const FILENAME_HEADER: &str = "x-serval-filename";
+/// The header that says who or what stored a blob in the content-addressable store.
+pub const CREATOR_HEADER: &str = "x-serval-creator";
+/// The header that labels a blob in the content-addressable store, as `key=value`. Send it once for
+/// each label.
+pub const LABEL_HEADER: &str = "x-serval-label";
+/// The header that carries the integrity of the data a storage peer sends another a replica of.
+/// The peer refuses the replica if what arrives doesn't match it.
+pub const INTEGRITY_HEADER: &str = "x-serval-integrity";
+
+// How big a blob's description may be, as JSON. S3 limits how much metadata an object can carry.
+const MAX_BLOB_INFO_SIZE: usize = 1024;
+
+/// What a blob in the content-addressable store is, so that blobs such as job inputs and outputs
+/// can be told apart and found again. Uploads to `/v1/storage/data` describe a blob with their
+/// Content-Type and the `x-serval-*` headers, and reads of it respond with them. Everything is
+/// optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BlobInfo {
+    /// The media type of the blob, such as `application/json`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// The name of the file the blob was uploaded from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    /// Who or what stored the blob, such as a user or the job that produced it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+    /// Labels to find the blob by.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl BlobInfo {
+    /// Whether this says nothing about the blob at all.
+    pub fn is_empty(&self) -> bool {
+        self == &BlobInfo::default()
+    }
+
+    /// Read a blob's description from the headers of an upload. A Content-Type header is taken at
+    /// its word, whatever it says.
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self, ServalError> {
+        let text = |name| -> Result<Option<String>, ServalError> {
+            match headers.get(name) {
+                Some(value) => match value.to_str() {
+                    Ok(value) => Ok(Some(value.to_string())),
+                    Err(_) => Err(ServalError::InvalidBlobInfo(format!(
+                        "the {name} header is not printable ASCII"
+                    ))),
+                },
+                None => Ok(None),
+            }
+        };
+        let mut info = BlobInfo {
+            content_type: text(header::CONTENT_TYPE.as_str())?,
+            filename: text(FILENAME_HEADER)?,
+            creator: text(CREATOR_HEADER)?,
+            labels: BTreeMap::new(),
+        };
+        for label in headers.get_all(LABEL_HEADER) {
+            let Some((key, value)) = label.to_str().ok().and_then(|label| label.split_once('='))
+            else {
+                return Err(ServalError::InvalidBlobInfo(format!(
+                    "labels are sent as `{LABEL_HEADER}: key=value`"
+                )));
+            };
+            info.labels
+                .insert(key.trim().to_string(), value.trim().to_string());
+        }
+        info.validate()?;
+        Ok(info)
+    }
+
+    /// The headers that describe a blob, for uploading it or responding with it. Content-Type is
+    /// left out if the description doesn't say.
+    pub fn to_headers(&self) -> Result<HeaderMap, ServalError> {
+        self.validate()?;
+        let mut headers = HeaderMap::new();
+        let value = |text: &str| {
+            HeaderValue::from_str(text).map_err(|_| {
+                ServalError::InvalidBlobInfo(format!("`{text}` can't be sent as a header"))
+            })
+        };
+        if let Some(content_type) = &self.content_type {
+            headers.insert(header::CONTENT_TYPE, value(content_type)?);
+        }
+        if let Some(filename) = &self.filename {
+            headers.insert(FILENAME_HEADER, value(filename)?);
+        }
+        if let Some(creator) = &self.creator {
+            headers.insert(CREATOR_HEADER, value(creator)?);
+        }
+        for (key, label) in &self.labels {
+            headers.append(LABEL_HEADER, value(&format!("{key}={label}"))?);
+        }
+        Ok(headers)
+    }
+
+    /// Check that this description can be recorded wherever blobs are stored, and sent back in
+    /// headers: everything in it must be printable ASCII, label keys can't contain `=`, and it
+    /// must be small.
+    pub fn validate(&self) -> Result<(), ServalError> {
+        let printable = |text: &str| text.chars().all(|c| c.is_ascii_graphic() || c == ' ');
+        let fields = [&self.content_type, &self.filename, &self.creator];
+        let texts = fields.into_iter().flatten().chain(self.labels.values());
+        if let Some(text) = texts.into_iter().find(|text| !printable(text)) {
+            return Err(ServalError::InvalidBlobInfo(format!(
+                "`{text}` is not printable ASCII"
+            )));
+        }
+        if let Some(key) = self
+            .labels
+            .keys()
+            .find(|key| key.is_empty() || !key.chars().all(|c| c.is_ascii_graphic() && c != '='))
+        {
+            return Err(ServalError::InvalidBlobInfo(format!(
+                "`{key}` is not a label key; keys are printable ASCII, without spaces or `=`"
+            )));
+        }
+        let size = serde_json::to_vec(self).map_or(usize::MAX, |json| json.len());
+        if size > MAX_BLOB_INFO_SIZE {
+            return Err(ServalError::InvalidBlobInfo(format!(
+                "descriptions of blobs may be at most {MAX_BLOB_INFO_SIZE} bytes as JSON"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether the blob has the given label: `key` to match any value, or `key=value`.
+    pub fn has_label(&self, label: &str) -> bool {
+        match label.split_once('=') {
+            Some((key, value)) => {
+                self.labels.get(key.trim()).map(String::as_str) == Some(value.trim())
+            }
+            None => self.labels.contains_key(label.trim()),
+        }
+    }
+}
+
+/// A blob in the content-addressable store, along with its description, as listed by
+/// `/v1/storage/blobs`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DescribedBlob {
+    pub integrity: String,
+    #[serde(flatten)]
+    pub info: BlobInfo,
+}
+
+/// Query parameters for the `/v1/storage/blobs` listing of described blobs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BlobQuery {
+    /// Only list blobs with this label: `key` for any value, or `key=value`. Without it, every blob
+    /// with a description is listed.
+    pub label: Option<String>,
+}
+
+/// Query parameters for uploads to the content-addressable store at `/v1/storage/data`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UploadQuery {
+    /// The manifest namespace whose storage quota the upload counts against. Uploads that don't
+    /// name one share a quota.
+    pub namespace: Option<String>,
+}
+
+/// Query parameters for storing an executable at `/v1/storage/manifests/:name/executable/:version`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ExecutableQuery {
+    /// The integrity of a blob already in the content-addressable store to use as the executable,
+    /// instead of reading it from the request body.
+    pub integrity: Option<String>,
+}
+
+/// Query parameters for patches between stored blobs at `/v1/storage/diffs`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DiffQuery {
+    /// The integrity of the blob the patch applies to.
+    pub from: String,
+    /// The integrity of the blob the patch produces.
+    pub to: String,
+}
+
+/// How much a namespace has stored and how much it may store, as listed by `/v1/storage/usage`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct NamespaceUsage {
+    pub namespace: String,
+    /// The total size of everything stored for this namespace, in bytes.
+    pub bytes: u64,
+    /// How many manifests, executables and blobs are stored for this namespace.
+    pub objects: u64,
+    /// The most bytes this namespace may store, if it is limited.
+    pub byte_quota: Option<u64>,
+    /// The most objects this namespace may store, if it is limited.
+    pub object_quota: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(instance_id: &str, roles: Vec<ServalRole>, latency_ms: Option<f64>) -> MeshMember {
+        MeshMember {
+            instance_id: instance_id.to_string(),
+            address: IpAddr::from([127, 0, 0, 1]),
+            http_address: None,
+            roles,
+            capabilities: Vec::new(),
+            latency_ms,
+        }
+    }
+
+    fn ids(members: &[MeshMember]) -> Vec<&str> {
+        members
+            .iter()
+            .map(|member| member.instance_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn peer_queries_filter_sort_and_count() {
+        let members = vec![
+            member("c", vec![ServalRole::Runner], None),
+            member(
+                "a",
+                vec![ServalRole::Runner, ServalRole::Storage],
+                Some(4.5),
+            ),
+            member("d", vec![ServalRole::Storage], Some(0.5)),
+            member("b", vec![ServalRole::Runner], Some(1.25)),
+        ];
+
+        let everyone = PeerQuery::default().apply(members.clone()).unwrap();
+        assert_eq!(ids(&everyone), vec!["c", "a", "d", "b"]);
+
+        let fastest = PeerQuery::new(Some(3), &[], Some(PeerSort::Latency));
+        assert_eq!(
+            ids(&fastest.apply(members.clone()).unwrap()),
+            vec!["d", "b", "a"]
+        );
+
+        let runners = PeerQuery::new(None, &[ServalRole::Runner], Some(PeerSort::InstanceId));
+        assert_eq!(
+            ids(&runners.apply(members.clone()).unwrap()),
+            vec!["a", "b", "c"]
+        );
+
+        let both = PeerQuery::new(None, &[ServalRole::Runner, ServalRole::Storage], None);
+        assert_eq!(both.roles.as_deref(), Some("runner,storage"));
+        assert_eq!(ids(&both.apply(members.clone()).unwrap()), vec!["a"]);
+
+        let bogus = PeerQuery {
+            roles: Some(String::from("runner,janitor")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            bogus.apply(members),
+            Err(ServalError::InvalidRole(_))
+        ));
+    }
+
+    #[test]
+    fn manifest_queries_filter_and_page() {
+        let names: Vec<String> = [
+            "sh.serval.loudify",
+            "sh.serval.birdfeeder",
+            "com.example.loudify",
+            "sh.serval.extra.tool",
+            "sh.serval.lower",
+        ]
+        .iter()
+        .map(|name| name.to_string())
+        .collect();
+
+        let (page, total) = ManifestQuery::default().apply(names.clone());
+        assert_eq!(total, 5);
+        assert_eq!(page[0], "com.example.loudify");
+
+        let in_namespace = ManifestQuery {
+            namespace: Some("sh.serval".to_string()),
+            ..Default::default()
+        };
+        let (page, total) = in_namespace.apply(names.clone());
+        assert_eq!(total, 3);
+        assert_eq!(
+            page,
+            vec![
+                "sh.serval.birdfeeder",
+                "sh.serval.loudify",
+                "sh.serval.lower"
+            ]
+        );
+
+        let paged = ManifestQuery {
+            prefix: Some("sh.serval.lo".to_string()),
+            offset: Some(1),
+            limit: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(paged.apply(names), (vec!["sh.serval.lower".to_string()], 2));
+    }
+
+    #[test]
+    fn blob_descriptions_go_through_headers_and_find_labels() {
+        let info = BlobInfo {
+            content_type: Some("image/png".to_string()),
+            filename: Some("cat picture.png".to_string()),
+            creator: Some("sh.serval.loudify@1.0.0".to_string()),
+            labels: BTreeMap::from([
+                ("kind".to_string(), "output".to_string()),
+                ("run".to_string(), "a=b".to_string()),
+            ]),
+        };
+        let headers = info.to_headers().unwrap();
+        assert_eq!(headers.get_all(LABEL_HEADER).iter().count(), 2);
+        assert_eq!(BlobInfo::from_headers(&headers).unwrap(), info);
+        assert!(BlobInfo::from_headers(&HeaderMap::new())
+            .unwrap()
+            .is_empty());
+
+        assert!(info.has_label("kind"));
+        assert!(info.has_label("kind=output"));
+        assert!(info.has_label("run=a=b"));
+        assert!(!info.has_label("kind=input"));
+        assert!(!info.has_label("input"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(LABEL_HEADER, HeaderValue::from_static("no value"));
+        assert!(BlobInfo::from_headers(&headers).is_err());
+        for labels in [("", "empty"), ("spaced key", "x")] {
+            let mut bad = info.clone();
+            bad.labels
+                .insert(labels.0.to_string(), labels.1.to_string());
+            assert!(bad.validate().is_err());
         }
+        let unprintable = BlobInfo {
+            filename: Some("caf\u{e9}.txt".to_string()),
+            ..Default::default()
+        };
+        assert!(unprintable.to_headers().is_err());
+        let huge = BlobInfo {
+            creator: Some("x".repeat(MAX_BLOB_INFO_SIZE)),
+            ..Default::default()
+        };
+        assert!(huge.validate().is_err());
     }
 }
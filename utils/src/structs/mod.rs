@@ -10,6 +10,7 @@ use uuid::Uuid;
 use crate::errors::ServalError;
 
 pub mod api;
+pub mod versions;
 
 /// The results of running a Wasm executable.
 #[derive(Debug)]
@@ -29,7 +30,7 @@ pub struct Manifest {
     name: String,
     /// The namespace this Wasm manifest belongs to.
     namespace: String,
-    /// A semver-compatible version string. Semver not yet enforced.
+    /// A semantic version string. Enforced when the manifest is stored.
     version: String,
     /// Path to a compiled Wasm exectuable.
     binary: PathBuf,
@@ -99,6 +100,22 @@ impl Manifest {
         &self.version
     }
 
+    /// The namespace this manifest belongs to.
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// Whether a manifest could belong to the given namespace. Namespaces become part of storage
+    /// keys, which are shaped like paths, so they can't be empty or include a slash; and storage
+    /// keeps its own records under keys that start with an underscore, so they can't either.
+    pub fn is_valid_namespace(namespace: &str) -> bool {
+        !namespace.is_empty() && !namespace.contains('/') && !namespace.starts_with('_')
+    }
+
     /// Get the fully-qualified-by-namespace name for this job type manifest.
     pub fn fq_name(&self) -> String {
         let name = self.name.to_ascii_lowercase();
@@ -115,6 +132,35 @@ impl Manifest {
         Manifest::make_manifest_key(&self.fq_name())
     }
 
+    /// Given a name and a version but no manifest, build the key for that version of the manifest.
+    /// Every version we store is kept under its own key; the unversioned key holds the latest.
+    pub fn make_versioned_manifest_key(name: &str, version: &str) -> String {
+        format!("{name}.{version}.manifest.toml")
+    }
+
+    /// Get the storage key for this version of this manifest.
+    pub fn versioned_manifest_key(&self) -> String {
+        Manifest::make_versioned_manifest_key(&self.fq_name(), &self.version)
+    }
+
+    /// The prefix shared by the keys of every version of the named manifest, and of its
+    /// executables. Namespaces may contain dots, so other manifests' keys can share it too; check
+    /// what a key holds with parse_manifest_key() and parse_executable_key().
+    pub fn make_key_prefix(name: &str) -> String {
+        format!("{name}.")
+    }
+
+    /// Work out which manifest a storage key holds, if it holds one: its fully-qualified name, plus
+    /// the version for versioned keys.
+    pub fn parse_manifest_key(key: &str) -> Option<(String, Option<String>)> {
+        let rest = key.strip_suffix(".manifest.toml")?;
+        if let Some((name, version)) = split_version(rest) {
+            return Some((name.to_string(), Some(version.to_string())));
+        }
+        let (_, name) = rest.rsplit_once('.')?;
+        is_name(name).then(|| (rest.to_string(), None))
+    }
+
     /// Given a name and a version but no manifest, build an executable key.
     pub fn make_executable_key(name: &str, version: &str) -> String {
         format!("{name}.{version}.wasm")
@@ -124,6 +170,28 @@ impl Manifest {
     pub fn executable_key(&self) -> String {
         Manifest::make_executable_key(&self.fq_name(), &self.version)
     }
+
+    /// Work out which executable a storage key holds, if it holds one: the fully-qualified name and
+    /// version of its manifest.
+    pub fn parse_executable_key(key: &str) -> Option<(String, String)> {
+        let (name, version) = split_version(key.strip_suffix(".wasm")?)?;
+        Some((name.to_string(), version.to_string()))
+    }
+}
+
+// Split `namespace.name.version` into the fully-qualified name and the version. Names are only
+// letters and underscores, and versions are semantic versions, so the version starts after the
+// first dot that follows something shaped like a name.
+fn split_version(key: &str) -> Option<(&str, &str)> {
+    key.match_indices('.').find_map(|(dot, _)| {
+        let (name, version) = (&key[..dot], &key[dot + 1..]);
+        let (_, last) = name.rsplit_once('.')?;
+        (is_name(last) && versions::parse_version(version).is_ok()).then_some((name, version))
+    })
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphabetic() || c == '_')
 }
 
 impl Display for Manifest {
@@ -175,6 +243,11 @@ impl<'de> Deserialize<'de> for Manifest {
                 "Manifest names may include only alphanumeric characters plus _ (underscore).",
             ));
         }
+        if !Manifest::is_valid_namespace(&inner.namespace) {
+            return Err(D::Error::custom(
+                "Manifest namespaces must not be empty, start with _, or include a / (slash).",
+            ));
+        }
 
         Ok(Manifest {
             name: inner.name,
@@ -333,4 +406,76 @@ required_permissions = []
         let result = Manifest::from_string(valid_manifest);
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn manifest_invalid_namespace() {
+        for namespace in ["", "sh/serval", "_usage", "_pins", "_charges.sh"] {
+            let manifest = format!(
+                r###"
+name = "loudify"
+namespace = "{namespace}"
+binary = "/tmp/loudify.wasm"
+version = "1"
+description = "SHOUT SHOUT LET IT ALL OUT"
+"###
+            );
+            assert!(Manifest::from_string(&manifest).is_err(), "{namespace}");
+            assert!(!Manifest::is_valid_namespace(namespace), "{namespace}");
+        }
+        assert!(Manifest::is_valid_namespace("sh.serval"));
+        assert!(Manifest::is_valid_namespace("sh_serval"));
+    }
+
+    #[test]
+    fn storage_keys_name_the_manifest_and_version_they_hold() {
+        let manifest = Manifest::from_string(
+            r###"
+name = "loudify"
+namespace = "sh.serval"
+binary = "/tmp/loudify.wasm"
+version = "1.0.2"
+description = "SHOUT SHOUT LET IT ALL OUT"
+"###,
+        )
+        .unwrap();
+        // These are the keys manifests and executables have always been stored under.
+        assert_eq!(manifest.manifest_key(), "sh.serval.loudify.manifest.toml");
+        assert_eq!(manifest.executable_key(), "sh.serval.loudify.1.0.2.wasm");
+        assert_eq!(
+            manifest.versioned_manifest_key(),
+            "sh.serval.loudify.1.0.2.manifest.toml"
+        );
+
+        assert_eq!(
+            Manifest::parse_manifest_key(&manifest.manifest_key()),
+            Some(("sh.serval.loudify".to_string(), None))
+        );
+        assert_eq!(
+            Manifest::parse_manifest_key(&manifest.versioned_manifest_key()),
+            Some(("sh.serval.loudify".to_string(), Some("1.0.2".to_string())))
+        );
+        assert_eq!(
+            Manifest::parse_manifest_key(&manifest.executable_key()),
+            None
+        );
+        assert_eq!(
+            Manifest::parse_executable_key(&manifest.executable_key()),
+            Some(("sh.serval.loudify".to_string(), "1.0.2".to_string()))
+        );
+        assert_eq!(
+            Manifest::parse_executable_key("sh.serval.loudify.2.0.0-beta.1.wasm"),
+            Some(("sh.serval.loudify".to_string(), "2.0.0-beta.1".to_string()))
+        );
+
+        // Namespaces can look a lot like versions without confusing anything.
+        assert_eq!(
+            Manifest::parse_manifest_key("v1.2.3.loudify.4.5.6.manifest.toml"),
+            Some(("v1.2.3.loudify".to_string(), Some("4.5.6".to_string())))
+        );
+        assert_eq!(
+            Manifest::parse_manifest_key("v1.2.3.loudify.manifest.toml"),
+            Some(("v1.2.3.loudify".to_string(), None))
+        );
+        assert_eq!(Manifest::parse_manifest_key("_pins/sha256/abc"), None);
+    }
 }
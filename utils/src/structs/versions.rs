@@ -0,0 +1,165 @@
+//! Semantic versions for manifests, and the requirements callers use to choose between them.
+
+use std::cmp::Ordering;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use semver::{Version, VersionReq};
+
+use crate::errors::ServalError;
+
+/// Parse a manifest version, which must be a semantic version such as `1.2.3` or `2.0.0-beta.1`.
+pub fn parse_version(version: &str) -> Result<Version, ServalError> {
+    Version::parse(version).map_err(|e| {
+        ServalError::InvalidVersion(format!("`{version}` is not a semantic version; {e}"))
+    })
+}
+
+/// Order version strings by semver precedence. Strings that aren't valid versions sort after all
+/// the ones that are, alphabetically.
+pub fn compare_versions(left: &str, right: &str) -> Ordering {
+    match (Version::parse(left), Version::parse(right)) {
+        (Ok(left), Ok(right)) => left.cmp(&right),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => left.cmp(right),
+    }
+}
+
+/// Which version of a job a caller wants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionRequirement {
+    /// The highest stored version. Pre-releases are only chosen if nothing else is stored.
+    Latest,
+    /// The highest stored version matching a semver requirement: `1.2` (which means `^1.2`), `^2`,
+    /// `~1.4.1`, `=1.0.0`, and so on.
+    Matching(VersionReq),
+}
+
+impl VersionRequirement {
+    /// Split a job specification like `sh.serval.loudify@^1.2` into the fully-qualified job name and
+    /// the version requirement. A name on its own asks for the latest version.
+    pub fn split(spec: &str) -> Result<(&str, VersionRequirement), ServalError> {
+        match spec.split_once('@') {
+            Some((name, requirement)) => Ok((name, requirement.parse()?)),
+            None => Ok((spec, VersionRequirement::Latest)),
+        }
+    }
+
+    /// Choose the highest of the given versions that satisfies this requirement, ignoring any that
+    /// aren't valid semantic versions.
+    pub fn resolve<'a>(&self, versions: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+        let candidates = versions
+            .into_iter()
+            .filter_map(|version| Some((Version::parse(version).ok()?, version)));
+        match self {
+            VersionRequirement::Latest => candidates
+                .max_by(|(left, _), (right, _)| {
+                    // A release beats any pre-release, whatever their numbers.
+                    (left.pre.is_empty(), left).cmp(&(right.pre.is_empty(), right))
+                })
+                .map(|(_, version)| version),
+            VersionRequirement::Matching(requirement) => candidates
+                .filter(|(parsed, _)| requirement.matches(parsed))
+                .max_by(|(left, _), (right, _)| left.cmp(right))
+                .map(|(_, version)| version),
+        }
+    }
+}
+
+impl FromStr for VersionRequirement {
+    type Err = ServalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" | "latest" => Ok(VersionRequirement::Latest),
+            requirement => VersionReq::parse(requirement)
+                .map(VersionRequirement::Matching)
+                .map_err(|e| {
+                    ServalError::InvalidVersion(format!(
+                        "`{requirement}` is not a version requirement; {e}"
+                    ))
+                }),
+        }
+    }
+}
+
+impl Display for VersionRequirement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionRequirement::Latest => write!(f, "latest"),
+            VersionRequirement::Matching(requirement) => write!(f, "{requirement}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STORED: [&str; 7] = [
+        "1.2.0",
+        "1.10.3",
+        "1.2.7",
+        "2.0.0",
+        "2.1.0-beta.1",
+        "3.0.0-rc.1",
+        "not-a-version",
+    ];
+
+    fn resolve(spec: &str) -> Option<&'static str> {
+        let (_, requirement) = VersionRequirement::split(spec).unwrap();
+        requirement.resolve(STORED)
+    }
+
+    #[test]
+    fn requirements_pick_the_highest_match() {
+        assert_eq!(resolve("sh.serval.loudify"), Some("2.0.0"));
+        assert_eq!(resolve("sh.serval.loudify@latest"), Some("2.0.0"));
+        assert_eq!(resolve("sh.serval.loudify@1.2"), Some("1.10.3"));
+        assert_eq!(resolve("sh.serval.loudify@~1.2"), Some("1.2.7"));
+        assert_eq!(resolve("sh.serval.loudify@=1.2.0"), Some("1.2.0"));
+        assert_eq!(resolve("sh.serval.loudify@^2"), Some("2.0.0"));
+        assert_eq!(
+            resolve("sh.serval.loudify@>=2.1.0-beta"),
+            Some("2.1.0-beta.1")
+        );
+        assert_eq!(resolve("sh.serval.loudify@^4"), None);
+
+        // With nothing but pre-releases to choose from, the latest is the highest of those.
+        let latest = VersionRequirement::Latest.resolve(["0.1.0-alpha", "0.1.0-beta"]);
+        assert_eq!(latest, Some("0.1.0-beta"));
+    }
+
+    #[test]
+    fn specifications_are_validated() {
+        let (name, requirement) = VersionRequirement::split("sh.serval.loudify@^1.2").unwrap();
+        assert_eq!(name, "sh.serval.loudify");
+        assert_eq!(requirement.to_string(), "^1.2");
+
+        assert!(matches!(
+            VersionRequirement::split("sh.serval.loudify@banana"),
+            Err(ServalError::InvalidVersion(_))
+        ));
+        assert!(parse_version("1.2").is_err());
+        assert!(parse_version("1.2.3-rc.1").is_ok());
+    }
+
+    #[test]
+    fn versions_sort_by_precedence() {
+        let mut versions = STORED.to_vec();
+        versions.sort_by(|left, right| compare_versions(left, right));
+        assert_eq!(
+            versions,
+            vec![
+                "1.2.0",
+                "1.2.7",
+                "1.10.3",
+                "2.0.0",
+                "2.1.0-beta.1",
+                "3.0.0-rc.1",
+                "not-a-version"
+            ]
+        );
+    }
+}